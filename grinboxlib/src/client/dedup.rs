@@ -0,0 +1,189 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::client::{CloseReason, GrinboxSubscriptionHandler};
+use crate::types::{GrinboxAddress, Slate, TxProof};
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+const DEFAULT_MAX_AGE_SECS: u64 = 3600;
+
+/// How long slates are remembered to spot them being delivered again, by count and by age.
+/// Either limit can be lifted, but lifting both lets the window grow for as long as the
+/// subscription lives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DedupWindow {
+    pub max_entries: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for DedupWindow {
+    fn default() -> DedupWindow {
+        DedupWindow {
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            max_age: Some(Duration::from_secs(DEFAULT_MAX_AGE_SECS)),
+        }
+    }
+}
+
+impl DedupWindow {
+    pub fn new() -> DedupWindow {
+        DedupWindow::default()
+    }
+
+    pub fn with_max_entries(mut self, max_entries: Option<usize>) -> DedupWindow {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> DedupWindow {
+        self.max_age = max_age;
+        self
+    }
+}
+
+/// The slates seen within a `DedupWindow`, oldest first.
+struct SeenSlates {
+    window: DedupWindow,
+    order: VecDeque<(String, Instant)>,
+    keys: HashSet<String>,
+}
+
+impl SeenSlates {
+    fn new(window: DedupWindow) -> SeenSlates {
+        SeenSlates {
+            window,
+            order: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Records `key` as seen at `now`, returning whether it had already been seen within the
+    /// window. A duplicate does not push its entry's expiry back.
+    fn seen(&mut self, key: String, now: Instant) -> bool {
+        self.expire(now);
+        if self.keys.contains(&key) {
+            return true;
+        }
+
+        self.keys.insert(key.clone());
+        self.order.push_back((key, now));
+        if let Some(max_entries) = self.window.max_entries {
+            while self.order.len() > max_entries {
+                self.pop_oldest();
+            }
+        }
+        false
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let max_age = match self.window.max_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        while let Some(&(_, seen_at)) = self.order.front() {
+            if now < seen_at || now.duration_since(seen_at) < max_age {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((key, _)) = self.order.pop_front() {
+            self.keys.remove(&key);
+        }
+    }
+}
+
+/// Wraps a subscription handler so a slate delivered again within `window`, as happens when the
+/// relay redelivers messages after a reconnect, reaches `inner` only once. Slates are told apart
+/// by sender and slate id.
+pub struct DedupingHandler<H: GrinboxSubscriptionHandler> {
+    inner: H,
+    seen: Mutex<SeenSlates>,
+}
+
+impl<H: GrinboxSubscriptionHandler> DedupingHandler<H> {
+    pub fn new(inner: H, window: DedupWindow) -> DedupingHandler<H> {
+        DedupingHandler {
+            inner,
+            seen: Mutex::new(SeenSlates::new(window)),
+        }
+    }
+
+    fn is_duplicate(&self, from: &GrinboxAddress, slate: &Slate) -> bool {
+        let key = format!("{}/{}", from.stripped(), slate.id);
+        let duplicate = self.seen.lock().unwrap().seen(key, Instant::now());
+        if duplicate {
+            debug!("dropping slate {} from {} delivered again", slate.id, from.stripped());
+        }
+        duplicate
+    }
+}
+
+impl<H: GrinboxSubscriptionHandler> GrinboxSubscriptionHandler for DedupingHandler<H> {
+    fn on_open(&self) {
+        self.inner.on_open()
+    }
+
+    fn on_slate(&self, from: &GrinboxAddress, to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>) {
+        if !self.is_duplicate(from, slate) {
+            self.inner.on_slate(from, to, slate, proof)
+        }
+    }
+
+    fn on_close(&self, result: CloseReason) {
+        self.inner.on_close(result)
+    }
+
+    fn on_dropped(&self) {
+        self.inner.on_dropped()
+    }
+
+    fn on_reestablished(&self) {
+        self.inner.on_reestablished()
+    }
+
+    fn acknowledge_slates(&self) -> bool {
+        self.inner.acknowledge_slates()
+    }
+
+    fn on_slate_acknowledged(&self, from: &GrinboxAddress, slate_id: &Uuid) {
+        self.inner.on_slate_acknowledged(from, slate_id)
+    }
+
+    fn dispatch_slate(&self, from: &GrinboxAddress, to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>) {
+        if !self.is_duplicate(from, slate) {
+            self.inner.dispatch_slate(from, to, slate, proof)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_suppressed_only_within_window() {
+        let start = Instant::now();
+        let window = DedupWindow::new()
+            .with_max_entries(Some(2))
+            .with_max_age(Some(Duration::from_secs(60)));
+        let mut seen = SeenSlates::new(window);
+
+        assert!(!seen.seen("a".to_string(), start));
+        assert!(seen.seen("a".to_string(), start + Duration::from_secs(30)));
+
+        // Past the age limit the slate is delivered again.
+        assert!(!seen.seen("a".to_string(), start + Duration::from_secs(90)));
+
+        // Pushed out of the window by newer slates.
+        let later = start + Duration::from_secs(100);
+        assert!(!seen.seen("b".to_string(), later));
+        assert!(!seen.seen("c".to_string(), later));
+        assert!(!seen.seen("a".to_string(), later));
+        assert!(seen.seen("c".to_string(), later));
+    }
+}
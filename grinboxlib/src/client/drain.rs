@@ -0,0 +1,145 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::client::{CloseReason, GrinboxSubscriber, GrinboxSubscriptionHandler};
+use crate::error::{ErrorKind, Result};
+use crate::types::{GrinboxAddress, Slate, TxProof};
+
+enum DrainEvent {
+    Opened,
+    Slate(GrinboxAddress, Slate, Option<TxProof>),
+    Closed,
+}
+
+struct DrainHandler {
+    sender: mpsc::Sender<DrainEvent>,
+}
+
+impl GrinboxSubscriptionHandler for DrainHandler {
+    fn on_open(&self) {
+        let _ = self.sender.send(DrainEvent::Opened);
+    }
+
+    fn on_slate(&self, from: &GrinboxAddress, _to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>) {
+        let proof = proof.map(|proof| proof.clone());
+        let _ = self.sender.send(DrainEvent::Slate(from.clone(), slate.clone(), proof));
+    }
+
+    fn on_close(&self, _result: CloseReason) {
+        let _ = self.sender.send(DrainEvent::Closed);
+    }
+
+    fn on_dropped(&self) {
+        let _ = self.sender.send(DrainEvent::Closed);
+    }
+
+    fn on_reestablished(&self) {}
+}
+
+/// Subscribes, collects the slates the relay had queued and unsubscribes again, for wallets
+/// that process slates in batches rather than through a `GrinboxSubscriptionHandler`. The relay
+/// sends queued slates right after subscribing, so the queue is taken to be empty once no slate
+/// arrived for `timeout`.
+///
+/// `subscribe` runs on a clone of `subscriber`, so it may block until `unsubscribe` is called
+/// on the original.
+pub fn drain<S>(subscriber: &S, timeout: Duration) -> Result<Vec<(GrinboxAddress, Slate, Option<TxProof>)>>
+where
+    S: GrinboxSubscriber + Clone + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let handler = DrainHandler { sender: sender.clone() };
+    let mut running = subscriber.clone();
+    let subscription = std::thread::spawn(move || {
+        let result = running.subscribe(Box::new(handler));
+        let _ = sender.send(DrainEvent::Closed);
+        result
+    });
+
+    let mut slates = vec![];
+    loop {
+        match receiver.recv_timeout(timeout) {
+            Ok(DrainEvent::Opened) => {}
+            Ok(DrainEvent::Slate(from, slate, proof)) => slates.push((from, slate, proof)),
+            Ok(DrainEvent::Closed) | Err(_) => break,
+        }
+    }
+    subscriber.unsubscribe();
+
+    match subscription.join() {
+        Ok(Err(e)) => {
+            if slates.is_empty() {
+                return Err(e);
+            }
+            warn!("subscription ended with an error after draining {} slates: {}", slates.len(), e);
+        }
+        Ok(Ok(())) => {}
+        Err(_) => return Err(ErrorKind::GenericError("subscription thread panicked".to_string()).into()),
+    }
+    Ok(slates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use crate::utils::crypto::public_key_from_secret_key;
+    use crate::utils::secp::{Secp256k1, SecretKey};
+
+    /// Delivers the slates queued for it on subscribing, then stays subscribed until told not to.
+    #[derive(Clone)]
+    struct QueuedSubscriber {
+        queued: Arc<Mutex<Vec<(GrinboxAddress, Slate)>>>,
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl GrinboxSubscriber for QueuedSubscriber {
+        fn subscribe(&mut self, handler: Box<GrinboxSubscriptionHandler + Send>) -> Result<()> {
+            handler.on_open();
+            let to = address(9);
+            for (from, mut slate) in self.queued.lock().unwrap().drain(..) {
+                handler.dispatch_slate(&from, &to, &mut slate, None);
+            }
+            while !self.stopped.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            handler.on_close(CloseReason::Normal);
+            Ok(())
+        }
+
+        fn unsubscribe(&self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+
+        fn is_running(&self) -> bool {
+            !self.stopped.load(Ordering::SeqCst)
+        }
+    }
+
+    fn address(secret: u8) -> GrinboxAddress {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        GrinboxAddress::new(public_key, None, None)
+    }
+
+    #[test]
+    fn drain_collects_queued_slates_and_unsubscribes() {
+        let subscriber = QueuedSubscriber {
+            queued: Arc::new(Mutex::new(vec![
+                (address(1), Slate::blank(2)),
+                (address(2), Slate::blank(2)),
+                (address(3), Slate::blank(2)),
+            ])),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+
+        let slates = drain(&subscriber, Duration::from_millis(200)).unwrap();
+
+        let senders: Vec<GrinboxAddress> = slates.into_iter().map(|(from, _, _)| from).collect();
+        assert_eq!(senders, vec![address(1), address(2), address(3)]);
+        assert!(!subscriber.is_running());
+    }
+}
@@ -0,0 +1,865 @@
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::Stream;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use uuid::Uuid;
+use ws::util::Token;
+use ws::{connect, CloseCode, Handler, Handshake, Message, Result as WsResult, Sender};
+
+use crate::client::{
+    drain, post_and_confirm, CloseReason, GrinboxPublisher, GrinboxSubscriber, GrinboxSubscriptionHandler,
+};
+use crate::error::{Error, ErrorKind, Result};
+use crate::types::{
+    GrinboxAddress, GrinboxMessage, GrinboxRequest, GrinboxResponse, Slate, TxProof, TxProofErrorKind,
+};
+use crate::utils::crypto::{sign_challenge, Hex};
+use crate::utils::secp::SecretKey;
+
+const KEEPALIVE_TOKEN: Token = Token(1);
+const DEFAULT_KEEPALIVE_INTERVAL_MS: u64 = 30_000;
+const DEFAULT_RECONNECT_MAX_SECS: u64 = 32;
+const POST_TIMEOUT_SECS: u64 = 30;
+const REQUIRE_TLS: bool = cfg!(feature = "require-tls");
+
+/// Connects an address to its relay: posts slates signed with its key and keeps a subscription
+/// open on a background thread, reconnecting when it drops. Clones share the subscription,
+/// which is stopped once the last clone is dropped.
+#[derive(Clone)]
+pub struct GrinboxClient {
+    address: GrinboxAddress,
+    secret_key: SecretKey,
+    url: String,
+    settings: ConnectionSettings,
+    broker: Arc<GrinboxBroker>,
+}
+
+/// How a subscription's connections are kept up.
+#[derive(Clone, Copy, Debug)]
+struct ConnectionSettings {
+    keepalive_interval_ms: u64,
+    reconnect_max_secs: u64,
+    auto_reconnect: bool,
+}
+
+impl GrinboxClient {
+    /// Fails with `GenericError` when `protocol_unsecure` asks for plaintext in a build with the
+    /// `require-tls` feature.
+    pub fn new(address: &GrinboxAddress, secret_key: &SecretKey, protocol_unsecure: bool) -> Result<GrinboxClient> {
+        check_tls(protocol_unsecure, REQUIRE_TLS)?;
+        Ok(GrinboxClient {
+            address: address.clone(),
+            secret_key: secret_key.clone(),
+            url: address.relay_url(protocol_unsecure),
+            settings: ConnectionSettings {
+                keepalive_interval_ms: DEFAULT_KEEPALIVE_INTERVAL_MS,
+                reconnect_max_secs: DEFAULT_RECONNECT_MAX_SECS,
+                auto_reconnect: true,
+            },
+            broker: Arc::new(GrinboxBroker::new()),
+        })
+    }
+
+    /// Pings the relay after this many milliseconds instead of every 30 seconds, e.g. more often
+    /// behind a NAT that forgets idle connections quickly. Applies to subscriptions started after.
+    pub fn with_keepalive_interval_ms(mut self, keepalive_interval_ms: u64) -> GrinboxClient {
+        self.settings.keepalive_interval_ms = keepalive_interval_ms;
+        self
+    }
+
+    /// Caps the wait between reconnect attempts, which doubles from one second, at this many
+    /// seconds instead of 32.
+    pub fn with_reconnect_max_secs(mut self, reconnect_max_secs: u64) -> GrinboxClient {
+        self.settings.reconnect_max_secs = reconnect_max_secs;
+        self
+    }
+
+    /// With `false`, a subscription whose connection drops is closed rather than reconnected.
+    pub fn with_auto_reconnect(mut self, auto_reconnect: bool) -> GrinboxClient {
+        self.settings.auto_reconnect = auto_reconnect;
+        self
+    }
+
+    pub fn address(&self) -> &GrinboxAddress {
+        &self.address
+    }
+
+    /// Subscribes on a background thread and returns right away; `handler` hears about the
+    /// subscription from there. Fails if this client is already subscribed.
+    pub fn start(&self, handler: Box<GrinboxSubscriptionHandler + Send>) -> Result<()> {
+        self.broker.start(&self.url, &self.address, &self.secret_key, self.settings, handler)
+    }
+
+    /// Like `start`, but hands received slates out as a stream rather than to a handler. The
+    /// stream ends along with the subscription.
+    pub fn subscribe_stream(
+        &self,
+    ) -> Result<Box<dyn Stream<Item = (GrinboxAddress, Slate, TxProof), Error = Error> + Send>> {
+        let (slates, received) = unbounded();
+        self.start(Box::new(StreamHandler { slates }))?;
+        Ok(Box::new(received.map_err(|_| Error::from(ErrorKind::GrinboxWebsocketAbnormalTermination))))
+    }
+
+    /// Posts `slate` to `recipient`'s address and then confirms the relay queued it, by draining
+    /// `recipient`'s queue within `timeout` and looking for it. Only callers holding the
+    /// recipient's key can probe this way, and the probe takes whatever was queued for it, so it
+    /// is meant for tests and monitoring addresses rather than wallets.
+    pub fn post_and_probe(&self, slate: &Slate, recipient: &GrinboxClient, timeout: Duration) -> Result<()> {
+        self.post_slate(slate, recipient.address())?;
+
+        let queued = drain(recipient, timeout)?;
+        if queued
+            .iter()
+            .any(|(from, queued, _)| from == self.address() && queued.id == slate.id)
+        {
+            Ok(())
+        } else {
+            let error = format!("slate {} was not queued for {}", slate.id, recipient.address().stripped());
+            Err(ErrorKind::GenericError(error).into())
+        }
+    }
+
+    /// Closes the subscription and ends the background thread, without waiting for it.
+    pub fn stop(&self) {
+        self.broker.stop();
+    }
+}
+
+impl GrinboxPublisher for GrinboxClient {
+    fn post_slate(&self, slate: &Slate, to: &GrinboxAddress) -> Result<()> {
+        let str = serde_json::to_string(slate)
+            .map_err(|e| ErrorKind::GenericError(format!("could not serialize slate: {}", e)))?;
+        post_encrypted(&self.url, &self.address, to, str, &self.secret_key)
+    }
+}
+
+/// Feeds the slates of a subscription to the stream returned by `subscribe_stream`.
+struct StreamHandler {
+    slates: UnboundedSender<(GrinboxAddress, Slate, TxProof)>,
+}
+
+impl GrinboxSubscriptionHandler for StreamHandler {
+    fn on_open(&self) {}
+
+    fn on_slate(&self, from: &GrinboxAddress, _to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>) {
+        match proof {
+            Some(proof) => {
+                if self.slates.unbounded_send((from.clone(), slate.clone(), proof.clone())).is_err() {
+                    debug!("dropping slate {}, nobody is reading the stream", slate.id);
+                }
+            }
+            None => warn!("dropping slate {} from {} without a proof", slate.id, from.stripped()),
+        }
+    }
+
+    fn on_close(&self, _result: CloseReason) {}
+
+    fn on_dropped(&self) {}
+
+    fn on_reestablished(&self) {}
+}
+
+/// What a recipient sends back in place of a slate, encrypted the same way, once its handler
+/// took the slate with this id.
+#[derive(Serialize, Deserialize)]
+struct SlateAck {
+    acknowledged: Uuid,
+}
+
+/// Encrypts `content` to `to` and posts it from `from` through the relay at `url`, returning
+/// once the relay accepted it.
+fn post_encrypted(
+    url: &str,
+    from: &GrinboxAddress,
+    to: &GrinboxAddress,
+    content: String,
+    secret_key: &SecretKey,
+) -> Result<()> {
+    let message = GrinboxMessage::new(content, to, &to.public_key()?, secret_key)?;
+    let message = serde_json::to_string(&message)
+        .map_err(|e| ErrorKind::GenericError(format!("could not serialize message: {}", e)))?;
+    post_and_confirm(url, from, to, &message, secret_key, Duration::from_secs(POST_TIMEOUT_SECS))
+}
+
+/// The slate id acknowledged by `str` from `from`, or `None` if `str` is no acknowledgement.
+/// Only called once the signature over `str` has been checked.
+fn acknowledged_slate_id(from: &str, str: &str, secret_key: &SecretKey) -> Option<Uuid> {
+    let from = GrinboxAddress::from_str(from).ok()?;
+    let message: GrinboxMessage = serde_json::from_str(str).ok()?;
+    let key = message.key(&from.public_key().ok()?, secret_key).ok()?;
+    let decrypted = message.decrypt_with_key(&key).ok()?;
+    serde_json::from_str::<SlateAck>(&decrypted)
+        .ok()
+        .map(|ack| ack.acknowledged)
+}
+
+impl GrinboxSubscriber for GrinboxClient {
+    /// Subscribes and blocks until the subscription ends, e.g. after `unsubscribe`.
+    fn subscribe(&mut self, handler: Box<GrinboxSubscriptionHandler + Send>) -> Result<()> {
+        self.start(handler)?;
+        self.broker.wait();
+        Ok(())
+    }
+
+    fn unsubscribe(&self) {
+        self.stop();
+    }
+
+    fn is_running(&self) -> bool {
+        self.broker.is_running()
+    }
+}
+
+fn check_tls(protocol_unsecure: bool, require_tls: bool) -> Result<()> {
+    if protocol_unsecure && require_tls {
+        return Err(ErrorKind::GenericError("plaintext relays are disabled in this build".to_string()).into());
+    }
+    Ok(())
+}
+
+/// The background thread holding a client's subscription and what is needed to stop it.
+struct GrinboxBroker {
+    running: Arc<AtomicBool>,
+    sender: Arc<Mutex<Option<Sender>>>,
+    stop_signal: Mutex<Option<mpsc::Sender<()>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl GrinboxBroker {
+    fn new() -> GrinboxBroker {
+        GrinboxBroker {
+            running: Arc::new(AtomicBool::new(false)),
+            sender: Arc::new(Mutex::new(None)),
+            stop_signal: Mutex::new(None),
+            thread: Mutex::new(None),
+        }
+    }
+
+    fn start(
+        &self,
+        url: &str,
+        address: &GrinboxAddress,
+        secret_key: &SecretKey,
+        settings: ConnectionSettings,
+        handler: Box<GrinboxSubscriptionHandler + Send>,
+    ) -> Result<()> {
+        let mut thread = self.thread.lock().unwrap();
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(ErrorKind::GenericError("already subscribed".to_string()).into());
+        }
+        if let Some(previous) = thread.take() {
+            let _ = previous.join();
+        }
+
+        let (stop_signal, stopped) = mpsc::channel();
+        *self.stop_signal.lock().unwrap() = Some(stop_signal);
+
+        let subscription = Subscription {
+            url: url.to_string(),
+            address: address.clone(),
+            secret_key: secret_key.clone(),
+            settings,
+            running: self.running.clone(),
+            sender: self.sender.clone(),
+        };
+        *thread = Some(std::thread::spawn(move || subscription.run(handler, stopped)));
+        Ok(())
+    }
+
+    /// Shuts the open connection down, if any, and wakes the reconnect loop if it is waiting,
+    /// so the background thread ends promptly either way.
+    fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.shutdown();
+        }
+        self.stop_signal.lock().unwrap().take();
+    }
+
+    fn wait(&self) {
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            // A handler holding the last clone of its own client drops it on this very thread.
+            if thread.thread().id() != std::thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for GrinboxBroker {
+    fn drop(&mut self) {
+        self.stop();
+        self.wait();
+    }
+}
+
+struct Subscription {
+    url: String,
+    address: GrinboxAddress,
+    secret_key: SecretKey,
+    settings: ConnectionSettings,
+    running: Arc<AtomicBool>,
+    sender: Arc<Mutex<Option<Sender>>>,
+}
+
+impl Subscription {
+    /// Keeps the subscription up until stopped, waiting `reconnect_delay` between attempts. Only
+    /// a first connection that never opens, or a dropped one when reconnecting is off, ends it
+    /// with an error.
+    fn run(self, handler: Box<GrinboxSubscriptionHandler + Send>, stopped: mpsc::Receiver<()>) {
+        let handler: Rc<Box<GrinboxSubscriptionHandler + Send>> = Rc::new(handler);
+        let mut retries: u32 = 0;
+        let mut was_open = false;
+        let mut close_reason = CloseReason::Normal;
+
+        loop {
+            let opened = Rc::new(Cell::new(false));
+            let result = connect(self.url.as_str(), |out: Sender| {
+                *self.sender.lock().unwrap() = Some(out.clone());
+                // `stop` may have run before the sender was stored for it to shut down.
+                if !self.running.load(Ordering::SeqCst) {
+                    let _ = out.shutdown();
+                }
+                GrinboxWebsocketClient {
+                    sender: out,
+                    url: self.url.clone(),
+                    handler: handler.clone(),
+                    address: self.address.clone(),
+                    secret_key: self.secret_key.clone(),
+                    keepalive_interval_ms: self.settings.keepalive_interval_ms,
+                    opened: opened.clone(),
+                    reestablished: was_open,
+                }
+            });
+            self.sender.lock().unwrap().take();
+
+            if !self.running.load(Ordering::SeqCst) {
+                break;
+            }
+
+            if opened.get() && !self.settings.auto_reconnect {
+                close_reason = CloseReason::Abnormal(ErrorKind::GrinboxWebsocketAbnormalTermination.into());
+                break;
+            } else if opened.get() {
+                was_open = true;
+                retries = 0;
+                handler.on_dropped();
+            } else if !was_open {
+                let error = match result {
+                    Err(e) => ErrorKind::GenericError(format!("could not connect to {}: {}", self.url, e)),
+                    Ok(()) => ErrorKind::GrinboxWebsocketAbnormalTermination,
+                };
+                close_reason = CloseReason::Abnormal(error.into());
+                break;
+            }
+
+            let delay = reconnect_delay(retries, self.settings.reconnect_max_secs);
+            retries = retries.saturating_add(1);
+            match stopped.recv_timeout(delay) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        }
+
+        self.running.store(false, Ordering::SeqCst);
+        handler.on_close(close_reason);
+    }
+}
+
+/// How long to wait before reconnecting after `retries` failed attempts: `2^retries` seconds,
+/// but never more than `max_secs`.
+fn reconnect_delay(retries: u32, max_secs: u64) -> Duration {
+    Duration::from_secs(std::cmp::min(max_secs, 1u64 << std::cmp::min(retries, 63)))
+}
+
+/// Handles one connection of a subscription: answers the relay's challenge with a subscribe
+/// request and hands the slates it delivers to the subscription handler.
+struct GrinboxWebsocketClient {
+    sender: Sender,
+    url: String,
+    handler: Rc<Box<GrinboxSubscriptionHandler + Send>>,
+    address: GrinboxAddress,
+    secret_key: SecretKey,
+    keepalive_interval_ms: u64,
+    opened: Rc<Cell<bool>>,
+    reestablished: bool,
+}
+
+impl GrinboxWebsocketClient {
+    fn subscribe(&self, challenge: &str) -> WsResult<()> {
+        let signature = match sign_challenge(challenge, &self.secret_key) {
+            Ok(signature) => signature.to_hex(),
+            Err(e) => {
+                error!("could not sign challenge: {}", e);
+                return self.sender.close(CloseCode::Error);
+            }
+        };
+        let request = GrinboxRequest::Subscribe {
+            address: self.address.public_key.clone(),
+            signature,
+        };
+        self.sender.send(serde_json::to_string(&request).unwrap())
+    }
+
+    fn deliver(&self, from: String, to: Option<String>, str: String, signature: String, challenge: String) {
+        let sender = from.clone();
+        let message = str.clone();
+        let (mut slate, mut proof) =
+            match TxProof::from_response(from, str, challenge, signature, &self.secret_key, Some(&self.address)) {
+                Ok(received) => received,
+                Err(TxProofErrorKind::ParseSlate) => {
+                    match acknowledged_slate_id(&sender, &message, &self.secret_key) {
+                        Some(slate_id) => match GrinboxAddress::from_str(&sender) {
+                            Ok(from) => self.handler.on_slate_acknowledged(&from, &slate_id),
+                            Err(_) => error!("could not parse address {}", sender),
+                        },
+                        None => error!("could not parse slate from {}", sender),
+                    }
+                    return;
+                }
+                Err(e) => {
+                    error!("could not verify slate: {:?}", e);
+                    return;
+                }
+            };
+
+        let to = to
+            .and_then(|to| GrinboxAddress::from_str(&to).ok())
+            .unwrap_or_else(|| self.address.clone());
+        let from = proof.address.clone();
+        self.handler.dispatch_slate(&from, &to, &mut slate, Some(&mut proof));
+
+        if self.handler.acknowledge_slates() {
+            self.acknowledge(&from, &slate.id);
+        }
+    }
+
+    /// Posts the acknowledgement on its own thread, so waiting for the relay to accept it does
+    /// not hold up this connection.
+    fn acknowledge(&self, to: &GrinboxAddress, slate_id: &Uuid) {
+        let ack = serde_json::to_string(&SlateAck { acknowledged: *slate_id }).unwrap();
+        let url = self.url.clone();
+        let from = self.address.clone();
+        let to = to.clone();
+        let secret_key = self.secret_key.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = post_encrypted(&url, &from, &to, ack, &secret_key) {
+                error!("could not acknowledge slate to {}: {}", to.stripped(), e);
+            }
+        });
+    }
+}
+
+impl Handler for GrinboxWebsocketClient {
+    fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+        self.opened.set(true);
+        if self.reestablished {
+            self.handler.on_reestablished();
+        } else {
+            self.handler.on_open();
+        }
+        self.sender.timeout(self.keepalive_interval_ms, KEEPALIVE_TOKEN)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> WsResult<()> {
+        if event == KEEPALIVE_TOKEN {
+            self.sender.ping(vec![])?;
+            self.sender.timeout(self.keepalive_interval_ms, KEEPALIVE_TOKEN)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> WsResult<()> {
+        let responses = match GrinboxResponse::parse_all(&msg.to_string()) {
+            Ok(responses) => responses,
+            Err(_) => {
+                error!("could not parse response from relay!");
+                return Ok(());
+            }
+        };
+
+        for response in responses {
+            match response {
+                GrinboxResponse::Challenge { str, .. } => self.subscribe(&str)?,
+                GrinboxResponse::Slate {
+                    from,
+                    to,
+                    str,
+                    signature,
+                    challenge,
+                    ..
+                } => self.deliver(from, to, str, signature, challenge),
+                GrinboxResponse::Error { kind, description, .. } => {
+                    error!("relay error {}: {}", kind, description);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Instant;
+    use ws::WebSocket;
+
+    use crate::utils::crypto::public_key_from_secret_key;
+    use crate::utils::secp::Secp256k1;
+
+    const CHALLENGE: &str = "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc";
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Opened,
+        Slate(Uuid),
+        Acknowledged(GrinboxAddress, Uuid),
+        Closed,
+    }
+
+    struct EventHandler {
+        events: mpsc::Sender<Event>,
+        acknowledge: bool,
+    }
+
+    impl EventHandler {
+        fn new(events: mpsc::Sender<Event>) -> EventHandler {
+            EventHandler {
+                events,
+                acknowledge: false,
+            }
+        }
+    }
+
+    impl GrinboxSubscriptionHandler for EventHandler {
+        fn on_open(&self) {
+            let _ = self.events.send(Event::Opened);
+        }
+        fn on_slate(&self, _from: &GrinboxAddress, _to: &GrinboxAddress, slate: &mut Slate, _proof: Option<&mut TxProof>) {
+            let _ = self.events.send(Event::Slate(slate.id));
+        }
+        fn on_close(&self, _result: CloseReason) {
+            let _ = self.events.send(Event::Closed);
+        }
+        fn on_dropped(&self) {}
+        fn on_reestablished(&self) {}
+
+        fn acknowledge_slates(&self) -> bool {
+            self.acknowledge
+        }
+
+        fn on_slate_acknowledged(&self, from: &GrinboxAddress, slate_id: &Uuid) {
+            let _ = self.events.send(Event::Acknowledged(from.clone(), *slate_id));
+        }
+    }
+
+    /// Hands out a challenge to every connection and acknowledges whatever it is sent.
+    fn stub_relay() -> u16 {
+        let relay = WebSocket::new(move |out: Sender| {
+            out.send(format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE)).unwrap();
+            move |_msg: Message| out.send(r#"{"type":"Ok"}"#)
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = relay.local_addr().unwrap().port();
+        std::thread::spawn(move || relay.run().unwrap());
+        port
+    }
+
+    /// Hands out a challenge to every connection and delivers posted slates to whoever
+    /// subscribed to their recipient, like a relay serving a single domain. Slates for
+    /// recipients that are not subscribed are queued until they are.
+    fn routing_relay() -> (u16, Arc<Mutex<HashMap<String, Sender>>>) {
+        let subscribers = Arc::new(Mutex::new(HashMap::new()));
+        let routes = subscribers.clone();
+        let queues: Arc<Mutex<HashMap<String, Vec<String>>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        let relay = WebSocket::new(move |out: Sender| {
+            let routes = routes.clone();
+            let queues = queues.clone();
+            out.send(format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE)).unwrap();
+            move |msg: Message| {
+                match serde_json::from_str(&msg.to_string()).unwrap() {
+                    GrinboxRequest::Subscribe { address, .. } => {
+                        for slate in queues.lock().unwrap().remove(&address).unwrap_or_default() {
+                            out.send(slate)?;
+                        }
+                        routes.lock().unwrap().insert(address, out.clone());
+                    }
+                    GrinboxRequest::PostSlate {
+                        from, to, str, signature, ..
+                    } => {
+                        let recipient = GrinboxAddress::from_str(&to).unwrap().public_key;
+                        let slate = GrinboxResponse::Slate {
+                            from,
+                            to: Some(to),
+                            str,
+                            signature,
+                            challenge: CHALLENGE.to_string(),
+                            correlation_id: None,
+                        };
+                        let slate = serde_json::to_string(&slate).unwrap();
+                        match routes.lock().unwrap().get(&recipient) {
+                            Some(subscriber) => subscriber.send(slate)?,
+                            None => queues.lock().unwrap().entry(recipient).or_insert_with(Vec::new).push(slate),
+                        }
+                    }
+                    _ => {}
+                }
+                out.send(r#"{"type":"Ok"}"#)
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = relay.local_addr().unwrap().port();
+        std::thread::spawn(move || relay.run().unwrap());
+        (port, subscribers)
+    }
+
+    fn wait_for_subscribers(subscribers: &Arc<Mutex<HashMap<String, Sender>>>, count: usize) {
+        let started = Instant::now();
+        while subscribers.lock().unwrap().len() < count {
+            assert!(started.elapsed() < Duration::from_secs(5), "relay never saw the subscriptions");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn local_address(secret: u8, port: u16) -> (SecretKey, GrinboxAddress) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        (secret_key, GrinboxAddress::new(public_key, Some("127.0.0.1".to_string()), Some(port)))
+    }
+
+    #[test]
+    fn plaintext_is_refused_only_when_tls_is_required() {
+        assert!(check_tls(false, true).is_ok());
+        assert!(check_tls(true, false).is_ok());
+
+        let error = check_tls(true, true).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::GenericError("plaintext relays are disabled in this build".to_string()))
+        );
+    }
+
+    #[cfg(feature = "require-tls")]
+    #[test]
+    fn unsecure_client_cannot_be_constructed_when_tls_is_required() {
+        let (secret_key, address) = local_address(1, 13420);
+        assert!(GrinboxClient::new(&address, &secret_key, true).is_err());
+        assert!(GrinboxClient::new(&address, &secret_key, false).is_ok());
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn dropping_client_ends_its_background_thread() {
+        let (secret_key, address) = local_address(1, stub_relay());
+        let client = GrinboxClient::new(&address, &secret_key, true).unwrap();
+
+        let (events, received) = mpsc::channel();
+        client.start(Box::new(EventHandler::new(events))).unwrap();
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)), Ok(Event::Opened));
+        assert!(client.is_running());
+
+        drop(client);
+
+        // Dropping waited for the thread, which closed the subscription on its way out.
+        assert_eq!(received.try_recv(), Ok(Event::Closed));
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn recipient_acknowledges_slate_back_to_sender() {
+        let (port, subscribers) = routing_relay();
+        let (sender_key, sender_address) = local_address(1, port);
+        let (recipient_key, recipient_address) = local_address(2, port);
+        let sender = GrinboxClient::new(&sender_address, &sender_key, true).unwrap();
+        let recipient = GrinboxClient::new(&recipient_address, &recipient_key, true).unwrap();
+
+        let (sender_events, sent) = mpsc::channel();
+        sender.start(Box::new(EventHandler::new(sender_events))).unwrap();
+        let (recipient_events, received) = mpsc::channel();
+        recipient
+            .start(Box::new(EventHandler {
+                events: recipient_events,
+                acknowledge: true,
+            }))
+            .unwrap();
+        wait_for_subscribers(&subscribers, 2);
+
+        let slate = Slate::blank(2);
+        sender.post_slate(&slate, &recipient_address).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(received.recv_timeout(timeout), Ok(Event::Opened));
+        assert_eq!(received.recv_timeout(timeout), Ok(Event::Slate(slate.id)));
+        assert_eq!(sent.recv_timeout(timeout), Ok(Event::Opened));
+        assert_eq!(sent.recv_timeout(timeout), Ok(Event::Acknowledged(recipient_address, slate.id)));
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn received_slates_can_be_read_as_a_stream() {
+        let (port, subscribers) = routing_relay();
+        let (sender_key, sender_address) = local_address(1, port);
+        let (recipient_key, recipient_address) = local_address(2, port);
+        let sender = GrinboxClient::new(&sender_address, &sender_key, true).unwrap();
+        let recipient = GrinboxClient::new(&recipient_address, &recipient_key, true).unwrap();
+
+        let mut slates = recipient.subscribe_stream().unwrap().wait();
+        wait_for_subscribers(&subscribers, 1);
+
+        let slate = Slate::blank(2);
+        sender.post_slate(&slate, &recipient_address).unwrap();
+
+        let (from, received, proof) = slates.next().unwrap().unwrap();
+        assert_eq!(from, sender_address);
+        assert_eq!(received.id, slate.id);
+        assert!(proof.matches_slate_id(&slate.id));
+
+        recipient.stop();
+        assert!(slates.next().is_none());
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn probe_confirms_slate_was_queued_for_recipient() {
+        let timeout = Duration::from_millis(500);
+        let clients = |port| {
+            let (sender_key, sender_address) = local_address(1, port);
+            let (recipient_key, recipient_address) = local_address(2, port);
+            (
+                GrinboxClient::new(&sender_address, &sender_key, true).unwrap(),
+                GrinboxClient::new(&recipient_address, &recipient_key, true).unwrap(),
+            )
+        };
+
+        let (sender, recipient) = clients(routing_relay().0);
+        sender.post_and_probe(&Slate::blank(2), &recipient, timeout).unwrap();
+        assert!(!recipient.is_running());
+
+        // A relay that accepts the post but never queues it fails the probe.
+        let (sender, recipient) = clients(stub_relay());
+        let error = sender.post_and_probe(&Slate::blank(2), &recipient, timeout).unwrap_err();
+        match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::GenericError(_)) => {}
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn client_pings_at_configured_keepalive_interval() {
+        struct PingCounter {
+            out: Sender,
+            pings: Arc<Mutex<usize>>,
+        }
+
+        impl Handler for PingCounter {
+            fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+                self.out.send(format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE))
+            }
+
+            fn on_frame(&mut self, frame: ws::Frame) -> WsResult<Option<ws::Frame>> {
+                if frame.opcode() == ws::OpCode::Ping {
+                    *self.pings.lock().unwrap() += 1;
+                }
+                Ok(Some(frame))
+            }
+        }
+
+        let pings = Arc::new(Mutex::new(0));
+        let counted = pings.clone();
+        let relay = WebSocket::new(move |out| PingCounter {
+            out,
+            pings: counted.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = relay.local_addr().unwrap().port();
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (secret_key, address) = local_address(1, port);
+        let client = GrinboxClient::new(&address, &secret_key, true)
+            .unwrap()
+            .with_keepalive_interval_ms(50);
+        let (events, _) = mpsc::channel();
+        client.start(Box::new(EventHandler::new(events))).unwrap();
+
+        // At the default interval the first ping would take 30 seconds.
+        let started = Instant::now();
+        while *pings.lock().unwrap() < 3 {
+            assert!(started.elapsed() < Duration::from_secs(5), "relay was not pinged often enough");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff_never_exceeds_configured_cap() {
+        assert_eq!(reconnect_delay(0, 32), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3, 32), Duration::from_secs(8));
+        assert_eq!(reconnect_delay(5, 32), Duration::from_secs(32));
+
+        for max_secs in &[0, 1, 5, 32, 600] {
+            for retries in 0..100 {
+                assert!(reconnect_delay(retries, *max_secs) <= Duration::from_secs(*max_secs));
+            }
+            assert_eq!(reconnect_delay(99, *max_secs), Duration::from_secs(*max_secs));
+        }
+    }
+
+    #[cfg(not(feature = "require-tls"))]
+    #[test]
+    fn dropped_subscription_is_closed_when_reconnecting_is_off() {
+        struct Closer {
+            out: Sender,
+        }
+
+        impl Handler for Closer {
+            fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+                self.out.close(CloseCode::Away)
+            }
+        }
+
+        let connections = Arc::new(Mutex::new(0));
+        let counted = connections.clone();
+        let relay = WebSocket::new(move |out| {
+            *counted.lock().unwrap() += 1;
+            Closer { out }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let port = relay.local_addr().unwrap().port();
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (secret_key, address) = local_address(1, port);
+        let client = GrinboxClient::new(&address, &secret_key, true)
+            .unwrap()
+            .with_auto_reconnect(false);
+        let (events, received) = mpsc::channel();
+        client.start(Box::new(EventHandler::new(events))).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(received.recv_timeout(timeout), Ok(Event::Opened));
+        assert_eq!(received.recv_timeout(timeout), Ok(Event::Closed));
+        assert!(!client.is_running());
+        assert_eq!(*connections.lock().unwrap(), 1);
+    }
+}
@@ -1,10 +1,93 @@
+use uuid::Uuid;
+
 use crate::client::CloseReason;
 use crate::types::{GrinboxAddress, Slate, TxProof};
 
 pub trait GrinboxSubscriptionHandler: Send {
     fn on_open(&self);
-    fn on_slate(&self, from: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>);
+    /// `to` is the subscribed address the slate was delivered to, parsed from the full address
+    /// the relay sends with it, so clients holding several subscriptions know which key to use.
+    fn on_slate(&self, from: &GrinboxAddress, to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>);
     fn on_close(&self, result: CloseReason);
     fn on_dropped(&self);
     fn on_reestablished(&self);
-}
\ No newline at end of file
+
+    fn before_slate(&self, _from: &GrinboxAddress, _slate: &mut Slate) {}
+    fn after_slate(&self, _from: &GrinboxAddress, _slate: &Slate) {}
+
+    /// Whether `GrinboxClient` should let senders know once `on_slate` has taken their slate, by
+    /// posting an acknowledgement back to them through the relay. Off unless a handler opts in.
+    fn acknowledge_slates(&self) -> bool {
+        false
+    }
+
+    /// `from` acknowledged receiving the slate with id `slate_id` this subscriber sent it.
+    fn on_slate_acknowledged(&self, _from: &GrinboxAddress, _slate_id: &Uuid) {}
+
+    /// Runs `before_slate`, `on_slate` and `after_slate` in order; websocket clients should
+    /// deliver received slates through this rather than calling `on_slate` directly.
+    fn dispatch_slate(&self, from: &GrinboxAddress, to: &GrinboxAddress, slate: &mut Slate, proof: Option<&mut TxProof>) {
+        self.before_slate(from, slate);
+        self.on_slate(from, to, slate, proof);
+        self.after_slate(from, slate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use crate::utils::crypto::public_key_from_secret_key;
+    use crate::utils::secp::{Secp256k1, SecretKey};
+
+    struct RecordingHandler {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl RecordingHandler {
+        fn new() -> RecordingHandler {
+            RecordingHandler {
+                calls: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    fn address(secret: u8) -> GrinboxAddress {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        GrinboxAddress::new(public_key, None, None)
+    }
+
+    impl GrinboxSubscriptionHandler for RecordingHandler {
+        fn on_open(&self) {}
+        fn on_slate(&self, _from: &GrinboxAddress, _to: &GrinboxAddress, _slate: &mut Slate, _proof: Option<&mut TxProof>) {
+            self.calls.lock().unwrap().push("on_slate");
+        }
+        fn on_close(&self, _result: CloseReason) {}
+        fn on_dropped(&self) {}
+        fn on_reestablished(&self) {}
+
+        fn before_slate(&self, _from: &GrinboxAddress, _slate: &mut Slate) {
+            self.calls.lock().unwrap().push("before_slate");
+        }
+
+        fn after_slate(&self, _from: &GrinboxAddress, _slate: &Slate) {
+            self.calls.lock().unwrap().push("after_slate");
+        }
+    }
+
+    #[test]
+    fn dispatch_slate_runs_hooks_around_on_slate() {
+        let mut slate = Slate::blank(2);
+
+        let handler = RecordingHandler::new();
+        handler.dispatch_slate(&address(1), &address(2), &mut slate, None);
+
+        assert_eq!(
+            *handler.calls.lock().unwrap(),
+            vec!["before_slate", "on_slate", "after_slate"]
+        );
+    }
+}
@@ -1,9 +1,25 @@
 mod close_reason;
+mod dedup;
+mod drain;
+mod grinbox_client;
 mod grinbox_publisher;
 mod grinbox_subscriber;
 mod grinbox_subscription_handler;
+mod post_and_confirm;
+mod post_over_stream;
+mod queuing_publisher;
+mod relay_info;
 
 pub use self::close_reason::CloseReason;
+pub use self::dedup::{DedupWindow, DedupingHandler};
+pub use self::drain::drain;
+pub use self::grinbox_client::GrinboxClient;
 pub use self::grinbox_publisher::GrinboxPublisher;
 pub use self::grinbox_subscriber::GrinboxSubscriber;
-pub use self::grinbox_subscription_handler::GrinboxSubscriptionHandler;
\ No newline at end of file
+pub use self::grinbox_subscription_handler::GrinboxSubscriptionHandler;
+pub use self::post_and_confirm::{
+    post_and_confirm, post_and_confirm_with_headers, post_and_confirm_with_relay_info, PostStep, SlatePost,
+};
+pub use self::post_over_stream::post_over_stream;
+pub use self::queuing_publisher::QueuingPublisher;
+pub use self::relay_info::{fetch_relay_info, fetch_relay_info_with_headers, RelayInfo, RelayInfoCache};
\ No newline at end of file
@@ -0,0 +1,526 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use ws::util::Token;
+use url::Url;
+use ws::{connect, CloseCode, Handler, Message, Request, Result as WsResult, Sender};
+
+use crate::client::RelayInfoCache;
+use crate::error::{ErrorKind, Result};
+use crate::types::{GrinboxAddress, GrinboxError, GrinboxRequest, GrinboxResponse};
+use crate::utils::crypto::{sign_challenge, Hex};
+use crate::utils::secp::SecretKey;
+
+const TIMEOUT: Token = Token(1);
+
+/// Times a slate is signed again after the relay said the challenge it covered is no longer
+/// valid, e.g. because the relay restarted in between.
+const MAX_RESIGNS: usize = 2;
+
+type Outcome = Arc<Mutex<Option<std::result::Result<(), ErrorKind>>>>;
+
+/// What a transport should do after handing a relay message to `SlatePost`.
+#[derive(Debug, PartialEq)]
+pub enum PostStep {
+    /// Send this text message to the relay and keep waiting.
+    Send(String),
+    /// Keep waiting for the relay's answer.
+    Wait,
+    /// The exchange is over with this outcome; the connection can be closed.
+    Done(std::result::Result<(), ErrorKind>),
+}
+
+/// The relay side of posting one slate, independent of the transport carrying the messages.
+/// `post_and_confirm` drives it over a `ws` connection and `post_over_stream` over a stream the
+/// caller already holds.
+pub struct SlatePost {
+    from: String,
+    to: String,
+    str: String,
+    secret_key: SecretKey,
+    message_expiration_in_seconds: Option<u32>,
+    signed_challenge: Mutex<Option<String>>,
+    resigns: AtomicUsize,
+}
+
+impl SlatePost {
+    pub fn new(from: &GrinboxAddress, to: &GrinboxAddress, str: &str, secret_key: &SecretKey) -> SlatePost {
+        SlatePost {
+            from: from.stripped(),
+            to: to.stripped(),
+            str: str.to_string(),
+            secret_key: secret_key.clone(),
+            message_expiration_in_seconds: None,
+            signed_challenge: Mutex::new(None),
+            resigns: AtomicUsize::new(0),
+        }
+    }
+
+    /// Asks the relay to drop the slate if it is not delivered within this many seconds, instead
+    /// of keeping it for the relay's default expiration.
+    pub fn with_message_expiration(mut self, message_expiration_in_seconds: Option<u32>) -> SlatePost {
+        self.message_expiration_in_seconds = message_expiration_in_seconds;
+        self
+    }
+
+    /// Like `new`, but refuses recipients that are not on the network identified by
+    /// `network_version_bytes` before anything is sent, guarding against mainnet and testnet
+    /// mix-ups.
+    pub fn new_strict(
+        from: &GrinboxAddress,
+        to: &GrinboxAddress,
+        str: &str,
+        secret_key: &SecretKey,
+        network_version_bytes: &[u8],
+    ) -> Result<SlatePost> {
+        to.check_network(network_version_bytes)?;
+        Ok(SlatePost::new(from, to, str, secret_key))
+    }
+
+    pub fn on_message(&self, text: &str) -> PostStep {
+        let response = match serde_json::from_str::<GrinboxResponse>(text) {
+            Ok(response) => response,
+            Err(_) => return PostStep::Done(Err(ErrorKind::GrinboxProtocolError(GrinboxError::UnknownError))),
+        };
+
+        match response {
+            GrinboxResponse::Challenge { str, .. } => {
+                // Relays send a challenge along with some errors; the slate is not posted twice
+                // over the same one.
+                if self.signed_challenge.lock().unwrap().as_ref() == Some(&str) {
+                    return PostStep::Wait;
+                }
+                self.post(&str)
+            }
+            GrinboxResponse::Error {
+                kind: GrinboxError::InvalidChallenge,
+                ..
+            } if self.resigns.fetch_add(1, Ordering::SeqCst) < MAX_RESIGNS => {
+                PostStep::Send(serde_json::to_string(&GrinboxRequest::Challenge).unwrap())
+            }
+            GrinboxResponse::Ok { .. } => PostStep::Done(Ok(())),
+            GrinboxResponse::Error { kind, .. } => PostStep::Done(Err(ErrorKind::GrinboxProtocolError(kind))),
+            _ => PostStep::Wait,
+        }
+    }
+
+    fn post(&self, challenge: &str) -> PostStep {
+        let mut signed = self.str.clone();
+        signed.push_str(challenge);
+        let signature = match sign_challenge(&signed, &self.secret_key) {
+            Ok(signature) => signature.to_hex(),
+            Err(_) => return PostStep::Done(Err(ErrorKind::SecpError)),
+        };
+        *self.signed_challenge.lock().unwrap() = Some(challenge.to_string());
+
+        let request = GrinboxRequest::PostSlate {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            str: self.str.clone(),
+            signature,
+            message_expiration_in_seconds: self.message_expiration_in_seconds,
+            correlation_id: None,
+            hops: None,
+            challenge: Some(challenge.to_string()),
+        };
+        PostStep::Send(serde_json::to_string(&request).unwrap())
+    }
+}
+
+struct PostHandler {
+    out: Sender,
+    post: SlatePost,
+    headers: Vec<(String, String)>,
+    outcome: Outcome,
+}
+
+impl PostHandler {
+    fn finish(&self, outcome: std::result::Result<(), ErrorKind>, close_code: CloseCode) -> WsResult<()> {
+        *self.outcome.lock().unwrap() = Some(outcome);
+        self.out.close(close_code)
+    }
+}
+
+impl Handler for PostHandler {
+    fn build_request(&mut self, url: &Url) -> WsResult<Request> {
+        let mut request = Request::from_url(url)?;
+        for (name, value) in &self.headers {
+            request.headers_mut().push((name.clone(), value.as_bytes().to_vec()));
+        }
+        Ok(request)
+    }
+
+    fn on_message(&mut self, msg: Message) -> WsResult<()> {
+        match self.post.on_message(&msg.to_string()) {
+            PostStep::Send(request) => self.out.send(request),
+            PostStep::Wait => Ok(()),
+            PostStep::Done(outcome) => self.finish(outcome, CloseCode::Normal),
+        }
+    }
+
+    fn on_timeout(&mut self, _event: Token) -> WsResult<()> {
+        // Closing needs an open connection, which a connect that hangs never gets to, so the
+        // event loop of this one post is stopped instead.
+        let timed_out = ErrorKind::GenericError("timed out waiting for the relay".to_string());
+        *self.outcome.lock().unwrap() = Some(Err(timed_out));
+        self.out.shutdown()
+    }
+}
+
+/// Posts `str` from `from` to `to` through the relay at `url` on the calling thread: connects,
+/// signs the relay's challenge, posts and returns once the relay answers or `timeout` passes.
+/// Meant for tests and tools that need a deterministic result rather than a subscription.
+pub fn post_and_confirm(
+    url: &str,
+    from: &GrinboxAddress,
+    to: &GrinboxAddress,
+    str: &str,
+    secret_key: &SecretKey,
+    timeout: Duration,
+) -> Result<()> {
+    post_and_confirm_with_headers(url, &[], from, to, str, secret_key, timeout)
+}
+
+/// Like `post_and_confirm_with_headers`, but first looks up what the relay advertised about
+/// itself in `relay_info`, connecting to ask for it the first time, and refuses slates over the
+/// relay's size limit with `SlateTooLarge` without posting them. `headers` are sent when asking
+/// too.
+pub fn post_and_confirm_with_relay_info(
+    url: &str,
+    relay_info: &RelayInfoCache,
+    headers: &[(String, String)],
+    from: &GrinboxAddress,
+    to: &GrinboxAddress,
+    str: &str,
+    secret_key: &SecretKey,
+    timeout: Duration,
+) -> Result<()> {
+    relay_info.get_or_fetch_with_headers(url, headers, timeout)?.check_slate(str)?;
+    post_and_confirm_with_headers(url, headers, from, to, str, secret_key, timeout)
+}
+
+/// Like `post_and_confirm`, but also sends `headers` with the websocket upgrade request, for
+/// relays sitting behind a proxy that wants e.g. an `Authorization` header.
+pub fn post_and_confirm_with_headers(
+    url: &str,
+    headers: &[(String, String)],
+    from: &GrinboxAddress,
+    to: &GrinboxAddress,
+    str: &str,
+    secret_key: &SecretKey,
+    timeout: Duration,
+) -> Result<()> {
+    let outcome: Outcome = Arc::new(Mutex::new(None));
+    let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+
+    connect(url, |out: Sender| {
+        // Registered right away, so the timeout also covers connecting and the handshake.
+        if out.timeout(millis, TIMEOUT).is_err() {
+            error!("could not schedule post timeout!");
+        }
+        PostHandler {
+            out,
+            post: SlatePost::new(from, to, str, secret_key),
+            headers: headers.to_vec(),
+            outcome: outcome.clone(),
+        }
+    })
+    .map_err(|e| ErrorKind::GenericError(format!("could not connect to {}: {}", url, e)))?;
+
+    let outcome = outcome.lock().unwrap().take();
+    match outcome {
+        Some(Ok(())) => Ok(()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err(ErrorKind::GrinboxWebsocketAbnormalTermination.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use ws::{Handshake, WebSocket};
+
+    use crate::utils::crypto::{public_key_from_secret_key, verify_signature};
+    use crate::utils::secp::{Secp256k1, Signature};
+
+    const CHALLENGE: &str = "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc";
+
+    /// Hands out a fixed challenge and answers the first post with `response`, recording it.
+    fn stub_relay(response: &'static str) -> (String, Arc<Mutex<Vec<GrinboxRequest>>>) {
+        let posts = Arc::new(Mutex::new(vec![]));
+        let recorded = posts.clone();
+
+        let relay = WebSocket::new(move |out: Sender| {
+            let recorded = recorded.clone();
+            out.send(format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE)).unwrap();
+            move |msg: Message| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(serde_json::from_str(&msg.to_string()).unwrap());
+                out.send(response)
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+
+        (url, posts)
+    }
+
+    fn key(secret: u8) -> (SecretKey, GrinboxAddress) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        (secret_key, GrinboxAddress::new(public_key, None, None))
+    }
+
+    #[test]
+    fn posts_signed_slate_and_returns_ok() {
+        let (url, posts) = stub_relay(r#"{"type":"Ok"}"#);
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+
+        post_and_confirm(&url, &from, &to, "slate", &secret_key, Duration::from_secs(5)).unwrap();
+
+        let posts = posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        match posts[0] {
+            GrinboxRequest::PostSlate {
+                ref str,
+                ref signature,
+                ..
+            } => {
+                let signed = format!("{}{}", str, CHALLENGE);
+                let public_key = from.public_key().unwrap();
+                assert!(verify_signature(&signed, &Signature::from_hex(signature).unwrap(), &public_key).is_ok());
+            }
+            ref request => panic!("unexpected request: {:?}", request),
+        }
+    }
+
+    #[test]
+    fn plaintext_can_be_chosen_per_relay() {
+        let (url, posts) = stub_relay(r#"{"type":"Ok"}"#);
+        let port = url.rsplit(':').next().unwrap().parse::<u16>().unwrap();
+        let (secret_key, home) = key(1);
+        let (recipient_key, _) = key(2);
+        let public_key = public_key_from_secret_key(&recipient_key).unwrap();
+        let dev = GrinboxAddress::new(public_key, Some("127.0.0.1".to_string()), Some(port));
+
+        // The home relay stays on TLS while the development relay is reached in plaintext.
+        assert!(home.relay_url(false).starts_with("wss://"));
+        assert_eq!(dev.relay_url(true), url);
+
+        post_and_confirm(&dev.relay_url(true), &home, &dev, "slate", &secret_key, Duration::from_secs(5)).unwrap();
+        assert_eq!(posts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sends_configured_headers_in_upgrade_request() {
+        struct HeaderRecorder {
+            out: Sender,
+            authorization: Arc<Mutex<Option<String>>>,
+        }
+
+        impl Handler for HeaderRecorder {
+            fn on_request(&mut self, req: &Request) -> WsResult<ws::Response> {
+                *self.authorization.lock().unwrap() = req
+                    .header("Authorization")
+                    .map(|value| String::from_utf8_lossy(value).to_string());
+                ws::Response::from_request(req)
+            }
+
+            fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+                self.out.send(format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE))
+            }
+
+            fn on_message(&mut self, _msg: Message) -> WsResult<()> {
+                self.out.send(r#"{"type":"Ok"}"#)
+            }
+        }
+
+        let authorization = Arc::new(Mutex::new(None));
+        let recorded = authorization.clone();
+        let relay = WebSocket::new(move |out| HeaderRecorder {
+            out,
+            authorization: recorded.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let headers = vec![("Authorization".to_string(), "Bearer token".to_string())];
+        post_and_confirm_with_headers(&url, &headers, &from, &to, "slate", &secret_key, Duration::from_secs(5)).unwrap();
+
+        assert_eq!(*authorization.lock().unwrap(), Some("Bearer token".to_string()));
+    }
+
+    #[test]
+    fn slate_post_can_be_driven_without_a_socket() {
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let post = SlatePost::new(&from, &to, "slate", &secret_key);
+
+        let challenge = format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE);
+        match post.on_message(&challenge) {
+            PostStep::Send(request) => match serde_json::from_str(&request).unwrap() {
+                GrinboxRequest::PostSlate { str, to: recipient, .. } => {
+                    assert_eq!(str, "slate");
+                    assert_eq!(recipient, to.stripped());
+                }
+                request => panic!("unexpected request: {:?}", request),
+            },
+            step => panic!("unexpected step: {:?}", step),
+        }
+
+        assert_eq!(post.on_message(r#"{"type":"Info","network":"","version_bytes":[]}"#), PostStep::Wait);
+        assert_eq!(post.on_message(r#"{"type":"Ok"}"#), PostStep::Done(Ok(())));
+    }
+
+    #[test]
+    fn slate_post_carries_requested_expiration() {
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let post = SlatePost::new(&from, &to, "slate", &secret_key).with_message_expiration(Some(600));
+
+        let challenge = format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE);
+        match post.on_message(&challenge) {
+            PostStep::Send(request) => match serde_json::from_str(&request).unwrap() {
+                GrinboxRequest::PostSlate { message_expiration_in_seconds, .. } => {
+                    assert_eq!(message_expiration_in_seconds, Some(600));
+                }
+                request => panic!("unexpected request: {:?}", request),
+            },
+            step => panic!("unexpected step: {:?}", step),
+        }
+    }
+
+    #[test]
+    fn strict_post_refuses_recipient_on_other_network() {
+        use crate::types::{GRINBOX_ADDRESS_VERSION_MAINNET, GRINBOX_ADDRESS_VERSION_TESTNET};
+
+        let (secret_key, from) = key(1);
+        let (recipient_key, _) = key(2);
+        let public_key = public_key_from_secret_key(&recipient_key).unwrap();
+        let testnet = GrinboxAddress::new_raw(public_key, None, None, GRINBOX_ADDRESS_VERSION_TESTNET.to_vec());
+
+        assert!(SlatePost::new_strict(&from, &testnet, "slate", &secret_key, &GRINBOX_ADDRESS_VERSION_MAINNET).is_err());
+        assert!(SlatePost::new_strict(&from, &testnet, "slate", &secret_key, &GRINBOX_ADDRESS_VERSION_TESTNET).is_ok());
+    }
+
+    #[test]
+    fn oversized_slate_is_refused_on_advertised_limit() {
+        let (url, requests) = stub_relay(r#"{"type":"Info","network":"mainnet","version_bytes":[1,11],"max_slate_bytes":8}"#);
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let relay_info = RelayInfoCache::new();
+
+        for _ in 0..2 {
+            let error = post_and_confirm_with_relay_info(
+                &url,
+                &relay_info,
+                &[],
+                &from,
+                &to,
+                "a slate over eight bytes",
+                &secret_key,
+                Duration::from_secs(5),
+            )
+            .unwrap_err();
+            assert_eq!(error.downcast_ref::<ErrorKind>(), Some(&ErrorKind::SlateTooLarge(24, 8)));
+        }
+
+        // Info was asked for once and no slate was posted.
+        match requests.lock().unwrap().as_slice() {
+            [GrinboxRequest::Info] => {}
+            requests => panic!("unexpected requests: {:?}", requests),
+        }
+        assert_eq!(relay_info.get(&url).unwrap().max_slate_bytes, Some(8));
+    }
+
+    #[test]
+    fn slate_is_signed_again_when_relay_challenge_changed() {
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let post = SlatePost::new(&from, &to, "slate", &secret_key);
+
+        let challenge = |str: &str| format!(r#"{{"type":"Challenge","str":"{}"}}"#, str);
+        match post.on_message(&challenge("before-restart")) {
+            PostStep::Send(_) => {}
+            step => panic!("unexpected step: {:?}", step),
+        }
+
+        // The relay restarted between handing out the challenge and receiving the post.
+        let stale = r#"{"type":"Error","kind":"InvalidChallenge","code":"INVALID_CHALLENGE","description":"invalid challenge!"}"#;
+        match post.on_message(stale) {
+            PostStep::Send(request) => match serde_json::from_str(&request).unwrap() {
+                GrinboxRequest::Challenge => {}
+                request => panic!("unexpected request: {:?}", request),
+            },
+            step => panic!("unexpected step: {:?}", step),
+        }
+
+        match post.on_message(&challenge("after-restart")) {
+            PostStep::Send(request) => match serde_json::from_str(&request).unwrap() {
+                GrinboxRequest::PostSlate { signature, challenge, .. } => {
+                    assert_eq!(challenge, Some("after-restart".to_string()));
+                    let signed = "slateafter-restart";
+                    let public_key = from.public_key().unwrap();
+                    assert!(verify_signature(signed, &Signature::from_hex(&signature).unwrap(), &public_key).is_ok());
+                }
+                request => panic!("unexpected request: {:?}", request),
+            },
+            step => panic!("unexpected step: {:?}", step),
+        }
+        assert_eq!(post.on_message(&challenge("after-restart")), PostStep::Wait);
+        assert_eq!(post.on_message(r#"{"type":"Ok"}"#), PostStep::Done(Ok(())));
+
+        // Re-signing is bounded, so a relay that keeps refusing does not keep the post going.
+        assert_eq!(post.on_message(stale), PostStep::Send(r#"{"type":"Challenge"}"#.to_string()));
+        assert_eq!(
+            post.on_message(stale),
+            PostStep::Done(Err(ErrorKind::GrinboxProtocolError(GrinboxError::InvalidChallenge)))
+        );
+    }
+
+    #[test]
+    fn times_out_when_relay_never_answers_the_handshake() {
+        // Connections are accepted by the kernel but the upgrade request is never answered.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+
+        let started = Instant::now();
+        let error = post_and_confirm(&url, &from, &to, "slate", &secret_key, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::GenericError("timed out waiting for the relay".to_string()))
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn returns_relay_error() {
+        let (url, _) = stub_relay(
+            r#"{"type":"Error","kind":"InvalidSignature","code":"INVALID_SIGNATURE","description":"invalid signature!"}"#,
+        );
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+
+        let error = post_and_confirm(&url, &from, &to, "slate", &secret_key, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature))
+        );
+    }
+}
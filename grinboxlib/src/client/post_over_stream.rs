@@ -0,0 +1,342 @@
+use std::io;
+
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use rand::Rng;
+use tokio_io::io::{flush, read_exact, write_all};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::client::{PostStep, SlatePost};
+use crate::error::{Error, ErrorKind};
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+const OPCODE_MASK: u8 = 0x0F;
+const LENGTH_MASK: u8 = 0x7F;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+const CLOSE_NORMAL: u16 = 1000;
+/// Longest upgrade response read from the relay.
+const MAX_HEAD_BYTES: usize = 8192;
+/// Longest message read from the relay; its answers to a post are small.
+const MAX_MESSAGE_BYTES: u64 = 1 << 20;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// Posts a slate over `stream`, a connection to the relay at `host` that the caller already
+/// established, e.g. over TLS or through their own networking stack, instead of one opened with
+/// `ws::connect`. `headers` are sent with the websocket upgrade request. The future resolves once
+/// the relay answered the post; bounding how long it may take is left to the caller's runtime.
+pub fn post_over_stream<S>(
+    stream: S,
+    host: &str,
+    headers: &[(String, String)],
+    post: SlatePost,
+) -> Box<dyn Future<Item = (), Error = Error> + Send>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let exchange = write_all(stream, upgrade_request(host, headers).into_bytes())
+        .and_then(|(stream, _)| flush(stream))
+        .and_then(read_head)
+        .and_then(|(stream, head)| {
+            let status = head.lines().next().unwrap_or("").to_string();
+            if status.starts_with("HTTP/1.1 101") {
+                Ok(stream)
+            } else {
+                Err(websocket_error(&format!("upgrade refused: {}", status)))
+            }
+        })
+        .and_then(move |stream| {
+            future::loop_fn((stream, post), |(stream, post)| {
+                read_message(stream).and_then(move |(stream, message)| -> IoFuture<Loop<_, _>> {
+                    match post.on_message(&message) {
+                        PostStep::Send(request) => Box::new(
+                            write_frame(stream, OPCODE_TEXT, request.as_bytes())
+                                .map(move |stream| Loop::Continue((stream, post))),
+                        ),
+                        PostStep::Wait => Box::new(future::ok(Loop::Continue((stream, post)))),
+                        PostStep::Done(outcome) => Box::new(
+                            write_frame(stream, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())
+                                .map(move |_| Loop::Break(outcome)),
+                        ),
+                    }
+                })
+            })
+        })
+        .map_err(|e| Error::from(ErrorKind::GenericError(format!("could not post over the stream: {}", e))))
+        .and_then(|outcome| outcome.map_err(Error::from));
+    Box::new(exchange)
+}
+
+fn upgrade_request(host: &str, headers: &[(String, String)]) -> String {
+    let mut key = [0u8; 16];
+    rand::thread_rng().fill(&mut key);
+    let mut request = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        host,
+        base64::encode(&key)
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+/// Reads the upgrade response up to the blank line ending it, one byte at a time so nothing the
+/// relay sends after it is consumed.
+fn read_head<S: AsyncRead + Send + 'static>(stream: S) -> IoFuture<(S, String)> {
+    Box::new(future::loop_fn((stream, vec![]), |(stream, mut head): (S, Vec<u8>)| {
+        if head.len() >= MAX_HEAD_BYTES {
+            return Either::A(future::err(websocket_error("upgrade response is too large")));
+        }
+        Either::B(read_exact(stream, [0u8; 1]).map(move |(stream, byte)| {
+            head.push(byte[0]);
+            if head.ends_with(b"\r\n\r\n") {
+                Loop::Break((stream, String::from_utf8_lossy(&head).to_string()))
+            } else {
+                Loop::Continue((stream, head))
+            }
+        }))
+    }))
+}
+
+/// Reads the next text message, answering pings on the way. The relay closing the connection is
+/// an `UnexpectedEof` error.
+fn read_message<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> IoFuture<(S, String)> {
+    Box::new(future::loop_fn((stream, vec![]), |(stream, message): (S, Vec<u8>)| {
+        read_frame(stream, message.len()).and_then(move |(stream, head, payload)| -> IoFuture<Loop<_, _>> {
+            let mut message = message;
+            match head & OPCODE_MASK {
+                OPCODE_PING => Box::new(
+                    write_frame(stream, OPCODE_PONG, &payload).map(move |stream| Loop::Continue((stream, message))),
+                ),
+                OPCODE_PONG => Box::new(future::ok(Loop::Continue((stream, message)))),
+                OPCODE_CLOSE => Box::new(future::err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "relay closed the connection",
+                ))),
+                _ => {
+                    message.extend_from_slice(&payload);
+                    if head & FIN == 0 {
+                        return Box::new(future::ok(Loop::Continue((stream, message))));
+                    }
+                    match String::from_utf8(message) {
+                        Ok(text) => Box::new(future::ok(Loop::Break((stream, text)))),
+                        Err(_) => Box::new(future::err(websocket_error("relay sent a message that is not text"))),
+                    }
+                }
+            }
+        })
+    }))
+}
+
+/// Reads one frame, returning its first header byte and its payload. `received` is what the
+/// message it belongs to already holds, so messages past `MAX_MESSAGE_BYTES` are refused before
+/// their payload is read.
+fn read_frame<S: AsyncRead + Send + 'static>(stream: S, received: usize) -> IoFuture<(S, u8, Vec<u8>)> {
+    let length = read_exact(stream, [0u8; 2]).and_then(|(stream, head)| -> IoFuture<(S, u8, u64)> {
+        if head[1] & MASKED != 0 {
+            return Box::new(future::err(websocket_error("relay sent a masked frame")));
+        }
+        match head[1] & LENGTH_MASK {
+            126 => Box::new(
+                read_exact(stream, [0u8; 2])
+                    .map(move |(stream, length)| (stream, head[0], u64::from(u16::from_be_bytes(length)))),
+            ),
+            127 => Box::new(
+                read_exact(stream, [0u8; 8]).map(move |(stream, length)| (stream, head[0], u64::from_be_bytes(length))),
+            ),
+            length => Box::new(future::ok((stream, head[0], u64::from(length)))),
+        }
+    });
+    Box::new(length.and_then(move |(stream, head, length)| {
+        if received as u64 + length > MAX_MESSAGE_BYTES {
+            return Either::A(future::err(websocket_error("relay sent a message that is too large")));
+        }
+        Either::B(read_exact(stream, vec![0u8; length as usize]).map(move |(stream, payload)| (stream, head, payload)))
+    }))
+}
+
+/// Client frames have to be masked, with a key the relay cannot predict.
+fn write_frame<S: AsyncWrite + Send + 'static>(stream: S, opcode: u8, payload: &[u8]) -> IoFuture<S> {
+    let mut frame = vec![FIN | opcode];
+    let length = payload.len();
+    if length < 126 {
+        frame.push(MASKED | length as u8);
+    } else if length <= 0xFFFF {
+        frame.push(MASKED | 126);
+        frame.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        frame.push(MASKED | 127);
+        frame.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+
+    let mut mask = [0u8; 4];
+    rand::thread_rng().fill(&mut mask);
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    Box::new(write_all(stream, frame).and_then(|(stream, _)| flush(stream)))
+}
+
+fn websocket_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("websocket: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Async, Poll};
+    use std::io::{Cursor, Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    use crate::types::{GrinboxAddress, GrinboxError, GrinboxRequest};
+    use crate::utils::crypto::{public_key_from_secret_key, verify_signature, Hex};
+    use crate::utils::secp::{Secp256k1, SecretKey, Signature};
+
+    const CHALLENGE: &str = "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc";
+
+    /// The client end of an in-memory connection: reads what the relay end was scripted to send
+    /// and keeps everything written to it for the test to look at.
+    struct MemoryStream {
+        incoming: Cursor<Vec<u8>>,
+        outgoing: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.incoming.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.outgoing.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for MemoryStream {}
+
+    impl AsyncWrite for MemoryStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    /// A stream over which the relay accepts the upgrade and then sends `frames`, given as
+    /// opcode and payload.
+    fn relay_sending(frames: &[(u8, &str)]) -> (MemoryStream, Arc<Mutex<Vec<u8>>>) {
+        let mut incoming = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n".to_vec();
+        for (opcode, payload) in frames {
+            // Every payload here is short enough for the one byte length.
+            incoming.push(FIN | opcode);
+            incoming.push(payload.len() as u8);
+            incoming.extend_from_slice(payload.as_bytes());
+        }
+        let outgoing = Arc::new(Mutex::new(vec![]));
+        let stream = MemoryStream {
+            incoming: Cursor::new(incoming),
+            outgoing: outgoing.clone(),
+        };
+        (stream, outgoing)
+    }
+
+    /// Splits what the client wrote into its upgrade request and its unmasked frames.
+    fn written(outgoing: &[u8]) -> (String, Vec<(u8, Vec<u8>)>) {
+        let head_length = outgoing.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(outgoing[..head_length].to_vec()).unwrap();
+
+        let mut frames = vec![];
+        let mut rest = &outgoing[head_length..];
+        while !rest.is_empty() {
+            assert_eq!(rest[1] & MASKED, MASKED);
+            let length = (rest[1] & LENGTH_MASK) as usize;
+            assert!(length < 126);
+            let mask = &rest[2..6];
+            let payload: Vec<u8> = rest[6..6 + length].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+            frames.push((rest[0] & OPCODE_MASK, payload));
+            rest = &rest[6 + length..];
+        }
+        (head, frames)
+    }
+
+    fn key(secret: u8) -> (SecretKey, GrinboxAddress) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        (secret_key, GrinboxAddress::new(public_key, None, None))
+    }
+
+    #[test]
+    fn posts_over_an_in_memory_stream() {
+        let challenge = format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE);
+        let (stream, outgoing) = relay_sending(&[
+            (OPCODE_TEXT, challenge.as_str()),
+            (OPCODE_PING, "beat"),
+            (OPCODE_TEXT, r#"{"type":"Ok"}"#),
+        ]);
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+        let headers = vec![("Authorization".to_string(), "Bearer token".to_string())];
+
+        post_over_stream(stream, "relay.example.com", &headers, SlatePost::new(&from, &to, "slate", &secret_key))
+            .wait()
+            .unwrap();
+
+        let (head, frames) = written(&outgoing.lock().unwrap());
+        assert!(head.starts_with("GET / HTTP/1.1\r\nHost: relay.example.com\r\n"));
+        assert!(head.contains("\r\nAuthorization: Bearer token\r\n"));
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0, OPCODE_TEXT);
+        match serde_json::from_slice(&frames[0].1).unwrap() {
+            GrinboxRequest::PostSlate { str, signature, .. } => {
+                let signed = format!("{}{}", str, CHALLENGE);
+                let public_key = from.public_key().unwrap();
+                assert!(verify_signature(&signed, &Signature::from_hex(&signature).unwrap(), &public_key).is_ok());
+            }
+            request => panic!("unexpected request: {:?}", request),
+        }
+        assert_eq!(frames[1], (OPCODE_PONG, b"beat".to_vec()));
+        assert_eq!(frames[2], (OPCODE_CLOSE, CLOSE_NORMAL.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn returns_relay_error_over_a_stream() {
+        let challenge = format!(r#"{{"type":"Challenge","str":"{}"}}"#, CHALLENGE);
+        let error = r#"{"type":"Error","kind":"InvalidSignature","code":"INVALID_SIGNATURE","description":"no"}"#;
+        let (stream, _) = relay_sending(&[(OPCODE_TEXT, challenge.as_str()), (OPCODE_TEXT, error)]);
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+
+        let error = post_over_stream(stream, "relay.example.com", &[], SlatePost::new(&from, &to, "slate", &secret_key))
+            .wait()
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature))
+        );
+    }
+
+    #[test]
+    fn refused_upgrade_is_an_error() {
+        let stream = MemoryStream {
+            incoming: Cursor::new(b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()),
+            outgoing: Arc::new(Mutex::new(vec![])),
+        };
+        let (secret_key, from) = key(1);
+        let (_, to) = key(2);
+
+        assert!(post_over_stream(stream, "relay.example.com", &[], SlatePost::new(&from, &to, "slate", &secret_key))
+            .wait()
+            .is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::client::GrinboxPublisher;
+use crate::error::{ErrorKind, Result};
+use crate::types::{GrinboxAddress, Slate};
+
+/// Wraps a publisher so slates that could not be posted while the relay was unreachable are
+/// kept, up to `capacity`, and posted again by `flush` once the connection is back. Slates the
+/// relay refused for good are not queued, as posting them again would fail the same way.
+pub struct QueuingPublisher<P: GrinboxPublisher> {
+    inner: P,
+    capacity: usize,
+    queue: Mutex<VecDeque<(Slate, GrinboxAddress)>>,
+}
+
+impl<P: GrinboxPublisher> QueuingPublisher<P> {
+    pub fn new(inner: P, capacity: usize) -> QueuingPublisher<P> {
+        QueuingPublisher {
+            inner,
+            capacity,
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// How many slates are waiting to be posted.
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Posts queued slates in the order they were queued, stopping at the first one that fails
+    /// again. Meant to be called when the subscription is reestablished; returns how many
+    /// slates were posted.
+    pub fn flush(&self) -> usize {
+        let mut queue = self.queue.lock().unwrap();
+        self.flush_queue(&mut queue)
+    }
+
+    fn flush_queue(&self, queue: &mut VecDeque<(Slate, GrinboxAddress)>) -> usize {
+        let mut posted = 0;
+        while let Some((slate, to)) = queue.pop_front() {
+            if let Err(e) = self.inner.post_slate(&slate, &to) {
+                if is_transient(&e) {
+                    queue.push_front((slate, to));
+                    break;
+                }
+                error!("dropping queued slate for {}: {}", to.stripped(), e);
+                continue;
+            }
+            posted += 1;
+        }
+        posted
+    }
+}
+
+impl<P: GrinboxPublisher> GrinboxPublisher for QueuingPublisher<P> {
+    /// Returns `Ok` for slates that were queued; `queued` tells whether any are waiting.
+    fn post_slate(&self, slate: &Slate, to: &GrinboxAddress) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+
+        // Slates queued earlier go first, so the relay sees posts in the order they were made.
+        if !queue.is_empty() {
+            self.flush_queue(&mut queue);
+        }
+
+        let result = if queue.is_empty() {
+            self.inner.post_slate(slate, to)
+        } else {
+            Err(ErrorKind::GenericError("earlier slates are still queued".to_string()).into())
+        };
+
+        match result {
+            Err(ref e) if is_transient(e) && queue.len() < self.capacity => {
+                warn!("could not post slate to {}, queueing it: {}", to.stripped(), e);
+                queue.push_back((slate.clone(), to.clone()));
+                Ok(())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether posting the same slate again later may succeed.
+fn is_transient(e: &failure::Error) -> bool {
+    match e.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::GrinboxProtocolError(kind)) => kind.is_retryable(),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::types::GrinboxError;
+    use crate::utils::crypto::public_key_from_secret_key;
+    use crate::utils::secp::{Secp256k1, SecretKey};
+
+    struct FlakyPublisher {
+        reachable: AtomicBool,
+        posted: Mutex<Vec<GrinboxAddress>>,
+    }
+
+    impl GrinboxPublisher for FlakyPublisher {
+        fn post_slate(&self, _slate: &Slate, to: &GrinboxAddress) -> Result<()> {
+            if !self.reachable.load(Ordering::SeqCst) {
+                return Err(ErrorKind::GrinboxWebsocketAbnormalTermination.into());
+            }
+            self.posted.lock().unwrap().push(to.clone());
+            Ok(())
+        }
+    }
+
+    struct RefusingPublisher;
+
+    impl GrinboxPublisher for RefusingPublisher {
+        fn post_slate(&self, _slate: &Slate, _to: &GrinboxAddress) -> Result<()> {
+            Err(ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature).into())
+        }
+    }
+
+    fn address(secret: u8) -> GrinboxAddress {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        GrinboxAddress::new(public_key, None, None)
+    }
+
+    #[test]
+    fn posts_while_disconnected_are_delivered_after_reconnect() {
+        let publisher = QueuingPublisher::new(
+            FlakyPublisher {
+                reachable: AtomicBool::new(false),
+                posted: Mutex::new(vec![]),
+            },
+            10,
+        );
+
+        publisher.post_slate(&Slate::blank(2), &address(1)).unwrap();
+        publisher.post_slate(&Slate::blank(2), &address(2)).unwrap();
+        assert_eq!(publisher.queued(), 2);
+        assert!(publisher.inner.posted.lock().unwrap().is_empty());
+
+        publisher.inner.reachable.store(true, Ordering::SeqCst);
+        assert_eq!(publisher.flush(), 2);
+        assert_eq!(publisher.queued(), 0);
+        assert_eq!(*publisher.inner.posted.lock().unwrap(), vec![address(1), address(2)]);
+    }
+
+    #[test]
+    fn queue_is_bounded() {
+        let publisher = QueuingPublisher::new(
+            FlakyPublisher {
+                reachable: AtomicBool::new(false),
+                posted: Mutex::new(vec![]),
+            },
+            1,
+        );
+
+        publisher.post_slate(&Slate::blank(2), &address(1)).unwrap();
+        assert!(publisher.post_slate(&Slate::blank(2), &address(2)).is_err());
+        assert_eq!(publisher.queued(), 1);
+    }
+
+    #[test]
+    fn refused_slates_are_not_queued() {
+        let publisher = QueuingPublisher::new(RefusingPublisher, 10);
+        assert!(publisher.post_slate(&Slate::blank(2), &address(1)).is_err());
+        assert_eq!(publisher.queued(), 0);
+    }
+}
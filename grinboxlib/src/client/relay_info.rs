@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+use ws::util::Token;
+use ws::{connect, CloseCode, Handler, Handshake, Message, Request, Result as WsResult, Sender};
+
+use crate::error::{ErrorKind, Result};
+use crate::types::{GrinboxRequest, GrinboxResponse};
+
+const TIMEOUT: Token = Token(1);
+
+/// What a relay advertises about itself in its `Info` response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayInfo {
+    pub network: String,
+    pub version_bytes: Vec<u8>,
+    pub max_slate_bytes: Option<usize>,
+}
+
+impl RelayInfo {
+    pub fn from_response(response: &GrinboxResponse) -> Option<RelayInfo> {
+        match *response {
+            GrinboxResponse::Info {
+                ref network,
+                ref version_bytes,
+                max_slate_bytes,
+            } => Some(RelayInfo {
+                network: network.clone(),
+                version_bytes: version_bytes.clone(),
+                max_slate_bytes,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Fails with `SlateTooLarge` for slates the relay said it would refuse. Relays that do not
+    /// advertise a limit are assumed to take any slate.
+    pub fn check_slate(&self, str: &str) -> Result<()> {
+        match self.max_slate_bytes {
+            Some(max_slate_bytes) if str.len() > max_slate_bytes => {
+                Err(ErrorKind::SlateTooLarge(str.len(), max_slate_bytes).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+struct InfoHandler {
+    out: Sender,
+    headers: Vec<(String, String)>,
+    info: Arc<Mutex<Option<RelayInfo>>>,
+}
+
+impl Handler for InfoHandler {
+    fn build_request(&mut self, url: &Url) -> WsResult<Request> {
+        let mut request = Request::from_url(url)?;
+        for (name, value) in &self.headers {
+            request.headers_mut().push((name.clone(), value.as_bytes().to_vec()));
+        }
+        Ok(request)
+    }
+
+    fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+        self.out.send(serde_json::to_string(&GrinboxRequest::Info).unwrap())
+    }
+
+    fn on_message(&mut self, msg: Message) -> WsResult<()> {
+        // The relay greets every connection with a challenge, which is of no use here.
+        let info = serde_json::from_str::<GrinboxResponse>(&msg.to_string())
+            .ok()
+            .and_then(|response| RelayInfo::from_response(&response));
+        if info.is_some() {
+            *self.info.lock().unwrap() = info;
+            return self.out.close(CloseCode::Normal);
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, _event: Token) -> WsResult<()> {
+        // A connect that hangs never opens a connection to close.
+        self.out.shutdown()
+    }
+}
+
+/// Connects to the relay at `url` and asks for its `Info`, waiting at most `timeout`.
+pub fn fetch_relay_info(url: &str, timeout: Duration) -> Result<RelayInfo> {
+    fetch_relay_info_with_headers(url, &[], timeout)
+}
+
+/// Like `fetch_relay_info`, but also sends `headers` with the websocket upgrade request.
+pub fn fetch_relay_info_with_headers(url: &str, headers: &[(String, String)], timeout: Duration) -> Result<RelayInfo> {
+    let info = Arc::new(Mutex::new(None));
+    let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+
+    connect(url, |out: Sender| {
+        // Registered right away, so the timeout also covers connecting and the handshake.
+        if out.timeout(millis, TIMEOUT).is_err() {
+            error!("could not schedule relay info timeout!");
+        }
+        InfoHandler {
+            out,
+            headers: headers.to_vec(),
+            info: info.clone(),
+        }
+    })
+    .map_err(|e| ErrorKind::GenericError(format!("could not connect to {}: {}", url, e)))?;
+
+    let info = info.lock().unwrap().take();
+    info.ok_or_else(|| ErrorKind::GenericError(format!("{} did not send its info", url)).into())
+}
+
+/// Keeps the `Info` of every relay posted through, fetched the first time each is connected
+/// to, so slates the relay would refuse are refused before connecting to post them.
+#[derive(Default)]
+pub struct RelayInfoCache {
+    relays: Mutex<HashMap<String, RelayInfo>>,
+}
+
+impl RelayInfoCache {
+    pub fn new() -> RelayInfoCache {
+        RelayInfoCache::default()
+    }
+
+    pub fn get(&self, url: &str) -> Option<RelayInfo> {
+        self.relays.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn get_or_fetch(&self, url: &str, timeout: Duration) -> Result<RelayInfo> {
+        self.get_or_fetch_with_headers(url, &[], timeout)
+    }
+
+    /// Like `get_or_fetch`, but also sends `headers` when connecting to ask for the `Info`.
+    pub fn get_or_fetch_with_headers(
+        &self,
+        url: &str,
+        headers: &[(String, String)],
+        timeout: Duration,
+    ) -> Result<RelayInfo> {
+        if let Some(info) = self.get(url) {
+            return Ok(info);
+        }
+
+        let info = fetch_relay_info_with_headers(url, headers, timeout)?;
+        self.relays.lock().unwrap().insert(url.to_string(), info.clone());
+        Ok(info)
+    }
+}
@@ -7,6 +7,8 @@ pub enum ErrorKind {
     GenericError(String),
     #[fail(display = "\x1b[31;1merror:\x1b[0m secp error")]
     SecpError,
+    #[fail(display = "\x1b[31;1merror:\x1b[0m signature does not match!")]
+    IncorrectSignature,
     #[fail(display = "\x1b[31;1merror:\x1b[0m invalid character!")]
     InvalidBase58Character(char, usize),
     #[fail(display = "\x1b[31;1merror:\x1b[0m invalid length!")]
@@ -25,6 +27,10 @@ pub enum ErrorKind {
     Encryption,
     #[fail(display = "\x1b[31;1merror:\x1b[0m unable to decrypt message")]
     Decryption,
+    #[fail(display = "\x1b[31;1merror:\x1b[0m memo is longer than {} bytes!", 0)]
+    MemoTooLong(usize),
+    #[fail(display = "\x1b[31;1merror:\x1b[0m slate is {} bytes, the relay accepts at most {}!", 0, 1)]
+    SlateTooLarge(usize, usize),
     #[fail(display = "\x1b[31;1merror:\x1b[0m unable to verify proof")]
     VerifyProof,
     #[fail(display = "\x1b[31;1merror:\x1b[0m grinbox websocket terminated unexpectedly!")]
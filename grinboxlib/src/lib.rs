@@ -1,15 +1,21 @@
+extern crate base64;
 extern crate colored;
 extern crate failure;
+extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate parking_lot;
 extern crate regex;
 extern crate secp256k1zkp;
 extern crate serde;
+extern crate serde_cbor;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate sha2;
+extern crate tokio_io;
+extern crate url;
+extern crate uuid;
 extern crate ws;
 
 extern crate grin_core;
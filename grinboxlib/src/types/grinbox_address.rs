@@ -6,12 +6,16 @@ use crate::utils::is_mainnet;
 use crate::utils::secp::PublicKey;
 use crate::utils::crypto::Base58;
 
-pub const GRINBOX_ADDRESS_REGEX: &str = r"^(grinbox://)?(?P<public_key>[123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz]{52})(@(?P<domain>[a-zA-Z0-9\.]+)(:(?P<port>[0-9]*))?)?$";
+pub const GRINBOX_ADDRESS_REGEX: &str = r"^(grinbox://)?(?P<public_key>[123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz]{52})(@(\[(?P<ipv6>[0-9a-fA-F:\.]+)\]|(?P<domain>[a-zA-Z0-9\.]+))(:(?P<port>[0-9]*))?)?$";
 pub const GRINBOX_ADDRESS_VERSION_MAINNET: [u8; 2] = [1, 11];
 pub const GRINBOX_ADDRESS_VERSION_TESTNET: [u8; 2] = [1, 120];
 pub const DEFAULT_GRINBOX_DOMAIN: &str = "grinbox.io";
 pub const DEFAULT_GRINBOX_PORT: u16 = 443;
+const GRINBOX_SCHEME: &str = "grinbox://";
 
+/// Version bytes of addresses on the current network. New address types keep these two bytes and
+/// add a checksum tag on top (see `GrinboxAddress::new_tagged`), so their strings fail the
+/// checksum of clients that only know plain addresses instead of being mistaken for them.
 pub fn version_bytes() -> Vec<u8> {
     if is_mainnet() {
         GRINBOX_ADDRESS_VERSION_MAINNET.to_vec()
@@ -20,6 +24,14 @@ pub fn version_bytes() -> Vec<u8> {
     }
 }
 
+pub fn network_name() -> &'static str {
+    if is_mainnet() {
+        "mainnet"
+    } else {
+        "testnet"
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GrinboxAddress {
     pub public_key: String,
@@ -47,62 +59,249 @@ impl GrinboxAddress {
         }
     }
 
-    pub fn from_str(s: &str) -> Result<Self> {
-        let re = Regex::new(GRINBOX_ADDRESS_REGEX).unwrap();
-        let captures = re.captures(s);
-        if captures.is_none() {
-            Err(ErrorKind::GrinboxAddressParsingError(s.to_string()))?;
+    /// An address of a newer type, identified by `tag`. The tag is covered by the checksum but not
+    /// encoded, so the string only parses with `from_str_tagged` given the same tag.
+    pub fn new_tagged(public_key: PublicKey, domain: Option<String>, port: Option<u16>, tag: &[u8]) -> Self {
+        Self {
+            public_key: public_key.to_base58_check_tagged(version_bytes(), tag),
+            domain: domain.unwrap_or(DEFAULT_GRINBOX_DOMAIN.to_string()),
+            port: port.unwrap_or(DEFAULT_GRINBOX_PORT),
+            version_bytes: None,
         }
+    }
 
-        let captures = captures.unwrap();
-        let public_key = captures.name("public_key").unwrap().as_str().to_string();
-        let domain = captures.name("domain").map(|m| m.as_str().to_string());
-        let port = captures
-            .name("port")
-            .map(|m| u16::from_str_radix(m.as_str(), 10).unwrap());
-
+    pub fn from_str(s: &str) -> Result<Self> {
+        let (public_key, domain, port) = parse_address(s)?;
         let public_key = PublicKey::from_base58_check(&public_key, version_bytes())?;
-
         Ok(GrinboxAddress::new(public_key, domain, port))
     }
 
     pub fn from_str_raw(s: &str) -> Result<Self> {
-        let re = Regex::new(GRINBOX_ADDRESS_REGEX).unwrap();
-        let captures = re.captures(s);
-        if captures.is_none() {
-            Err(ErrorKind::GrinboxAddressParsingError(s.to_string()))?;
-        }
-
-        let captures = captures.unwrap();
-        let public_key = captures.name("public_key").unwrap().as_str().to_string();
-        let domain = captures.name("domain").map(|m| m.as_str().to_string());
-        let port = captures
-            .name("port")
-            .map(|m| u16::from_str_radix(m.as_str(), 10).unwrap());
-
+        let (public_key, domain, port) = parse_address(s)?;
         let (public_key, version_bytes) = PublicKey::from_base58_check_raw(&public_key, 2)?;
-
         Ok(GrinboxAddress::new_raw(public_key, domain, port, version_bytes))
     }
 
+    pub fn from_str_tagged(s: &str, tag: &[u8]) -> Result<Self> {
+        let (public_key, domain, port) = parse_address(s)?;
+        let public_key = PublicKey::from_base58_check_tagged(&public_key, version_bytes(), tag)?;
+        Ok(GrinboxAddress::new_tagged(public_key, domain, port, tag))
+    }
+
     pub fn public_key(&self) -> Result<PublicKey> {
         PublicKey::from_base58_check(&self.public_key, version_bytes())
     }
 
+    pub fn public_key_tagged(&self, tag: &[u8]) -> Result<PublicKey> {
+        PublicKey::from_base58_check_tagged(&self.public_key, version_bytes(), tag)
+    }
+
+    /// Fails with `InvalidBase58Version` unless the address belongs to the network identified
+    /// by `expected` version bytes, so strict clients can refuse cross-network counterparties.
+    pub fn check_network(&self, expected: &[u8]) -> Result<()> {
+        let (_, version_bytes) = PublicKey::from_base58_check_raw(&self.public_key, 2)?;
+        if version_bytes != expected {
+            return Err(ErrorKind::InvalidBase58Version.into());
+        }
+        Ok(())
+    }
+
+    /// The domain as it goes before a port in a URL, with IPv6 literals in brackets.
+    pub fn host(&self) -> String {
+        if self.domain.contains(':') {
+            format!("[{}]", self.domain)
+        } else {
+            self.domain.clone()
+        }
+    }
+
+    /// The address without its `grinbox://` scheme, as relays expect it in requests.
     pub fn stripped(&self) -> String {
-        format!("{}", self)[10..].to_string()
+        let formatted = self.to_string();
+        if formatted.starts_with(GRINBOX_SCHEME) {
+            formatted[GRINBOX_SCHEME.len()..].to_string()
+        } else {
+            formatted
+        }
+    }
+
+    /// Websocket URL of the relay serving this address. Plaintext `ws://` is only used when the
+    /// caller opts in for this connection, so a client can reach a plaintext development relay
+    /// without giving up TLS towards every other relay.
+    pub fn relay_url(&self, protocol_unsecure: bool) -> String {
+        if protocol_unsecure {
+            warn!("connecting to relay {}:{} over plaintext ws://", self.host(), self.port);
+            format!("ws://{}:{}", self.host(), self.port)
+        } else {
+            format!("wss://{}:{}", self.host(), self.port)
+        }
     }
 }
 
+/// Splits `s` into its still encoded public key, domain and port. Strings that do not match
+/// the address format, including ones with an empty or out of range port, are refused with
+/// `GrinboxAddressParsingError`.
+fn parse_address(s: &str) -> Result<(String, Option<String>, Option<u16>)> {
+    let re = Regex::new(GRINBOX_ADDRESS_REGEX).unwrap();
+    let captures = re
+        .captures(s)
+        .ok_or_else(|| ErrorKind::GrinboxAddressParsingError(s.to_string()))?;
+
+    let public_key = captures.name("public_key").unwrap().as_str().to_string();
+    let domain = captured_domain(&captures);
+    let port = match captures.name("port") {
+        Some(port) => Some(
+            u16::from_str_radix(port.as_str(), 10)
+                .map_err(|_| ErrorKind::GrinboxAddressParsingError(s.to_string()))?,
+        ),
+        None => None,
+    };
+    Ok((public_key, domain, port))
+}
+
+/// IPv6 literals are matched without their brackets, so they are stored the same way whether or
+/// not a port follows. Domains are case-insensitive and kept in lowercase, so addresses compare
+/// and route the same however the domain was typed.
+fn captured_domain(captures: &regex::Captures) -> Option<String> {
+    captures
+        .name("ipv6")
+        .or_else(|| captures.name("domain"))
+        .map(|m| m.as_str().to_lowercase())
+}
+
 impl Display for GrinboxAddress {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "grinbox://{}", self.public_key)?;
+        write!(f, "{}{}", GRINBOX_SCHEME, self.public_key)?;
         if self.domain != DEFAULT_GRINBOX_DOMAIN || self.port != DEFAULT_GRINBOX_PORT {
-            write!(f, "@{}", self.domain)?;
+            write!(f, "@{}", self.host())?;
             if self.port != DEFAULT_GRINBOX_PORT {
                 write!(f, ":{}", self.port)?;
             }
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_name_matches_version_bytes() {
+        // Example addresses from the integration docs, one per network.
+        let mainnet = "gVuQ7cspvtjKZNBuoxyjrLbNTXhqKt7Hd3MnjfMBr3kSE6z3XkCp";
+        let testnet = "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN";
+        let (current, other) = match network_name() {
+            "mainnet" => (mainnet, testnet),
+            "testnet" => (testnet, mainnet),
+            name => panic!("unexpected network name: {}", name),
+        };
+
+        assert!(GrinboxAddress::from_str(current).is_ok());
+        assert!(GrinboxAddress::from_str(other).is_err());
+        assert_eq!(
+            GrinboxAddress::from_str_raw(mainnet).unwrap().version_bytes,
+            Some(GRINBOX_ADDRESS_VERSION_MAINNET.to_vec())
+        );
+        assert_eq!(
+            GrinboxAddress::from_str_raw(testnet).unwrap().version_bytes,
+            Some(GRINBOX_ADDRESS_VERSION_TESTNET.to_vec())
+        );
+    }
+
+    fn public_key() -> PublicKey {
+        use crate::utils::crypto::public_key_from_secret_key;
+        use crate::utils::secp::{Secp256k1, SecretKey};
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        public_key_from_secret_key(&secret_key).unwrap()
+    }
+
+    #[test]
+    fn untagged_addresses_are_unchanged() {
+        let plain = GrinboxAddress::new(public_key(), None, None);
+        let empty_tag = GrinboxAddress::new_tagged(public_key(), None, None, &[]);
+        assert_eq!(plain, empty_tag);
+
+        let parsed = GrinboxAddress::from_str(&plain.to_string()).unwrap();
+        assert_eq!(parsed.public_key().unwrap(), public_key());
+    }
+
+    #[test]
+    fn ipv6_relay_round_trips() {
+        let address = GrinboxAddress::new(public_key(), Some("2001:db8::1".to_string()), Some(13420));
+        let formatted = address.to_string();
+        assert!(formatted.ends_with("@[2001:db8::1]:13420"));
+        assert_eq!(address.relay_url(false), "wss://[2001:db8::1]:13420");
+
+        let parsed = GrinboxAddress::from_str(&formatted).unwrap();
+        assert_eq!(parsed.domain, "2001:db8::1");
+        assert_eq!(parsed.port, 13420);
+        assert_eq!(parsed, address);
+
+        let without_port = GrinboxAddress::from_str(&format!("{}@[::1]", address.public_key)).unwrap();
+        assert_eq!(without_port.domain, "::1");
+        assert_eq!(without_port.port, DEFAULT_GRINBOX_PORT);
+        assert!(without_port.to_string().ends_with("@[::1]"));
+    }
+
+    #[test]
+    fn stripped_drops_only_the_scheme() {
+        let default = GrinboxAddress::new(public_key(), None, None);
+        assert_eq!(default.stripped(), default.public_key);
+
+        let custom = GrinboxAddress::new(public_key(), Some("relay.example.com".to_string()), Some(13420));
+        assert_eq!(custom.stripped(), format!("{}@relay.example.com:13420", custom.public_key));
+        assert_eq!(GrinboxAddress::from_str(&custom.stripped()).unwrap(), custom);
+    }
+
+    #[test]
+    fn domain_case_is_ignored() {
+        let public_key = GrinboxAddress::new(public_key(), None, None).public_key;
+        let upper = GrinboxAddress::from_str(&format!("{}@RELAY.Example.COM:13420", public_key)).unwrap();
+        let lower = GrinboxAddress::from_str(&format!("{}@relay.example.com:13420", public_key)).unwrap();
+        assert_eq!(upper, lower);
+        assert_eq!(upper.relay_url(false), "wss://relay.example.com:13420");
+
+        let raw = GrinboxAddress::from_str_raw(&format!("{}@GRINBOX.IO", public_key)).unwrap();
+        assert_eq!(raw.domain, DEFAULT_GRINBOX_DOMAIN);
+        assert_eq!(raw.public_key, public_key);
+    }
+
+    #[test]
+    fn invalid_ports_are_parsing_errors() {
+        let public_key = GrinboxAddress::new(public_key(), None, None).public_key;
+        for address in &[
+            format!("{}@relay.example.com:", public_key),
+            format!("{}@relay.example.com:99999", public_key),
+            format!("{}@[::1]:65536", public_key),
+        ] {
+            for result in vec![
+                GrinboxAddress::from_str(address),
+                GrinboxAddress::from_str_raw(address),
+                GrinboxAddress::from_str_tagged(address, b"v2"),
+            ] {
+                let kind = result.unwrap_err().downcast::<ErrorKind>().unwrap();
+                assert_eq!(kind, ErrorKind::GrinboxAddressParsingError(address.clone()));
+            }
+        }
+
+        let highest = GrinboxAddress::from_str(&format!("{}@relay.example.com:65535", public_key)).unwrap();
+        assert_eq!(highest.port, 65535);
+    }
+
+    #[test]
+    fn tagged_addresses_only_parse_with_their_tag() {
+        let tagged = GrinboxAddress::new_tagged(public_key(), None, None, b"v2");
+        assert_ne!(tagged.public_key, GrinboxAddress::new(public_key(), None, None).public_key);
+
+        assert!(GrinboxAddress::from_str(&tagged.to_string()).is_err());
+        assert!(GrinboxAddress::from_str_tagged(&tagged.to_string(), b"v3").is_err());
+
+        let parsed = GrinboxAddress::from_str_tagged(&tagged.to_string(), b"v2").unwrap();
+        assert_eq!(parsed, tagged);
+        assert_eq!(parsed.public_key_tagged(b"v2").unwrap(), public_key());
+        assert!(parsed.public_key().is_err());
+    }
+}
@@ -1,11 +1,15 @@
 use rand::{Rng, thread_rng};
 use ring::{aead, digest, pbkdf2};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::error::{ErrorKind, Result};
 use crate::utils::{from_hex, to_hex};
 use crate::utils::secp::{Secp256k1, PublicKey, SecretKey};
 use crate::types::GrinboxAddress;
 
+/// Longest memo, in bytes, that can be attached to a message.
+pub const MAX_MEMO_BYTES: usize = 256;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GrinboxMessage {
     #[serde(default)]
@@ -13,6 +17,40 @@ pub struct GrinboxMessage {
     encrypted_message: String,
     salt: String,
     nonce: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encrypted_memo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo_nonce: Option<String>,
+    /// When the message was sealed, in seconds since the unix epoch. It is covered by the
+    /// signature over the posted slate, so relays can refuse stale resubmissions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+fn seal(key: &[u8; 32], plaintext: &str) -> Result<(String, String)> {
+    let nonce: [u8; 12] = thread_rng().gen();
+    let mut enc_bytes = plaintext.as_bytes().to_vec();
+    let suffix_len = aead::CHACHA20_POLY1305.tag_len();
+    for _ in 0..suffix_len {
+        enc_bytes.push(0);
+    }
+    let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, key)
+        .map_err(|_| ErrorKind::Encryption)?;
+    aead::seal_in_place(&sealing_key, &nonce, &[], &mut enc_bytes, suffix_len)
+        .map_err(|_| ErrorKind::Encryption)?;
+    Ok((to_hex(enc_bytes), to_hex(nonce.to_vec())))
+}
+
+fn open(key: &[u8; 32], encrypted: &str, nonce: &str) -> Result<String> {
+    let mut encrypted = from_hex(encrypted.to_string()).map_err(|_| ErrorKind::Decryption)?;
+    let nonce = from_hex(nonce.to_string()).map_err(|_| ErrorKind::Decryption)?;
+
+    let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, key)
+        .map_err(|_| ErrorKind::Decryption)?;
+    let decrypted_data = aead::open_in_place(&opening_key, &nonce, &[], 0, &mut encrypted)
+        .map_err(|_| ErrorKind::Decryption)?;
+
+    String::from_utf8(decrypted_data.to_vec()).map_err(|_| ErrorKind::Decryption.into())
 }
 
 impl GrinboxMessage {
@@ -22,6 +60,22 @@ impl GrinboxMessage {
         receiver_public_key: &PublicKey,
         secret_key: &SecretKey,
     ) -> Result<GrinboxMessage> {
+        GrinboxMessage::new_with_memo(message, None, destination, receiver_public_key, secret_key)
+    }
+
+    /// Like `new`, also attaching `memo`, a short note for the recipient that is encrypted to
+    /// them along with the message. Memos longer than `MAX_MEMO_BYTES` are refused.
+    pub fn new_with_memo(
+        message: String,
+        memo: Option<&str>,
+        destination: &GrinboxAddress,
+        receiver_public_key: &PublicKey,
+        secret_key: &SecretKey,
+    ) -> Result<GrinboxMessage> {
+        if memo.map(|memo| memo.len() > MAX_MEMO_BYTES).unwrap_or(false) {
+            return Err(ErrorKind::MemoTooLong(MAX_MEMO_BYTES).into());
+        }
+
         let secp = Secp256k1::new();
         let mut common_secret = receiver_public_key.clone();
         common_secret
@@ -31,24 +85,29 @@ impl GrinboxMessage {
         let common_secret_slice = &common_secret_ser[1..33];
 
         let salt: [u8; 8] = thread_rng().gen();
-        let nonce: [u8; 12] = thread_rng().gen();
         let mut key = [0; 32];
         pbkdf2::derive(&digest::SHA512, 100, &salt, common_secret_slice, &mut key);
-        let mut enc_bytes = message.as_bytes().to_vec();
-        let suffix_len = aead::CHACHA20_POLY1305.tag_len();
-        for _ in 0..suffix_len {
-            enc_bytes.push(0);
-        }
-        let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)
-            .map_err(|_| ErrorKind::Encryption)?;
-        aead::seal_in_place(&sealing_key, &nonce, &[], &mut enc_bytes, suffix_len)
-            .map_err(|_| ErrorKind::Encryption)?;
+
+        let (encrypted_message, nonce) = seal(&key, &message)?;
+        let (encrypted_memo, memo_nonce) = match memo {
+            Some(memo) => {
+                let (encrypted_memo, memo_nonce) = seal(&key, memo)?;
+                (Some(encrypted_memo), Some(memo_nonce))
+            }
+            None => (None, None),
+        };
 
         Ok(GrinboxMessage {
             destination: Some(destination.clone()),
-            encrypted_message: to_hex(enc_bytes),
+            encrypted_message,
             salt: to_hex(salt.to_vec()),
-            nonce: to_hex(nonce.to_vec()),
+            nonce,
+            encrypted_memo,
+            memo_nonce,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since_epoch| since_epoch.as_secs()),
         })
     }
 
@@ -70,16 +129,71 @@ impl GrinboxMessage {
     }
 
     pub fn decrypt_with_key(&self, key: &[u8; 32]) -> Result<String> {
-        let mut encrypted_message =
-            from_hex(self.encrypted_message.clone()).map_err(|_| ErrorKind::Decryption)?;
-        let nonce = from_hex(self.nonce.clone()).map_err(|_| ErrorKind::Decryption)?;
+        open(key, &self.encrypted_message, &self.nonce)
+    }
 
-        let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, key)
-            .map_err(|_| ErrorKind::Decryption)?;
-        let decrypted_data =
-            aead::open_in_place(&opening_key, &nonce, &[], 0, &mut encrypted_message)
-                .map_err(|_| ErrorKind::Decryption)?;
+    /// The memo attached by the sender, if any.
+    pub fn decrypt_memo_with_key(&self, key: &[u8; 32]) -> Result<Option<String>> {
+        match (&self.encrypted_memo, &self.memo_nonce) {
+            (Some(encrypted_memo), Some(memo_nonce)) => open(key, encrypted_memo, memo_nonce).map(Some),
+            (None, None) => Ok(None),
+            _ => Err(ErrorKind::Decryption.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::crypto::public_key_from_secret_key;
+
+    fn keys(secret: u8) -> (SecretKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        (secret_key, public_key)
+    }
+
+    #[test]
+    fn memo_round_trips_to_recipient() {
+        let (sender_secret_key, sender_public_key) = keys(1);
+        let (receiver_secret_key, receiver_public_key) = keys(2);
+        let destination = GrinboxAddress::new(receiver_public_key.clone(), None, None);
+
+        let message = GrinboxMessage::new_with_memo(
+            "slate".to_string(),
+            Some("for the pizza"),
+            &destination,
+            &receiver_public_key,
+            &sender_secret_key,
+        )
+        .unwrap();
+        let message: GrinboxMessage = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+
+        let key = message.key(&sender_public_key, &receiver_secret_key).unwrap();
+        assert!(message.timestamp.is_some());
+        assert_eq!(message.decrypt_with_key(&key).unwrap(), "slate");
+        assert_eq!(message.decrypt_memo_with_key(&key).unwrap(), Some("for the pizza".to_string()));
+
+        let message = GrinboxMessage::new("slate".to_string(), &destination, &receiver_public_key, &sender_secret_key).unwrap();
+        let key = message.key(&sender_public_key, &receiver_secret_key).unwrap();
+        assert_eq!(message.decrypt_memo_with_key(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn long_memo_is_refused() {
+        let (sender_secret_key, _) = keys(1);
+        let (_, receiver_public_key) = keys(2);
+        let destination = GrinboxAddress::new(receiver_public_key.clone(), None, None);
 
-        String::from_utf8(decrypted_data.to_vec()).map_err(|_| ErrorKind::Decryption.into())
+        let memo = "a".repeat(MAX_MEMO_BYTES + 1);
+        assert!(GrinboxMessage::new_with_memo(
+            "slate".to_string(),
+            Some(&memo),
+            &destination,
+            &receiver_public_key,
+            &sender_secret_key,
+        )
+        .is_err());
     }
 }
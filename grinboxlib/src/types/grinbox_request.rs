@@ -5,6 +5,7 @@ use std::fmt::{Display, Formatter, Result};
 #[serde(tag = "type")]
 pub enum GrinboxRequest {
     Challenge,
+    Info,
     Subscribe {
         address: String,
         signature: String,
@@ -15,16 +16,29 @@ pub enum GrinboxRequest {
         str: String,
         signature: String,
         message_expiration_in_seconds: Option<u32>,
+        correlation_id: Option<String>,
+        /// How many more relays the slate may be forwarded through; relays count it down so
+        /// misconfigured relays pointing at each other cannot bounce a slate forever.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hops: Option<u8>,
+        /// The challenge the signature covers, so a relay that has handed out another one since,
+        /// e.g. after restarting, can say so instead of reporting an invalid signature.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        challenge: Option<String>,
     },
     Unsubscribe {
         address: String,
     },
+    UnsubscribeById {
+        subscription_id: String,
+    },
 }
 
 impl Display for GrinboxRequest {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match *self {
             GrinboxRequest::Challenge => write!(f, "{}", "Challenge".bright_purple()),
+            GrinboxRequest::Info => write!(f, "{}", "Info".bright_purple()),
             GrinboxRequest::Subscribe {
                 ref address,
                 signature: _,
@@ -40,12 +54,23 @@ impl Display for GrinboxRequest {
                 "Unsubscribe".bright_purple(),
                 address.bright_green()
             ),
+            GrinboxRequest::UnsubscribeById {
+                ref subscription_id,
+            } => write!(
+                f,
+                "{} from {}",
+                "Unsubscribe".bright_purple(),
+                subscription_id.bright_green()
+            ),
             GrinboxRequest::PostSlate {
                 ref from,
                 ref to,
                 str: _,
                 signature: _,
                 message_expiration_in_seconds: _,
+                correlation_id: _,
+                hops: _,
+                challenge: _,
             } => write!(
                 f,
                 "{} from {} to {}",
@@ -8,6 +8,49 @@ pub enum GrinboxError {
     InvalidSignature,
     InvalidChallenge,
     TooManySubscriptions,
+    RateLimited,
+    Unauthorized,
+    MessageTooLarge,
+    FederationDnsError,
+    FederationTimeout,
+    FederationBusy,
+}
+
+impl GrinboxError {
+    /// Whether the same request may succeed if sent again later, as opposed to errors that
+    /// will keep failing until the request itself is changed.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            GrinboxError::UnknownError => true,
+            GrinboxError::InvalidChallenge => true,
+            GrinboxError::RateLimited => true,
+            GrinboxError::InvalidRequest => false,
+            GrinboxError::InvalidSignature => false,
+            GrinboxError::TooManySubscriptions => false,
+            GrinboxError::Unauthorized => false,
+            GrinboxError::MessageTooLarge => false,
+            GrinboxError::FederationDnsError => false,
+            GrinboxError::FederationTimeout => true,
+            GrinboxError::FederationBusy => true,
+        }
+    }
+
+    /// Stable identifier for the error that clients can branch on, unlike the `Display` text.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            GrinboxError::UnknownError => "UNKNOWN_ERROR",
+            GrinboxError::InvalidRequest => "INVALID_REQUEST",
+            GrinboxError::InvalidSignature => "INVALID_SIGNATURE",
+            GrinboxError::InvalidChallenge => "INVALID_CHALLENGE",
+            GrinboxError::TooManySubscriptions => "TOO_MANY_SUBSCRIPTIONS",
+            GrinboxError::RateLimited => "RATE_LIMITED",
+            GrinboxError::Unauthorized => "UNAUTHORIZED",
+            GrinboxError::MessageTooLarge => "MESSAGE_TOO_LARGE",
+            GrinboxError::FederationDnsError => "FEDERATION_DNS_ERROR",
+            GrinboxError::FederationTimeout => "FEDERATION_TIMEOUT",
+            GrinboxError::FederationBusy => "FEDERATION_BUSY",
+        }
+    }
 }
 
 impl Display for GrinboxError {
@@ -18,46 +61,238 @@ impl Display for GrinboxError {
             GrinboxError::InvalidSignature => write!(f, "{}", "invalid signature!"),
             GrinboxError::InvalidChallenge => write!(f, "{}", "invalid challenge!"),
             GrinboxError::TooManySubscriptions => write!(f, "{}", "too many subscriptions!"),
+            GrinboxError::RateLimited => write!(f, "{}", "rate limited!"),
+            GrinboxError::Unauthorized => write!(f, "{}", "unauthorized!"),
+            GrinboxError::MessageTooLarge => write!(f, "{}", "message too large!"),
+            GrinboxError::FederationDnsError => write!(f, "{}", "could not resolve recipient relay!"),
+            GrinboxError::FederationTimeout => write!(f, "{}", "recipient relay did not respond in time!"),
+            GrinboxError::FederationBusy => write!(f, "{}", "too many slates are waiting to be forwarded!"),
         }
     }
 }
 
+/// The limit a request ran into, sent along errors caused by one so a client can adapt to it.
+#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct ErrorLimit {
+    pub max: usize,
+    pub current: usize,
+}
+
+/// Who the relay claims to be, so a client can check it reached the relay it meant to before
+/// signing anything for it.
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct RelayIdentity {
+    pub domain: String,
+    pub port: u16,
+    pub network: String,
+    pub version: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum GrinboxResponse {
-    Ok,
+    Ok {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        effective_expiration: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscription_id: Option<String>,
+    },
     Error {
         kind: GrinboxError,
+        #[serde(default)]
+        code: String,
         description: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<ErrorLimit>,
     },
     Challenge {
         str: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relay: Option<RelayIdentity>,
+    },
+    Info {
+        network: String,
+        version_bytes: Vec<u8>,
+        /// Largest slate the relay accepts, left out by relays that do not advertise it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_slate_bytes: Option<usize>,
     },
     Slate {
         from: String,
+        #[serde(default)]
+        to: Option<String>,
         str: String,
         signature: String,
         challenge: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<String>,
+    },
+    /// A slate this subscriber posted to `to` expired in the relay before it was delivered.
+    DeliveryExpired {
+        to: String,
     },
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GrinboxResponses {
+    Batch(Vec<GrinboxResponse>),
+    Single(GrinboxResponse),
+}
+
+impl GrinboxResponse {
+    /// Parses a relay message that holds either a single response or a batch of them as a
+    /// top-level array, returning the responses in the order they were sent.
+    pub fn parse_all(text: &str) -> serde_json::Result<Vec<GrinboxResponse>> {
+        match serde_json::from_str(text)? {
+            GrinboxResponses::Batch(responses) => Ok(responses),
+            GrinboxResponses::Single(response) => Ok(vec![response]),
+        }
+    }
+}
+
 impl Display for GrinboxResponse {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match *self {
-            GrinboxResponse::Ok => write!(f, "{}", "Ok".cyan()),
+            GrinboxResponse::Ok {
+                effective_expiration: _,
+                subscription_id: _,
+            } => write!(f, "{}", "Ok".cyan()),
             GrinboxResponse::Error {
                 ref kind,
+                code: _,
                 description: _,
+                limit: _,
             } => write!(f, "{}: {}", "error".bright_red(), kind),
-            GrinboxResponse::Challenge { ref str } => {
+            GrinboxResponse::Challenge { ref str, relay: _ } => {
                 write!(f, "{} {}", "Challenge".cyan(), str.bright_green())
             }
+            GrinboxResponse::Info {
+                ref network,
+                version_bytes: _,
+                max_slate_bytes: _,
+            } => write!(f, "{} {}", "Info".cyan(), network.bright_green()),
             GrinboxResponse::Slate {
                 ref from,
+                to: _,
                 str: _,
                 signature: _,
                 challenge: _,
+                correlation_id: _,
             } => write!(f, "{} from {}", "Slate".cyan(), from.bright_green()),
+            GrinboxResponse::DeliveryExpired { ref to } => {
+                write!(f, "{} to {}", "DeliveryExpired".cyan(), to.bright_green())
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_errors() {
+        assert!(GrinboxError::UnknownError.is_retryable());
+        assert!(GrinboxError::InvalidChallenge.is_retryable());
+        assert!(GrinboxError::RateLimited.is_retryable());
+        assert!(GrinboxError::FederationTimeout.is_retryable());
+        assert!(GrinboxError::FederationBusy.is_retryable());
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        assert_eq!(GrinboxError::UnknownError.code(), "UNKNOWN_ERROR");
+        assert_eq!(GrinboxError::InvalidRequest.code(), "INVALID_REQUEST");
+        assert_eq!(GrinboxError::InvalidSignature.code(), "INVALID_SIGNATURE");
+        assert_eq!(GrinboxError::InvalidChallenge.code(), "INVALID_CHALLENGE");
+        assert_eq!(GrinboxError::TooManySubscriptions.code(), "TOO_MANY_SUBSCRIPTIONS");
+        assert_eq!(GrinboxError::RateLimited.code(), "RATE_LIMITED");
+        assert_eq!(GrinboxError::Unauthorized.code(), "UNAUTHORIZED");
+        assert_eq!(GrinboxError::MessageTooLarge.code(), "MESSAGE_TOO_LARGE");
+        assert_eq!(GrinboxError::FederationDnsError.code(), "FEDERATION_DNS_ERROR");
+        assert_eq!(GrinboxError::FederationTimeout.code(), "FEDERATION_TIMEOUT");
+        assert_eq!(GrinboxError::FederationBusy.code(), "FEDERATION_BUSY");
+    }
+
+    #[test]
+    fn error_limit_is_optional_on_the_wire() {
+        let response: GrinboxResponse = serde_json::from_str(
+            r#"{"type":"Error","kind":"TooManySubscriptions","code":"TOO_MANY_SUBSCRIPTIONS","description":"too many subscriptions!"}"#,
+        )
+        .unwrap();
+        match response {
+            GrinboxResponse::Error { limit, .. } => assert_eq!(limit, None),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn ok_without_expiration_keeps_wire_format() {
+        let response = GrinboxResponse::Ok {
+            effective_expiration: None,
+            subscription_id: None,
+        };
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"type":"Ok"}"#);
+
+        match serde_json::from_str::<GrinboxResponse>(r#"{"type":"Ok"}"#).unwrap() {
+            GrinboxResponse::Ok {
+                effective_expiration,
+                subscription_id,
+            } => {
+                assert_eq!(effective_expiration, None);
+                assert_eq!(subscription_id, None);
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn slate_without_correlation_id_keeps_wire_format() {
+        let response = GrinboxResponse::Slate {
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            str: "slate".to_string(),
+            signature: "signature".to_string(),
+            challenge: "challenge".to_string(),
+            correlation_id: None,
+        };
+        assert!(!serde_json::to_string(&response).unwrap().contains("correlation_id"));
+
+        let slate = r#"{"type":"Slate","from":"alice","str":"slate","signature":"signature","challenge":"challenge"}"#;
+        match serde_json::from_str::<GrinboxResponse>(slate).unwrap() {
+            GrinboxResponse::Slate { correlation_id, .. } => assert_eq!(correlation_id, None),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn parses_single_and_batched_responses() {
+        let responses = GrinboxResponse::parse_all(r#"{"type":"Ok"}"#).unwrap();
+        assert_eq!(responses.len(), 1);
+
+        let responses = GrinboxResponse::parse_all(
+            r#"[{"type":"Ok"},{"type":"Challenge","str":"7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc"}]"#,
+        )
+        .unwrap();
+        assert_eq!(responses.len(), 2);
+        match (&responses[0], &responses[1]) {
+            (GrinboxResponse::Ok { .. }, GrinboxResponse::Challenge { str, relay: None }) => {
+                assert_eq!(str, "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc")
+            }
+            responses => panic!("unexpected responses: {:?}", responses),
+        }
+
+        assert!(GrinboxResponse::parse_all(r#"[{"type":"Ok"},{"type":"Nope"}]"#).is_err());
+    }
+
+    #[test]
+    fn permanent_errors() {
+        assert!(!GrinboxError::InvalidRequest.is_retryable());
+        assert!(!GrinboxError::InvalidSignature.is_retryable());
+        assert!(!GrinboxError::TooManySubscriptions.is_retryable());
+        assert!(!GrinboxError::Unauthorized.is_retryable());
+        assert!(!GrinboxError::MessageTooLarge.is_retryable());
+        assert!(!GrinboxError::FederationDnsError.is_retryable());
+    }
+}
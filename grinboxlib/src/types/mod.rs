@@ -2,14 +2,16 @@ mod grinbox_address;
 mod grinbox_message;
 mod grinbox_request;
 mod grinbox_response;
+mod serializer;
 mod tx_proof;
 
 pub use grin_wallet::libwallet::slate::Slate;
 pub use parking_lot::{Mutex, MutexGuard};
 pub use std::sync::Arc;
 
-pub use self::grinbox_address::{GrinboxAddress, GRINBOX_ADDRESS_VERSION_MAINNET, GRINBOX_ADDRESS_VERSION_TESTNET, version_bytes};
-pub use self::grinbox_message::GrinboxMessage;
+pub use self::grinbox_address::{GrinboxAddress, GRINBOX_ADDRESS_VERSION_MAINNET, GRINBOX_ADDRESS_VERSION_TESTNET, version_bytes, network_name};
+pub use self::grinbox_message::{GrinboxMessage, MAX_MEMO_BYTES};
 pub use self::grinbox_request::GrinboxRequest;
-pub use self::grinbox_response::{GrinboxError, GrinboxResponse};
+pub use self::grinbox_response::{ErrorLimit, GrinboxError, GrinboxResponse, RelayIdentity};
+pub use self::serializer::{serializer_for_subprotocols, CborSerializer, JsonSerializer, Serializer, CBOR_SUBPROTOCOL, JSON_SUBPROTOCOL};
 pub use self::tx_proof::{TxProof, ErrorKind as TxProofErrorKind};
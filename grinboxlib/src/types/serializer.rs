@@ -0,0 +1,163 @@
+use serde::Serialize;
+use ws::Message;
+
+use crate::error::{ErrorKind, Result};
+use crate::types::{GrinboxRequest, GrinboxResponse};
+
+/// Websocket subprotocol for JSON text messages, which is also what connections that do not
+/// ask for a subprotocol get.
+pub const JSON_SUBPROTOCOL: &str = "grinbox.json";
+/// Websocket subprotocol for CBOR binary messages, which are considerably smaller than JSON.
+pub const CBOR_SUBPROTOCOL: &str = "grinbox.cbor";
+
+/// How requests and responses are encoded on a connection, chosen by the websocket
+/// subprotocol the client asks for.
+pub trait Serializer: Send + Sync {
+    fn subprotocol(&self) -> &'static str;
+    fn encode_request(&self, request: &GrinboxRequest) -> Result<Message>;
+    fn decode_request(&self, message: Message) -> Result<GrinboxRequest>;
+    fn encode_response(&self, response: &GrinboxResponse) -> Result<Message>;
+    fn decode_response(&self, message: Message) -> Result<GrinboxResponse>;
+}
+
+pub struct JsonSerializer;
+
+impl Serializer for JsonSerializer {
+    fn subprotocol(&self) -> &'static str {
+        JSON_SUBPROTOCOL
+    }
+
+    fn encode_request(&self, request: &GrinboxRequest) -> Result<Message> {
+        Ok(Message::text(to_json(request)?))
+    }
+
+    fn decode_request(&self, message: Message) -> Result<GrinboxRequest> {
+        let text = message.into_text().map_err(serialization_error)?;
+        Ok(serde_json::from_str(&text).map_err(serialization_error)?)
+    }
+
+    fn encode_response(&self, response: &GrinboxResponse) -> Result<Message> {
+        Ok(Message::text(to_json(response)?))
+    }
+
+    fn decode_response(&self, message: Message) -> Result<GrinboxResponse> {
+        let text = message.into_text().map_err(serialization_error)?;
+        Ok(serde_json::from_str(&text).map_err(serialization_error)?)
+    }
+}
+
+pub struct CborSerializer;
+
+impl Serializer for CborSerializer {
+    fn subprotocol(&self) -> &'static str {
+        CBOR_SUBPROTOCOL
+    }
+
+    fn encode_request(&self, request: &GrinboxRequest) -> Result<Message> {
+        Ok(Message::binary(to_cbor(request)?))
+    }
+
+    fn decode_request(&self, message: Message) -> Result<GrinboxRequest> {
+        Ok(serde_cbor::from_slice(&message.into_data()).map_err(serialization_error)?)
+    }
+
+    fn encode_response(&self, response: &GrinboxResponse) -> Result<Message> {
+        Ok(Message::binary(to_cbor(response)?))
+    }
+
+    fn decode_response(&self, message: Message) -> Result<GrinboxResponse> {
+        Ok(serde_cbor::from_slice(&message.into_data()).map_err(serialization_error)?)
+    }
+}
+
+/// The serializer for the first of `subprotocols` that is supported, in the client's order of
+/// preference.
+pub fn serializer_for_subprotocols(subprotocols: &[&str]) -> Option<Box<dyn Serializer>> {
+    subprotocols.iter().find_map(|subprotocol| match *subprotocol {
+        JSON_SUBPROTOCOL => Some(Box::new(JsonSerializer) as Box<dyn Serializer>),
+        CBOR_SUBPROTOCOL => Some(Box::new(CborSerializer) as Box<dyn Serializer>),
+        _ => None,
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(value).map_err(serialization_error)?)
+}
+
+fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(serde_cbor::to_vec(value).map_err(serialization_error)?)
+}
+
+fn serialization_error<E: std::fmt::Display>(e: E) -> ErrorKind {
+    ErrorKind::GenericError(format!("could not serialize message: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slate_round_trips_under_cbor() {
+        let serializer = serializer_for_subprotocols(&["unknown", CBOR_SUBPROTOCOL, JSON_SUBPROTOCOL]).unwrap();
+        assert_eq!(serializer.subprotocol(), CBOR_SUBPROTOCOL);
+
+        let request = GrinboxRequest::PostSlate {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            str: "slate".to_string(),
+            signature: "signature".to_string(),
+            message_expiration_in_seconds: Some(60),
+            correlation_id: None,
+            hops: Some(2),
+            challenge: None,
+        };
+        let message = serializer.encode_request(&request).unwrap();
+        assert!(message.is_binary());
+        match serializer.decode_request(message).unwrap() {
+            GrinboxRequest::PostSlate { from, to, str, message_expiration_in_seconds, hops, .. } => {
+                assert_eq!((from.as_str(), to.as_str(), str.as_str()), ("alice", "bob", "slate"));
+                assert_eq!(message_expiration_in_seconds, Some(60));
+                assert_eq!(hops, Some(2));
+            }
+            request => panic!("unexpected request: {:?}", request),
+        }
+
+        let response = GrinboxResponse::Slate {
+            from: "alice".to_string(),
+            to: Some("bob".to_string()),
+            str: "slate".to_string(),
+            signature: "signature".to_string(),
+            challenge: "challenge".to_string(),
+            correlation_id: Some("trace".to_string()),
+        };
+        let message = serializer.encode_response(&response).unwrap();
+        let json_length = JsonSerializer.encode_response(&response).unwrap().len();
+        assert!(message.len() < json_length);
+        match serializer.decode_response(message).unwrap() {
+            GrinboxResponse::Slate { from, str, correlation_id, .. } => {
+                assert_eq!((from.as_str(), str.as_str()), ("alice", "slate"));
+                assert_eq!(correlation_id, Some("trace".to_string()));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("not serializable"))
+        }
+    }
+
+    #[test]
+    fn failing_serialization_is_an_error() {
+        assert!(to_json(&Unserializable).is_err());
+        assert!(to_cbor(&Unserializable).is_err());
+    }
+
+    #[test]
+    fn json_serializer_refuses_binary_messages() {
+        assert!(JsonSerializer.decode_request(Message::binary(vec![0xa0])).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use uuid::Uuid;
+
 use crate::types::{GrinboxAddress, GrinboxMessage, Slate};
 use crate::utils::secp::{Commitment, SecretKey, Signature};
 use crate::utils::crypto::{Hex, verify_signature};
@@ -15,7 +17,7 @@ pub enum ErrorKind {
     ParseSlate,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TxProof {
     pub address: GrinboxAddress,
     pub message: String,
@@ -66,6 +68,23 @@ impl TxProof {
             .map_err(|_| ErrorKind::ParseSlate)
     }
 
+    /// Whether the proof is for the slate with id `expected`, so a wallet can tie a received
+    /// proof to the transaction it recorded. Proofs that do not verify match no slate.
+    pub fn matches_slate_id(&self, expected: &Uuid) -> bool {
+        self.verify_extract(None)
+            .map(|(_, slate)| slate.id == *expected)
+            .unwrap_or(false)
+    }
+
+    /// The memo the sender attached to the slate, decrypted with the proof's key.
+    pub fn memo(&self) -> Result<Option<String>, ErrorKind> {
+        let encrypted_message: GrinboxMessage =
+            serde_json::from_str(&self.message).map_err(|_| ErrorKind::ParseGrinboxMessage)?;
+        encrypted_message
+            .decrypt_memo_with_key(&self.key)
+            .map_err(|_| ErrorKind::DecryptMessage)
+    }
+
     pub fn from_response(
         from: String,
         message: String,
@@ -104,3 +123,51 @@ impl TxProof {
         Ok((slate, proof))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::crypto::{public_key_from_secret_key, sign_challenge};
+    use crate::utils::secp::{PublicKey, Secp256k1};
+
+    fn keys(secret: u8) -> (SecretKey, PublicKey) {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        (secret_key, public_key)
+    }
+
+    #[test]
+    fn proof_matches_only_its_slate_id() {
+        let (sender_secret_key, sender_public_key) = keys(1);
+        let (receiver_secret_key, receiver_public_key) = keys(2);
+        let sender = GrinboxAddress::new(sender_public_key, None, None);
+        let receiver = GrinboxAddress::new(receiver_public_key.clone(), None, None);
+
+        let slate = Slate::blank(2);
+        let message = GrinboxMessage::new(
+            serde_json::to_string(&slate).unwrap(),
+            &receiver,
+            &receiver_public_key,
+            &sender_secret_key,
+        )
+        .unwrap();
+        let message = serde_json::to_string(&message).unwrap();
+        let challenge = "challenge".to_string();
+        let signature = sign_challenge(&format!("{}{}", message, challenge), &sender_secret_key).unwrap();
+
+        let (received, proof) = TxProof::from_response(
+            sender.to_string(),
+            message,
+            challenge,
+            signature.to_hex(),
+            &receiver_secret_key,
+            Some(&receiver),
+        )
+        .unwrap();
+
+        assert_eq!(received.id, slate.id);
+        assert!(proof.matches_slate_id(&slate.id));
+        assert!(!proof.matches_slate_id(&Uuid::nil()));
+    }
+}
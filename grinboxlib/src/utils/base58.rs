@@ -17,6 +17,9 @@ pub trait ToBase58 {
     /// Converts a value of `self` to a base58 value, returning the owned string.
     fn to_base58(&self) -> String;
     fn to_base58_check(&self, version: Vec<u8>) -> String;
+    /// Like `to_base58_check`, but the checksum also covers `tag`, which is not itself encoded.
+    /// An empty tag yields the same string as `to_base58_check`.
+    fn to_base58_check_tagged(&self, version: Vec<u8>, tag: &[u8]) -> String;
 }
 
 /// A trait for converting base58 encoded values.
@@ -24,6 +27,9 @@ pub trait FromBase58 {
     /// Convert a value of `self`, interpreted as base58 encoded data, into an owned vector of bytes, returning a vector.
     fn from_base58(&self) -> Result<Vec<u8>>;
     fn from_base58_check(&self, version_bytes: usize) -> Result<(Vec<u8>, Vec<u8>)>;
+    /// Counterpart of `to_base58_check_tagged`; fails the checksum unless `tag` matches the one
+    /// used for encoding.
+    fn from_base58_check_tagged(&self, version_bytes: usize, tag: &[u8]) -> Result<(Vec<u8>, Vec<u8>)>;
 }
 
 impl ToBase58 for [u8] {
@@ -70,9 +76,13 @@ impl ToBase58 for [u8] {
     }
 
     fn to_base58_check(&self, version: Vec<u8>) -> String {
+        self.to_base58_check_tagged(version, &[])
+    }
+
+    fn to_base58_check_tagged(&self, version: Vec<u8>, tag: &[u8]) -> String {
         let mut payload: Vec<u8> = version.iter().chain(self.iter()).map(|x| *x).collect();
-        let mut checksum = double_sha256(&payload);
-        payload.append(&mut checksum[..4].to_vec());
+        let mut checksum = tagged_checksum(&payload, tag);
+        payload.append(&mut checksum);
         payload.to_base58()
     }
 }
@@ -154,13 +164,17 @@ impl FromBase58 for str {
     }
 
     fn from_base58_check(&self, version_bytes: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.from_base58_check_tagged(version_bytes, &[])
+    }
+
+    fn from_base58_check_tagged(&self, version_bytes: usize, tag: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
         let mut payload: Vec<u8> = self.from_base58()?;
         if payload.len() < 5 {
             Err(ErrorKind::InvalidBase58Checksum)?;
         }
         let checksum_index = payload.len() - 4;
         let provided_checksum = payload.split_off(checksum_index);
-        let checksum = double_sha256(&payload)[..4].to_vec();
+        let checksum = tagged_checksum(&payload, tag);
         if checksum != provided_checksum {
             Err(ErrorKind::InvalidBase58Checksum)?;
         }
@@ -171,6 +185,11 @@ impl FromBase58 for str {
     }
 }
 
+fn tagged_checksum(payload: &[u8], tag: &[u8]) -> Vec<u8> {
+    let tagged: Vec<u8> = payload.iter().chain(tag.iter()).map(|x| *x).collect();
+    double_sha256(&tagged)[..4].to_vec()
+}
+
 fn double_sha256(payload: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.input(&payload);
@@ -1,8 +1,9 @@
+use rand::Rng;
 use sha2::{Digest, Sha256};
 
 use crate::error::{ErrorKind, Result};
 use super::base58::{FromBase58, ToBase58};
-use super::secp::{Message, Secp256k1, Signature, Commitment, PublicKey, SecretKey};
+use super::secp::{Error as SecpError, Message, Secp256k1, Signature, Commitment, PublicKey, SecretKey};
 use super::{from_hex, to_hex};
 
 pub trait Hex<T> {
@@ -17,6 +18,49 @@ pub trait Base58<T> {
     fn from_base58_check(str: &str, version_bytes: Vec<u8>) -> Result<T>;
     fn from_base58_check_raw(str: &str, version_bytes: usize) -> Result<(T, Vec<u8>)>;
     fn to_base58_check(&self, version: Vec<u8>) -> String;
+
+    fn from_base58_check_tagged(str: &str, version_bytes: Vec<u8>, tag: &[u8]) -> Result<T>;
+    fn to_base58_check_tagged(&self, version: Vec<u8>, tag: &[u8]) -> String;
+}
+
+/// A public key parsed from its base58-check string form.
+pub struct Base58Key(PublicKey);
+
+impl Base58Key {
+    pub fn from_str(str: &str) -> Result<Base58Key> {
+        let (public_key, _) = PublicKey::from_base58_check_raw(str, 2)?;
+        Ok(Base58Key(public_key))
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+/// A signature parsed from its DER hex string form.
+pub struct HexSignature(Signature);
+
+impl HexSignature {
+    pub fn from_str(str: &str) -> Result<HexSignature> {
+        Ok(HexSignature(Signature::from_hex(str)?))
+    }
+
+    pub fn signature(&self) -> &Signature {
+        &self.0
+    }
+}
+
+/// The message a signature is expected to cover.
+pub struct Challenge(String);
+
+impl Challenge {
+    pub fn new(str: &str) -> Challenge {
+        Challenge(str.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 fn serialize_public_key(public_key: &PublicKey) -> Vec<u8> {
@@ -56,17 +100,25 @@ impl Base58<PublicKey> for PublicKey {
     }
 
     fn from_base58_check(str: &str, version_expect: Vec<u8>) -> Result<PublicKey> {
+        PublicKey::from_base58_check_tagged(str, version_expect, &[])
+    }
+
+    fn to_base58_check(&self, version: Vec<u8>) -> String {
+        serialize_public_key(self).to_base58_check(version)
+    }
+
+    fn from_base58_check_tagged(str: &str, version_expect: Vec<u8>, tag: &[u8]) -> Result<PublicKey> {
         let secp = Secp256k1::new();
         let n_version = version_expect.len();
-        let (version_actual, key_bytes) = str::from_base58_check(str, n_version)?;
+        let (version_actual, key_bytes) = str::from_base58_check_tagged(str, n_version, tag)?;
         if version_actual != version_expect {
             return Err(ErrorKind::InvalidBase58Version.into());
         }
         PublicKey::from_slice(&secp, &key_bytes).map_err(|_| ErrorKind::InvalidBase58Key.into())
     }
 
-    fn to_base58_check(&self, version: Vec<u8>) -> String {
-        serialize_public_key(self).to_base58_check(version)
+    fn to_base58_check_tagged(&self, version: Vec<u8>, tag: &[u8]) -> String {
+        serialize_public_key(self).to_base58_check_tagged(version, tag)
     }
 }
 
@@ -107,6 +159,13 @@ impl Hex<Commitment> for Commitment {
     }
 }
 
+/// A fresh base58 encoded challenge made of 32 random bytes.
+pub fn random_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill(&mut bytes);
+    bytes[..].to_base58()
+}
+
 pub fn public_key_from_secret_key(secret_key: &SecretKey) -> Result<PublicKey> {
     let secp = Secp256k1::new();
     PublicKey::from_secret_key(&secp, secret_key).map_err(|_| ErrorKind::SecpError.into())
@@ -121,6 +180,8 @@ pub fn sign_challenge(challenge: &str, secret_key: &SecretKey) -> Result<Signatu
         .map_err(|_| ErrorKind::SecpError.into())
 }
 
+/// Fails with `IncorrectSignature` when `signature` does not match, and with `SecpError` when
+/// verification itself could not be carried out.
 pub fn verify_signature(
     challenge: &str,
     signature: &Signature,
@@ -128,8 +189,49 @@ pub fn verify_signature(
 ) -> Result<()> {
     let mut hasher = Sha256::new();
     hasher.input(challenge.as_bytes());
-    let message = Message::from_slice(hasher.result().as_slice())?;
+    let message = Message::from_slice(hasher.result().as_slice()).map_err(|_| ErrorKind::SecpError)?;
     let secp = Secp256k1::new();
-    secp.verify(&message, signature, public_key)
-        .map_err(|_| ErrorKind::SecpError.into())
+    secp.verify(&message, signature, public_key).map_err(|e| match e {
+        SecpError::IncorrectSignature => ErrorKind::IncorrectSignature.into(),
+        _ => ErrorKind::SecpError.into(),
+    })
+}
+
+/// Typed counterpart of `verify_signature`, so the key, challenge and signature strings cannot
+/// be swapped by accident:
+///
+/// ```compile_fail
+/// use grinboxlib::utils::crypto::{verify_challenge, Base58Key, Challenge, HexSignature};
+///
+/// fn check(key: &Base58Key, challenge: &Challenge, signature: &HexSignature) {
+///     verify_challenge(key, challenge, signature).unwrap();
+/// }
+/// ```
+pub fn verify_challenge(
+    challenge: &Challenge,
+    signature: &HexSignature,
+    public_key: &Base58Key,
+) -> Result<()> {
+    verify_signature(challenge.as_str(), signature.signature(), public_key.public_key())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::version_bytes;
+
+    #[test]
+    fn verify_challenge_with_typed_arguments() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let signature = sign_challenge("challenge", &secret_key).unwrap();
+
+        let key = Base58Key::from_str(&public_key.to_base58_check(version_bytes())).unwrap();
+        let signature = HexSignature::from_str(&signature.to_hex()).unwrap();
+
+        assert!(verify_challenge(&Challenge::new("challenge"), &signature, &key).is_ok());
+        let error = verify_challenge(&Challenge::new("other"), &signature, &key).unwrap_err();
+        assert_eq!(error.downcast_ref::<ErrorKind>(), Some(&ErrorKind::IncorrectSignature));
+    }
 }
@@ -18,15 +18,18 @@ pub fn to_hex(bytes: Vec<u8>) -> String {
 
 /// Decode a hex string into bytes.
 pub fn from_hex(hex_str: String) -> Result<Vec<u8>> {
-    if hex_str.len() % 2 == 1 {
-        Err(ErrorKind::NumberParsingError)?;
-    }
-    let hex_trim = if &hex_str[..2] == "0x" {
-        hex_str[2..].to_owned()
+    let hex_trim = if hex_str.starts_with("0x") {
+        &hex_str[2..]
     } else {
-        hex_str.clone()
+        &hex_str[..]
     };
-    let vec = split_n(&hex_trim.trim()[..], 2)
+    let hex_trim = hex_trim.trim();
+    // Signatures parsed here come off the wire, so anything split_n cannot slice safely is an
+    // error rather than a panic.
+    if hex_trim.is_empty() || hex_trim.len() % 2 == 1 || !hex_trim.is_ascii() {
+        Err(ErrorKind::NumberParsingError)?;
+    }
+    let vec = split_n(hex_trim, 2)
         .iter()
         .map(|b| u8::from_str_radix(b, 16).map_err(|_| ErrorKind::NumberParsingError.into()))
         .collect::<Result<Vec<u8>>>()?;
@@ -39,3 +42,34 @@ fn split_n(s: &str, n: usize) -> Vec<&str> {
         .collect()
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_parsing_error(hex_str: &str) -> bool {
+        match from_hex(hex_str.to_string()) {
+            Err(e) => match e.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::NumberParsingError) => true,
+                _ => false,
+            },
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn from_hex_rejects_short_and_malformed_input() {
+        assert!(is_parsing_error(""));
+        assert!(is_parsing_error("0"));
+        assert!(is_parsing_error("0x"));
+        assert!(is_parsing_error("0xA"));
+        assert!(is_parsing_error("zz"));
+        assert!(is_parsing_error("éé"));
+    }
+
+    #[test]
+    fn from_hex_decodes_with_and_without_prefix() {
+        assert_eq!(from_hex("0a0B".to_string()).unwrap(), vec![0x0a, 0x0b]);
+        assert_eq!(from_hex("0x0a0B".to_string()).unwrap(), vec![0x0a, 0x0b]);
+    }
+}
@@ -1,3 +1,3 @@
-pub use secp256k1zkp::{Message, Secp256k1, Signature};
+pub use secp256k1zkp::{Error, Message, Secp256k1, Signature};
 pub use secp256k1zkp::pedersen::Commitment;
 pub use secp256k1zkp::key::{PublicKey, SecretKey};
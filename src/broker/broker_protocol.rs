@@ -1,5 +1,23 @@
+use std::sync::{mpsc, Arc};
+
 use futures::sync::mpsc::UnboundedSender;
 
+use grinboxlib::error::Result;
+
+use crate::broker::diagnostics::BrokerDiagnostics;
+use crate::broker::publish_counters::PublishCounters;
+
+/// Longest time a message is kept queued; requested expirations outside of `1..=MAX` fall back
+/// to it.
+pub const MAX_MESSAGE_EXPIRATION_IN_SECONDS: u32 = 86400;
+
+pub fn effective_message_expiration(message_expiration_in_seconds: Option<u32>) -> u32 {
+    match message_expiration_in_seconds {
+        Some(message_expiration_in_seconds @ 1...MAX_MESSAGE_EXPIRATION_IN_SECONDS) => message_expiration_in_seconds,
+        _ => MAX_MESSAGE_EXPIRATION_IN_SECONDS,
+    }
+}
+
 #[derive(Debug)]
 pub enum BrokerRequest {
     Subscribe {
@@ -15,14 +33,81 @@ pub enum BrokerRequest {
         payload: String,
         reply_to: String,
         message_expiration_in_seconds: Option<u32>,
+        correlation_id: Option<String>,
+        /// When set, the broker is asked to confirm it took the message and the outcome is sent
+        /// here as `Published` or `PublishFailed`.
+        receipt_sender: Option<UnboundedSender<BrokerResponse>>,
+    },
+    /// Maintenance operation that drains every message queued for `from_subject` into the
+    /// queue for `to_subject`, preserving each message's `reply_to`.
+    MoveQueue {
+        from_subject: String,
+        to_subject: String,
+    },
+    /// Settles a message delivered with an `ack_id`. Undelivered messages go back to their
+    /// queue to be redelivered, a bounded number of times.
+    Acknowledge {
+        ack_id: String,
+        delivered: bool,
     },
 }
 
+/// Reported once by the broker thread when it stops. The thread never terminates the process,
+/// deciding whether to reconnect or exit is left to the embedding application.
+#[derive(Debug)]
+pub enum BrokerStatus {
+    SessionFailed(String),
+    SessionEnded,
+}
+
+/// A broker backend the relay can run on. Connections only talk to it through the request
+/// channel returned by `start`, so backends can be swapped without touching the server.
+pub trait MessageBroker {
+    /// Spawns the broker thread and returns the sender its requests are read from.
+    fn start(&mut self, status_sender: mpsc::Sender<BrokerStatus>) -> Result<UnboundedSender<BrokerRequest>>;
+
+    /// Per destination publish counts, shared with the running session.
+    fn publish_counters(&self) -> Arc<PublishCounters>;
+
+    /// Uptime and disconnect details of the broker session.
+    fn diagnostics(&self) -> Arc<BrokerDiagnostics>;
+}
+
 #[derive(Debug)]
 pub enum BrokerResponse {
     Message {
         subject: String,
         payload: String,
         reply_to: String,
+        correlation_id: Option<String>,
+        ack_id: Option<String>,
+    },
+    /// A message `subject` posted to `to` expired before it could be delivered.
+    DeliveryExpired {
+        subject: String,
+        to: String,
+    },
+    /// The broker confirmed it took a message posted with a `receipt_sender`.
+    Published {
+        reply_to: String,
+        message_expiration_in_seconds: Option<u32>,
+    },
+    /// The broker did not confirm a message posted with a `receipt_sender` in time.
+    PublishFailed {
+        reply_to: String,
     },
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn effective_expiration_is_clamped() {
+        assert_eq!(effective_message_expiration(Some(600)), 600);
+        assert_eq!(effective_message_expiration(Some(86400)), 86400);
+        assert_eq!(effective_message_expiration(Some(86401)), 86400);
+        assert_eq!(effective_message_expiration(Some(0)), 86400);
+        assert_eq!(effective_message_expiration(None), 86400);
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+use futures::{future, Future, Poll};
+use native_tls::TlsConnector;
+use tokio::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tls::TlsStream;
+
+use crate::broker::stomp::session::ConnectFuture;
+
+/// The connection to the STOMP broker, either plain TCP or TLS over TCP.
+pub enum BrokerStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl BrokerStream {
+    /// Connects to `address`, negotiating TLS when `tls_domain` is given. The broker's certificate
+    /// has to be valid for `tls_domain`, otherwise the connection fails.
+    pub fn connect(address: &SocketAddr, tls_domain: Option<String>) -> ConnectFuture<BrokerStream> {
+        let tcp_stream = TcpStream::connect(address);
+        let domain = match tls_domain {
+            Some(domain) => domain,
+            None => return Box::new(tcp_stream.map(BrokerStream::Plain)),
+        };
+
+        let connector = match TlsConnector::new() {
+            Ok(connector) => tokio_tls::TlsConnector::from(connector),
+            Err(e) => return Box::new(future::err(io::Error::new(io::ErrorKind::Other, e))),
+        };
+        Box::new(tcp_stream.and_then(move |stream| {
+            connector
+                .connect(&domain, stream)
+                .map(BrokerStream::Tls)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        }))
+    }
+}
+
+impl Read for BrokerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            BrokerStream::Plain(ref mut stream) => stream.read(buf),
+            BrokerStream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for BrokerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            BrokerStream::Plain(ref mut stream) => stream.write(buf),
+            BrokerStream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            BrokerStream::Plain(ref mut stream) => stream.flush(),
+            BrokerStream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for BrokerStream {}
+
+impl AsyncWrite for BrokerStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            BrokerStream::Plain(ref mut stream) => AsyncWrite::shutdown(stream),
+            BrokerStream::Tls(ref mut stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
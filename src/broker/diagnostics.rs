@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Health of the broker session as last reported by it, for operators to inspect.
+pub struct BrokerDiagnostics {
+    state: Mutex<DiagnosticsState>,
+}
+
+struct DiagnosticsState {
+    connected_at: Option<Instant>,
+    connections: u32,
+    heartbeat: Option<(u32, u32)>,
+    last_disconnect_reason: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DiagnosticsSnapshot {
+    /// How long the current session has been connected, if it is.
+    pub uptime: Option<Duration>,
+    /// Negotiated `(tx_ms, rx_ms)` heartbeat intervals of the current session.
+    pub heartbeat: Option<(u32, u32)>,
+    pub reconnects: u32,
+    pub last_disconnect_reason: Option<String>,
+}
+
+impl BrokerDiagnostics {
+    pub fn new() -> BrokerDiagnostics {
+        BrokerDiagnostics {
+            state: Mutex::new(DiagnosticsState {
+                connected_at: None,
+                connections: 0,
+                heartbeat: None,
+                last_disconnect_reason: None,
+            }),
+        }
+    }
+
+    pub fn connected(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.connected_at = Some(now);
+        state.connections += 1;
+    }
+
+    pub fn heartbeat_negotiated(&self, tx_ms: u32, rx_ms: u32) {
+        self.state.lock().unwrap().heartbeat = Some((tx_ms, rx_ms));
+    }
+
+    pub fn disconnected(&self, reason: String) {
+        let mut state = self.state.lock().unwrap();
+        state.connected_at = None;
+        state.heartbeat = None;
+        state.last_disconnect_reason = Some(reason);
+    }
+
+    pub fn snapshot(&self, now: Instant) -> DiagnosticsSnapshot {
+        let state = self.state.lock().unwrap();
+        DiagnosticsSnapshot {
+            uptime: state.connected_at.map(|connected_at| now.duration_since(connected_at)),
+            heartbeat: state.heartbeat,
+            reconnects: state.connections.saturating_sub(1),
+            last_disconnect_reason: state.last_disconnect_reason.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_reflects_reconnects() {
+        let diagnostics = BrokerDiagnostics::new();
+        let start = Instant::now();
+
+        diagnostics.connected(start);
+        diagnostics.heartbeat_negotiated(10000, 20000);
+        diagnostics.disconnected("HeartBeatTimeout".to_string());
+        diagnostics.connected(start + Duration::from_secs(5));
+        diagnostics.heartbeat_negotiated(10000, 10000);
+
+        assert_eq!(
+            diagnostics.snapshot(start + Duration::from_secs(65)),
+            DiagnosticsSnapshot {
+                uptime: Some(Duration::from_secs(60)),
+                heartbeat: Some((10000, 10000)),
+                reconnects: 1,
+                last_disconnect_reason: Some("HeartBeatTimeout".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+
+use futures::{
+    Stream,
+    sync::mpsc::{unbounded, UnboundedSender},
+};
+
+use grinboxlib::error::Result;
+
+use crate::broker::{BrokerRequest, BrokerResponse, BrokerStatus, MessageBroker};
+use crate::broker::diagnostics::BrokerDiagnostics;
+use crate::broker::publish_counters::PublishCounters;
+
+const TRACKED_DESTINATIONS: usize = 100;
+
+/// Queues messages in memory, so the server can be exercised against a broker in tests without
+/// running one. Messages to a subject nobody is subscribed to are held until someone is;
+/// expirations and acknowledgements are ignored.
+pub struct InMemoryBroker {
+    publish_counters: Arc<PublishCounters>,
+    diagnostics: Arc<BrokerDiagnostics>,
+}
+
+impl InMemoryBroker {
+    pub fn new() -> InMemoryBroker {
+        InMemoryBroker {
+            publish_counters: Arc::new(PublishCounters::new(TRACKED_DESTINATIONS)),
+            diagnostics: Arc::new(BrokerDiagnostics::new()),
+        }
+    }
+}
+
+impl MessageBroker for InMemoryBroker {
+    fn publish_counters(&self) -> Arc<PublishCounters> {
+        self.publish_counters.clone()
+    }
+
+    fn diagnostics(&self) -> Arc<BrokerDiagnostics> {
+        self.diagnostics.clone()
+    }
+
+    fn start(&mut self, status_sender: mpsc::Sender<BrokerStatus>) -> Result<UnboundedSender<BrokerRequest>> {
+        let (tx, rx) = unbounded();
+        let publish_counters = self.publish_counters.clone();
+        std::thread::spawn(move || {
+            let mut queues = MemoryQueues::new();
+            for request in rx.wait() {
+                match request {
+                    Ok(request) => queues.handle(request, &publish_counters),
+                    Err(()) => break,
+                }
+            }
+            let _ = status_sender.send(BrokerStatus::SessionEnded);
+        });
+        Ok(tx)
+    }
+}
+
+struct MemoryQueues {
+    consumers: HashMap<String, (String, UnboundedSender<BrokerResponse>)>,
+    pending: HashMap<String, Vec<BrokerResponse>>,
+}
+
+impl MemoryQueues {
+    fn new() -> MemoryQueues {
+        MemoryQueues {
+            consumers: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn handle(&mut self, request: BrokerRequest, publish_counters: &PublishCounters) {
+        match request {
+            BrokerRequest::Subscribe { id, subject, response_sender } => {
+                for message in self.pending.remove(&subject).unwrap_or_default() {
+                    let _ = response_sender.unbounded_send(message);
+                }
+                self.consumers.insert(subject, (id, response_sender));
+            },
+            BrokerRequest::Unsubscribe { id } => {
+                self.consumers.retain(|_, (consumer_id, _)| *consumer_id != id);
+            },
+            BrokerRequest::PostMessage { subject, payload, reply_to, message_expiration_in_seconds, correlation_id, receipt_sender } => {
+                publish_counters.record(&subject);
+                if let Some(receipt_sender) = receipt_sender {
+                    let _ = receipt_sender.unbounded_send(BrokerResponse::Published {
+                        reply_to: reply_to.clone(),
+                        message_expiration_in_seconds,
+                    });
+                }
+                let message = BrokerResponse::Message {
+                    subject: subject.clone(),
+                    payload,
+                    reply_to,
+                    correlation_id,
+                    ack_id: None,
+                };
+                self.deliver(subject, message);
+            },
+            BrokerRequest::MoveQueue { from_subject, to_subject } => {
+                for message in self.pending.remove(&from_subject).unwrap_or_default() {
+                    self.deliver(to_subject.clone(), message);
+                }
+            },
+            BrokerRequest::Acknowledge { .. } => {},
+        }
+    }
+
+    fn deliver(&mut self, subject: String, message: BrokerResponse) {
+        let message = match self.consumers.get(&subject) {
+            Some((_, sender)) => match sender.unbounded_send(message) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            },
+            None => message,
+        };
+        self.pending.entry(subject).or_insert_with(Vec::new).push(message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::broker::self_test;
+
+    #[test]
+    fn in_memory_broker_passes_self_test() {
+        let (status_sender, _status_receiver) = mpsc::channel();
+        let sender = InMemoryBroker::new().start(status_sender).unwrap();
+        self_test(&sender, Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn messages_wait_for_their_subscriber() {
+        let (status_sender, _status_receiver) = mpsc::channel();
+        let sender = InMemoryBroker::new().start(status_sender).unwrap();
+        sender.unbounded_send(BrokerRequest::PostMessage {
+            subject: "bob".to_string(),
+            payload: "slate".to_string(),
+            reply_to: "alice".to_string(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            receipt_sender: None,
+        }).unwrap();
+
+        let (response_sender, response_receiver) = unbounded();
+        sender.unbounded_send(BrokerRequest::Subscribe {
+            id: "connection/0".to_string(),
+            subject: "bob".to_string(),
+            response_sender,
+        }).unwrap();
+
+        match response_receiver.wait().next() {
+            Some(Ok(BrokerResponse::Message { payload, reply_to, .. })) => {
+                assert_eq!(payload, "slate");
+                assert_eq!(reply_to, "alice");
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn unsubscribed_consumer_gets_no_messages() {
+        let mut queues = MemoryQueues::new();
+        let counters = PublishCounters::new(TRACKED_DESTINATIONS);
+        let (response_sender, response_receiver) = unbounded();
+        queues.handle(BrokerRequest::Subscribe {
+            id: "connection/0".to_string(),
+            subject: "bob".to_string(),
+            response_sender,
+        }, &counters);
+        queues.handle(BrokerRequest::Unsubscribe { id: "connection/0".to_string() }, &counters);
+        queues.handle(BrokerRequest::PostMessage {
+            subject: "bob".to_string(),
+            payload: "slate".to_string(),
+            reply_to: "alice".to_string(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            receipt_sender: None,
+        }, &counters);
+
+        drop(queues);
+        assert_eq!(response_receiver.wait().count(), 0);
+        assert_eq!(counters.top(1).len(), 1);
+    }
+}
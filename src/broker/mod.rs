@@ -1,6 +1,21 @@
 mod broker_protocol;
+mod broker_stream;
+mod diagnostics;
+#[cfg(test)]
+mod memory_broker;
+mod nats_broker;
+mod publish_counters;
 mod rabbit_broker;
+mod self_test;
 mod stomp;
+mod subscription_registry;
 
-pub use self::broker_protocol::{BrokerRequest, BrokerResponse};
+pub use self::broker_protocol::{effective_message_expiration, BrokerRequest, BrokerResponse, BrokerStatus, MessageBroker};
+pub use self::diagnostics::BrokerDiagnostics;
+#[cfg(test)]
+pub use self::memory_broker::InMemoryBroker;
+pub use self::nats_broker::NatsBroker;
+pub use self::publish_counters::PublishCounters;
 pub use self::rabbit_broker::Broker;
+pub use self::self_test::self_test;
+pub use self::stomp::frame::log_full_bodies;
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+
+use futures::{
+    Stream,
+    sync::mpsc::{unbounded, UnboundedSender},
+    Future
+};
+use nitox::commands::{ConnectCommand, PubCommand, SubCommand, UnsubCommand};
+use nitox::{NatsClient, NatsClientOptions, NatsError};
+use tokio::executor::current_thread;
+
+use grinboxlib::error::{ErrorKind, Result};
+
+use crate::broker::{BrokerRequest, BrokerResponse, BrokerStatus, MessageBroker};
+use crate::broker::diagnostics::BrokerDiagnostics;
+use crate::broker::publish_counters::PublishCounters;
+
+const TRACKED_DESTINATIONS: usize = 100;
+
+/// Runs the relay on NATS. Subjects map one to one onto NATS subjects and the sender is carried
+/// in the NATS reply-to field. NATS keeps nothing for subjects without subscribers, so slates
+/// posted to an offline address are lost, and expirations, acknowledgements, queue moves and
+/// correlation ids are not supported.
+pub struct NatsBroker {
+    cluster_uri: String,
+    username: String,
+    password: String,
+    publish_counters: Arc<PublishCounters>,
+    diagnostics: Arc<BrokerDiagnostics>,
+}
+
+impl NatsBroker {
+    pub fn new(cluster_uri: String, username: String, password: String) -> NatsBroker {
+        NatsBroker {
+            cluster_uri,
+            username,
+            password,
+            publish_counters: Arc::new(PublishCounters::new(TRACKED_DESTINATIONS)),
+            diagnostics: Arc::new(BrokerDiagnostics::new()),
+        }
+    }
+
+    fn client_options(&self) -> Result<NatsClientOptions> {
+        let connect_command = ConnectCommand::builder()
+            .user(Some(self.username.clone()))
+            .pass(Some(self.password.clone()))
+            .build()
+            .map_err(ErrorKind::GenericError)?;
+
+        let options = NatsClientOptions::builder()
+            .connect_command(connect_command)
+            .cluster_uri(self.cluster_uri.clone())
+            .build()
+            .map_err(ErrorKind::GenericError)?;
+        Ok(options)
+    }
+}
+
+impl MessageBroker for NatsBroker {
+    fn publish_counters(&self) -> Arc<PublishCounters> {
+        self.publish_counters.clone()
+    }
+
+    fn diagnostics(&self) -> Arc<BrokerDiagnostics> {
+        self.diagnostics.clone()
+    }
+
+    fn start(&mut self, status_sender: mpsc::Sender<BrokerStatus>) -> Result<UnboundedSender<BrokerRequest>> {
+        let (tx, rx) = unbounded();
+        let options = self.client_options()?;
+        let publish_counters = self.publish_counters.clone();
+        let diagnostics = self.diagnostics.clone();
+        std::thread::spawn(move || {
+            let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("could not start broker runtime: {}", e);
+                    let _ = status_sender.send(BrokerStatus::SessionFailed(e.to_string()));
+                    return;
+                }
+            };
+
+            let client = match runtime.block_on(NatsClient::from_options(options).and_then(|client| client.connect())) {
+                Ok(client) => Arc::new(client),
+                Err(e) => {
+                    error!("could not connect to nats: {}", e);
+                    let _ = status_sender.send(BrokerStatus::SessionFailed(e.to_string()));
+                    return;
+                }
+            };
+            info!("established nats connection");
+            diagnostics.connected(Instant::now());
+
+            let mut session = NatsSession::new(client, publish_counters);
+            let request_loop = rx.for_each(move |request| {
+                session.handle(request);
+                Ok(())
+            });
+
+            let _ = runtime.block_on(request_loop);
+
+            error!("broker thread ending!");
+            diagnostics.disconnected("request channel closed".to_string());
+
+            let _ = status_sender.send(BrokerStatus::SessionEnded);
+        });
+
+        Ok(tx)
+    }
+}
+
+struct NatsSession {
+    client: Arc<NatsClient>,
+    publish_counters: Arc<PublishCounters>,
+    subscription_ids: HashMap<String, String>,
+}
+
+impl NatsSession {
+    fn new(client: Arc<NatsClient>, publish_counters: Arc<PublishCounters>) -> NatsSession {
+        NatsSession {
+            client,
+            publish_counters,
+            subscription_ids: HashMap::new(),
+        }
+    }
+
+    fn handle(&mut self, request: BrokerRequest) {
+        match request {
+            BrokerRequest::Subscribe { id, subject, response_sender } => {
+                self.subscribe(id, subject, response_sender);
+            },
+            BrokerRequest::Unsubscribe { id } => {
+                self.unsubscribe(&id);
+            },
+            BrokerRequest::PostMessage { subject, payload, reply_to, message_expiration_in_seconds, receipt_sender, .. } => {
+                self.publish(subject, payload, reply_to, message_expiration_in_seconds, receipt_sender);
+            },
+            BrokerRequest::MoveQueue { from_subject, to_subject } => {
+                warn!("nats broker cannot move [{}] to [{}]: it keeps no queues", from_subject, to_subject);
+            },
+            BrokerRequest::Acknowledge { .. } => {},
+        }
+    }
+
+    fn subscribe(&mut self, id: String, subject: String, sender: UnboundedSender<BrokerResponse>) {
+        let command = match SubCommand::builder().subject(subject.clone()).build() {
+            Ok(command) => command,
+            Err(e) => {
+                error!("could not build nats subscription for [{}]: {}", subject, e);
+                return;
+            }
+        };
+
+        self.unsubscribe(&id);
+        self.subscription_ids.insert(id, command.sid.clone());
+
+        let subscription = self
+            .client
+            .subscribe(command)
+            .and_then(move |messages| {
+                messages.for_each(move |message| {
+                    let response = message_response(&subject, &message.payload, message.reply_to);
+                    // The connection went away; ending the stream drops the subscription.
+                    sender
+                        .unbounded_send(response)
+                        .map_err(|_| NatsError::InnerBrokenChain)
+                })
+            })
+            .map_err(|e| debug!("nats subscription ended: {}", e));
+        current_thread::spawn(subscription);
+    }
+
+    fn unsubscribe(&mut self, id: &str) {
+        if let Some(sid) = self.subscription_ids.remove(id) {
+            match UnsubCommand::builder().sid(sid).build() {
+                Ok(command) => {
+                    current_thread::spawn(
+                        self.client
+                            .unsubscribe(command)
+                            .map_err(|e| error!("could not unsubscribe from nats: {}", e))
+                    );
+                }
+                Err(e) => error!("could not build nats unsubscription: {}", e),
+            }
+        }
+    }
+
+    /// NATS has no receipts; a requested one is answered once the message was written to the
+    /// connection.
+    fn publish(&self, subject: String, payload: String, reply_to: String, message_expiration_in_seconds: Option<u32>, receipt_sender: Option<UnboundedSender<BrokerResponse>>) {
+        self.publish_counters.record(&subject);
+        match PubCommand::builder().subject(subject).reply_to(Some(reply_to.clone())).payload(payload).build() {
+            Ok(command) => {
+                current_thread::spawn(
+                    self.client
+                        .publish(command)
+                        .then(move |result| {
+                            let response = match result {
+                                Ok(()) => BrokerResponse::Published { reply_to, message_expiration_in_seconds },
+                                Err(e) => {
+                                    error!("could not publish to nats: {}", e);
+                                    BrokerResponse::PublishFailed { reply_to }
+                                }
+                            };
+                            if let Some(receipt_sender) = receipt_sender {
+                                let _ = receipt_sender.unbounded_send(response);
+                            }
+                            Ok::<(), ()>(())
+                        })
+                );
+            }
+            Err(e) => error!("could not build nats message: {}", e),
+        }
+    }
+}
+
+/// Turns a NATS delivery into the response consumers expect from any broker. NATS has no
+/// acknowledgements, so messages never carry an `ack_id`.
+fn message_response(subject: &str, payload: &[u8], reply_to: Option<String>) -> BrokerResponse {
+    BrokerResponse::Message {
+        subject: subject.to_string(),
+        payload: String::from_utf8_lossy(payload).to_string(),
+        reply_to: reply_to.unwrap_or_default(),
+        correlation_id: None,
+        ack_id: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delivery_keeps_sender_from_reply_to() {
+        match message_response("bob", b"slate", Some("alice".to_string())) {
+            BrokerResponse::Message { subject, payload, reply_to, correlation_id, ack_id } => {
+                assert_eq!(subject, "bob");
+                assert_eq!(payload, "slate");
+                assert_eq!(reply_to, "alice");
+                assert_eq!(correlation_id, None);
+                assert_eq!(ack_id, None);
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+}
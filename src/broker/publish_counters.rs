@@ -0,0 +1,91 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+
+/// Counts published slates per destination so operators can see which recipients receive the
+/// most traffic. Subjects are only kept hashed with a key drawn at random when the counters are
+/// created, so the published hashes cannot be matched against guessed addresses, and at most `capacity` of them are tracked: a
+/// new subject replaces the least counted one and inherits its count, so heavy destinations
+/// stay visible while memory stays bounded.
+pub struct PublishCounters {
+    capacity: usize,
+    key: RandomState,
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl PublishCounters {
+    pub fn new(capacity: usize) -> PublishCounters {
+        PublishCounters {
+            capacity,
+            key: RandomState::new(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn hash_subject(&self, subject: &str) -> String {
+        let mut hasher = self.key.build_hasher();
+        subject.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    pub fn record(&self, subject: &str) {
+        let key = self.hash_subject(subject);
+        let mut counts = self.counts.lock().unwrap();
+
+        if !counts.contains_key(&key) && counts.len() >= self.capacity {
+            let evicted = counts
+                .iter()
+                .min_by_key(|&(_, count)| *count)
+                .map(|(key, count)| (key.clone(), *count));
+            if let Some((evicted_key, evicted_count)) = evicted {
+                counts.remove(&evicted_key);
+                counts.insert(key.clone(), evicted_count);
+            }
+        }
+
+        *counts.entry(key).or_insert(0) += 1;
+    }
+
+    /// The `n` most published to destinations with their counts, busiest first.
+    pub fn top(&self, n: usize) -> Vec<(String, usize)> {
+        let mut top: Vec<(String, usize)> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_publishes_per_hashed_subject() {
+        let counters = PublishCounters::new(2);
+        for subject in &["alice", "bob", "alice", "alice", "bob", "carol"] {
+            counters.record(subject);
+        }
+
+        let top = counters.top(2);
+        assert_eq!(top[0], (counters.hash_subject("alice"), 3));
+        assert_eq!(top.len(), 2);
+        assert!(top.iter().all(|(key, _)| key != "alice" && key != "bob" && key != "carol"));
+
+        assert_eq!(counters.top(1), vec![(counters.hash_subject("alice"), 3)]);
+    }
+
+    #[test]
+    fn hashes_are_keyed_per_instance() {
+        let first = PublishCounters::new(1);
+        let second = PublishCounters::new(1);
+        assert_eq!(first.hash_subject("alice"), first.hash_subject("alice"));
+        assert_ne!(first.hash_subject("alice"), second.hash_subject("alice"));
+    }
+}
@@ -1,70 +1,323 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use tokio::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use futures::{
+    future::Either,
     Stream,
     sync::mpsc::{unbounded, UnboundedSender},
     Future
 };
 
-use grinboxlib::error::Result;
+use grinboxlib::error::{ErrorKind, Result};
 
-use crate::broker::{BrokerRequest, BrokerResponse};
-use crate::broker::stomp::session::SessionEvent;
+use crate::broker::{effective_message_expiration, BrokerRequest, BrokerResponse, BrokerStatus, MessageBroker};
+use crate::broker::broker_stream::BrokerStream;
+use crate::broker::diagnostics::BrokerDiagnostics;
+use crate::broker::publish_counters::PublishCounters;
+use crate::broker::subscription_registry::SubscriptionRegistry;
+use crate::broker::stomp::message_builder::MessageBuilder;
+use crate::broker::stomp::session::{GenerateReceipt, SessionEvent};
 use crate::broker::stomp::session_builder::SessionBuilder;
-use crate::broker::stomp::connection::{HeartBeat, Credentials};
-use crate::broker::stomp::header::{Header, HeaderName, SUBSCRIPTION};
+use crate::broker::stomp::connection::{AcceptVersion, HeartBeat, Credentials, MinimumVersion};
+use crate::broker::stomp::header::{Header, HeaderList, HeaderName, StompVersion, ACK, MESSAGE, SUBSCRIPTION};
 use crate::broker::stomp::subscription::AckMode;
 use crate::broker::stomp::frame::Frame;
 
-type Session = crate::broker::stomp::session::Session<TcpStream>;
+type Session = crate::broker::stomp::session::Session<BrokerStream>;
 
-const DEFAULT_QUEUE_EXPIRATION: &str = "86400000";
-const DEFAULT_MESSAGE_EXPIRATION: u32 = 86400;
+const DEFAULT_QUEUE_EXPIRATION: Duration = Duration::from_secs(86400);
 const REPLY_TO_HEADER_NAME: &str = "grinbox-reply-to";
+const CORRELATION_ID_HEADER_NAME: &str = "grinbox-correlation-id";
+const QUEUE_MOVE_MARKER_HEADER_NAME: &str = "grinbox-queue-move-marker";
+const RECIPIENT_HEADER_NAME: &str = "grinbox-to";
+const DEATH_REASON_HEADER_NAME: &str = "x-first-death-reason";
+const REQUEUE_HEADER_NAME: &str = "requeue";
+const DEAD_LETTER_EXCHANGE: &str = "amq.direct";
+const EXPIRED_ROUTING_KEY: &str = "grinbox-expired";
+const CONTENT_ENCODING_HEADER_NAME: &str = "content-encoding";
+const GZIP_CONTENT_ENCODING: &str = "gzip";
+const TRACKED_DESTINATIONS: usize = 100;
+const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How long the broker has to confirm a message published with a receipt request.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the broker has to confirm DISCONNECT when the relay shuts down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most slates kept while the broker session is not connected; more are refused.
+const MAX_QUEUED_PUBLISHES: usize = 10000;
+/// Least time between two writes of the subscription registry.
+const REGISTRY_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Times a message its client could not take goes back to its queue before it is rejected for
+/// good, so a client that keeps failing does not have it redelivered in a loop.
+const MAX_REDELIVERIES: u32 = 5;
+/// Most messages whose failed deliveries are counted at once.
+const TRACKED_REDELIVERIES: usize = 10000;
 
 pub struct Broker {
     address: SocketAddr,
     username: String,
     password: String,
+    subscription_registry_path: Option<PathBuf>,
+    accept_versions: Option<String>,
+    min_version: Option<String>,
+    compress_payloads: bool,
+    queue_expiration: Duration,
+    delivery_acks: bool,
+    expiry_notifications: bool,
+    reconnect: bool,
+    min_reconnect_interval: Duration,
+    max_reconnect_delay: Duration,
+    tls_domain: Option<String>,
+    publish_counters: Arc<PublishCounters>,
+    diagnostics: Arc<BrokerDiagnostics>,
+}
+
+/// Delay before the given reconnection attempt, doubling from one second up to `max_delay`.
+fn reconnect_delay(attempt: u32, max_delay: Duration) -> Duration {
+    std::cmp::min(Duration::from_secs(1u64 << attempt.min(32)), max_delay)
+}
+
+/// Decides how long to wait before each reconnection attempt.
+struct ReconnectPacer {
+    attempt: u32,
+    min_interval: Duration,
+    max_delay: Duration,
+    last_attempt: Option<Instant>,
+}
+
+impl ReconnectPacer {
+    fn new(min_interval: Duration, max_delay: Duration) -> ReconnectPacer {
+        ReconnectPacer {
+            attempt: 0,
+            min_interval,
+            max_delay,
+            last_attempt: None,
+        }
+    }
+
+    fn attempt_started(&mut self, now: Instant) {
+        self.last_attempt = Some(now);
+    }
+
+    /// The backoff for the next attempt, stretched so it does not start sooner than
+    /// `min_interval` after the previous one. A session the broker expired is not backed off
+    /// from, as the broker is up and only dropped the session for being inactive.
+    fn next_delay(&mut self, was_connected: bool, session_expired: bool, now: Instant) -> Duration {
+        if was_connected {
+            self.attempt = 0;
+        }
+        let backoff = if session_expired {
+            Duration::from_secs(0)
+        } else {
+            let backoff = reconnect_delay(self.attempt, self.max_delay);
+            self.attempt = self.attempt.saturating_add(1);
+            backoff
+        };
+
+        let debounce = match self.last_attempt {
+            Some(last_attempt) => self
+                .min_interval
+                .checked_sub(now.duration_since(last_attempt))
+                .unwrap_or(Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        };
+        std::cmp::max(backoff, debounce)
+    }
+}
+
+fn parse_stomp_version(version: &str) -> Result<StompVersion> {
+    version
+        .trim()
+        .parse::<StompVersion>()
+        .map_err(|_| ErrorKind::GenericError(format!("invalid STOMP version `{}`", version)).into())
 }
 
 impl Broker {
-    pub fn new(address: SocketAddr, username: String, password: String) -> Broker {
+    pub fn new(address: SocketAddr, username: String, password: String, subscription_registry_path: Option<PathBuf>) -> Broker {
         Broker {
             address,
             username,
             password,
+            subscription_registry_path,
+            accept_versions: None,
+            min_version: None,
+            compress_payloads: false,
+            queue_expiration: DEFAULT_QUEUE_EXPIRATION,
+            delivery_acks: false,
+            expiry_notifications: false,
+            reconnect: false,
+            min_reconnect_interval: Duration::from_secs(0),
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+            tls_domain: None,
+            publish_counters: Arc::new(PublishCounters::new(TRACKED_DESTINATIONS)),
+            diagnostics: Arc::new(BrokerDiagnostics::new()),
         }
     }
 
-    pub fn start(&mut self) -> Result<UnboundedSender<BrokerRequest>> {
+    pub fn with_stomp_versions(mut self, accept_versions: Option<String>, min_version: Option<String>) -> Broker {
+        self.accept_versions = accept_versions;
+        self.min_version = min_version;
+        self
+    }
+
+    /// Gzips published payloads to reduce broker memory and disk usage. Compressed messages are
+    /// marked with a `content-encoding` header, so messages queued with either setting are
+    /// always delivered correctly.
+    pub fn with_payload_compression(mut self, compress_payloads: bool) -> Broker {
+        self.compress_payloads = compress_payloads;
+        self
+    }
+
+    /// How long an unused queue is kept by the broker before it is deleted along with any
+    /// messages still in it. Queues are declared with it as `x-expires`, which RabbitMQ refuses
+    /// to change on an existing queue, so queues declared with another expiration have to be
+    /// drained and deleted before it changes.
+    pub fn with_queue_expiration(mut self, queue_expiration: Duration) -> Broker {
+        self.queue_expiration = queue_expiration;
+        self
+    }
+
+    /// Subscribes with client acknowledgements, so a slate that could not be written to its
+    /// client stays queued and is redelivered instead of being lost. A slate that fails
+    /// `MAX_REDELIVERIES` times in a row is rejected rather than redelivered again.
+    pub fn with_delivery_acks(mut self, delivery_acks: bool) -> Broker {
+        self.delivery_acks = delivery_acks;
+        self
+    }
+
+    /// Dead-letters messages whose expiration passes before they are delivered, so their sender
+    /// is told with a `DeliveryExpired` response if it is subscribed at that moment. The setting
+    /// is part of the arguments queues are declared with, which RabbitMQ refuses to change on an
+    /// existing queue, so queues declared without it have to be deleted before it is turned on
+    /// and the other way round.
+    pub fn with_expiry_notifications(mut self, expiry_notifications: bool) -> Broker {
+        self.expiry_notifications = expiry_notifications;
+        self
+    }
+
+    /// Opens a new session whenever the current one ends instead of stopping the broker thread,
+    /// waiting longer after each failed attempt. Active subscriptions are re-established and
+    /// messages published while disconnected are sent once the new session connects.
+    pub fn with_reconnect(mut self, reconnect: bool) -> Broker {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Keeps at least `min_reconnect_interval` between the starts of consecutive sessions. The
+    /// backoff starts over whenever a session got connected, so without this a broker that
+    /// accepts connections and drops them right away is reconnected to every second.
+    pub fn with_min_reconnect_interval(mut self, min_reconnect_interval: Duration) -> Broker {
+        self.min_reconnect_interval = min_reconnect_interval;
+        self
+    }
+
+    /// Caps the backoff between reconnection attempts, 30 seconds unless set. The delay still
+    /// starts at one second and doubles until it reaches the cap.
+    pub fn with_max_reconnect_delay(mut self, max_reconnect_delay: Duration) -> Broker {
+        self.max_reconnect_delay = max_reconnect_delay;
+        self
+    }
+
+    /// Connects over TLS, verifying the broker's certificate against `tls_domain`, so credentials
+    /// and slates are not sent in cleartext. A certificate that does not verify fails the session.
+    pub fn with_tls(mut self, tls_domain: Option<String>) -> Broker {
+        self.tls_domain = tls_domain;
+        self
+    }
+
+    fn session_builder(&self) -> Result<SessionBuilder> {
+        let mut builder = SessionBuilder::new()
+            .with(Credentials(&self.username, &self.password))
+            .with(HeartBeat(10000, 10000));
+
+        if let Some(ref accept_versions) = self.accept_versions {
+            let versions = accept_versions
+                .split(',')
+                .map(parse_stomp_version)
+                .collect::<Result<Vec<StompVersion>>>()?;
+            builder = builder.with(AcceptVersion(versions));
+        }
+
+        if let Some(ref min_version) = self.min_version {
+            builder = builder.with(MinimumVersion(parse_stomp_version(min_version)?));
+        }
+
+        builder
+            .validate()
+            .map_err(|e| ErrorKind::GenericError(e.to_string()))?;
+        Ok(builder)
+    }
+}
+
+impl MessageBroker for Broker {
+    fn publish_counters(&self) -> Arc<PublishCounters> {
+        self.publish_counters.clone()
+    }
+
+    fn diagnostics(&self) -> Arc<BrokerDiagnostics> {
+        self.diagnostics.clone()
+    }
+
+    fn start(&mut self, status_sender: mpsc::Sender<BrokerStatus>) -> Result<UnboundedSender<BrokerRequest>> {
         let (tx, rx) = unbounded();
         let address = self.address.clone();
-        let username = self.username.clone();
-        let password = self.password.clone();
+        let session_builder = self.session_builder()?;
+        let compress_payloads = self.compress_payloads;
+        let queue_expiration = queue_expiration_header(self.queue_expiration)?;
+        // Held queues are released once they would have expired had the relay not restarted.
+        let hold_timeout = self.queue_expiration;
+        let delivery_acks = self.delivery_acks;
+        let expiry_notifications = self.expiry_notifications;
+        let reconnect = self.reconnect;
+        let min_reconnect_interval = self.min_reconnect_interval;
+        let max_reconnect_delay = self.max_reconnect_delay;
+        let tls_domain = self.tls_domain.clone();
+        let publish_counters = self.publish_counters.clone();
+        let diagnostics = self.diagnostics.clone();
+        let registry = self
+            .subscription_registry_path
+            .clone()
+            .map(|path| Arc::new(SubscriptionRegistry::new(path)));
         std::thread::spawn(move || {
-            let tcp_stream = Box::new(TcpStream::connect(&address));
-
-            let session = SessionBuilder::new()
-                .with(Credentials(&username, &password))
-                .with(HeartBeat(10000, 10000))
-                .build(tcp_stream);
-
-            let session = BrokerSession {
-                session: Arc::new(Mutex::new(session)),
-                session_number: 0,
-                consumers: Arc::new(Mutex::new(HashMap::new())),
-                subject_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
-                subscription_id_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
+            // The builder is consumed by every session it builds, so each attempt gets a copy.
+            let build_session = || {
+                let stream = BrokerStream::connect(&address, tls_domain.clone());
+                session_builder.clone().build(stream)
+            };
+
+            let session = match build_session() {
+                Ok(session) => session,
+                Err(e) => {
+                    error!("could not build broker session: {}", e);
+                    let _ = status_sender.send(BrokerStatus::SessionFailed(e.to_string()));
+                    return;
+                }
+            };
+
+            let mut runtime = match tokio::runtime::current_thread::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("could not start broker runtime: {}", e);
+                    let _ = status_sender.send(BrokerStatus::SessionFailed(e.to_string()));
+                    return;
+                }
             };
 
+            let mut session = BrokerSession::new(session, registry, compress_payloads, queue_expiration, hold_timeout, delivery_acks, expiry_notifications, publish_counters, diagnostics);
+
             let mut session_clone = session.clone();
 
-            let request_loop = rx
+            let mut request_loop: Box<dyn Future<Item = (), Error = std::io::Error>> = Box::new(rx
                 .for_each(move |request| {
                     match request {
                         BrokerRequest::Subscribe { id, subject, response_sender } => {
@@ -73,28 +326,250 @@ impl Broker {
                         BrokerRequest::Unsubscribe { id } => {
                             session_clone.unsubscribe(&id);
                         },
-                        BrokerRequest::PostMessage { subject, payload, reply_to, message_expiration_in_seconds } => {
-                            session_clone.publish(&subject, &payload, &reply_to, message_expiration_in_seconds);
+                        BrokerRequest::PostMessage { subject, payload, reply_to, message_expiration_in_seconds, correlation_id, receipt_sender } => {
+                            session_clone.publish(&subject, &payload, &reply_to, message_expiration_in_seconds, correlation_id.as_ref().map(|id| id.as_str()), receipt_sender);
+                        },
+                        BrokerRequest::MoveQueue { from_subject, to_subject } => {
+                            session_clone.move_queue(from_subject, to_subject);
+                        },
+                        BrokerRequest::Acknowledge { ack_id, delivered } => {
+                            session_clone.acknowledge(&ack_id, delivered);
                         },
                     }
                     Ok(())
                 })
-                .map_err(|()| std::io::Error::new(std::io::ErrorKind::Other, ""));
+                .map_err(|()| std::io::Error::new(std::io::ErrorKind::Other, "")));
+
+            let mut pacer = ReconnectPacer::new(min_reconnect_interval, max_reconnect_delay);
+            pacer.attempt_started(Instant::now());
+            loop {
+                // The request loop outlives sessions, so requests keep being served by whichever
+                // session is current.
+                request_loop = match runtime.block_on(session.clone().select2(request_loop)) {
+                    Ok(Either::A(((), request_loop))) => request_loop,
+                    Err(Either::A((e, request_loop))) => {
+                        error!("broker session failed: {}", e);
+                        request_loop
+                    }
+                    Ok(Either::B(_)) => {
+                        // Nobody can send requests anymore; let the broker take what was
+                        // already sent before hanging up.
+                        session.close(CLOSE_TIMEOUT);
+                        if let Err(e) = runtime.block_on(session.clone()) {
+                            error!("broker session failed while closing: {}", e);
+                        }
+                        session.save_registry();
+                        let dropped = session.queued_publishes.lock().unwrap().len();
+                        if dropped > 0 {
+                            error!("dropping {} messages that were waiting for the broker session", dropped);
+                        }
+                        break;
+                    }
+                    Err(Either::B(_)) => break,
+                };
+
+                if !reconnect {
+                    break;
+                }
 
-            let f = session.select(request_loop).map_err(|_| {}).map(|_| {});
+                let delay = pacer.next_delay(session.was_connected(), session.was_expired(), Instant::now());
+                warn!("reconnecting to broker in {:?} (attempt {})", delay, pacer.attempt);
+                std::thread::sleep(delay);
 
-            tokio::run(f);
+                match build_session() {
+                    Ok(new_session) => {
+                        session.replace_session(new_session);
+                        pacer.attempt_started(Instant::now());
+                    }
+                    Err(e) => {
+                        error!("could not build broker session: {}", e);
+                        let _ = status_sender.send(BrokerStatus::SessionFailed(e.to_string()));
+                        return;
+                    }
+                }
+            }
 
             error!("broker thread ending!");
 
-            // TODO: attempt reconnection and re-establishment of subscriptions?
-            std::process::exit(1);
+            let _ = status_sender.send(BrokerStatus::SessionEnded);
         });
 
         Ok(tx)
     }
 }
 
+fn message_expiration(message_expiration_in_seconds: Option<u32>) -> String {
+    format!("{}", effective_message_expiration(message_expiration_in_seconds) * 1000)
+}
+
+fn compress_payload(payload: &str) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload.as_bytes())?;
+    encoder.finish()
+}
+
+/// Returns the frame body as text, inflating it first if it was published compressed.
+fn frame_payload(frame: &Frame) -> std::io::Result<String> {
+    let encoding = frame.headers.get(HeaderName::from_str(CONTENT_ENCODING_HEADER_NAME));
+    if encoding == Some(GZIP_CONTENT_ENCODING) {
+        let mut payload = String::new();
+        GzDecoder::new(&frame.body[..]).read_to_string(&mut payload)?;
+        Ok(payload)
+    } else {
+        String::from_utf8(frame.body.clone())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// RabbitMQ takes `x-expires` in milliseconds and refuses zero or anything that does not fit in
+/// 32 bits, so such durations are reported as configuration errors instead of reaching it.
+fn queue_expiration_header(queue_expiration: Duration) -> Result<String> {
+    let millis = queue_expiration.as_secs() * 1000 + u64::from(queue_expiration.subsec_millis());
+    if millis == 0 || millis > u64::from(u32::max_value()) {
+        return Err(ErrorKind::GenericError(format!("invalid queue expiration {:?}", queue_expiration)).into());
+    }
+    Ok(millis.to_string())
+}
+
+/// Arguments every queue is declared with. RabbitMQ refuses to redeclare a queue with different
+/// arguments, so subscriptions and publishes all go through this.
+fn queue_arguments(queue_expiration: &str, expiry_notifications: bool) -> HeaderList {
+    let mut headers = HeaderList::new();
+    headers.push(Header::new(HeaderName::from_str("x-expires"), queue_expiration));
+    if expiry_notifications {
+        headers.push(Header::new(HeaderName::from_str("x-dead-letter-exchange"), DEAD_LETTER_EXCHANGE));
+        headers.push(Header::new(HeaderName::from_str("x-dead-letter-routing-key"), EXPIRED_ROUTING_KEY));
+    }
+    headers
+}
+
+fn publish_frame(subject: &str, payload: &str, reply_to: &str, message_expiration_in_seconds: Option<u32>, correlation_id: Option<&str>, queue_arguments: &HeaderList, compress_payloads: bool) -> Frame {
+    let destination = format!("/queue/{}", subject);
+
+    let body = if compress_payloads {
+        match compress_payload(payload) {
+            Ok(body) => Some(body),
+            Err(e) => {
+                error!("could not compress payload, publishing it uncompressed: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut frame = Frame::send(&destination, body.as_ref().map(|body| &body[..]).unwrap_or(payload.as_bytes()));
+    for header in queue_arguments.iter() {
+        frame.headers.push(header.clone());
+    }
+    frame.headers.push(Header::new(HeaderName::from_str("expiration"), &message_expiration(message_expiration_in_seconds)));
+    frame.headers.push(Header::new(HeaderName::from_str(REPLY_TO_HEADER_NAME), reply_to));
+    frame.headers.push(Header::new(HeaderName::from_str(RECIPIENT_HEADER_NAME), subject));
+
+    if let Some(correlation_id) = correlation_id {
+        frame.headers.push(Header::new(HeaderName::from_str(CORRELATION_ID_HEADER_NAME), correlation_id));
+    }
+
+    if body.is_some() {
+        frame.headers.push(Header::new(HeaderName::from_str(CONTENT_ENCODING_HEADER_NAME), GZIP_CONTENT_ENCODING));
+    }
+
+    frame
+}
+
+/// The sender subject and recipient of a dead-lettered message, unless it was dead-lettered for
+/// another reason than its expiration or misses the headers to tell who sent it.
+fn expiry_notification(frame: &Frame) -> Option<(String, String)> {
+    let reason = frame.headers.get(HeaderName::from_str(DEATH_REASON_HEADER_NAME));
+    if reason.is_some() && reason != Some("expired") {
+        return None;
+    }
+
+    let reply_to = frame.headers.get(HeaderName::from_str(REPLY_TO_HEADER_NAME))?;
+    let to = frame.headers.get(HeaderName::from_str(RECIPIENT_HEADER_NAME))?;
+    // Senders subscribe to their public key while `reply_to` may carry their relay as well.
+    let sender = reply_to.split('@').next().unwrap_or(reply_to);
+    Some((sender.to_string(), to.to_string()))
+}
+
+/// Identifies a message across its deliveries, which each come with another ack id.
+fn message_key(frame: &Frame) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for name in &[REPLY_TO_HEADER_NAME, RECIPIENT_HEADER_NAME, CORRELATION_ID_HEADER_NAME] {
+        frame.headers.get(HeaderName::from_str(name)).hash(&mut hasher);
+    }
+    frame.body.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn is_session_expired(frame: &Frame) -> bool {
+    let message = frame.headers.get(MESSAGE).unwrap_or("").to_lowercase();
+    let body = String::from_utf8_lossy(&frame.body).to_lowercase();
+    [message, body]
+        .iter()
+        .any(|text| text.contains("session") && text.contains("expired"))
+}
+
+/// Drains an old queue into a new one. A marker message is published to the old queue once the
+/// draining consumer is attached; queues are FIFO, so when it comes back everything that was
+/// queued before the move has been forwarded.
+struct QueueMove {
+    to_subject: String,
+    marker: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum QueueMoveStep {
+    Forward {
+        subject: String,
+        payload: String,
+        reply_to: String,
+        message_expiration_in_seconds: Option<u32>,
+        correlation_id: Option<String>,
+    },
+    Skip,
+    Done,
+}
+
+impl QueueMove {
+    fn new(to_subject: String, marker: String) -> QueueMove {
+        QueueMove {
+            to_subject,
+            marker,
+        }
+    }
+
+    fn step(&self, frame: &Frame) -> QueueMoveStep {
+        if frame.headers.get(HeaderName::from_str(QUEUE_MOVE_MARKER_HEADER_NAME)) == Some(self.marker.as_str()) {
+            return QueueMoveStep::Done;
+        }
+
+        let reply_to = match frame.headers.get(HeaderName::from_str(REPLY_TO_HEADER_NAME)) {
+            Some(reply_to) => reply_to,
+            None => return QueueMoveStep::Skip,
+        };
+        let payload = match frame_payload(frame) {
+            Ok(payload) => payload,
+            Err(_) => return QueueMoveStep::Skip,
+        };
+
+        QueueMoveStep::Forward {
+            subject: self.to_subject.clone(),
+            payload,
+            reply_to: reply_to.to_string(),
+            message_expiration_in_seconds: frame
+                .headers
+                .get(HeaderName::from_str("expiration"))
+                .and_then(|expiration| expiration.parse::<u32>().ok())
+                .map(|expiration| expiration / 1000),
+            correlation_id: frame
+                .headers
+                .get(HeaderName::from_str(CORRELATION_ID_HEADER_NAME))
+                .map(|id| id.to_string()),
+        }
+    }
+}
+
 struct Consumer {
     subject: String,
     subscription_id: String,
@@ -111,41 +586,356 @@ impl Consumer {
     }
 }
 
+/// A consumer re-created from the subscription registry for a client that has not come back
+/// since the restart.
+struct HeldSubscription {
+    subscription_id: String,
+    until: Instant,
+}
+
+/// A message posted while the session was not connected, published once it is.
+struct QueuedPublish {
+    subject: String,
+    payload: String,
+    reply_to: String,
+    message_expiration_in_seconds: Option<u32>,
+    correlation_id: Option<String>,
+    receipt_sender: Option<UnboundedSender<BrokerResponse>>,
+}
+
+/// A published message the broker has yet to confirm.
+struct PendingReceipt {
+    sender: UnboundedSender<BrokerResponse>,
+    reply_to: String,
+    message_expiration_in_seconds: Option<u32>,
+    deadline: Instant,
+}
+
+impl PendingReceipt {
+    fn failed(self) {
+        let _ = self.sender.unbounded_send(BrokerResponse::PublishFailed { reply_to: self.reply_to });
+    }
+}
+
 #[derive(Clone)]
 struct BrokerSession {
     session: Arc<Mutex<Session>>,
-    session_number: u32,
+    session_number: Arc<AtomicUsize>,
+    connected: Arc<AtomicBool>,
+    online: Arc<AtomicBool>,
+    expired: Arc<AtomicBool>,
+    queued_publishes: Arc<Mutex<VecDeque<QueuedPublish>>>,
     consumers: Arc<Mutex<HashMap<String, Consumer>>>,
     subject_to_consumer_id_lookup: Arc<Mutex<HashMap<String, String>>>,
     subscription_id_to_consumer_id_lookup: Arc<Mutex<HashMap<String, String>>>,
+    registry: Option<Arc<SubscriptionRegistry>>,
+    registry_loaded: Arc<AtomicBool>,
+    registry_changed: Arc<AtomicBool>,
+    registry_saved_at: Arc<Mutex<Option<Instant>>>,
+    held_subscriptions: Arc<Mutex<HashMap<String, HeldSubscription>>>,
+    hold_timeout: Duration,
+    queue_moves: Arc<Mutex<HashMap<String, QueueMove>>>,
+    compress_payloads: bool,
+    queue_arguments: HeaderList,
+    delivery_acks: bool,
+    expiry_notifications: bool,
+    expired_subscription: Arc<Mutex<Option<String>>>,
+    pending_receipts: Arc<Mutex<HashMap<String, PendingReceipt>>>,
+    delivered_messages: Arc<Mutex<HashMap<String, u64>>>,
+    redeliveries: Arc<Mutex<HashMap<u64, u32>>>,
+    publish_counters: Arc<PublishCounters>,
+    diagnostics: Arc<BrokerDiagnostics>,
 }
 
 impl BrokerSession {
+    fn new(session: Session, registry: Option<Arc<SubscriptionRegistry>>, compress_payloads: bool, queue_expiration: String, hold_timeout: Duration, delivery_acks: bool, expiry_notifications: bool, publish_counters: Arc<PublishCounters>, diagnostics: Arc<BrokerDiagnostics>) -> BrokerSession {
+        BrokerSession {
+            session: Arc::new(Mutex::new(session)),
+            session_number: Arc::new(AtomicUsize::new(0)),
+            connected: Arc::new(AtomicBool::new(false)),
+            online: Arc::new(AtomicBool::new(false)),
+            expired: Arc::new(AtomicBool::new(false)),
+            queued_publishes: Arc::new(Mutex::new(VecDeque::new())),
+            consumers: Arc::new(Mutex::new(HashMap::new())),
+            subject_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
+            subscription_id_to_consumer_id_lookup: Arc::new(Mutex::new(HashMap::new())),
+            registry,
+            registry_loaded: Arc::new(AtomicBool::new(false)),
+            registry_changed: Arc::new(AtomicBool::new(false)),
+            registry_saved_at: Arc::new(Mutex::new(None)),
+            held_subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            hold_timeout,
+            queue_moves: Arc::new(Mutex::new(HashMap::new())),
+            compress_payloads,
+            queue_arguments: queue_arguments(&queue_expiration, expiry_notifications),
+            delivery_acks,
+            expiry_notifications,
+            expired_subscription: Arc::new(Mutex::new(None)),
+            pending_receipts: Arc::new(Mutex::new(HashMap::new())),
+            delivered_messages: Arc::new(Mutex::new(HashMap::new())),
+            redeliveries: Arc::new(Mutex::new(HashMap::new())),
+            publish_counters,
+            diagnostics,
+        }
+    }
+
+    fn session_number(&self) -> usize {
+        self.session_number.load(Ordering::SeqCst)
+    }
+
+    fn was_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Whether the broker ended the session with a session-expired error.
+    fn was_expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    /// Whether frames sent now reach the broker; they are dropped before the session connected
+    /// and after it was lost.
+    fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    /// Disconnects once the broker confirmed everything sent so far; the session future ends
+    /// when it did or `timeout` passed.
+    fn close(&mut self, timeout: Duration) {
+        self.session.lock().unwrap().close(timeout);
+    }
+
+    /// Swaps in a freshly built session after the previous one ended. Subscription ids belonged
+    /// to the old session, so they are forgotten here and consumers, held ones included, are
+    /// subscribed again once the new session connects.
+    fn replace_session(&mut self, session: Session) {
+        *self.session.lock().unwrap() = session;
+        self.session_number.fetch_add(1, Ordering::SeqCst);
+        self.connected.store(false, Ordering::SeqCst);
+        self.online.store(false, Ordering::SeqCst);
+        self.expired.store(false, Ordering::SeqCst);
+        self.subscription_id_to_consumer_id_lookup.lock().unwrap().clear();
+        *self.expired_subscription.lock().unwrap() = None;
+        // So were the ack ids of messages handed out but not settled.
+        self.delivered_messages.lock().unwrap().clear();
+
+        // Receipts for the old session will never arrive.
+        for (_, pending_receipt) in self.pending_receipts.lock().unwrap().drain() {
+            pending_receipt.failed();
+        }
+
+        let mut queue_moves = self.queue_moves.lock().unwrap();
+        for queue_move in queue_moves.values() {
+            warn!("abandoning queue move to [{}] after losing the broker session", queue_move.to_subject);
+        }
+        queue_moves.clear();
+    }
+
+    /// Issues a new subscription for every consumer. Frames sent before a session connects are
+    /// dropped, so this runs on every connect rather than only after a reconnect.
+    fn resubscribe_consumers(&mut self) {
+        let ack_mode = if self.delivery_acks {
+            AckMode::ClientIndividual
+        } else {
+            AckMode::Auto
+        };
+
+        let mut lookup = self.subscription_id_to_consumer_id_lookup.lock().unwrap();
+        let mut consumers = self.consumers.lock().unwrap();
+        lookup.clear();
+        for (id, consumer) in consumers.iter_mut() {
+            consumer.subscription_id = self
+                .session
+                .lock()
+                .unwrap()
+                .subscription(&consumer.subject)
+                .with(ack_mode)
+                .with(self.queue_arguments.clone())
+                .start();
+            lookup.insert(consumer.subscription_id.clone(), id.clone());
+        }
+        if !consumers.is_empty() {
+            info!("re-established {} broker subscriptions", consumers.len());
+        }
+    }
+
     fn on_connected(&mut self) {
-        info!("established broker session");
+        match self.session.lock().unwrap().negotiated_version() {
+            Some(version) => info!("established broker session [{}] using STOMP {}", self.session_number(), version.as_str()),
+            None => info!("established broker session [{}]", self.session_number()),
+        }
+        self.connected.store(true, Ordering::SeqCst);
+        self.online.store(true, Ordering::SeqCst);
+        self.resubscribe_consumers();
+        self.hold_registered_subscriptions(Instant::now());
+        if self.expiry_notifications {
+            self.subscribe_expired();
+        }
+        self.publish_queued();
+    }
+
+    fn publish_queued(&self) {
+        let queued: Vec<QueuedPublish> = self.queued_publishes.lock().unwrap().drain(..).collect();
+        if !queued.is_empty() {
+            info!("publishing {} messages posted while disconnected", queued.len());
+        }
+        for queued in queued {
+            self.publish(&queued.subject, &queued.payload, &queued.reply_to, queued.message_expiration_in_seconds, queued.correlation_id.as_ref().map(|id| id.as_str()), queued.receipt_sender);
+        }
+    }
+
+    /// Binds a queue to the dead-letter routing key. It lives as long as the session, so messages
+    /// expiring while the relay is disconnected go unnoticed.
+    fn subscribe_expired(&mut self) {
+        let subscription_id = self
+            .session
+            .lock()
+            .unwrap()
+            .subscription(&format!("/exchange/{}/{}", DEAD_LETTER_EXCHANGE, EXPIRED_ROUTING_KEY))
+            .with(AckMode::Auto)
+            .start();
+        *self.expired_subscription.lock().unwrap() = Some(subscription_id);
+    }
+
+    /// Re-creates consumers for subjects that were subscribed before the restart, read from the
+    /// registry on the first connect. They use client acks and never acknowledge, so messages
+    /// stay queued until the owning client subscribes; clients that are not back within
+    /// `hold_timeout` are given up on by `release_expired_holds`.
+    fn hold_registered_subscriptions(&mut self, now: Instant) {
+        if !self.registry_loaded.swap(true, Ordering::SeqCst) {
+            let subjects = match self.registry {
+                Some(ref registry) => registry.load().unwrap_or_else(|e| {
+                    error!("could not load subscription registry: {}", e);
+                    vec![]
+                }),
+                None => vec![],
+            };
+
+            let subjects: Vec<String> = {
+                let subscribed = self.subject_to_consumer_id_lookup.lock().unwrap();
+                subjects.into_iter().filter(|subject| !subscribed.contains_key(subject)).collect()
+            };
+            let mut held_subscriptions = self.held_subscriptions.lock().unwrap();
+            for subject in subjects {
+                debug!("holding subscription for [{}] until its client returns", subject);
+                held_subscriptions.insert(subject, HeldSubscription {
+                    subscription_id: String::new(),
+                    until: now + self.hold_timeout,
+                });
+            }
+        }
+
+        for (subject, held_subscription) in self.held_subscriptions.lock().unwrap().iter_mut() {
+            held_subscription.subscription_id = self
+                .session
+                .lock()
+                .unwrap()
+                .subscription(subject)
+                .with(AckMode::Client)
+                .with(self.queue_arguments.clone())
+                .start();
+        }
+    }
+
+    /// Drops held consumers whose client did not come back in time. The messages they were
+    /// handed but never acknowledged go back to the queue, which the broker then expires like
+    /// any other unused queue.
+    fn release_expired_holds(&self, now: Instant) {
+        let expired: Vec<String> = self
+            .held_subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, held_subscription)| held_subscription.until <= now)
+            .map(|(subject, _)| subject.clone())
+            .collect();
+        for subject in expired {
+            info!("releasing subscription for [{}], its client did not return", subject);
+            self.release_held_subscription(&subject);
+        }
+    }
+
+    fn release_held_subscription(&self, subject: &str) {
+        if let Some(held_subscription) = self.held_subscriptions.lock().unwrap().remove(subject) {
+            self
+                .session
+                .lock()
+                .unwrap()
+                .unsubscribe(&held_subscription.subscription_id);
+            self.persist_subscriptions();
+        }
+    }
+
+    /// Marks the registry as out of date. It is written from `poll` at most once every
+    /// `REGISTRY_SAVE_INTERVAL`, so clients subscribing in bursts do not each cost a write.
+    fn persist_subscriptions(&self) {
+        if self.registry.is_some() {
+            self.registry_changed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn save_registry_if_due(&self, now: Instant) {
+        if !self.registry_changed.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(saved_at) = *self.registry_saved_at.lock().unwrap() {
+            if now < saved_at + REGISTRY_SAVE_INTERVAL {
+                return;
+            }
+        }
+        *self.registry_saved_at.lock().unwrap() = Some(now);
+        self.save_registry();
+    }
+
+    /// Writes every subscribed subject, held ones included, so they are held again should the
+    /// relay restart before their clients return.
+    fn save_registry(&self) {
+        let registry = match self.registry {
+            Some(ref registry) => registry,
+            None => return,
+        };
+        if !self.registry_changed.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut subjects: Vec<String> = self
+            .subject_to_consumer_id_lookup
+            .lock()
+            .unwrap()
+            .keys()
+            .chain(self.held_subscriptions.lock().unwrap().keys())
+            .cloned()
+            .collect();
+        subjects.sort();
+        if let Err(e) = registry.save(&subjects) {
+            error!("could not save subscription registry: {}", e);
+            self.registry_changed.store(true, Ordering::SeqCst);
+        }
     }
 
     fn subscribe(&mut self, id: String, subject: String, sender: UnboundedSender<BrokerResponse>) {
+        self.release_held_subscription(&subject);
         self.unsubscribe_by_subject(&subject);
 
+        let ack_mode = if self.delivery_acks {
+            AckMode::ClientIndividual
+        } else {
+            AckMode::Auto
+        };
+
         let subscription_id = self
             .session
             .lock()
             .unwrap()
             .subscription(&subject)
-            .with(AckMode::Auto)
-            .with(
-                Header::new(
-                    HeaderName::from_str("x-expires"),
-                    DEFAULT_QUEUE_EXPIRATION
-                )
-            )
+            .with(ack_mode)
+            .with(self.queue_arguments.clone())
             .start();
 
         let consumer = Consumer::new(subject.clone(), subscription_id.clone(), sender);
         self.subject_to_consumer_id_lookup.lock().unwrap().insert(subject, id.clone());
         self.subscription_id_to_consumer_id_lookup.lock().unwrap().insert(subscription_id, id.clone());
         self.consumers.lock().unwrap().insert(id, consumer);
+        self.persist_subscriptions();
     }
 
     fn unsubscribe_by_subject(&mut self, subject: &str) {
@@ -177,55 +967,251 @@ impl BrokerSession {
             } else {
                 error!("could not find consumer for id [{}]", id);
             }
+            self.persist_subscriptions();
         }
     }
 
-    fn publish(&self, subject: &str, payload: &str, reply_to: &str, message_expiration_in_seconds: Option<u32>) {
-        let destination = format!("/queue/{}", subject);
-        let message_expiration = match message_expiration_in_seconds {
-            Some(message_expiration_in_seconds @ 1 ... 86400) => format!("{}", message_expiration_in_seconds * 1000),
-            _ => format!("{}", DEFAULT_MESSAGE_EXPIRATION * 1000),
-        };
+    fn publish(&self, subject: &str, payload: &str, reply_to: &str, message_expiration_in_seconds: Option<u32>, correlation_id: Option<&str>, receipt_sender: Option<UnboundedSender<BrokerResponse>>) {
+        if !self.is_online() {
+            self.queue_publish(subject, payload, reply_to, message_expiration_in_seconds, correlation_id, receipt_sender);
+            return;
+        }
 
-        self
-            .session
-            .lock()
-            .unwrap()
-            .message(&destination, payload)
-            .with(
-                Header::new(
-                    HeaderName::from_str("x-expires"),
-                    DEFAULT_QUEUE_EXPIRATION
-                )
-            )
-            .with(
-                Header::new(
-                    HeaderName::from_str("expiration"),
-                    &message_expiration
-                )
-            )
+        // Building the frame, which includes compressing the payload, does not need the session,
+        // so the lock is only held while the frame is written. Publishes and session polling
+        // all run on the broker thread, so this narrows the lock but does not make publishing
+        // any faster.
+        let frame = publish_frame(subject, payload, reply_to, message_expiration_in_seconds, correlation_id, &self.queue_arguments, self.compress_payloads);
+        match receipt_sender {
+            Some(sender) => {
+                let now = Instant::now();
+                self.expire_receipts(now);
+
+                let mut session = self.session.lock().unwrap();
+                let builder = MessageBuilder::new(&mut *session, frame).with(GenerateReceipt);
+                if let Some(ref receipt_request) = builder.receipt_request {
+                    self.pending_receipts.lock().unwrap().insert(receipt_request.id.clone(), PendingReceipt {
+                        sender,
+                        reply_to: reply_to.to_string(),
+                        message_expiration_in_seconds,
+                        deadline: now + RECEIPT_TIMEOUT,
+                    });
+                }
+                builder.send();
+            }
+            None => self.session.lock().unwrap().send_frame(frame),
+        }
+        self.publish_counters.record(subject);
+    }
+
+    /// Keeps a message until the session connects, as the broker would never see it if it were
+    /// sent now. Once `MAX_QUEUED_PUBLISHES` are waiting further messages are refused, which
+    /// posters asking for a receipt are told with `PublishFailed`.
+    fn queue_publish(&self, subject: &str, payload: &str, reply_to: &str, message_expiration_in_seconds: Option<u32>, correlation_id: Option<&str>, receipt_sender: Option<UnboundedSender<BrokerResponse>>) {
+        let mut queued_publishes = self.queued_publishes.lock().unwrap();
+        if queued_publishes.len() >= MAX_QUEUED_PUBLISHES {
+            error!("dropping message for [{}], too many are waiting for the broker session", subject);
+            if let Some(sender) = receipt_sender {
+                let _ = sender.unbounded_send(BrokerResponse::PublishFailed { reply_to: reply_to.to_string() });
+            }
+            return;
+        }
+
+        queued_publishes.push_back(QueuedPublish {
+            subject: subject.to_string(),
+            payload: payload.to_string(),
+            reply_to: reply_to.to_string(),
+            message_expiration_in_seconds,
+            correlation_id: correlation_id.map(|id| id.to_string()),
+            receipt_sender,
+        });
+    }
+
+    fn on_receipt(&mut self, receipt_id: &str) {
+        if let Some(pending_receipt) = self.pending_receipts.lock().unwrap().remove(receipt_id) {
+            let _ = pending_receipt.sender.unbounded_send(BrokerResponse::Published {
+                reply_to: pending_receipt.reply_to,
+                message_expiration_in_seconds: pending_receipt.message_expiration_in_seconds,
+            });
+        }
+    }
+
+    /// Fails receipts the broker did not confirm in time. There is no timer of its own, so this
+    /// runs whenever the session is polled, which broker heartbeats make happen regularly, and
+    /// on every publish.
+    fn expire_receipts(&self, now: Instant) {
+        let mut pending_receipts = self.pending_receipts.lock().unwrap();
+        let expired: Vec<String> = pending_receipts
+            .iter()
+            .filter(|(_, pending_receipt)| pending_receipt.deadline <= now)
+            .map(|(receipt_id, _)| receipt_id.clone())
+            .collect();
+        for receipt_id in expired {
+            if let Some(pending_receipt) = pending_receipts.remove(&receipt_id) {
+                warn!("broker did not confirm message [{}] in time", receipt_id);
+                pending_receipt.failed();
+            }
+        }
+    }
+
+    /// Moves every message queued for `from_subject` over to `to_subject`, keeping their
+    /// `reply_to`. Once drained the old queue is left without consumers and is removed by its
+    /// `x-expires` policy.
+    fn move_queue(&mut self, from_subject: String, to_subject: String) {
+        self.release_held_subscription(&from_subject);
+        self.unsubscribe_by_subject(&from_subject);
+        self.persist_subscriptions();
+
+        let mut session = self.session.lock().unwrap();
+        let subscription_id = session
+            .subscription(&from_subject)
+            .with(AckMode::Auto)
+            .with(self.queue_arguments.clone())
+            .start();
+
+        let marker = format!("{}/{}", self.session_number(), subscription_id);
+        session
+            .message(&format!("/queue/{}", from_subject), "")
+            .with(self.queue_arguments.clone())
             .with(
                 Header::new(
-                    HeaderName::from_str(REPLY_TO_HEADER_NAME),
-                    reply_to
+                    HeaderName::from_str(QUEUE_MOVE_MARKER_HEADER_NAME),
+                    &marker
                 )
             )
             .send();
+
+        info!("moving queue [{}] to [{}]", from_subject, to_subject);
+        self.queue_moves.lock().unwrap().insert(subscription_id, QueueMove::new(to_subject, marker));
+    }
+
+    /// Returns whether the frame belonged to a queue move and has been dealt with.
+    fn on_queue_move_message(&mut self, subscription_id: &str, frame: &Frame) -> bool {
+        let step = match self.queue_moves.lock().unwrap().get(subscription_id) {
+            Some(queue_move) => queue_move.step(frame),
+            None => return false,
+        };
+
+        match step {
+            QueueMoveStep::Forward { subject, payload, reply_to, message_expiration_in_seconds, correlation_id } => {
+                self.publish(&subject, &payload, &reply_to, message_expiration_in_seconds, correlation_id.as_ref().map(|id| id.as_str()), None);
+            },
+            QueueMoveStep::Skip => {
+                error!("dropping malformed message while moving queue [{}]", subscription_id);
+            },
+            QueueMoveStep::Done => {
+                if let Some(queue_move) = self.queue_moves.lock().unwrap().remove(subscription_id) {
+                    info!("finished moving queue to [{}]", queue_move.to_subject);
+                }
+                self.session.lock().unwrap().unsubscribe(subscription_id);
+            },
+        }
+        true
+    }
+
+    fn acknowledge(&self, ack_id: &str, delivered: bool) {
+        let frame = self.acknowledgement(ack_id, delivered);
+        self.session.lock().unwrap().send_frame(frame);
+    }
+
+    /// ACKs delivered messages and NACKs the others back to their queue, unless they already
+    /// went back `MAX_REDELIVERIES` times. Those are NACKed without requeueing, which drops
+    /// them, or dead-letters them when expiry notifications are on; their sender is not told,
+    /// as the broker marks them rejected rather than expired.
+    fn acknowledgement(&self, ack_id: &str, delivered: bool) -> Frame {
+        let message = self.delivered_messages.lock().unwrap().remove(ack_id);
+        if delivered {
+            if let Some(message) = message {
+                self.redeliveries.lock().unwrap().remove(&message);
+            }
+            return Frame::ack(ack_id);
+        }
+
+        let mut frame = Frame::nack(ack_id);
+        if let Some(message) = message {
+            let mut redeliveries = self.redeliveries.lock().unwrap();
+            let count = redeliveries.remove(&message).unwrap_or(0);
+            if count >= MAX_REDELIVERIES {
+                warn!("rejecting message [{}], its client failed to take it {} times", ack_id, count + 1);
+                frame.headers.push(Header::new(HeaderName::from_str(REQUEUE_HEADER_NAME), "false"));
+            } else {
+                if redeliveries.len() >= TRACKED_REDELIVERIES {
+                    warn!("forgetting the redeliveries of {} messages", redeliveries.len());
+                    redeliveries.clear();
+                }
+                redeliveries.insert(message, count + 1);
+            }
+        }
+        frame
+    }
+
+    /// Tells the sender of a message that expired undelivered, if it is subscribed right now.
+    fn on_expired_message(&self, frame: &Frame) {
+        let (sender, to) = match expiry_notification(frame) {
+            Some(notification) => notification,
+            None => return,
+        };
+
+        let consumer_id = match self.subject_to_consumer_id_lookup.lock().unwrap().get(&sender) {
+            Some(consumer_id) => consumer_id.clone(),
+            None => {
+                debug!("sender [{}] of expired message is not subscribed", sender);
+                return;
+            }
+        };
+
+        if let Some(consumer) = self.consumers.lock().unwrap().get(&consumer_id) {
+            let response = BrokerResponse::DeliveryExpired {
+                subject: sender,
+                to,
+            };
+            if consumer.sender.unbounded_send(response).is_err() {
+                error!("failed sending broker message to channel!");
+            }
+        }
     }
 
     fn on_message(&mut self, frame: Frame) {
         if let Some(subscription_id) = frame.headers.get(SUBSCRIPTION) {
+            if self.on_queue_move_message(subscription_id, &frame) {
+                return;
+            }
+
+            if self.expired_subscription.lock().unwrap().as_ref().map(|id| id.as_str()) == Some(subscription_id) {
+                self.on_expired_message(&frame);
+                return;
+            }
+
             match self.subscription_id_to_consumer_id_lookup.lock().unwrap().get(subscription_id) {
                 Some(consumer_id) => {
                     match self.consumers.lock().unwrap().get(consumer_id) {
                         Some(consumer) => {
                             if let Some(reply_to) = frame.headers.get(HeaderName::from_str(REPLY_TO_HEADER_NAME))
                                 {
-                                    let payload = std::str::from_utf8(&frame.body).unwrap();
+                                    let payload = match frame_payload(&frame) {
+                                        Ok(payload) => payload,
+                                        Err(e) => {
+                                            error!("could not decode message payload: {}", e);
+                                            return;
+                                        }
+                                    };
+                                    let ack_id = if self.delivery_acks {
+                                        frame.headers.get(ACK).map(|id| id.to_string())
+                                    } else {
+                                        None
+                                    };
+                                    if let Some(ref ack_id) = ack_id {
+                                        self.delivered_messages.lock().unwrap().insert(ack_id.clone(), message_key(&frame));
+                                    }
                                     let response = BrokerResponse::Message {
                                         subject: consumer.subject.clone(),
-                                        payload: payload.to_string(),
+                                        payload,
                                         reply_to: reply_to.to_string(),
+                                        correlation_id: frame
+                                            .headers
+                                            .get(HeaderName::from_str(CORRELATION_ID_HEADER_NAME))
+                                            .map(|id| id.to_string()),
+                                        ack_id,
                                     };
                                     if consumer.sender.unbounded_send(response).is_err() {
                                         error!("failed sending broker message to channel!");
@@ -240,7 +1226,17 @@ impl BrokerSession {
                     }
                 }
                 None => {
-                    error!("missing consumer for message frame [{}]", subscription_id);
+                    let held = self
+                        .held_subscriptions
+                        .lock()
+                        .unwrap()
+                        .values()
+                        .any(|held_subscription| held_subscription.subscription_id == subscription_id);
+                    if held {
+                        debug!("holding message for [{}] until its client returns", subscription_id);
+                    } else {
+                        error!("missing consumer for message frame [{}]", subscription_id);
+                    }
                 }
             }
         }
@@ -252,6 +1248,11 @@ impl Future for BrokerSession {
     type Error = std::io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let now = Instant::now();
+        self.expire_receipts(now);
+        self.release_expired_holds(now);
+        self.save_registry_if_due(now);
+
         let msg = match try_ready!(self.session.lock().unwrap().poll()) {
             None => {
                 return Ok(Async::Ready(()));
@@ -262,9 +1263,19 @@ impl Future for BrokerSession {
         trace!("msg: {:?}", msg);
         match msg {
             SessionEvent::Connected => {
+                self.diagnostics.connected(Instant::now());
                 self.on_connected();
             }
 
+            SessionEvent::Receipt { id, .. } => {
+                self.on_receipt(&id);
+            }
+
+            SessionEvent::HeartbeatNegotiated { tx_ms, rx_ms } => {
+                info!("negotiated broker heartbeat: tx {}ms, rx {}ms", tx_ms, rx_ms);
+                self.diagnostics.heartbeat_negotiated(tx_ms, rx_ms);
+            }
+
             SessionEvent::Message {
                 destination: _destination,
                 ack_mode: _ack_mode,
@@ -274,11 +1285,20 @@ impl Future for BrokerSession {
             }
 
             SessionEvent::Error(frame) => {
+                if is_session_expired(&frame) {
+                    warn!("session [{}] expired, ending session", self.session_number());
+                    self.online.store(false, Ordering::SeqCst);
+                    self.expired.store(true, Ordering::SeqCst);
+                    self.diagnostics.disconnected("session expired".to_string());
+                    return Ok(Async::Ready(()));
+                }
                 error!("session error event: {}", frame);
             }
 
             SessionEvent::Disconnected(reason) => {
-                warn!("session [{}] disconnected due to [{:?}]", self.session_number, reason);
+                warn!("session [{}] disconnected due to [{:?}]", self.session_number(), reason);
+                self.online.store(false, Ordering::SeqCst);
+                self.diagnostics.disconnected(format!("{:?}", reason));
                 return Ok(Async::Ready(()));
             }
 
@@ -289,4 +1309,571 @@ impl Future for BrokerSession {
 
         Ok(Async::NotReady)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::broker::stomp::frame::Command;
+    use crate::broker::stomp::header::{HeaderList, DESTINATION};
+    use crate::broker::stomp::session::ConnectFuture;
+
+    fn broker_session() -> BrokerSession {
+        broker_session_with_registry(None)
+    }
+
+    fn broker_session_with_registry(registry: Option<Arc<SubscriptionRegistry>>) -> BrokerSession {
+        let stream: ConnectFuture<BrokerStream> = Box::new(futures::future::empty());
+        let session = SessionBuilder::new().build(stream).unwrap();
+        BrokerSession::new(session, registry, false, "86400000".to_string(), DEFAULT_QUEUE_EXPIRATION, false, false, Arc::new(PublishCounters::new(TRACKED_DESTINATIONS)), Arc::new(BrokerDiagnostics::new()))
+    }
+
+    fn subscribed_destinations(session: &BrokerSession) -> Vec<String> {
+        let mut destinations: Vec<String> = session
+            .session
+            .lock()
+            .unwrap()
+            .state
+            .subscriptions
+            .values()
+            .map(|subscription| subscription.destination.clone())
+            .collect();
+        destinations.sort();
+        destinations
+    }
+
+    fn message_frame(headers: Vec<(&str, &str)>, body: &str) -> Frame {
+        let mut list = HeaderList::new();
+        for (name, value) in headers {
+            list.push(Header::new(HeaderName::from_str(name), value));
+        }
+        Frame {
+            command: Command::Message,
+            headers: list,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn error_frame(message: &str, body: &str) -> Frame {
+        let mut headers = HeaderList::new();
+        headers.push(Header::new(MESSAGE, message));
+        Frame {
+            command: Command::Error,
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn unreachable_broker_reports_status_instead_of_exiting() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let (status_sender, status_receiver) = mpsc::channel();
+        let mut broker = Broker::new(address, "guest".to_string(), "guest".to_string(), None);
+        broker.start(status_sender).unwrap();
+
+        match status_receiver.recv_timeout(std::time::Duration::from_secs(10)) {
+            Ok(BrokerStatus::SessionEnded) => {}
+            status => panic!("unexpected broker status: {:?}", status),
+        }
+    }
+
+    #[test]
+    fn unsubscribing_one_consumer_keeps_the_other() {
+        let mut session = broker_session();
+        let (sender, _receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "subject-a".to_string(), sender.clone());
+        session.subscribe("connection/1".to_string(), "subject-b".to_string(), sender);
+
+        session.unsubscribe("connection/0");
+
+        let consumers = session.consumers.lock().unwrap();
+        assert!(!consumers.contains_key("connection/0"));
+        assert_eq!(consumers.get("connection/1").map(|consumer| consumer.subject.as_str()), Some("subject-b"));
+        assert_eq!(session.subscription_id_to_consumer_id_lookup.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn publishes_are_counted_per_destination() {
+        let mut session = broker_session();
+        session.on_connected();
+        for subject in &["alice", "bob", "alice", "carol", "alice", "bob"] {
+            session.publish(subject, "slate", "dave", None, None, None);
+        }
+
+        let counts: Vec<usize> = session
+            .publish_counters
+            .top(10)
+            .into_iter()
+            .map(|(_, count)| count)
+            .collect();
+        assert_eq!(counts, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn message_is_delivered_once_to_its_subscriber() {
+        let mut session = broker_session();
+        let (sender, receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender);
+        let subscription_id = session
+            .subscription_id_to_consumer_id_lookup
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .cloned()
+            .unwrap();
+
+        let frame = publish_frame("alice", "slate", "alice", None, None, &queue_arguments("86400000", false), false);
+        let mut frame = Frame {
+            command: Command::Message,
+            ..frame
+        };
+        frame.headers.push(Header::new(SUBSCRIPTION, &subscription_id));
+        session.on_message(frame);
+        drop(session);
+
+        let messages: Vec<BrokerResponse> = receiver.wait().map(|message| message.unwrap()).collect();
+        assert_eq!(messages.len(), 1);
+        match messages[0] {
+            BrokerResponse::Message { ref subject, ref reply_to, .. } => {
+                assert_eq!(subject, "alice");
+                assert_eq!(reply_to, "alice");
+            }
+            ref message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn message_is_rejected_after_too_many_failed_deliveries() {
+        let mut session = broker_session();
+        session.delivery_acks = true;
+        let (sender, _receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender);
+        let subscription_id = session
+            .subscription_id_to_consumer_id_lookup
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .cloned()
+            .unwrap();
+        let deliver = |session: &mut BrokerSession, body: &str, ack_id: &str| {
+            let headers = vec![(SUBSCRIPTION.as_str(), subscription_id.as_str()), ("ack", ack_id), (REPLY_TO_HEADER_NAME, "bob")];
+            session.on_message(message_frame(headers, body));
+        };
+        let requeue = |frame: &Frame| frame.headers.get(HeaderName::from_str(REQUEUE_HEADER_NAME)).map(|requeue| requeue.to_string());
+
+        // RabbitMQ hands the message out with a new ack id every time it comes back.
+        for attempt in 0..MAX_REDELIVERIES {
+            let ack_id = format!("ack-{}", attempt);
+            deliver(&mut session, "slate", &ack_id);
+            assert_eq!(requeue(&session.acknowledgement(&ack_id, false)), None);
+        }
+        deliver(&mut session, "slate", "ack-last");
+        assert_eq!(requeue(&session.acknowledgement("ack-last", false)), Some("false".to_string()));
+        assert!(session.redeliveries.lock().unwrap().is_empty());
+
+        // Another message is counted on its own, and a delivery forgets the failures before it.
+        deliver(&mut session, "other slate", "ack-other");
+        assert_eq!(requeue(&session.acknowledgement("ack-other", false)), None);
+        deliver(&mut session, "other slate", "ack-other-again");
+        assert_eq!(requeue(&session.acknowledgement("ack-other-again", true)), None);
+        assert!(session.redeliveries.lock().unwrap().is_empty());
+        assert!(session.delivered_messages.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sender_is_notified_when_message_expires_undelivered() {
+        let mut session = broker_session();
+        let (sender, receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender);
+        *session.expired_subscription.lock().unwrap() = Some("expired".to_string());
+
+        // bob never subscribed, so the message comes back through the dead-letter exchange.
+        let frame = publish_frame("bob", "slate", "alice@relay.example.com", Some(1), None, &queue_arguments("86400000", true), false);
+        let mut frame = Frame {
+            command: Command::Message,
+            ..frame
+        };
+        frame.headers.push(Header::new(SUBSCRIPTION, "expired"));
+        frame.headers.push(Header::new(HeaderName::from_str(DEATH_REASON_HEADER_NAME), "expired"));
+        session.on_message(frame);
+        drop(session);
+
+        let messages: Vec<BrokerResponse> = receiver.wait().map(|message| message.unwrap()).collect();
+        assert_eq!(messages.len(), 1);
+        match messages[0] {
+            BrokerResponse::DeliveryExpired { ref subject, ref to } => {
+                assert_eq!(subject, "alice");
+                assert_eq!(to, "bob");
+            }
+            ref message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn subscriptions_are_replayed_after_reconnecting() {
+        let mut session = broker_session();
+        let (sender, _receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender.clone());
+        session.subscribe("connection/1".to_string(), "bob".to_string(), sender);
+
+        // Simulate the disconnect by swapping in a session over a fresh mock stream.
+        let stream: ConnectFuture<BrokerStream> = Box::new(futures::future::empty());
+        session.replace_session(SessionBuilder::new().build(stream).unwrap());
+        assert!(session.subscription_id_to_consumer_id_lookup.lock().unwrap().is_empty());
+        assert!(session.session.lock().unwrap().state.subscriptions.is_empty());
+
+        session.on_connected();
+        assert_eq!(subscribed_destinations(&session), vec!["alice".to_string(), "bob".to_string()]);
+
+        let lookup = session.subscription_id_to_consumer_id_lookup.lock().unwrap();
+        for (id, consumer) in session.consumers.lock().unwrap().iter() {
+            assert_eq!(lookup.get(&consumer.subscription_id), Some(id));
+        }
+        assert_eq!(lookup.len(), 2);
+        assert_eq!(session.session_number(), 1);
+    }
+
+    #[test]
+    fn registered_consumers_are_recreated_after_restart() {
+        let path = std::env::temp_dir().join("grinbox-held-subscriptions-test");
+        let registry = Arc::new(SubscriptionRegistry::new(path.clone()));
+        registry.save(&["alice".to_string(), "bob".to_string()]).unwrap();
+
+        // The relay starts over the registry the previous process left behind.
+        let mut session = broker_session_with_registry(Some(registry.clone()));
+        let started_at = Instant::now();
+        session.on_connected();
+        assert_eq!(subscribed_destinations(&session), vec!["alice".to_string(), "bob".to_string()]);
+
+        // alice's client comes back and takes over the queue, bob's never does.
+        let (sender, _receiver) = unbounded();
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender);
+        assert_eq!(subscribed_destinations(&session), vec!["alice".to_string(), "bob".to_string()]);
+        assert_eq!(session.held_subscriptions.lock().unwrap().keys().collect::<Vec<_>>(), vec!["bob"]);
+
+        session.release_expired_holds(started_at + DEFAULT_QUEUE_EXPIRATION - Duration::from_secs(1));
+        assert_eq!(subscribed_destinations(&session), vec!["alice".to_string(), "bob".to_string()]);
+        session.release_expired_holds(started_at + DEFAULT_QUEUE_EXPIRATION + Duration::from_secs(1));
+        assert_eq!(subscribed_destinations(&session), vec!["alice".to_string()]);
+
+        session.save_registry();
+        assert_eq!(registry.load().unwrap(), vec!["alice".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn registry_writes_are_batched() {
+        let path = std::env::temp_dir().join("grinbox-batched-registry-test");
+        let _ = std::fs::remove_file(&path);
+        let registry = Arc::new(SubscriptionRegistry::new(path.clone()));
+        let mut session = broker_session_with_registry(Some(registry.clone()));
+        let (sender, _receiver) = unbounded();
+        let now = Instant::now();
+
+        session.subscribe("connection/0".to_string(), "alice".to_string(), sender.clone());
+        session.save_registry_if_due(now);
+        assert_eq!(registry.load().unwrap(), vec!["alice".to_string()]);
+
+        session.subscribe("connection/1".to_string(), "bob".to_string(), sender.clone());
+        session.subscribe("connection/2".to_string(), "carol".to_string(), sender);
+        session.save_registry_if_due(now + Duration::from_secs(1));
+        assert_eq!(registry.load().unwrap(), vec!["alice".to_string()]);
+
+        session.save_registry_if_due(now + REGISTRY_SAVE_INTERVAL);
+        assert_eq!(registry.load().unwrap(), vec!["alice".to_string(), "bob".to_string(), "carol".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn publishes_while_reconnecting_are_sent_once_connected() {
+        let mut session = broker_session();
+        session.on_connected();
+
+        let stream: ConnectFuture<BrokerStream> = Box::new(futures::future::empty());
+        session.replace_session(SessionBuilder::new().build(stream).unwrap());
+        let (sender, _receiver) = unbounded();
+        session.publish("bob", "slate", "alice", None, None, Some(sender));
+        session.publish("carol", "slate", "alice", None, None, None);
+        assert_eq!(session.queued_publishes.lock().unwrap().len(), 2);
+        assert!(session.pending_receipts.lock().unwrap().is_empty());
+        assert!(session.publish_counters.top(10).is_empty());
+
+        session.on_connected();
+        assert!(session.queued_publishes.lock().unwrap().is_empty());
+        assert_eq!(session.pending_receipts.lock().unwrap().len(), 1);
+        assert_eq!(session.publish_counters.top(10).len(), 2);
+    }
+
+    #[test]
+    fn receipt_confirms_publish() {
+        let mut session = broker_session();
+        session.on_connected();
+        let (sender, receiver) = unbounded();
+        session.publish("bob", "slate", "alice", Some(600), None, Some(sender));
+
+        let receipt_id = session.pending_receipts.lock().unwrap().keys().next().cloned().unwrap();
+        assert!(session.session.lock().unwrap().state.outstanding_receipts.contains_key(&receipt_id));
+        session.on_receipt(&receipt_id);
+        drop(session);
+
+        let responses: Vec<BrokerResponse> = receiver.wait().map(|response| response.unwrap()).collect();
+        match responses[..] {
+            [BrokerResponse::Published { ref reply_to, message_expiration_in_seconds }] => {
+                assert_eq!(reply_to, "alice");
+                assert_eq!(message_expiration_in_seconds, Some(600));
+            }
+            ref responses => panic!("unexpected responses: {:?}", responses),
+        }
+    }
+
+    #[test]
+    fn unconfirmed_publish_fails_after_timeout() {
+        let mut session = broker_session();
+        session.on_connected();
+        let (sender, receiver) = unbounded();
+        session.publish("bob", "slate", "alice", None, None, Some(sender));
+
+        session.expire_receipts(Instant::now());
+        assert_eq!(session.pending_receipts.lock().unwrap().len(), 1);
+        session.expire_receipts(Instant::now() + RECEIPT_TIMEOUT);
+        assert!(session.pending_receipts.lock().unwrap().is_empty());
+        drop(session);
+
+        let responses: Vec<BrokerResponse> = receiver.wait().map(|response| response.unwrap()).collect();
+        match responses[..] {
+            [BrokerResponse::PublishFailed { ref reply_to }] => assert_eq!(reply_to, "alice"),
+            ref responses => panic!("unexpected responses: {:?}", responses),
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_cap() {
+        let delays: Vec<u64> = (0..8)
+            .map(|attempt| reconnect_delay(attempt, DEFAULT_MAX_RECONNECT_DELAY).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn reconnect_backoff_never_exceeds_configured_cap() {
+        for &max_secs in &[0, 5, 120] {
+            let max_delay = Duration::from_secs(max_secs);
+            let mut pacer = ReconnectPacer::new(Duration::from_secs(0), max_delay);
+            let delays: Vec<Duration> = (0..40).map(|_| pacer.next_delay(false, Instant::now())).collect();
+            assert!(delays.iter().all(|delay| *delay <= max_delay));
+            assert_eq!(delays.last(), Some(&max_delay));
+        }
+
+        let delays: Vec<u64> = (0..9)
+            .map(|attempt| reconnect_delay(attempt, Duration::from_secs(120)).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 64, 120, 120]);
+    }
+
+    #[test]
+    fn flapping_sessions_respect_min_reconnect_interval() {
+        let min_interval = Duration::from_secs(5);
+        let mut pacer = ReconnectPacer::new(min_interval, DEFAULT_MAX_RECONNECT_DELAY);
+        let mut now = Instant::now();
+        let mut attempts = vec![now];
+        pacer.attempt_started(now);
+
+        // Every session connects and is dropped right away, resetting the backoff each time.
+        for _ in 0..5 {
+            now += Duration::from_millis(10);
+            now += pacer.next_delay(true, false, now);
+            pacer.attempt_started(now);
+            attempts.push(now);
+        }
+
+        for pair in attempts.windows(2) {
+            assert!(pair[1].duration_since(pair[0]) >= min_interval);
+        }
+
+        // Sessions that stayed up longer than the interval are retried after the usual backoff.
+        now += Duration::from_secs(60);
+        assert_eq!(pacer.next_delay(true, false, now), reconnect_delay(0, DEFAULT_MAX_RECONNECT_DELAY));
+    }
+
+    #[test]
+    fn expired_sessions_are_replaced_without_backoff() {
+        let mut pacer = ReconnectPacer::new(Duration::from_secs(0), DEFAULT_MAX_RECONNECT_DELAY);
+        let now = Instant::now();
+        pacer.next_delay(false, false, now);
+        assert_eq!(pacer.next_delay(false, true, now), Duration::from_secs(0));
+        assert_eq!(pacer.next_delay(false, false, now), reconnect_delay(1, DEFAULT_MAX_RECONNECT_DELAY));
+    }
+
+    /// Reads one STOMP frame, skipping the heartbeat newlines before it.
+    fn read_frame(stream: &mut std::net::TcpStream) -> String {
+        let mut frame = vec![];
+        let mut byte = [0u8; 1];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                0 => return String::from_utf8(frame).unwrap(),
+                b'\r' | b'\n' if frame.is_empty() => {}
+                byte => frame.push(byte),
+            }
+        }
+    }
+
+    /// Accepts the relay's next connection, answers its CONNECT and waits for it to subscribe
+    /// to `destination`.
+    fn accept_subscriber(listener: &std::net::TcpListener, destination: &str) -> std::net::TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(read_frame(&mut stream).starts_with("CONNECT"));
+        stream.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+        let destination_header = format!("destination:{}", destination);
+        loop {
+            let frame = read_frame(&mut stream);
+            if frame.starts_with("SUBSCRIBE") && frame.lines().any(|line| line == destination_header) {
+                return stream;
+            }
+        }
+    }
+
+    #[test]
+    fn expired_session_is_resubscribed_right_away() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (status_sender, _status_receiver) = mpsc::channel();
+        let mut broker = Broker::new(address, "guest".to_string(), "guest".to_string(), None).with_reconnect(true);
+        let requests = broker.start(status_sender).unwrap();
+        let (response_sender, _response_receiver) = unbounded();
+        requests
+            .unbounded_send(BrokerRequest::Subscribe {
+                id: "connection/0".to_string(),
+                subject: "alice".to_string(),
+                response_sender,
+            })
+            .unwrap();
+
+        let (reconnected_sender, reconnected) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stream = accept_subscriber(&listener, "alice");
+            stream.write_all(b"ERROR\nmessage:Session expired\n\n\0").unwrap();
+            let expired_at = Instant::now();
+
+            let _stream = accept_subscriber(&listener, "alice");
+            reconnected_sender.send(expired_at.elapsed()).unwrap();
+        });
+
+        // A dropped session that had connected is otherwise retried after a second.
+        let elapsed = reconnected.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(elapsed < reconnect_delay(0, DEFAULT_MAX_RECONNECT_DELAY), "resubscribed after {:?}", elapsed);
+    }
+
+    #[test]
+    fn rejected_messages_do_not_notify_sender() {
+        let frame = message_frame(
+            vec![(REPLY_TO_HEADER_NAME, "alice"), (RECIPIENT_HEADER_NAME, "bob"), (DEATH_REASON_HEADER_NAME, "rejected")],
+            "slate",
+        );
+        assert_eq!(expiry_notification(&frame), None);
+    }
+
+    #[test]
+    fn queue_expiration_header_is_in_milliseconds() {
+        assert_eq!(queue_expiration_header(DEFAULT_QUEUE_EXPIRATION).unwrap(), "86400000");
+        assert_eq!(queue_expiration_header(Duration::from_millis(1500)).unwrap(), "1500");
+        assert!(queue_expiration_header(Duration::from_secs(0)).is_err());
+        assert!(queue_expiration_header(Duration::from_secs(60 * 86400)).is_err());
+    }
+
+    #[test]
+    fn message_expiration_header_uses_requested_seconds() {
+        assert_eq!(message_expiration(Some(600)), "600000");
+        assert_eq!(message_expiration(None), "86400000");
+        assert_eq!(message_expiration(Some(0)), "86400000");
+        assert_eq!(message_expiration(Some(86401)), "86400000");
+    }
+
+    #[test]
+    fn compressed_payload_round_trips() {
+        let payload = r#"{"str":"encrypted slate","challenge":"","signature":"abcdef"}"#;
+        let body = compress_payload(payload).unwrap();
+        let frame = Frame {
+            body,
+            ..message_frame(vec![(CONTENT_ENCODING_HEADER_NAME, GZIP_CONTENT_ENCODING)], "")
+        };
+        assert_eq!(frame_payload(&frame).unwrap(), payload);
+    }
+
+    #[test]
+    fn publish_frame_carries_message_headers() {
+        let frame = publish_frame("subject", "slate", "alice", Some(600), Some("c-1"), &queue_arguments("86400000", false), false);
+        assert_eq!(frame.headers.get(DESTINATION), Some("/queue/subject"));
+        assert_eq!(frame.headers.get(HeaderName::from_str("expiration")), Some("600000"));
+        assert_eq!(frame.headers.get(HeaderName::from_str(REPLY_TO_HEADER_NAME)), Some("alice"));
+        assert_eq!(frame.headers.get(HeaderName::from_str(CORRELATION_ID_HEADER_NAME)), Some("c-1"));
+        assert_eq!(frame.headers.get(HeaderName::from_str(CONTENT_ENCODING_HEADER_NAME)), None);
+        assert_eq!(frame_payload(&frame).unwrap(), "slate");
+    }
+
+    #[test]
+    fn published_frames_keep_their_order() {
+        let payloads: Vec<String> = (0..100).map(|i| format!("slate-{}", i)).collect();
+        let frames: Vec<Frame> = payloads
+            .iter()
+            .map(|payload| publish_frame("subject", payload, "alice", None, None, &queue_arguments("86400000", false), true))
+            .collect();
+
+        let decoded: Vec<String> = frames.iter().map(|frame| frame_payload(frame).unwrap()).collect();
+        assert_eq!(decoded, payloads);
+    }
+
+    #[test]
+    fn uncompressed_payload_is_passed_through() {
+        let frame = message_frame(vec![], "plain slate");
+        assert_eq!(frame_payload(&frame).unwrap(), "plain slate");
+    }
+
+    #[test]
+    fn detects_session_expired_error() {
+        assert!(is_session_expired(&error_frame("Session Expired", "")));
+        assert!(is_session_expired(&error_frame("", "the session has expired")));
+        assert!(!is_session_expired(&error_frame("not_found", "no queue")));
+    }
+    #[test]
+    fn queue_move_forwards_messages_until_marker() {
+        let queue_move = QueueMove::new("new-subject".to_string(), "0/7".to_string());
+        let old_queue = vec![
+            message_frame(vec![(REPLY_TO_HEADER_NAME, "alice"), ("expiration", "600000")], "slate-1"),
+            message_frame(vec![(REPLY_TO_HEADER_NAME, "bob"), (CORRELATION_ID_HEADER_NAME, "c-1")], "slate-2"),
+            message_frame(vec![(QUEUE_MOVE_MARKER_HEADER_NAME, "0/7")], ""),
+        ];
+
+        let steps: Vec<QueueMoveStep> = old_queue.iter().map(|frame| queue_move.step(frame)).collect();
+        assert_eq!(steps, vec![
+            QueueMoveStep::Forward {
+                subject: "new-subject".to_string(),
+                payload: "slate-1".to_string(),
+                reply_to: "alice".to_string(),
+                message_expiration_in_seconds: Some(600),
+                correlation_id: None,
+            },
+            QueueMoveStep::Forward {
+                subject: "new-subject".to_string(),
+                payload: "slate-2".to_string(),
+                reply_to: "bob".to_string(),
+                message_expiration_in_seconds: None,
+                correlation_id: Some("c-1".to_string()),
+            },
+            QueueMoveStep::Done,
+        ]);
+    }
+
+    #[test]
+    fn queue_move_ignores_markers_from_other_moves() {
+        let queue_move = QueueMove::new("new-subject".to_string(), "0/7".to_string());
+        let frame = message_frame(vec![(QUEUE_MOVE_MARKER_HEADER_NAME, "0/3")], "");
+        assert_eq!(queue_move.step(&frame), QueueMoveStep::Skip);
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::Stream;
+use uuid::Uuid;
+
+use grinboxlib::error::{ErrorKind, Result};
+
+use crate::broker::{BrokerRequest, BrokerResponse};
+
+/// Publishes a message to a scratch subject and waits for it to be consumed back, returning the
+/// round-trip time. Meant to be run once at startup, so a misconfigured broker is noticed before
+/// any client connects; the scratch queue is left to the broker's queue expiration.
+pub fn self_test(broker_sender: &UnboundedSender<BrokerRequest>, timeout: Duration) -> Result<Duration> {
+    let id = format!("self-test/{}", Uuid::new_v4());
+    let subject = format!("grinbox-self-test-{}", Uuid::new_v4());
+    let payload = Uuid::new_v4().to_string();
+
+    let (response_sender, response_receiver) = unbounded();
+    broker_request(broker_sender, BrokerRequest::Subscribe {
+        id: id.clone(),
+        subject: subject.clone(),
+        response_sender,
+    })?;
+
+    // The futures receiver has no timeout of its own, so it is drained from a thread.
+    let (received_sender, received_receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for response in response_receiver.wait() {
+            if received_sender.send(response).is_err() {
+                break;
+            }
+        }
+    });
+
+    let started_at = Instant::now();
+    broker_request(broker_sender, BrokerRequest::PostMessage {
+        subject: subject.clone(),
+        payload: payload.clone(),
+        reply_to: subject,
+        message_expiration_in_seconds: Some(60),
+        correlation_id: None,
+        receipt_sender: None,
+    })?;
+
+    let result = loop {
+        let remaining = match timeout.checked_sub(started_at.elapsed()) {
+            Some(remaining) => remaining,
+            None => break Err(self_test_error("no message came back before the timeout")),
+        };
+        match received_receiver.recv_timeout(remaining) {
+            Ok(Ok(BrokerResponse::Message { payload: received, ack_id, .. })) => {
+                if let Some(ack_id) = ack_id {
+                    let _ = broker_request(broker_sender, BrokerRequest::Acknowledge { ack_id, delivered: true });
+                }
+                if received == payload {
+                    break Ok(started_at.elapsed());
+                }
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(())) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                break Err(self_test_error("broker dropped the scratch subscription"))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                break Err(self_test_error("no message came back before the timeout"))
+            }
+        }
+    };
+
+    let _ = broker_request(broker_sender, BrokerRequest::Unsubscribe { id });
+    result
+}
+
+fn broker_request(broker_sender: &UnboundedSender<BrokerRequest>, request: BrokerRequest) -> Result<()> {
+    broker_sender
+        .unbounded_send(request)
+        .map_err(|_| self_test_error("broker is not running"))
+}
+
+fn self_test_error(reason: &str) -> failure::Error {
+    ErrorKind::GenericError(format!("broker self test failed: {}", reason)).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Delivers every posted message to the subscriber of its subject, like a working broker.
+    fn echo_broker() -> UnboundedSender<BrokerRequest> {
+        let (sender, receiver) = unbounded();
+        std::thread::spawn(move || {
+            let mut subscribers: HashMap<String, UnboundedSender<BrokerResponse>> = HashMap::new();
+            for request in receiver.wait() {
+                match request.unwrap() {
+                    BrokerRequest::Subscribe { subject, response_sender, .. } => {
+                        subscribers.insert(subject, response_sender);
+                    }
+                    BrokerRequest::PostMessage { subject, payload, reply_to, correlation_id, .. } => {
+                        if let Some(subscriber) = subscribers.get(&subject) {
+                            let _ = subscriber.unbounded_send(BrokerResponse::Message {
+                                subject: subject.clone(),
+                                payload,
+                                reply_to,
+                                correlation_id,
+                                ack_id: None,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+        sender
+    }
+
+    #[test]
+    fn self_test_succeeds_against_working_broker() {
+        let broker = echo_broker();
+        let latency = self_test(&broker, Duration::from_secs(5)).unwrap();
+        assert!(latency < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn self_test_fails_against_silent_broker() {
+        // Accepts requests but never delivers anything.
+        let (broker, _receiver) = unbounded();
+        let error = self_test(&broker, Duration::from_millis(100)).unwrap_err();
+        assert!(error.to_string().contains("before the timeout"));
+    }
+
+    #[test]
+    fn self_test_fails_against_stopped_broker() {
+        let (broker, receiver) = unbounded();
+        drop(receiver);
+        let error = self_test(&broker, Duration::from_secs(5)).unwrap_err();
+        assert!(error.to_string().contains("broker is not running"));
+    }
+}
@@ -112,10 +112,16 @@ fn parse_command(src: &[u8]) -> Result<Command, ParseError> {
 }
 
 fn get_line<'a>(src: &'a [u8]) -> Poll<(&'a [u8], &'a [u8]), ParseError> {
-    let mut split = src.splitn(2, |b| *b == b'\n');
+    // Without a `\n` the line is incomplete, even if it already ends in `\r`
+    let end = opt_nr!(src.iter().position(|b| *b == b'\n'));
 
-    let mut line = opt_nr!(split.next());
-    let remain = opt_nr!(split.next());
+    let mut line = &src[..end];
+    let remain = &src[(end + 1)..];
+
+    // Command and header lines never legitimately contain the frame terminator
+    if line.contains(&b'\0') {
+        return Err(ParseError::Invalid);
+    }
 
     if !line.is_empty() && line[line.len() - 1] == b'\r' {
         line = &line[..(line.len() - 1)];
@@ -148,4 +154,44 @@ impl Decoder for Codec {
             Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_line_on_empty_buffer_is_not_ready() {
+        match get_line(b"") {
+            Ok(Async::NotReady) => {}
+            _ => panic!("expected NotReady"),
+        }
+    }
+
+    #[test]
+    fn get_line_with_carriage_return_but_no_newline_is_not_ready() {
+        match get_line(b"CONNECTED\r") {
+            Ok(Async::NotReady) => {}
+            _ => panic!("expected NotReady"),
+        }
+    }
+
+    #[test]
+    fn get_line_with_null_byte_is_invalid() {
+        match get_line(b"version:1.\02\n") {
+            Err(ParseError::Invalid) => {}
+            _ => panic!("expected Invalid"),
+        }
+    }
+
+    #[test]
+    fn get_line_strips_carriage_return() {
+        match get_line(b"CONNECTED\r\nversion:1.2\n") {
+            Ok(Async::Ready((line, remain))) => {
+                assert_eq!(line, b"CONNECTED");
+                assert_eq!(remain, b"version:1.2\n");
+            }
+            _ => panic!("expected a complete line"),
+        }
+    }
+}
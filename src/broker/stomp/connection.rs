@@ -1,5 +1,11 @@
+use super::header::StompVersion;
+
 #[derive(Clone, Copy)]
 pub struct HeartBeat(pub u32, pub u32);
+#[derive(Clone)]
+pub struct AcceptVersion(pub Vec<StompVersion>);
+#[derive(Clone, Copy)]
+pub struct MinimumVersion(pub StompVersion);
 #[derive(Clone, Copy)]
 pub struct Credentials<'a>(pub &'a str, pub &'a str);
 #[derive(Clone)]
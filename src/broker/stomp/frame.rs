@@ -1,11 +1,21 @@
 use std::str::from_utf8;
 use std::fmt;
 use std::fmt::Formatter;
+use std::sync::atomic::{AtomicBool, Ordering};
 use bytes::BytesMut;
 
 use super::header::*;
 use super::subscription::AckMode;
 
+const MAX_LOGGED_BODY_BYTES: usize = 256;
+
+static LOG_FULL_BODIES: AtomicBool = AtomicBool::new(false);
+
+/// Controls whether `Display` renders complete frame bodies instead of a truncated preview.
+pub fn log_full_bodies(enabled: bool) {
+    LOG_FULL_BODIES.store(enabled, Ordering::Relaxed);
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum Command {
     Send,
@@ -110,11 +120,7 @@ impl fmt::Display for Frame {
             frame_string.push_str("\n");
         }
         frame_string.push_str("\n");
-        let body_string: &str = match from_utf8(self.body.as_ref()) {
-            Ok(ref s) => *s,
-            Err(_) => "<Binary content>", // Space is wasted in this case. Could shrink to fit?
-        };
-        frame_string.push_str(body_string);
+        frame_string.push_str(&self.body_summary());
 
         write!(f, "{}", frame_string)
     }
@@ -129,6 +135,30 @@ impl Frame {
         }
     }
 
+    fn is_textual(&self) -> bool {
+        match self.headers.get(CONTENT_TYPE) {
+            Some(content_type) => content_type.starts_with("text/") || content_type.contains("json"),
+            None => true,
+        }
+    }
+
+    fn body_summary(&self) -> String {
+        let body = match from_utf8(self.body.as_ref()) {
+            Ok(body) if self.is_textual() => body,
+            _ => return format!("<{} bytes of binary content>", self.body.len()),
+        };
+
+        if LOG_FULL_BODIES.load(Ordering::Relaxed) || body.len() <= MAX_LOGGED_BODY_BYTES {
+            return body.to_string();
+        }
+
+        let mut end = MAX_LOGGED_BODY_BYTES;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}... <{} bytes>", &body[..end], body.len())
+    }
+
     fn count_bytes(&self) -> usize {
         let mut space_required: usize = 0;
         // Add one to space calculations to make room for '\n'
@@ -171,11 +201,11 @@ impl Frame {
         )
     }
 
-    pub fn disconnect() -> Self {
+    pub fn disconnect(receipt_id: &str) -> Self {
         Self::empty(
             Command::Disconnect,
             header_list![
-                RECEIPT => "msg/disconnect"
+                RECEIPT => receipt_id
             ],
         )
     }
@@ -255,4 +285,25 @@ impl Frame {
             ],
         )
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn large_body_is_truncated_in_display() {
+        let body = "x".repeat(MAX_LOGGED_BODY_BYTES * 4);
+        let frame = Frame::send("/queue/test", body.as_bytes());
+        let displayed = frame.to_string();
+        assert!(displayed.len() < body.len());
+        assert!(displayed.ends_with(&format!("... <{} bytes>", body.len())));
+    }
+
+    #[test]
+    fn binary_body_reports_size() {
+        let mut frame = Frame::send("/queue/test", &[0, 159, 146, 150]);
+        frame.headers.push(Header::new(CONTENT_TYPE, "application/octet-stream"));
+        assert!(frame.to_string().ends_with("<4 bytes of binary content>"));
+    }
+}
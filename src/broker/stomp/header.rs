@@ -7,6 +7,9 @@ use unicode_segmentation::UnicodeSegmentation;
 // Ideally this would be a simple typedef. However:
 // See Rust bug #11047: https://github.com/mozilla/rust/issues/11047
 // Cannot call static methods (`with_capacity`) on type aliases (`HeaderList`)
+//
+// Lookups scan the list, as a frame only carries a handful of headers; indexing them by name
+// would hash and clone every name of every frame to save a few comparisons.
 #[derive(Clone, Debug)]
 pub struct HeaderList {
     pub headers: Vec<Header>,
@@ -186,6 +189,7 @@ standard_headers! {
     (Host, HOST, "host");
     (Id, ID, "id");
     (Login, LOGIN, "login");
+    (Message, MESSAGE, "message");
     (MessageId, MESSAGE_ID, "message-id");
     (Passcode, PASSCODE, "passcode");
     (Receipt, RECEIPT, "receipt");
@@ -198,12 +202,22 @@ standard_headers! {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum StompVersion {
     Stomp_v1_0,
     Stomp_v1_1,
     Stomp_v1_2,
 }
+
+impl StompVersion {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            StompVersion::Stomp_v1_0 => "1.0",
+            StompVersion::Stomp_v1_1 => "1.1",
+            StompVersion::Stomp_v1_2 => "1.2",
+        }
+    }
+}
 impl std::str::FromStr for StompVersion {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -286,6 +300,44 @@ mod test {
         assert!(encoded == Header::encode_value(unencoded));
     }
 
+    #[test]
+    fn get_returns_first_of_repeated_headers() {
+        let mut headers = HeaderList::new();
+        for i in 0..100 {
+            headers.push(Header::new(HeaderName::from_str(&format!("header-{}", i)), &i.to_string()));
+        }
+        // Repeated headers do not shadow the first one.
+        headers.push(Header::new(HeaderName::from_str("header-0"), "repeated"));
+        headers.push(Header::new(ACK, "ack-id"));
+
+        for i in 0..100 {
+            assert_eq!(headers.get(HeaderName::from_str(&format!("header-{}", i))), Some(&i.to_string()[..]));
+        }
+        assert_eq!(headers.get(HeaderName::from_str("header-0")), Some("0"));
+        assert_eq!(headers.get(ACK), Some("ack-id"));
+
+        headers.pop();
+        assert_eq!(headers.get(ACK), None);
+        headers.pop();
+        assert_eq!(headers.get(HeaderName::from_str("header-0")), Some("0"));
+
+        headers.retain(|header| header.get_value() != "0");
+        assert_eq!(headers.get(HeaderName::from_str("header-0")), None);
+        assert_eq!(headers.get(HeaderName::from_str("header-99")), Some("99"));
+
+        let mut other = HeaderList::new();
+        other.push(Header::new(SUBSCRIPTION, "sub-0"));
+        headers.concat(&mut other);
+        assert_eq!(headers.get(SUBSCRIPTION), Some("sub-0"));
+        assert_eq!(other.get(SUBSCRIPTION), None);
+        assert_eq!(headers.iter().count(), 100);
+
+        let mut drained = 0;
+        headers.drain(|_| drained += 1);
+        assert_eq!(drained, 100);
+        assert_eq!(headers.get(SUBSCRIPTION), None);
+    }
+
     #[test]
     fn encode_slash() {
         let unencoded = r"Hello\World";
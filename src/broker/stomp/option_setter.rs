@@ -2,7 +2,7 @@ use super::message_builder::MessageBuilder;
 use super::session_builder::SessionBuilder;
 use super::subscription_builder::SubscriptionBuilder;
 use super::header::*;
-use super::connection::{HeartBeat, Credentials, OwnedCredentials};
+use super::connection::{AcceptVersion, HeartBeat, Credentials, MinimumVersion, OwnedCredentials};
 use super::subscription::AckMode;
 use super::session::{ReceiptRequest, GenerateReceipt};
 
@@ -17,6 +17,13 @@ impl<'a, T> OptionSetter<MessageBuilder<'a, T>> for Header {
     }
 }
 
+impl<'a, T> OptionSetter<MessageBuilder<'a, T>> for HeaderList {
+    fn set_option(mut self, mut builder: MessageBuilder<'a, T>) -> MessageBuilder<'a, T> {
+        builder.frame.headers.concat(&mut self);
+        builder
+    }
+}
+
 impl<'a, 'b, T> OptionSetter<MessageBuilder<'b, T>> for SuppressedHeader<'a> {
     fn set_option(self, mut builder: MessageBuilder<'b, T>) -> MessageBuilder<'b, T> {
         let SuppressedHeader(key) = self;
@@ -42,6 +49,28 @@ impl OptionSetter<SessionBuilder> for HeartBeat {
     }
 }
 
+impl OptionSetter<SessionBuilder> for AcceptVersion {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        let versions: Vec<&str> = self.0.iter().map(|version| version.as_str()).collect();
+        builder
+            .config
+            .headers
+            .retain(|header| header.get_key() != ACCEPT_VERSION);
+        builder
+            .config
+            .headers
+            .push(Header::new(ACCEPT_VERSION, &versions.join(",")));
+        builder
+    }
+}
+
+impl OptionSetter<SessionBuilder> for MinimumVersion {
+    fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
+        builder.config.min_version = self.0;
+        builder
+    }
+}
+
 impl<'b> OptionSetter<SessionBuilder> for Credentials<'b> {
     fn set_option(self, mut builder: SessionBuilder) -> SessionBuilder {
         builder.config.credentials = Some(OwnedCredentials::from(self));
@@ -67,6 +96,13 @@ impl<'a, T> OptionSetter<SubscriptionBuilder<'a, T>> for Header {
     }
 }
 
+impl<'a, T> OptionSetter<SubscriptionBuilder<'a, T>> for HeaderList {
+    fn set_option(mut self, mut builder: SubscriptionBuilder<'a, T>) -> SubscriptionBuilder<'a, T> {
+        builder.headers.concat(&mut self);
+        builder
+    }
+}
+
 impl<'a, 'b, T> OptionSetter<SubscriptionBuilder<'b, T>> for SuppressedHeader<'a> {
     fn set_option(self, mut builder: SubscriptionBuilder<'b, T>) -> SubscriptionBuilder<'b, T> {
         let SuppressedHeader(key) = self;
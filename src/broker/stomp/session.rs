@@ -77,6 +77,10 @@ pub struct SessionState {
     next_transaction_id: u32,
     next_subscription_id: u32,
     next_receipt_id: u32,
+    disconnect_receipt_id: Option<String>,
+    close_deadline: Option<Delay>,
+    connected_frame_received: bool,
+    negotiated_version: Option<StompVersion>,
 
     rx_heartbeat: Option<HeartBeatDelay>,
     tx_heartbeat: Option<HeartBeatDelay>,
@@ -91,6 +95,10 @@ impl SessionState {
             next_transaction_id: 0,
             next_subscription_id: 0,
             next_receipt_id: 0,
+            disconnect_receipt_id: None,
+            close_deadline: None,
+            connected_frame_received: false,
+            negotiated_version: None,
             rx_heartbeat: None,
             tx_heartbeat: None,
             subscriptions: HashMap::new(),
@@ -137,7 +145,29 @@ impl<T> Session<T>
     }
 
     pub fn disconnect(&mut self) {
-        self.send_frame(Frame::disconnect());
+        let receipt_id = format!("msg/disconnect/{}", self.generate_receipt_id());
+        self.state.disconnect_receipt_id = Some(receipt_id.clone());
+        self.send_frame(Frame::disconnect(&receipt_id));
+    }
+
+    /// Flushes frames still waiting to be written, sends DISCONNECT and keeps the stream open
+    /// until the broker confirms it, so nothing sent before is lost. Gives up and drops the
+    /// stream once `timeout` passed without a receipt.
+    pub fn close(&mut self, timeout: Duration) {
+        if let StreamState::Connected(_) = self.stream {
+            self.poll_stream_complete();
+        }
+        if let StreamState::Connected(_) = self.stream {
+            self.disconnect();
+            self.state.close_deadline = Some(Delay::new(Instant::now() + timeout));
+        } else {
+            self.on_disconnect(DisconnectionReason::Requested);
+        }
+    }
+
+    /// The STOMP version the broker chose in its CONNECTED frame, once it arrived.
+    pub fn negotiated_version(&self) -> Option<StompVersion> {
+        self.state.negotiated_version
     }
 
     pub fn acknowledge_frame(&mut self, frame: &Frame, which: AckOrNack) {
@@ -254,6 +284,7 @@ impl<T> Session<T>
         self.stream = StreamState::Failed;
         self.state.tx_heartbeat = None;
         self.state.rx_heartbeat = None;
+        self.state.close_deadline = None;
     }
 
     fn on_stream_ready(&mut self) {
@@ -307,6 +338,28 @@ impl<T> Session<T>
     }
 
     fn on_connected_frame_received(&mut self, connected_frame: Frame) -> Result<()> {
+        // Only the first CONNECTED frame counts; acting on repeats would re-arm the heartbeats
+        // and tell the broker layer it connected again.
+        if self.state.connected_frame_received {
+            warn!("ignoring repeated CONNECTED frame");
+            return Ok(());
+        }
+        self.state.connected_frame_received = true;
+
+        // Servers speaking 1.0 do not send a version header
+        let version = connected_frame.headers.get(VERSION).unwrap_or("1.0").to_string();
+        let accepted = self.config.headers.get_accept_version().unwrap_or_default();
+        match version.trim().parse::<StompVersion>() {
+            Ok(negotiated) if accepted.contains(&negotiated) => {
+                self.state.negotiated_version = Some(negotiated);
+            }
+            _ => {
+                error!("server negotiated STOMP version {} which was not offered", version);
+                self.on_disconnect(DisconnectionReason::VersionMismatch(version));
+                return Ok(());
+            }
+        }
+
         // The Client's requested tx/rx HeartBeat timeouts
         let connection::HeartBeat(client_tx_ms, client_rx_ms) = self.config.heartbeat;
 
@@ -333,6 +386,10 @@ impl<T> Session<T>
         self.register_tx_heartbeat_timeout()?;
         self.register_rx_heartbeat_timeout()?;
 
+        self.events.push_back(SessionEvent::HeartbeatNegotiated {
+            tx_ms: agreed_upon_tx_ms,
+            rx_ms: agreed_upon_rx_ms,
+        });
         self.events.push_back(SessionEvent::Connected);
 
         Ok(())
@@ -346,7 +403,8 @@ impl<T> Session<T>
             }
         };
         if let Some(receipt_id) = receipt_id {
-            if receipt_id == "msg/disconnect" {
+            if self.state.disconnect_receipt_id.as_ref() == Some(&receipt_id) {
+                self.state.disconnect_receipt_id = None;
                 self.on_disconnect(DisconnectionReason::Requested);
             }
             if let Some(entry) = self.state.outstanding_receipts.remove(&receipt_id) {
@@ -425,11 +483,17 @@ pub enum DisconnectionReason {
     ClosedByOtherSide,
     HeartBeatTimeout,
     Requested,
+    CloseTimedOut,
+    VersionMismatch(String),
 }
 
 #[derive(Debug)]
 pub enum SessionEvent {
     Connected,
+    HeartbeatNegotiated {
+        tx_ms: u32,
+        rx_ms: u32,
+    },
     Error(Frame),
     Receipt {
         id: String,
@@ -488,6 +552,19 @@ impl<T> Stream for Session<T>
             self.reply_to_heartbeat()?;
         }
 
+        let close_timed_out = match self.state.close_deadline {
+            Some(ref mut deadline) => match deadline.poll() {
+                Ok(Async::NotReady) => false,
+                Ok(Async::Ready(())) => true,
+                Err(_e) => return Err(IoError::new(ErrorKind::Other, "timer")),
+            },
+            None => false,
+        };
+        if close_timed_out {
+            warn!("broker did not confirm DISCONNECT in time, closing anyway");
+            self.on_disconnect(DisconnectionReason::CloseTimedOut);
+        }
+
         self.poll_stream_complete();
 
         match self.events.pop_front() {
@@ -498,4 +575,168 @@ impl<T> Stream for Session<T>
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use super::super::codec::Codec;
+    use super::super::connection::{AcceptVersion, HeartBeat};
+    use super::super::session_builder::SessionBuilder;
+
+    fn session() -> Session<Cursor<Vec<u8>>> {
+        session_with(SessionBuilder::new())
+    }
+
+    fn session_with(builder: SessionBuilder) -> Session<Cursor<Vec<u8>>> {
+        builder
+            .build(Box::new(future::empty::<Cursor<Vec<u8>>, IoError>()))
+            .unwrap()
+    }
+
+    fn connected(headers: HeaderList) -> Frame {
+        Frame {
+            command: Command::Connected,
+            headers,
+            body: Vec::new(),
+        }
+    }
+
+    fn receipt(receipt_id: &str) -> Frame {
+        Frame {
+            command: Command::Receipt,
+            headers: header_list![
+                RECEIPT_ID => receipt_id
+            ],
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn spurious_disconnect_receipt_is_ignored() {
+        let mut session = session();
+        session.handle_receipt(receipt("msg/disconnect"));
+        assert!(session.events.is_empty());
+    }
+
+    #[test]
+    fn requested_disconnect_receipt_disconnects() {
+        let mut session = session();
+        session.disconnect();
+        session.handle_receipt(receipt("msg/disconnect/0"));
+        match session.events.pop_front() {
+            Some(SessionEvent::Disconnected(DisconnectionReason::Requested)) => {}
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn close_flushes_frames_and_waits_for_receipt() {
+        let mut session = session();
+        session.stream = StreamState::Connected(Codec.framed(Cursor::new(Vec::new())));
+        session.send_frame(Frame::send("/queue/bob", b"slate"));
+        session.close(Duration::from_secs(5));
+
+        let written = match session.stream {
+            StreamState::Connected(ref fr) => String::from_utf8_lossy(fr.get_ref().get_ref()).to_string(),
+            _ => panic!("stream was dropped before the receipt arrived"),
+        };
+        let send_at = written.find("SEND").unwrap();
+        let disconnect_at = written.find("DISCONNECT").unwrap();
+        assert!(send_at < disconnect_at);
+        assert!(session.events.is_empty());
+
+        session.handle_receipt(receipt("msg/disconnect/0"));
+        match session.events.pop_front() {
+            Some(SessionEvent::Disconnected(DisconnectionReason::Requested)) => {}
+            other => panic!("unexpected event: {:?}", other),
+        }
+        match session.stream {
+            StreamState::Failed => {}
+            _ => panic!("stream was kept after the receipt"),
+        }
+    }
+
+    #[test]
+    fn connected_frame_reports_negotiated_heartbeat() {
+        let mut session = session_with(SessionBuilder::new().with(HeartBeat(10000, 5000)));
+        session
+            .on_connected_frame_received(connected(header_list![
+                VERSION => "1.2",
+                HEART_BEAT => "20000,0"
+            ]))
+            .unwrap();
+
+        let (tx_ms, rx_ms) = select_heartbeat(10000, 5000, 20000, 0);
+        match session.events.pop_front() {
+            Some(SessionEvent::HeartbeatNegotiated { tx_ms: actual_tx_ms, rx_ms: actual_rx_ms }) => {
+                assert_eq!((actual_tx_ms, actual_rx_ms), (tx_ms, rx_ms));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        match session.events.pop_front() {
+            Some(SessionEvent::Connected) => {}
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repeated_connected_frame_is_ignored() {
+        let mut session = session();
+        for _ in 0..2 {
+            session
+                .on_connected_frame_received(connected(header_list![
+                    VERSION => "1.2"
+                ]))
+                .unwrap();
+        }
+
+        let connected_events = session
+            .events
+            .iter()
+            .filter(|event| match event {
+                SessionEvent::Connected => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(connected_events, 1);
+    }
+
+    #[test]
+    fn connected_frame_with_unoffered_version_disconnects() {
+        let mut session = session();
+        session
+            .on_connected_frame_received(connected(header_list![
+                VERSION => "1.1"
+            ]))
+            .unwrap();
+
+        match session.events.pop_front() {
+            Some(SessionEvent::Disconnected(DisconnectionReason::VersionMismatch(version))) => {
+                assert_eq!(version, "1.1");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(session.events.is_empty());
+        assert_eq!(session.negotiated_version(), None);
+    }
+
+    #[test]
+    fn offered_older_version_is_negotiated() {
+        let mut session = session_with(
+            SessionBuilder::new().with(AcceptVersion(vec![StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2])),
+        );
+        session
+            .on_connected_frame_received(connected(header_list![
+                VERSION => "1.1"
+            ]))
+            .unwrap();
+
+        assert_eq!(session.negotiated_version(), Some(StompVersion::Stomp_v1_1));
+        match session.events.pop_back() {
+            Some(SessionEvent::Connected) => {}
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+}
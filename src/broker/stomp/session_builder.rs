@@ -1,3 +1,5 @@
+use std::io::{Error as IoError, ErrorKind, Result};
+
 use super::option_setter::OptionSetter;
 use super::connection::{HeartBeat, OwnedCredentials};
 use super::header::*;
@@ -8,8 +10,10 @@ pub struct SessionConfig {
     pub credentials: Option<OwnedCredentials>,
     pub heartbeat: HeartBeat,
     pub headers: HeaderList,
+    pub min_version: StompVersion,
 }
 
+#[derive(Clone)]
 pub struct SessionBuilder {
     pub config: SessionConfig,
 }
@@ -23,15 +27,33 @@ impl SessionBuilder {
                 ACCEPT_VERSION => "1.2",
                 CONTENT_LENGTH => "0"
             ],
+            min_version: StompVersion::Stomp_v1_1,
         };
         SessionBuilder { config: config }
     }
 
-    pub fn build<T>(self, conn: ConnectFuture<T>) -> Session<T>
+    pub fn build<T>(self, conn: ConnectFuture<T>) -> Result<Session<T>>
         where
             T: tokio_io::AsyncWrite + tokio_io::AsyncRead + Send + 'static,
     {
-        Session::new(self.config, conn)
+        self.validate()?;
+        Ok(Session::new(self.config, conn))
+    }
+
+    /// Refuses configurations offering a protocol version older than `min_version`.
+    pub fn validate(&self) -> Result<()> {
+        let versions = self.config.headers.get_accept_version().unwrap_or_default();
+        match versions.iter().find(|version| **version < self.config.min_version) {
+            Some(version) => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "accept-version {} is below the minimum allowed version {}",
+                    version.as_str(),
+                    self.config.min_version.as_str()
+                ),
+            )),
+            None => Ok(()),
+        }
     }
 
     pub fn with<'b, O>(self, option_setter: O) -> SessionBuilder
@@ -42,3 +64,24 @@ impl SessionBuilder {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::connection::{AcceptVersion, MinimumVersion};
+
+    #[test]
+    fn accepts_versions_at_or_above_minimum() {
+        let builder = SessionBuilder::new()
+            .with(AcceptVersion(vec![StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2]))
+            .with(MinimumVersion(StompVersion::Stomp_v1_1));
+        assert!(builder.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_versions_below_minimum() {
+        let builder = SessionBuilder::new()
+            .with(AcceptVersion(vec![StompVersion::Stomp_v1_0]))
+            .with(MinimumVersion(StompVersion::Stomp_v1_1));
+        assert!(builder.validate().is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+
+/// Keeps the set of subscribed subjects on disk, one per line, so that broker consumers can be
+/// re-created after a restart before clients reconnect.
+pub struct SubscriptionRegistry {
+    path: PathBuf,
+}
+
+impl SubscriptionRegistry {
+    pub fn new(path: PathBuf) -> SubscriptionRegistry {
+        SubscriptionRegistry { path }
+    }
+
+    pub fn load(&self) -> Result<Vec<String>> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(contents
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    pub fn save(&self, subjects: &[String]) -> Result<()> {
+        let mut contents = subjects.join("\n");
+        contents.push('\n');
+        fs::write(&self.path, contents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subjects_survive_reload() {
+        let path = std::env::temp_dir().join("grinbox-subscription-registry-test");
+        let _ = fs::remove_file(&path);
+
+        let registry = SubscriptionRegistry::new(path.clone());
+        assert!(registry.load().unwrap().is_empty());
+
+        let subjects = vec!["subject-a".to_string(), "subject-b".to_string()];
+        registry.save(&subjects).unwrap();
+
+        let reloaded = SubscriptionRegistry::new(path.clone());
+        assert_eq!(reloaded.load().unwrap(), subjects);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
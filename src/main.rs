@@ -5,6 +5,8 @@ extern crate log;
 extern crate colored;
 extern crate env_logger;
 extern crate failure;
+extern crate flate2;
+extern crate native_tls;
 #[macro_use]
 extern crate futures;
 extern crate nitox;
@@ -14,6 +16,7 @@ extern crate tokio_codec;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_timer;
+extern crate tokio_tls;
 extern crate unicode_segmentation;
 extern crate bytes;
 extern crate nom;
@@ -23,10 +26,12 @@ extern crate ws;
 extern crate grinboxlib;
 
 mod broker;
+mod metrics;
 mod server;
 
-use broker::Broker;
-use server::AsyncServer;
+use broker::{Broker, MessageBroker, NatsBroker};
+use metrics::RelayMetrics;
+use server::{bind_listeners, listener_settings, AsyncServer, Authenticator, DefaultAuthenticator, DefaultIdGenerator, DnsCheckingFederator, FederationPool, Federator, ConnectionLimiter, IdGenerator, IdleReaper, OnionFederator, ServerConfig, WebsocketFederator};
 use std::net::ToSocketAddrs;
 
 fn main() {
@@ -34,12 +39,28 @@ fn main() {
 
     info!("hello, world!");
 
-    let broker_uri = std::env::var("BROKER_URI")
-        .unwrap_or_else(|_| "127.0.0.1:61613".to_string())
+    broker::log_full_bodies(std::env::var("LOG_FULL_FRAME_BODIES").is_ok());
+
+    let broker_kind = std::env::var("BROKER_KIND").unwrap_or("stomp".to_string());
+    let default_broker_uri = match broker_kind.as_str() {
+        "nats" => "127.0.0.1:4222",
+        _ => "127.0.0.1:61613",
+    };
+
+    let broker_address = std::env::var("BROKER_URI").unwrap_or_else(|_| default_broker_uri.to_string());
+    let broker_uri = broker_address
         .to_socket_addrs()
         .unwrap()
         .next();
 
+    // The certificate is checked against the host name given in BROKER_URI unless overridden.
+    let broker_tls_domain = match std::env::var("BROKER_TLS") {
+        Ok(_) => Some(std::env::var("BROKER_TLS_DOMAIN").unwrap_or_else(|_| {
+            broker_address.rsplitn(2, ':').last().unwrap().to_string()
+        })),
+        Err(_) => None,
+    };
+
     let username = std::env::var("BROKER_USERNAME").unwrap_or("guest".to_string());
     let password = std::env::var("BROKER_PASSWORD").unwrap_or("guest".to_string());
 
@@ -47,6 +68,56 @@ fn main() {
     let grinbox_port = std::env::var("GRINBOX_PORT").unwrap_or("13420".to_string());
     let grinbox_port = u16::from_str_radix(&grinbox_port, 10).expect("invalid GRINBOX_PORT given!");
     let grinbox_protocol_unsecure = std::env::var("GRINBOX_PROTOCOL_UNSECURE").map(|_| true).unwrap_or(false);
+    let max_challenges_per_minute = std::env::var("MAX_CHALLENGES_PER_MIN").unwrap_or("10".to_string());
+    let max_challenges_per_minute = u32::from_str_radix(&max_challenges_per_minute, 10).expect("invalid MAX_CHALLENGES_PER_MIN given!");
+    let max_message_bytes = std::env::var("MAX_MESSAGE_BYTES")
+        .ok()
+        .map(|bytes| usize::from_str_radix(&bytes, 10).expect("invalid MAX_MESSAGE_BYTES given!"));
+    let reject_self_sends = std::env::var("REJECT_SELF_SENDS").is_ok();
+    let publish_receipts = std::env::var("PUBLISH_RECEIPTS").is_ok();
+    let max_slate_bytes = std::env::var("MAX_SLATE_BYTES")
+        .unwrap_or("262144".to_string());
+    let max_slate_bytes = usize::from_str_radix(&max_slate_bytes, 10).expect("invalid MAX_SLATE_BYTES given!");
+    // Posts sign the slate followed by the challenge, which gets a kilobyte of room by default.
+    let max_signed_bytes = std::env::var("MAX_SIGNED_BYTES")
+        .ok()
+        .map(|bytes| usize::from_str_radix(&bytes, 10).expect("invalid MAX_SIGNED_BYTES given!"))
+        .unwrap_or(max_slate_bytes + 1024);
+    let max_slate_age = std::env::var("MAX_SLATE_AGE_SECS")
+        .ok()
+        .map(|secs| u64::from_str_radix(&secs, 10).expect("invalid MAX_SLATE_AGE_SECS given!"))
+        .map(std::time::Duration::from_secs);
+    let max_subscriptions = std::env::var("MAX_SUBSCRIPTIONS")
+        .unwrap_or("1".to_string());
+    let max_subscriptions = usize::from_str_radix(&max_subscriptions, 10).expect("invalid MAX_SUBSCRIPTIONS given!");
+    let challenge_ttl = std::env::var("CHALLENGE_TTL_SECS")
+        .unwrap_or("60".to_string());
+    let challenge_ttl = u64::from_str_radix(&challenge_ttl, 10).expect("invalid CHALLENGE_TTL_SECS given!");
+    let max_connection_backlog_bytes = std::env::var("MAX_CONNECTION_BACKLOG_BYTES")
+        .ok()
+        .map(|bytes| usize::from_str_radix(&bytes, 10).expect("invalid MAX_CONNECTION_BACKLOG_BYTES given!"));
+    let idle_timeout = std::env::var("IDLE_TIMEOUT_SECS")
+        .ok()
+        .map(|secs| u64::from_str_radix(&secs, 10).expect("invalid IDLE_TIMEOUT_SECS given!"))
+        .map(std::time::Duration::from_secs);
+    let reaper_interval = std::env::var("REAPER_INTERVAL_SECS")
+        .unwrap_or("60".to_string());
+    let reaper_interval = u64::from_str_radix(&reaper_interval, 10).expect("invalid REAPER_INTERVAL_SECS given!");
+    let top_destinations_interval = std::env::var("LOG_TOP_DESTINATIONS_SECS")
+        .ok()
+        .map(|secs| u64::from_str_radix(&secs, 10).expect("invalid LOG_TOP_DESTINATIONS_SECS given!"));
+    let broker_diagnostics_interval = std::env::var("LOG_BROKER_DIAGNOSTICS_SECS")
+        .ok()
+        .map(|secs| u64::from_str_radix(&secs, 10).expect("invalid LOG_BROKER_DIAGNOSTICS_SECS given!"));
+    let federation_dns_timeout = std::env::var("FEDERATION_DNS_TIMEOUT_MS")
+        .ok()
+        .map(|millis| u64::from_str_radix(&millis, 10).expect("invalid FEDERATION_DNS_TIMEOUT_MS given!"))
+        .map(std::time::Duration::from_millis);
+    let federation_peer_token = std::env::var("FEDERATION_PEER_TOKEN").ok();
+    let metrics_token = std::env::var("METRICS_TOKEN").ok();
+    let tor_socks_proxy = std::env::var("TOR_SOCKS_PROXY")
+        .ok()
+        .map(|address| address.parse::<std::net::SocketAddr>().expect("invalid TOR_SOCKS_PROXY given!"));
 
     if broker_uri.is_none() {
         error!("could not resolve broker uri!");
@@ -55,19 +126,170 @@ fn main() {
 
     let broker_uri = broker_uri.unwrap();
 
-    let bind_address =
-        std::env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:13420".to_string());
+    let bind_addresses: Vec<String> = std::env::var("BIND_ADDRESS")
+        .unwrap_or_else(|_| "0.0.0.0:13420".to_string())
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect();
+
+    info!("Broker: {} at {}", broker_kind, broker_uri);
+    info!("Bind addresses: {}", bind_addresses.join(", "));
+
+    let subscription_registry_path = std::env::var("SUBSCRIPTION_REGISTRY_PATH")
+        .ok()
+        .map(std::path::PathBuf::from);
 
-    info!("Broker URI: {}", broker_uri);
-    info!("Bind address: {}", bind_address);
+    let queue_expiration = std::env::var("BROKER_QUEUE_EXPIRATION_SECS")
+        .unwrap_or("86400".to_string());
+    let queue_expiration = u64::from_str_radix(&queue_expiration, 10).expect("invalid BROKER_QUEUE_EXPIRATION_SECS given!");
 
-    let mut broker = Broker::new(broker_uri, username, password);
-    let sender = broker.start().expect("failed initiating broker session");
+    let min_reconnect_interval = std::env::var("BROKER_MIN_RECONNECT_INTERVAL_MS")
+        .unwrap_or("0".to_string());
+    let min_reconnect_interval = u64::from_str_radix(&min_reconnect_interval, 10).expect("invalid BROKER_MIN_RECONNECT_INTERVAL_MS given!");
+    let reconnect_max_secs = std::env::var("BROKER_RECONNECT_MAX_SECS")
+        .unwrap_or("30".to_string());
+    let reconnect_max_secs = u64::from_str_radix(&reconnect_max_secs, 10).expect("invalid BROKER_RECONNECT_MAX_SECS given!");
+
+    let mut broker: Box<dyn MessageBroker> = match broker_kind.as_str() {
+        "stomp" => Box::new(
+            Broker::new(broker_uri, username, password, subscription_registry_path)
+                .with_stomp_versions(
+                    std::env::var("BROKER_STOMP_VERSIONS").ok(),
+                    std::env::var("BROKER_MIN_STOMP_VERSION").ok(),
+                )
+                .with_payload_compression(std::env::var("COMPRESS_BROKER_PAYLOADS").is_ok())
+                .with_queue_expiration(std::time::Duration::from_secs(queue_expiration))
+                .with_delivery_acks(std::env::var("BROKER_DELIVERY_ACKS").is_ok())
+                .with_expiry_notifications(std::env::var("NOTIFY_EXPIRED_DELIVERIES").is_ok())
+                .with_reconnect(std::env::var("BROKER_NO_RECONNECT").is_err())
+                .with_min_reconnect_interval(std::time::Duration::from_millis(min_reconnect_interval))
+                .with_max_reconnect_delay(std::time::Duration::from_secs(reconnect_max_secs))
+                .with_tls(broker_tls_domain)
+        ),
+        "nats" => Box::new(NatsBroker::new(broker_uri.to_string(), username, password)),
+        _ => panic!("invalid BROKER_KIND given!"),
+    };
+    let (status_sender, status_receiver) = std::sync::mpsc::channel();
+    let sender = broker.start(status_sender).expect("failed initiating broker session");
+    if std::env::var("BROKER_SELF_TEST").is_ok() {
+        match broker::self_test(&sender, std::time::Duration::from_secs(10)) {
+            Ok(latency) => info!("broker self test passed in {:?}", latency),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let diagnostics = broker.diagnostics();
+    std::thread::spawn(move || {
+        // The broker reconnects by itself unless told not to, so this only fires if its thread
+        // gives up or reconnecting is disabled.
+        match status_receiver.recv() {
+            Ok(status) => error!("broker stopped: {:?}", status),
+            Err(_) => error!("broker thread terminated unexpectedly!"),
+        }
+        error!("broker diagnostics: {:?}", diagnostics.snapshot(std::time::Instant::now()));
+        std::process::exit(1);
+    });
+    if let Some(broker_diagnostics_interval) = broker_diagnostics_interval {
+        let diagnostics = broker.diagnostics();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(broker_diagnostics_interval));
+            info!("broker diagnostics: {:?}", diagnostics.snapshot(std::time::Instant::now()));
+        });
+    }
+    if let Some(top_destinations_interval) = top_destinations_interval {
+        let publish_counters = broker.publish_counters();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(top_destinations_interval));
+            for (destination, count) in publish_counters.top(10) {
+                info!("destination {}: {} slates published", destination, count);
+            }
+        });
+    }
     let response_handlers_sender = AsyncServer::init();
+    let id_generator: std::sync::Arc<dyn IdGenerator> = std::sync::Arc::new(DefaultIdGenerator);
+    let authenticator: std::sync::Arc<dyn Authenticator> = std::sync::Arc::new(DefaultAuthenticator);
+    let mut federator: std::sync::Arc<dyn Federator> = std::sync::Arc::new(WebsocketFederator::new().with_peer_token(federation_peer_token.clone()));
+    if let Some(federation_dns_timeout) = federation_dns_timeout {
+        federator = std::sync::Arc::new(DnsCheckingFederator::new(
+            federator,
+            federation_dns_timeout,
+            std::time::Duration::from_secs(300),
+        ));
+    }
+    if let Some(tor_socks_proxy) = tor_socks_proxy {
+        info!("Federating to .onion relays through {}", tor_socks_proxy);
+        federator = std::sync::Arc::new(OnionFederator::new(federator, tor_socks_proxy).with_peer_token(federation_peer_token.clone()));
+    }
+    if let Some(idle_timeout) = idle_timeout {
+        info!("Closing connections idle for over {}s, checking every {}s", idle_timeout.as_secs(), reaper_interval);
+    }
+    let idle_reaper = std::sync::Arc::new(IdleReaper::new());
+    IdleReaper::spawn(
+        idle_reaper.clone(),
+        std::time::Duration::from_secs(reaper_interval),
+        idle_timeout,
+        std::time::Duration::from_secs(challenge_ttl),
+    );
+    let idle_reaper = Some(idle_reaper);
+    let config = ServerConfig {
+        grinbox_domain,
+        grinbox_port,
+        grinbox_protocol_unsecure,
+        max_challenges_per_minute,
+        max_message_bytes,
+        max_signed_bytes: Some(max_signed_bytes),
+        max_slate_bytes,
+        max_slate_age,
+        reject_self_sends,
+        max_subscriptions,
+        challenge_ttl: std::time::Duration::from_secs(challenge_ttl),
+        publish_receipts,
+        max_connection_backlog_bytes,
+        federation_peer_token,
+        metrics_token,
+    };
 
-    ws::Builder::new()
-        .build(|out| AsyncServer::new(out, sender.clone(), response_handlers_sender.clone(), &grinbox_domain, grinbox_port, grinbox_protocol_unsecure))
-        .unwrap()
-        .listen(&bind_address[..])
-        .unwrap();
+    if let Some(max_connection_backlog_bytes) = max_connection_backlog_bytes {
+        info!("Max connection backlog: {} bytes", max_connection_backlog_bytes);
+    }
+
+    let relay_metrics = std::sync::Arc::new(RelayMetrics::new().with_publish_counters(broker.publish_counters()));
+
+    let federation_workers = std::env::var("FEDERATION_WORKERS")
+        .unwrap_or("16".to_string());
+    let federation_workers = usize::from_str_radix(&federation_workers, 10).expect("invalid FEDERATION_WORKERS given!");
+    let federation_queue = std::env::var("FEDERATION_QUEUE")
+        .unwrap_or("256".to_string());
+    let federation_queue = usize::from_str_radix(&federation_queue, 10).expect("invalid FEDERATION_QUEUE given!");
+    info!("Federating through {} workers, queueing up to {} slates", federation_workers, federation_queue);
+    let federation_pool = std::sync::Arc::new(FederationPool::new(federation_workers, federation_queue, relay_metrics.clone()));
+
+    let connection_limiter = std::env::var("MAX_CONNS_PER_IP_PER_MIN")
+        .ok()
+        .map(|limit| u32::from_str_radix(&limit, 10).expect("invalid MAX_CONNS_PER_IP_PER_MIN given!"))
+        .map(|limit| {
+            info!("Max connections per IP: {} per minute", limit);
+            std::sync::Arc::new(ConnectionLimiter::new(limit, std::time::Duration::from_secs(60)))
+        });
+
+    let settings = listener_settings(&config);
+    let factory = move |out| AsyncServer::new(out, sender.clone(), response_handlers_sender.clone(), config.clone(), id_generator.clone(), authenticator.clone(), federator.clone(), federation_pool.clone(), idle_reaper.clone(), connection_limiter.clone(), relay_metrics.clone());
+    let listeners: Vec<_> = bind_listeners(&bind_addresses, settings, factory)
+        .expect("could not bind BIND_ADDRESS!")
+        .into_iter()
+        .map(|listener| {
+            std::thread::spawn(move || {
+                listener.run().unwrap();
+            })
+        })
+        .collect();
+
+    for listener in listeners {
+        if listener.join().is_err() {
+            error!("listener thread terminated unexpectedly!");
+        }
+    }
 }
@@ -0,0 +1,203 @@
+use std::fmt::Write;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::broker::PublishCounters;
+
+/// How many of the busiest destinations are listed on `/metrics`.
+const TOP_DESTINATIONS: usize = 10;
+
+/// Relay activity served on `/metrics`, shared by every connection of the process.
+pub struct RelayMetrics {
+    active_connections: AtomicU64,
+    messages_posted: AtomicU64,
+    federation_failures: AtomicU64,
+    federation_rejections: AtomicU64,
+    federations: AtomicU64,
+    federation_rtt_ms: AtomicU64,
+    federated_bytes: AtomicU64,
+    federation_workers: AtomicU64,
+    federation_queue_depth: AtomicU64,
+    subscriptions: AtomicU64,
+    messages_dropped_parse: AtomicU64,
+    requests_invalid: AtomicU64,
+    deliveries_failed: AtomicU64,
+    publish_counters: Option<Arc<PublishCounters>>,
+}
+
+impl RelayMetrics {
+    pub fn new() -> RelayMetrics {
+        RelayMetrics {
+            active_connections: AtomicU64::new(0),
+            messages_posted: AtomicU64::new(0),
+            federation_failures: AtomicU64::new(0),
+            federation_rejections: AtomicU64::new(0),
+            federations: AtomicU64::new(0),
+            federation_rtt_ms: AtomicU64::new(0),
+            federated_bytes: AtomicU64::new(0),
+            federation_workers: AtomicU64::new(0),
+            federation_queue_depth: AtomicU64::new(0),
+            subscriptions: AtomicU64::new(0),
+            messages_dropped_parse: AtomicU64::new(0),
+            requests_invalid: AtomicU64::new(0),
+            deliveries_failed: AtomicU64::new(0),
+            publish_counters: None,
+        }
+    }
+
+    /// Lists the destinations `publish_counters` saw the most slates for, as hashes.
+    pub fn with_publish_counters(mut self, publish_counters: Arc<PublishCounters>) -> RelayMetrics {
+        self.publish_counters = Some(publish_counters);
+        self
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A slate was queued in the broker or accepted by the recipient's relay.
+    pub fn message_posted(&self) {
+        self.messages_posted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The recipient's relay could not be reached or did not answer in time.
+    pub fn federation_failed(&self) {
+        self.federation_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The slate was refused, by the recipient's relay or by this one before forwarding it.
+    pub fn federation_rejected(&self) {
+        self.federation_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every forwarded slate, whatever became of it, so the average round trip can be derived.
+    pub fn federation_finished(&self, rtt: Duration) {
+        let millis = rtt.as_secs() * 1000 + u64::from(rtt.subsec_millis());
+        self.federations.fetch_add(1, Ordering::Relaxed);
+        self.federation_rtt_ms.fetch_add(millis, Ordering::Relaxed);
+    }
+
+    /// A slate of `bytes` was accepted by the recipient's relay.
+    pub fn message_federated(&self, bytes: usize) {
+        self.federated_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn federation_pool_started(&self, workers: usize) {
+        self.federation_workers.fetch_add(workers as u64, Ordering::Relaxed);
+    }
+
+    /// A slate is waiting for a federation worker.
+    pub fn federation_queued(&self) {
+        self.federation_queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn federation_dequeued(&self) {
+        self.federation_queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn subscribed(&self) {
+        self.subscriptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unsubscribed(&self, count: u64) {
+        self.subscriptions.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// A broker message was dropped because its payload could not be parsed.
+    pub fn message_dropped_parse(&self) {
+        self.messages_dropped_parse.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A client sent something that is not a valid request.
+    pub fn request_invalid(&self) {
+        self.requests_invalid.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A slate could not be written to the subscribed client.
+    pub fn delivery_failed(&self) {
+        self.deliveries_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The relay counters in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let metrics: [(&str, &str, u64); 13] = [
+            ("grinbox_active_connections", "gauge", self.active_connections.load(Ordering::Relaxed)),
+            ("grinbox_subscriptions", "gauge", self.subscriptions.load(Ordering::Relaxed)),
+            ("grinbox_messages_posted_total", "counter", self.messages_posted.load(Ordering::Relaxed)),
+            ("grinbox_federation_failures_total", "counter", self.federation_failures.load(Ordering::Relaxed)),
+            ("grinbox_federation_rejections_total", "counter", self.federation_rejections.load(Ordering::Relaxed)),
+            ("grinbox_federations_total", "counter", self.federations.load(Ordering::Relaxed)),
+            ("grinbox_federation_rtt_ms_total", "counter", self.federation_rtt_ms.load(Ordering::Relaxed)),
+            ("grinbox_federated_bytes_total", "counter", self.federated_bytes.load(Ordering::Relaxed)),
+            ("grinbox_federation_workers", "gauge", self.federation_workers.load(Ordering::Relaxed)),
+            ("grinbox_federation_queue_depth", "gauge", self.federation_queue_depth.load(Ordering::Relaxed)),
+            ("grinbox_messages_dropped_parse_total", "counter", self.messages_dropped_parse.load(Ordering::Relaxed)),
+            ("grinbox_requests_invalid_total", "counter", self.requests_invalid.load(Ordering::Relaxed)),
+            ("grinbox_deliveries_failed_total", "counter", self.deliveries_failed.load(Ordering::Relaxed)),
+        ];
+
+        let mut body = String::new();
+        for (name, kind, value) in metrics.iter() {
+            writeln!(body, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(body, "{} {}", name, value).unwrap();
+        }
+
+        // A destination entering the tracked set inherits the count of the one it pushed out,
+        // so these are reported as gauges rather than counters.
+        if let Some(ref publish_counters) = self.publish_counters {
+            writeln!(body, "# TYPE grinbox_top_destination_publishes gauge").unwrap();
+            for (destination, count) in publish_counters.top(TOP_DESTINATIONS) {
+                writeln!(body, "grinbox_top_destination_publishes{{destination=\"{}\"}} {}", destination, count).unwrap();
+            }
+        }
+        body
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = RelayMetrics::new();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+        metrics.subscribed();
+        metrics.message_posted();
+        metrics.message_federated(120);
+        metrics.federation_finished(Duration::from_millis(250));
+        metrics.federation_finished(Duration::from_millis(150));
+
+        let body = metrics.render();
+        assert!(body.contains("# TYPE grinbox_active_connections gauge\ngrinbox_active_connections 1\n"));
+        assert!(body.contains("grinbox_subscriptions 1\n"));
+        assert!(body.contains("grinbox_messages_posted_total 1\n"));
+        assert!(body.contains("grinbox_federation_failures_total 0\n"));
+        assert!(body.contains("grinbox_federated_bytes_total 120\n"));
+        assert!(body.contains("grinbox_federations_total 2\n"));
+        assert!(body.contains("grinbox_federation_rtt_ms_total 400\n"));
+    }
+
+    #[test]
+    fn renders_top_destinations() {
+        let publish_counters = Arc::new(PublishCounters::new(4));
+        for subject in &["alice", "bob", "alice"] {
+            publish_counters.record(subject);
+        }
+        let busiest = publish_counters.top(1)[0].0.clone();
+
+        let body = RelayMetrics::new().with_publish_counters(publish_counters).render();
+        assert!(body.contains("# TYPE grinbox_top_destination_publishes gauge\n"));
+        assert!(body.contains(&format!("grinbox_top_destination_publishes{{destination=\"{}\"}} 2\n", busiest)));
+        assert!(!body.contains("alice"));
+
+        assert!(!RelayMetrics::new().render().contains("grinbox_top_destination_publishes"));
+    }
+}
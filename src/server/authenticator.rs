@@ -0,0 +1,57 @@
+/// Decides whether a new connection may subscribe and post slates, on top of proving ownership
+/// of the address through the signed challenge. Connections that are rejected get an
+/// `Unauthorized` error for both; federation peers presenting the peer token are let through.
+pub trait Authenticator: Send + Sync {
+    /// Inspects the headers of the websocket handshake, e.g. for a bearer token or API key.
+    fn authenticate(&self, headers: &[(String, Vec<u8>)]) -> bool;
+}
+
+/// Accepts every connection, leaving the signature challenge as the only check.
+pub struct DefaultAuthenticator;
+
+impl Authenticator for DefaultAuthenticator {
+    fn authenticate(&self, _headers: &[(String, Vec<u8>)]) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TokenAuthenticator {
+        token: String,
+    }
+
+    impl Authenticator for TokenAuthenticator {
+        fn authenticate(&self, headers: &[(String, Vec<u8>)]) -> bool {
+            let expected = format!("Bearer {}", self.token);
+            headers
+                .iter()
+                .any(|(name, value)| name.eq_ignore_ascii_case("authorization") && value[..] == *expected.as_bytes())
+        }
+    }
+
+    fn headers(authorization: Option<&str>) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec![("Host".to_string(), b"127.0.0.1:13420".to_vec())];
+        if let Some(authorization) = authorization {
+            headers.push(("Authorization".to_string(), authorization.as_bytes().to_vec()));
+        }
+        headers
+    }
+
+    #[test]
+    fn default_authenticator_accepts_everyone() {
+        assert!(DefaultAuthenticator.authenticate(&headers(None)));
+    }
+
+    #[test]
+    fn token_authenticator_requires_valid_token() {
+        let authenticator = TokenAuthenticator {
+            token: "secret".to_string(),
+        };
+        assert!(!authenticator.authenticate(&headers(None)));
+        assert!(!authenticator.authenticate(&headers(Some("Bearer wrong"))));
+        assert!(authenticator.authenticate(&headers(Some("Bearer secret"))));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+
+/// Bytes written to a client that it has not yet been seen to read. Every message is followed by
+/// a ping carrying a sequence number; the client can only answer it after reading everything
+/// written before it, so its pong acknowledges those bytes.
+pub struct Backlog {
+    max_bytes: u64,
+    written: u64,
+    acknowledged: u64,
+    next_ping: u64,
+    pings: VecDeque<(u64, u64)>,
+    exceeded: bool,
+}
+
+impl Backlog {
+    pub fn new(max_bytes: usize) -> Backlog {
+        Backlog {
+            max_bytes: max_bytes as u64,
+            written: 0,
+            acknowledged: 0,
+            next_ping: 0,
+            pings: VecDeque::new(),
+            exceeded: false,
+        }
+    }
+
+    /// Records `bytes` about to be written, returning the payload of the ping to follow them,
+    /// or `None` when they would take the backlog past the cap. Once the cap is hit nothing
+    /// more is accepted, as the connection is being closed.
+    pub fn write(&mut self, bytes: usize) -> Option<Vec<u8>> {
+        if self.exceeded || self.pending() + bytes as u64 > self.max_bytes {
+            self.exceeded = true;
+            return None;
+        }
+
+        self.written += bytes as u64;
+        let ping = self.next_ping;
+        self.next_ping += 1;
+        self.pings.push_back((ping, self.written));
+        Some(ping.to_be_bytes().to_vec())
+    }
+
+    /// Acknowledges the bytes written before the ping `payload` answers. Pongs the relay did
+    /// not ask for are ignored.
+    pub fn pong(&mut self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let mut sequence = [0u8; 8];
+        sequence.copy_from_slice(payload);
+        let sequence = u64::from_be_bytes(sequence);
+
+        while let Some(&(ping, written)) = self.pings.front() {
+            if ping > sequence {
+                break;
+            }
+            self.acknowledged = written;
+            self.pings.pop_front();
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.written - self.acknowledged
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pongs_release_bytes_written_before_their_ping() {
+        let mut backlog = Backlog::new(100);
+        let first = backlog.write(40).unwrap();
+        let second = backlog.write(40).unwrap();
+        assert_eq!(backlog.pending(), 80);
+
+        backlog.pong(&first);
+        assert_eq!(backlog.pending(), 40);
+        backlog.pong(&[1, 2, 3]);
+        assert_eq!(backlog.pending(), 40);
+        backlog.pong(&second);
+        assert_eq!(backlog.pending(), 0);
+    }
+
+    #[test]
+    fn stops_accepting_writes_past_the_cap() {
+        let mut backlog = Backlog::new(100);
+        let ping = backlog.write(60).unwrap();
+        assert!(backlog.write(60).is_none());
+
+        // The connection is closing, acknowledged bytes do not reopen it.
+        backlog.pong(&ping);
+        assert_eq!(backlog.pending(), 0);
+        assert!(backlog.write(10).is_none());
+    }
+}
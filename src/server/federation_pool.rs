@@ -0,0 +1,108 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use grinboxlib::types::GrinboxError;
+
+use crate::metrics::RelayMetrics;
+
+/// Work handed to the pool. `Box<FnOnce()>` cannot be called on the compilers the relay is built
+/// with, so jobs are run through this instead.
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<F>) {
+        (*self)()
+    }
+}
+
+/// A fixed number of threads forwarding slates to other relays, fed from a queue of at most
+/// `queue_capacity` slates, so federation uses a bounded number of threads and connections
+/// however many slates come in.
+pub struct FederationPool {
+    sender: Mutex<SyncSender<Box<dyn Job>>>,
+    metrics: Arc<RelayMetrics>,
+}
+
+impl FederationPool {
+    pub fn new(workers: usize, queue_capacity: usize, metrics: Arc<RelayMetrics>) -> FederationPool {
+        let (sender, receiver) = sync_channel::<Box<dyn Job>>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let metrics = metrics.clone();
+            std::thread::spawn(move || work(&receiver, &metrics));
+        }
+        metrics.federation_pool_started(workers);
+
+        FederationPool {
+            sender: Mutex::new(sender),
+            metrics,
+        }
+    }
+
+    /// Queues `job` for the next free worker, or fails with `FederationBusy` when the queue is
+    /// full.
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, job: F) -> Result<(), GrinboxError> {
+        // Counted up front, as a worker may take the job before `try_send` returns.
+        self.metrics.federation_queued();
+        match self.sender.lock().unwrap().try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.metrics.federation_dequeued();
+                Err(GrinboxError::FederationBusy)
+            }
+        }
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Box<dyn Job>>>, metrics: &RelayMetrics) {
+    loop {
+        // The lock is only held while waiting, so other workers run their jobs meanwhile.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+        metrics.federation_dequeued();
+        job.run();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn posts_queue_then_are_refused_when_pool_is_saturated() {
+        let metrics = Arc::new(RelayMetrics::new());
+        let pool = FederationPool::new(1, 1, metrics.clone());
+        let (started, started_receiver) = channel();
+        let (release, released) = channel::<()>();
+        let (finished, finished_receiver) = channel();
+
+        // Keeps the only worker busy until released.
+        let blocking_started = started.clone();
+        pool.submit(move || {
+            blocking_started.send(()).unwrap();
+            released.recv().unwrap();
+        })
+        .unwrap();
+        started_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let queued_finished = finished.clone();
+        pool.submit(move || queued_finished.send("queued").unwrap()).unwrap();
+        assert!(metrics.render().contains("grinbox_federation_queue_depth 1\n"));
+        assert!(metrics.render().contains("grinbox_federation_workers 1\n"));
+
+        assert_eq!(pool.submit(move || finished.send("refused").unwrap()), Err(GrinboxError::FederationBusy));
+        assert!(metrics.render().contains("grinbox_federation_queue_depth 1\n"));
+
+        release.send(()).unwrap();
+        assert_eq!(finished_receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "queued");
+        assert!(finished_receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(metrics.render().contains("grinbox_federation_queue_depth 0\n"));
+    }
+}
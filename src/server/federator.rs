@@ -0,0 +1,540 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use ws::util::{Token, Url};
+use ws::{connect, CloseCode, Handler, Message, Request, Result as WsResult, Sender};
+
+use grinboxlib::error::{ErrorKind, Result};
+use grinboxlib::types::{GrinboxError, GrinboxRequest, GrinboxResponse};
+
+use super::socks::socks5_connect;
+use super::websocket_stream::WebsocketStream;
+
+/// Delivers requests to other relays on behalf of this one.
+pub trait Federator: Send + Sync {
+    /// Sends `request` to the relay at `url` once it has handed out a challenge, and returns the
+    /// relay's answer to it.
+    fn post(&self, url: &str, request: GrinboxRequest) -> Result<GrinboxResponse>;
+}
+
+/// What became of a slate forwarded to another relay.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FederationOutcome {
+    /// The remote relay queued the slate.
+    Accepted { effective_expiration: Option<u32> },
+    /// The slate was refused, by the remote relay or by this one before connecting to it.
+    Rejected { kind: GrinboxError, description: String },
+    /// The remote relay did not answer in time.
+    TimedOut,
+    /// Reaching or talking to the remote relay failed.
+    Failed,
+}
+
+/// The outcome of forwarding a slate along with how long the remote relay took to answer,
+/// which is zero for slates refused before connecting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FederationResult {
+    pub outcome: FederationOutcome,
+    pub rtt: Duration,
+}
+
+impl FederationResult {
+    pub fn new(outcome: FederationOutcome, rtt: Duration) -> FederationResult {
+        FederationResult { outcome, rtt }
+    }
+
+    /// Refused by this relay, without contacting the remote one.
+    pub fn refused(kind: GrinboxError, description: &str) -> FederationResult {
+        FederationResult::new(
+            FederationOutcome::Rejected {
+                kind,
+                description: description.to_string(),
+            },
+            Duration::from_secs(0),
+        )
+    }
+
+    /// What the originating client is told.
+    pub fn response(&self) -> GrinboxResponse {
+        let error = |kind: GrinboxError, description: String| GrinboxResponse::Error {
+            code: kind.code().to_string(),
+            kind,
+            description,
+            limit: None,
+        };
+
+        match self.outcome {
+            FederationOutcome::Accepted { effective_expiration } => GrinboxResponse::Ok {
+                effective_expiration,
+                subscription_id: None,
+            },
+            FederationOutcome::Rejected { ref kind, ref description } => error(kind.clone(), description.clone()),
+            FederationOutcome::TimedOut => error(GrinboxError::FederationTimeout, GrinboxError::FederationTimeout.to_string()),
+            FederationOutcome::Failed => error(GrinboxError::UnknownError, GrinboxError::UnknownError.to_string()),
+        }
+    }
+}
+
+/// Longest a remote relay gets to accept the connection and answer the forwarded request.
+const FEDERATION_TIMEOUT_MS: u64 = 10000;
+const TIMEOUT: Token = Token(1);
+
+/// Handshake header through which a relay proves it is a federation peer of the one it connects to.
+pub const FEDERATION_PEER_TOKEN_HEADER: &str = "X-Grinbox-Federation-Token";
+
+struct FederationHandler {
+    out: Sender,
+    peer_token: Option<String>,
+    request: String,
+    response: Arc<Mutex<Option<GrinboxResponse>>>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Handler for FederationHandler {
+    fn build_request(&mut self, url: &Url) -> WsResult<Request> {
+        let mut request = Request::from_url(url)?;
+        if let Some(ref peer_token) = self.peer_token {
+            request
+                .headers_mut()
+                .push((FEDERATION_PEER_TOKEN_HEADER.to_string(), peer_token.as_bytes().to_vec()));
+        }
+        Ok(request)
+    }
+
+    fn on_message(&mut self, msg: Message) -> WsResult<()> {
+        let response = match serde_json::from_str::<GrinboxResponse>(&msg.to_string()) {
+            Ok(response) => response,
+            Err(e) => {
+                error!("could not parse federation response: {}", e);
+                return self.out.close(CloseCode::Protocol);
+            }
+        };
+
+        let close_code = match response {
+            GrinboxResponse::Challenge { .. } => return self.out.send(self.request.clone()),
+            GrinboxResponse::Error { .. } => CloseCode::Abnormal,
+            GrinboxResponse::Ok { .. } => CloseCode::Normal,
+            _ => return Ok(()),
+        };
+
+        *self.response.lock().unwrap() = Some(response);
+        self.out.close(close_code)
+    }
+
+    fn on_timeout(&mut self, _event: Token) -> WsResult<()> {
+        self.timed_out.store(true, Ordering::SeqCst);
+        self.out.close(CloseCode::Away)
+    }
+}
+
+/// Opens a websocket connection to the remote relay for every request, presenting `peer_token`
+/// when there is one so the remote relay accepts slates signed over this relay's challenges.
+#[derive(Default)]
+pub struct WebsocketFederator {
+    peer_token: Option<String>,
+}
+
+impl WebsocketFederator {
+    pub fn new() -> WebsocketFederator {
+        WebsocketFederator::default()
+    }
+
+    pub fn with_peer_token(mut self, peer_token: Option<String>) -> WebsocketFederator {
+        self.peer_token = peer_token;
+        self
+    }
+}
+
+impl Federator for WebsocketFederator {
+    fn post(&self, url: &str, request: GrinboxRequest) -> Result<GrinboxResponse> {
+        let request = serde_json::to_string(&request)?;
+        let response = Arc::new(Mutex::new(None));
+        let timed_out = Arc::new(AtomicBool::new(false));
+
+        connect(url, |out: Sender| {
+            // Registered right away, so the timeout also covers connecting and the handshake
+            if out.timeout(FEDERATION_TIMEOUT_MS, TIMEOUT).is_err() {
+                error!("could not schedule federation timeout!");
+            }
+            FederationHandler {
+                out,
+                peer_token: self.peer_token.clone(),
+                request: request.clone(),
+                response: response.clone(),
+                timed_out: timed_out.clone(),
+            }
+        })
+        .map_err(|e| ErrorKind::GenericError(format!("could not connect to {}: {}", url, e)))?;
+
+        let response = response.lock().unwrap().take();
+        if response.is_none() && timed_out.load(Ordering::SeqCst) {
+            return Err(ErrorKind::GrinboxProtocolError(GrinboxError::FederationTimeout).into());
+        }
+        response.ok_or_else(|| ErrorKind::GenericError(format!("{} closed without responding", url)).into())
+    }
+}
+
+/// Resolves the remote relay's host before handing the request to `inner`, so slates addressed
+/// to a mistyped domain are refused with `FederationDnsError` instead of failing somewhere in
+/// the websocket connect. Lookups are cached for `cache_ttl`, and one that does not finish
+/// within `timeout` counts as unresolvable. A lookup that timed out is left to finish and
+/// caches its answer then; no other lookup of that host is started in the meantime, so a
+/// resolver that hangs costs one thread per host rather than one per slate.
+pub struct DnsCheckingFederator {
+    inner: Arc<dyn Federator>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    lookup: fn(&str) -> bool,
+    resolved: Arc<Mutex<HashMap<String, (bool, Instant)>>>,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl DnsCheckingFederator {
+    pub fn new(inner: Arc<dyn Federator>, timeout: Duration, cache_ttl: Duration) -> DnsCheckingFederator {
+        DnsCheckingFederator {
+            inner,
+            timeout,
+            cache_ttl,
+            lookup: lookup_host,
+            resolved: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_lookup(mut self, lookup: fn(&str) -> bool) -> DnsCheckingFederator {
+        self.lookup = lookup;
+        self
+    }
+
+    fn resolves(&self, host: &str) -> bool {
+        let now = Instant::now();
+        if let Some(&(resolves, resolved_at)) = self.resolved.lock().unwrap().get(host) {
+            if now.duration_since(resolved_at) < self.cache_ttl {
+                return resolves;
+            }
+        }
+
+        if !self.in_flight.lock().unwrap().insert(host.to_string()) {
+            debug!("dns lookup of {} still running", host);
+            return false;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let host_name = host.to_string();
+        let lookup = self.lookup;
+        let resolved = self.resolved.clone();
+        let in_flight = self.in_flight.clone();
+        std::thread::spawn(move || {
+            let resolves = lookup(&host_name);
+            resolved
+                .lock()
+                .unwrap()
+                .insert(host_name.clone(), (resolves, Instant::now()));
+            in_flight.lock().unwrap().remove(&host_name);
+            let _ = tx.send(resolves);
+        });
+
+        match rx.recv_timeout(self.timeout) {
+            Ok(resolves) => resolves,
+            Err(_) => {
+                warn!("dns lookup of {} timed out", host);
+                false
+            }
+        }
+    }
+}
+
+fn lookup_host(host: &str) -> bool {
+    host.to_socket_addrs()
+        .map(|mut addresses| addresses.next().is_some())
+        .unwrap_or(false)
+}
+
+impl Federator for DnsCheckingFederator {
+    fn post(&self, url: &str, request: GrinboxRequest) -> Result<GrinboxResponse> {
+        let host = url.splitn(2, "://").last().unwrap_or(url);
+        if !self.resolves(host) {
+            return Err(ErrorKind::GrinboxProtocolError(GrinboxError::FederationDnsError).into());
+        }
+        self.inner.post(url, request)
+    }
+}
+
+/// Sends requests for `.onion` relays through the Tor SOCKS5 proxy at `proxy`, and every other
+/// request to `clearnet` untouched. The websocket is opened over the stream the proxy connected,
+/// which Tor already encrypts and authenticates, so it is plain `ws://` whatever the url says.
+pub struct OnionFederator {
+    clearnet: Arc<dyn Federator>,
+    proxy: SocketAddr,
+    peer_token: Option<String>,
+}
+
+impl OnionFederator {
+    pub fn new(clearnet: Arc<dyn Federator>, proxy: SocketAddr) -> OnionFederator {
+        OnionFederator {
+            clearnet,
+            proxy,
+            peer_token: None,
+        }
+    }
+
+    pub fn with_peer_token(mut self, peer_token: Option<String>) -> OnionFederator {
+        self.peer_token = peer_token;
+        self
+    }
+}
+
+impl Federator for OnionFederator {
+    fn post(&self, url: &str, request: GrinboxRequest) -> Result<GrinboxResponse> {
+        let (host, port) = match onion_host_and_port(url) {
+            Some(host_and_port) => host_and_port,
+            None => return self.clearnet.post(url, request),
+        };
+
+        let request = serde_json::to_string(&request)?;
+        let timeout = Duration::from_millis(FEDERATION_TIMEOUT_MS);
+        let deadline = Instant::now() + timeout;
+        let stream = socks5_connect(&self.proxy, host, port, timeout).map_err(|e| onion_error(host, e))?;
+        let response = post_over_stream(stream, host, port, self.peer_token.as_ref(), &request, deadline)
+            .map_err(|e| onion_error(host, e))?;
+        Ok(response)
+    }
+}
+
+/// Forwards `request` to the relay at `host`:`port` over `stream`, already connected to it, the
+/// way `FederationHandler` does over the connections `ws` opens. Nothing is waited for past
+/// `deadline`.
+fn post_over_stream(
+    stream: TcpStream,
+    host: &str,
+    port: u16,
+    peer_token: Option<&String>,
+    request: &str,
+    deadline: Instant,
+) -> io::Result<GrinboxResponse> {
+    let mut headers = vec![];
+    if let Some(peer_token) = peer_token {
+        headers.push((FEDERATION_PEER_TOKEN_HEADER, peer_token.as_str()));
+    }
+    set_deadline(&stream, deadline)?;
+    let mut websocket = WebsocketStream::handshake(stream, &format!("{}:{}", host, port), &headers)?;
+
+    loop {
+        set_deadline(websocket.get_ref(), deadline)?;
+        let message = websocket.read_text()?;
+        let response = serde_json::from_str::<GrinboxResponse>(&message).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("could not parse federation response: {}", e))
+        })?;
+
+        match response {
+            GrinboxResponse::Challenge { .. } => websocket.send_text(request)?,
+            GrinboxResponse::Error { .. } | GrinboxResponse::Ok { .. } => {
+                let _ = websocket.close();
+                return Ok(response);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Bounds the reads and writes on `stream` by the time left until `deadline`.
+fn set_deadline(stream: &TcpStream, deadline: Instant) -> io::Result<()> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "federation timed out"));
+    }
+    stream.set_read_timeout(Some(deadline - now))?;
+    stream.set_write_timeout(Some(deadline - now))
+}
+
+fn onion_error(host: &str, e: io::Error) -> ErrorKind {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ErrorKind::GrinboxProtocolError(GrinboxError::FederationTimeout),
+        _ => ErrorKind::GenericError(format!("could not reach {} through tor: {}", host, e)),
+    }
+}
+
+/// The host and port of `url` when it points at an onion service.
+fn onion_host_and_port(url: &str) -> Option<(&str, u16)> {
+    let authority = url.splitn(2, "://").last().unwrap_or(url);
+    let mut parts = authority.rsplitn(2, ':');
+    let port = parts.next()?.parse().ok()?;
+    let host = parts.next()?;
+    if host.ends_with(".onion") {
+        Some((host, port))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+
+    struct UnreachableFederator;
+
+    impl Federator for UnreachableFederator {
+        fn post(&self, _url: &str, _request: GrinboxRequest) -> Result<GrinboxResponse> {
+            panic!("request should not have been federated");
+        }
+    }
+
+    #[test]
+    fn unresolvable_relay_is_refused_before_federating() {
+        let federator = DnsCheckingFederator::new(
+            Arc::new(UnreachableFederator),
+            Duration::from_secs(2),
+            Duration::from_secs(60),
+        );
+
+        let error = federator
+            .post("wss://relay.invalid:13420", GrinboxRequest::Info)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ErrorKind>(),
+            Some(&ErrorKind::GrinboxProtocolError(GrinboxError::FederationDnsError))
+        );
+    }
+
+    #[test]
+    fn timed_out_lookup_is_not_repeated() {
+        static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
+        fn slow_lookup(_host: &str) -> bool {
+            LOOKUPS.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(200));
+            true
+        }
+
+        let federator = DnsCheckingFederator::new(
+            Arc::new(UnreachableFederator),
+            Duration::from_millis(10),
+            Duration::from_secs(60),
+        )
+        .with_lookup(slow_lookup);
+
+        for _ in 0..3 {
+            assert!(!federator.resolves("relay.example.com"));
+        }
+        assert_eq!(LOOKUPS.load(Ordering::SeqCst), 1);
+
+        // The lookup that timed out still caches its answer once it finishes.
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(federator.resolves("relay.example.com"));
+        assert_eq!(LOOKUPS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn clearnet_relays_bypass_tor_proxy() {
+        struct ClearnetFederator;
+
+        impl Federator for ClearnetFederator {
+            fn post(&self, _url: &str, _request: GrinboxRequest) -> Result<GrinboxResponse> {
+                Ok(GrinboxResponse::Ok { effective_expiration: None, subscription_id: None })
+            }
+        }
+
+        // Nothing listens on the proxy address, so only requests that skip it can succeed.
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let federator = OnionFederator::new(Arc::new(ClearnetFederator), proxy);
+
+        assert!(federator.post("wss://relay.example.com:13420", GrinboxRequest::Info).is_ok());
+        let onion = format!("wss://{}.onion:13420", "a".repeat(56));
+        assert!(federator.post(&onion, GrinboxRequest::Info).is_err());
+    }
+
+    /// Greets with a challenge and accepts whatever is posted, recording the peer token each
+    /// connection presents.
+    struct StubRelay {
+        out: Sender,
+        peer_tokens: mpsc::Sender<Option<String>>,
+    }
+
+    impl Handler for StubRelay {
+        fn on_open(&mut self, handshake: ws::Handshake) -> WsResult<()> {
+            let peer_token = handshake
+                .request
+                .header(FEDERATION_PEER_TOKEN_HEADER)
+                .map(|token| String::from_utf8_lossy(token).to_string());
+            self.peer_tokens.send(peer_token).unwrap();
+            let challenge = GrinboxResponse::Challenge {
+                str: "challenge".to_string(),
+                relay: None,
+            };
+            self.out.send(serde_json::to_string(&challenge).unwrap())
+        }
+
+        fn on_message(&mut self, _msg: Message) -> WsResult<()> {
+            let ok = GrinboxResponse::Ok {
+                effective_expiration: Some(60),
+                subscription_id: None,
+            };
+            self.out.send(serde_json::to_string(&ok).unwrap())
+        }
+    }
+
+    /// A SOCKS5 proxy that connects its one client to `relay`, whatever host it asks for, and
+    /// records that host.
+    fn forwarding_proxy(relay: SocketAddr) -> (SocketAddr, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).unwrap();
+            client.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0u8; 5];
+            client.read_exact(&mut request).unwrap();
+            let mut host = vec![0u8; request[4] as usize + 2];
+            client.read_exact(&mut host).unwrap();
+            host.truncate(request[4] as usize);
+            sender.send(String::from_utf8(host).unwrap()).unwrap();
+            client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+            let relay = TcpStream::connect(relay).unwrap();
+            let (mut client_reader, mut relay_writer) = (client.try_clone().unwrap(), relay.try_clone().unwrap());
+            std::thread::spawn(move || io::copy(&mut client_reader, &mut relay_writer));
+            let (mut relay_reader, mut client_writer) = (relay, client);
+            let _ = io::copy(&mut relay_reader, &mut client_writer);
+        });
+        (address, receiver)
+    }
+
+    #[test]
+    fn onion_relays_are_reached_through_the_proxy() {
+        let (peer_tokens, presented_tokens) = mpsc::channel();
+        let relay = ws::Builder::new()
+            .build(move |out| StubRelay {
+                out,
+                peer_tokens: peer_tokens.clone(),
+            })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let relay_address = relay.local_addr().unwrap();
+        std::thread::spawn(move || relay.run().unwrap());
+        let (proxy, connects) = forwarding_proxy(relay_address);
+
+        let host = format!("{}.onion", "a".repeat(56));
+        let federator = OnionFederator::new(Arc::new(UnreachableFederator), proxy).with_peer_token(Some("peers".to_string()));
+        let response = federator
+            .post(&format!("wss://{}:13420", host), GrinboxRequest::Info)
+            .unwrap();
+
+        match response {
+            GrinboxResponse::Ok { effective_expiration, .. } => assert_eq!(effective_expiration, Some(60)),
+            response => panic!("unexpected response {:?}", response),
+        }
+        assert_eq!(connects.recv().unwrap(), host);
+        assert_eq!(presented_tokens.recv().unwrap(), Some("peers".to_string()));
+    }
+}
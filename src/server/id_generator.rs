@@ -0,0 +1,68 @@
+use uuid::Uuid;
+
+use grinboxlib::utils::crypto::random_challenge;
+
+/// Source of connection ids and challenges handed out to new connections.
+pub trait IdGenerator: Send + Sync {
+    fn connection_id(&self) -> String;
+    fn challenge(&self) -> String;
+}
+
+pub struct DefaultIdGenerator;
+
+impl IdGenerator for DefaultIdGenerator {
+    fn connection_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    fn challenge(&self) -> String {
+        random_challenge()
+    }
+}
+
+#[cfg(test)]
+pub struct SequentialIdGenerator {
+    next_connection_id: std::sync::atomic::AtomicUsize,
+    next_challenge: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl SequentialIdGenerator {
+    pub fn new() -> SequentialIdGenerator {
+        SequentialIdGenerator {
+            next_connection_id: std::sync::atomic::AtomicUsize::new(0),
+            next_challenge: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl IdGenerator for SequentialIdGenerator {
+    fn connection_id(&self) -> String {
+        let id = self
+            .next_connection_id
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        format!("connection-{}", id)
+    }
+
+    fn challenge(&self) -> String {
+        let id = self
+            .next_challenge
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        format!("challenge-{}", id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequential_generator_is_deterministic() {
+        let generator = SequentialIdGenerator::new();
+        assert_eq!(generator.connection_id(), "connection-0");
+        assert_eq!(generator.connection_id(), "connection-1");
+        assert_eq!(generator.challenge(), "challenge-0");
+        assert_eq!(generator.challenge(), "challenge-1");
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ws::CloseCode;
+
+use super::Server;
+
+/// Something the reaper can shut down once it went idle, and whose challenge it can expire.
+pub trait Reapable: Clone + Send + 'static {
+    fn close_idle(&self);
+    fn expire_challenge(&self);
+}
+
+impl Reapable for Arc<Mutex<Server>> {
+    fn close_idle(&self) {
+        if self.lock().unwrap().close(CloseCode::Away, "idle for too long").is_err() {
+            error!("could not close idle connection!");
+        }
+    }
+
+    fn expire_challenge(&self) {
+        self.lock().unwrap().challenge_expired = true;
+    }
+}
+
+struct TrackedConnection<C> {
+    connection: C,
+    last_activity: Instant,
+    /// When the connection's current challenge was issued, until the reaper expired it.
+    challenge_issued_at: Option<Instant>,
+}
+
+/// Tracks the last activity and challenge of every open connection. A single background sweep
+/// closes the connections that stayed idle for too long and expires the challenges that are
+/// past their TTL, instead of checking lazily on each message.
+pub struct IdleReaper<C: Reapable = Arc<Mutex<Server>>> {
+    connections: Mutex<HashMap<String, TrackedConnection<C>>>,
+}
+
+impl<C: Reapable> IdleReaper<C> {
+    pub fn new() -> IdleReaper<C> {
+        IdleReaper {
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(&self, id: &str, connection: C, now: Instant) {
+        self.connections.lock().unwrap().insert(
+            id.to_string(),
+            TrackedConnection {
+                connection,
+                last_activity: now,
+                challenge_issued_at: Some(now),
+            },
+        );
+    }
+
+    pub fn touch(&self, id: &str, now: Instant) {
+        if let Some(tracked) = self.connections.lock().unwrap().get_mut(id) {
+            tracked.last_activity = now;
+        }
+    }
+
+    pub fn challenge_issued(&self, id: &str, now: Instant) {
+        if let Some(tracked) = self.connections.lock().unwrap().get_mut(id) {
+            tracked.challenge_issued_at = Some(now);
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.connections.lock().unwrap().remove(id);
+    }
+
+    /// Closes every connection idle for longer than `idle_timeout`, when one is given, and
+    /// returns their ids. Challenges of the remaining connections issued more than
+    /// `challenge_ttl` ago are expired. The lock is only held while picking them out, so active
+    /// connections are barely held up.
+    pub fn reap(&self, now: Instant, idle_timeout: Option<Duration>, challenge_ttl: Duration) -> Vec<String> {
+        let (idle, stale): (Vec<(String, TrackedConnection<C>)>, Vec<C>) = {
+            let mut connections = self.connections.lock().unwrap();
+            let ids: Vec<String> = connections
+                .iter()
+                .filter(|(_, tracked)| {
+                    idle_timeout.map_or(false, |idle_timeout| now.duration_since(tracked.last_activity) > idle_timeout)
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+            let idle = ids
+                .into_iter()
+                .filter_map(|id| connections.remove(&id).map(|tracked| (id, tracked)))
+                .collect();
+
+            let mut stale = vec![];
+            for tracked in connections.values_mut() {
+                let expired = tracked
+                    .challenge_issued_at
+                    .map_or(false, |issued_at| now.duration_since(issued_at) > challenge_ttl);
+                if expired {
+                    tracked.challenge_issued_at = None;
+                    stale.push(tracked.connection.clone());
+                }
+            }
+            (idle, stale)
+        };
+
+        for connection in stale {
+            connection.expire_challenge();
+        }
+
+        idle.into_iter()
+            .map(|(id, tracked)| {
+                tracked.connection.close_idle();
+                id
+            })
+            .collect()
+    }
+
+    pub fn spawn(reaper: Arc<IdleReaper<C>>, interval: Duration, idle_timeout: Option<Duration>, challenge_ttl: Duration) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            reaper.reap(Instant::now(), idle_timeout, challenge_ttl);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone)]
+    struct RecordingConnection {
+        closed: Arc<Mutex<Vec<String>>>,
+        expired: Arc<Mutex<Vec<String>>>,
+        name: String,
+    }
+
+    impl Reapable for RecordingConnection {
+        fn close_idle(&self) {
+            self.closed.lock().unwrap().push(self.name.clone());
+        }
+
+        fn expire_challenge(&self) {
+            self.expired.lock().unwrap().push(self.name.clone());
+        }
+    }
+
+    #[test]
+    fn reaps_only_idle_connections() {
+        let closed = Arc::new(Mutex::new(vec![]));
+        let expired = Arc::new(Mutex::new(vec![]));
+        let connection = |name: &str| RecordingConnection {
+            closed: closed.clone(),
+            expired: expired.clone(),
+            name: name.to_string(),
+        };
+
+        let reaper = IdleReaper::new();
+        let start = Instant::now();
+        reaper.register("idle", connection("idle"), start);
+        reaper.register("active", connection("active"), start);
+
+        reaper.touch("active", start + Duration::from_secs(50));
+        assert!(reaper.reap(start + Duration::from_secs(60), Some(Duration::from_secs(60)), Duration::from_secs(600)).is_empty());
+
+        let reaped = reaper.reap(start + Duration::from_secs(61), Some(Duration::from_secs(60)), Duration::from_secs(600));
+        assert_eq!(reaped, vec!["idle".to_string()]);
+        assert_eq!(*closed.lock().unwrap(), vec!["idle".to_string()]);
+
+        reaper.remove("active");
+        assert!(reaper.reap(start + Duration::from_secs(600), Some(Duration::from_secs(60)), Duration::from_secs(600)).is_empty());
+        assert!(expired.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn expires_only_stale_challenges() {
+        let closed = Arc::new(Mutex::new(vec![]));
+        let expired = Arc::new(Mutex::new(vec![]));
+        let connection = |name: &str| RecordingConnection {
+            closed: closed.clone(),
+            expired: expired.clone(),
+            name: name.to_string(),
+        };
+
+        let reaper = IdleReaper::new();
+        let start = Instant::now();
+        reaper.register("stale", connection("stale"), start);
+        reaper.register("renewed", connection("renewed"), start);
+        reaper.challenge_issued("renewed", start + Duration::from_secs(50));
+
+        assert!(reaper.reap(start + Duration::from_secs(61), None, Duration::from_secs(60)).is_empty());
+        assert_eq!(*expired.lock().unwrap(), vec!["stale".to_string()]);
+
+        // An expired challenge is only expired once, until a new one is issued.
+        reaper.reap(start + Duration::from_secs(90), None, Duration::from_secs(60));
+        assert_eq!(*expired.lock().unwrap(), vec!["stale".to_string()]);
+
+        reaper.reap(start + Duration::from_secs(111), None, Duration::from_secs(60));
+        assert_eq!(*expired.lock().unwrap(), vec!["stale".to_string(), "renewed".to_string()]);
+        assert!(closed.lock().unwrap().is_empty());
+    }
+}
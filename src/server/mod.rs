@@ -5,41 +5,197 @@ use futures::{
     Future, Stream,
 };
 use std::collections::HashMap;
-use uuid::Uuid;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use ws::{CloseCode, Handler, Handshake, Message, Request, Response, Result as WsResult, Sender, connect};
+use ws::{CloseCode, Frame, Handler, Handshake, Message, OpCode, Request, Response, Result as WsResult, Sender};
 
 use grinboxlib::error::{ErrorKind, Result};
-use grinboxlib::types::{GrinboxAddress, GrinboxError, GrinboxRequest, GrinboxResponse};
-use grinboxlib::utils::crypto::{verify_signature, Base58, Hex};
-use grinboxlib::utils::secp::{PublicKey, Signature};
+use grinboxlib::types::{
+    network_name, serializer_for_subprotocols, version_bytes, ErrorLimit, GrinboxAddress, GrinboxError,
+    GrinboxMessage, GrinboxRequest, GrinboxResponse, JsonSerializer, RelayIdentity, Serializer,
+};
+use grinboxlib::utils::crypto::{verify_challenge, Base58Key, Challenge, HexSignature};
+
+use crate::broker::{effective_message_expiration, BrokerRequest, BrokerResponse};
+use crate::metrics::RelayMetrics;
+
+mod authenticator;
+mod backlog;
+mod federation_pool;
+mod federator;
+mod id_generator;
+mod idle_reaper;
+mod rate_limit;
+mod socks;
+mod websocket_stream;
+
+pub use self::authenticator::{Authenticator, DefaultAuthenticator};
+use self::backlog::Backlog;
+pub use self::federation_pool::FederationPool;
+pub use self::federator::{
+    DnsCheckingFederator, FederationOutcome, FederationResult, Federator, OnionFederator, WebsocketFederator,
+};
+use self::federator::FEDERATION_PEER_TOKEN_HEADER;
+pub use self::id_generator::{DefaultIdGenerator, IdGenerator};
+pub use self::idle_reaper::IdleReaper;
+pub use self::rate_limit::ConnectionLimiter;
+use self::rate_limit::RequestWindow;
+
+/// Relays a slate may still be forwarded through when the client did not say.
+const DEFAULT_FEDERATION_HOPS: u8 = 5;
+const MAX_CORRELATION_ID_LENGTH: usize = 64;
 
-use crate::broker::{BrokerRequest, BrokerResponse};
+/// Times a signature is checked when verification fails for another reason than a mismatch.
+const SIGNATURE_VERIFICATION_ATTEMPTS: usize = 3;
 
-static MAX_SUBSCRIPTIONS: usize = 1;
+/// Close code for connections refused because their address connected too often.
+const CONNECTION_RATE_LIMITED: CloseCode = CloseCode::Library(4029);
 
 pub struct BrokerResponseHandler {
     inner: std::sync::Arc<std::sync::Mutex<Server>>,
     response_receiver: UnboundedReceiver<BrokerResponse>,
+    broker_sender: UnboundedSender<BrokerRequest>,
+    metrics: std::sync::Arc<RelayMetrics>,
+    idle_reaper: Option<std::sync::Arc<IdleReaper>>,
+    /// Full address of the subscription whose slates the handler delivers, reported to the
+    /// client as their recipient. Handlers for publish receipts have none.
+    recipient: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub grinbox_domain: String,
+    pub grinbox_port: u16,
+    pub grinbox_protocol_unsecure: bool,
+    pub max_challenges_per_minute: u32,
+    pub max_message_bytes: Option<usize>,
+    /// Longest string a signature is verified against. The relay binary sets it to
+    /// `max_slate_bytes` plus a kilobyte unless configured; `None` leaves it unbounded.
+    pub max_signed_bytes: Option<usize>,
+    pub max_slate_bytes: usize,
+    /// How far the timestamp of a posted slate may be from the relay's clock, either way.
+    /// Slates without a timestamp are not checked.
+    pub max_slate_age: Option<Duration>,
+    pub reject_self_sends: bool,
+    pub max_subscriptions: usize,
+    pub challenge_ttl: Duration,
+    pub publish_receipts: bool,
+    /// How many bytes a client may be behind on reading before it is disconnected.
+    pub max_connection_backlog_bytes: Option<usize>,
+    /// Secret shared with the relays this one federates with. Connections presenting it may
+    /// forward slates signed over the challenge another relay issued.
+    pub federation_peer_token: Option<String>,
+    /// Bearer token `GET /metrics` has to present. The relay does not serve metrics without one.
+    pub metrics_token: Option<String>,
 }
 
 pub struct AsyncServer {
     id: String,
+    challenge: String,
+    challenge_issued_at: Instant,
+    id_generator: std::sync::Arc<dyn IdGenerator>,
     inner: std::sync::Arc<std::sync::Mutex<Server>>,
     nats_sender: UnboundedSender<BrokerRequest>,
     response_handlers_sender: UnboundedSender<BrokerResponseHandler>,
     subscriptions: HashMap<String, Subscription>,
-    grinbox_domain: String,
-    grinbox_port: u16,
-    grinbox_protocol_unsecure: bool,
+    receipt_sender: Option<UnboundedSender<BrokerResponse>>,
+    next_subscription_id: u32,
+    challenge_requests: RequestWindow,
+    config: ServerConfig,
+    authenticator: std::sync::Arc<dyn Authenticator>,
+    authenticated: bool,
+    federation_peer: bool,
+    federator: std::sync::Arc<dyn Federator>,
+    federation_pool: std::sync::Arc<FederationPool>,
+    idle_reaper: Option<std::sync::Arc<IdleReaper>>,
+    connection_limiter: Option<std::sync::Arc<ConnectionLimiter>>,
+    metrics: std::sync::Arc<RelayMetrics>,
+    opened: bool,
+    /// Payload bytes of the message being received so far, across its fragments.
+    message_bytes: usize,
 }
 
 pub struct Server {
     id: String,
     out: Sender,
+    serializer: std::sync::Arc<dyn Serializer>,
+    backlog: Option<Backlog>,
+    /// Set by the idle reaper once the connection's challenge is past its TTL.
+    challenge_expired: bool,
+}
+
+impl Server {
+    fn send(&mut self, response: &GrinboxResponse) -> WsResult<()> {
+        let message = match self.serializer.encode_response(response) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("[{}] could not serialize response: {}", self.id, e);
+                return self.close(CloseCode::Error, "could not serialize response");
+            }
+        };
+
+        if self.backlog.as_ref().map_or(false, |backlog| backlog.exceeded()) {
+            return Err(backlog_exceeded());
+        }
+        let written = self.backlog.as_mut().map(|backlog| backlog.write(message.len()));
+        let ping = match written {
+            Some(None) => {
+                warn!("[{}] {}", self.id.bright_green(), "outbound backlog exceeded, disconnecting".bright_red());
+                self.close(CloseCode::Policy, "outbound backlog exceeded")?;
+                return Err(backlog_exceeded());
+            }
+            Some(ping) => ping,
+            None => None,
+        };
+
+        self.out.send(message)?;
+        if let Some(ping) = ping {
+            self.out.ping(ping)?;
+        }
+        Ok(())
+    }
+
+    /// Acknowledges what the client read before answering one of the backlog pings.
+    fn pong(&mut self, payload: &[u8]) {
+        if let Some(ref mut backlog) = self.backlog {
+            backlog.pong(payload);
+        }
+    }
+
+    /// Closes the connection from outside its event loop, e.g. to kick a misbehaving client.
+    /// The client receives `code` and `reason` in the close frame.
+    pub fn close(&self, code: CloseCode, reason: &str) -> WsResult<()> {
+        info!("[{}] closing connection: {}", self.id.bright_green(), reason);
+        self.out.close_with_reason(code, reason.to_string())
+    }
+}
+
+/// Whether the handshake `headers` carry the federation peer token, when one is configured.
+fn is_federation_peer(headers: &[(String, Vec<u8>)], peer_token: Option<&String>) -> bool {
+    let peer_token = match peer_token {
+        Some(peer_token) => peer_token,
+        None => return false,
+    };
+    headers
+        .iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case(FEDERATION_PEER_TOKEN_HEADER) && value[..] == *peer_token.as_bytes())
+}
+
+/// Whether the request carries `Authorization: Bearer <metrics_token>`.
+fn is_metrics_authorized(headers: &[(String, Vec<u8>)], metrics_token: &str) -> bool {
+    let expected = format!("Bearer {}", metrics_token);
+    headers
+        .iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case("Authorization") && value[..] == *expected.as_bytes())
+}
+
+fn backlog_exceeded() -> ws::Error {
+    ws::Error::new(ws::ErrorKind::Capacity, "outbound backlog exceeded")
 }
 
-struct Subscription {}
+struct Subscription {
+    id: String,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct SignedPayload {
@@ -48,13 +204,249 @@ struct SignedPayload {
     signature: String,
 }
 
+fn parse_signed_payload(payload: &str, metrics: &RelayMetrics) -> Option<SignedPayload> {
+    match serde_json::from_str::<SignedPayload>(payload) {
+        Ok(signed_payload) => Some(signed_payload),
+        Err(e) => {
+            metrics.message_dropped_parse();
+            error!("dropping broker message with invalid payload: {}", e);
+            None
+        }
+    }
+}
+
+/// Whether a signature that did not verify was made over another challenge than `current`, as
+/// far as the client said which one it signed.
+fn is_stale_challenge(signed_challenge: Option<&str>, current: &str) -> bool {
+    signed_challenge.map_or(false, |signed_challenge| signed_challenge != current)
+}
+
+/// Refuses to hash challenges longer than `max_signed_bytes`, so a client cannot make the relay
+/// verify signatures over arbitrarily large strings.
+fn verify_bounded_signature(
+    public_key: &Base58Key,
+    challenge: &Challenge,
+    signature: &HexSignature,
+    max_signed_bytes: Option<usize>,
+) -> Result<()> {
+    if let Some(max_signed_bytes) = max_signed_bytes {
+        if challenge.as_str().len() > max_signed_bytes {
+            return Err(ErrorKind::GrinboxProtocolError(GrinboxError::InvalidRequest).into());
+        }
+    }
+    verify_with_retries(|| verify_challenge(challenge, signature, public_key))
+}
+
+/// Runs `verify` until it succeeds, the signature turns out not to match, or it failed
+/// `SIGNATURE_VERIFICATION_ATTEMPTS` times for other reasons. A mismatch is reported as
+/// `InvalidSignature`, repeated failures as the retryable `UnknownError`.
+fn verify_with_retries<F: FnMut() -> Result<()>>(mut verify: F) -> Result<()> {
+    for attempt in 1..=SIGNATURE_VERIFICATION_ATTEMPTS {
+        let error = match verify() {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if let Some(ErrorKind::IncorrectSignature) = error.downcast_ref::<ErrorKind>() {
+            return Err(ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature).into());
+        }
+        warn!("could not verify signature (attempt {} of {}): {}", attempt, SIGNATURE_VERIFICATION_ATTEMPTS, error);
+    }
+    Err(ErrorKind::GrinboxProtocolError(GrinboxError::UnknownError).into())
+}
+
+/// The protocol error to report for a failed verification, keeping `InvalidRequest` and
+/// `UnknownError` from `verify_bounded_signature` and falling back to `default` for anything
+/// else.
+fn verification_error(error: &failure::Error, default: GrinboxError) -> GrinboxError {
+    match error.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::GrinboxProtocolError(GrinboxError::InvalidRequest)) => GrinboxError::InvalidRequest,
+        Some(ErrorKind::GrinboxProtocolError(GrinboxError::UnknownError)) => GrinboxError::UnknownError,
+        _ => default,
+    }
+}
+
+/// Correlation ids are logged and written to broker headers as given, so they are kept to a short
+/// run of characters that need escaping in neither.
+fn is_valid_correlation_id(correlation_id: &str) -> bool {
+    !correlation_id.is_empty()
+        && correlation_id.len() <= MAX_CORRELATION_ID_LENGTH
+        && correlation_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Whether `str` is a `GrinboxMessage` sealed further than `max_slate_age` from `now`, in
+/// seconds since the unix epoch. Old slates are stale resubmissions; ones from the future come
+/// from a sender whose clock is off by more than the window.
+fn is_stale_slate(str: &str, max_slate_age: Duration, now: u64) -> bool {
+    let timestamp = match serde_json::from_str::<GrinboxMessage>(str) {
+        Ok(GrinboxMessage { timestamp: Some(timestamp), .. }) => timestamp,
+        _ => return false,
+    };
+    let age = if timestamp > now { timestamp - now } else { now - timestamp };
+    age > max_slate_age.as_secs()
+}
+
+/// A slate addressed to its own sender is published once like any other, so it reaches the
+/// sender's subscription a single time; relays can opt to refuse such posts altogether.
+fn self_send_error(from_address: &GrinboxAddress, to_address: &GrinboxAddress, config: &ServerConfig) -> Option<GrinboxError> {
+    if from_address.public_key != to_address.public_key {
+        return None;
+    }
+
+    if config.reject_self_sends {
+        warn!("rejecting slate sent by {} to itself", from_address.stripped());
+        Some(GrinboxError::InvalidRequest)
+    } else {
+        debug!("slate sent by {} to itself", from_address.stripped());
+        None
+    }
+}
+
+fn relay_identity(config: &ServerConfig) -> RelayIdentity {
+    RelayIdentity {
+        domain: config.grinbox_domain.clone(),
+        port: config.grinbox_port,
+        network: network_name().to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/// Settles a delivered broker message. Failed deliveries are counted, and when the broker
+/// delivered the message with an ack id it goes back to the queue for redelivery.
+fn acknowledge_delivery(broker_sender: &UnboundedSender<BrokerRequest>, ack_id: Option<String>, delivered: bool, metrics: &RelayMetrics) {
+    if !delivered {
+        metrics.delivery_failed();
+        match ack_id {
+            Some(_) => warn!("failed sending slate to client, returning it to the queue"),
+            None => error!("failed sending slate to client!"),
+        }
+    }
+
+    if let Some(ack_id) = ack_id {
+        if broker_sender
+            .unbounded_send(BrokerRequest::Acknowledge { ack_id, delivered })
+            .is_err()
+        {
+            error!("could not acknowledge delivery to broker!");
+        }
+    }
+}
+
+fn parse_request(msg: Message, serializer: &dyn Serializer, metrics: &RelayMetrics) -> Option<GrinboxRequest> {
+    match serializer.decode_request(msg) {
+        Ok(request) => Some(request),
+        Err(e) => {
+            metrics.request_invalid();
+            warn!("received invalid request: {}", e);
+            None
+        }
+    }
+}
+
+/// A sender that did not name its relay is a client of this one, so the remote relay has to be
+/// handed a reply address that routes back here rather than to the default domain.
+fn federated_sender_address(from: &str, from_address: &GrinboxAddress, config: &ServerConfig) -> GrinboxAddress {
+    let mut address = from_address.clone();
+    if !from.contains('@') {
+        address.domain = config.grinbox_domain.clone();
+        address.port = config.grinbox_port;
+    }
+    address
+}
+
+fn federate(
+    federator: &dyn Federator,
+    config: &ServerConfig,
+    from_address: &GrinboxAddress,
+    to_address: &GrinboxAddress,
+    str: String,
+    signature: String,
+    challenge: Option<String>,
+    message_expiration_in_seconds: Option<u32>,
+    correlation_id: Option<String>,
+    hops: Option<u8>,
+) -> FederationResult {
+    // Checked here as well as in `post_slate`, so nothing oversized is forwarded whichever way
+    // the slate got here, and before a connection to the remote relay is opened.
+    if str.len() > config.max_slate_bytes {
+        warn!("refusing to federate slate to {}: {} bytes is over the limit", to_address.stripped(), str.len());
+        return FederationResult::refused(GrinboxError::MessageTooLarge, &GrinboxError::MessageTooLarge.to_string());
+    }
+
+    let hops = hops.unwrap_or(DEFAULT_FEDERATION_HOPS);
+    if hops == 0 {
+        warn!("refusing to federate slate to {}: hop limit reached", to_address.stripped());
+        return FederationResult::refused(GrinboxError::UnknownError, "slate reached the federation hop limit");
+    }
+
+    let url = match config.grinbox_protocol_unsecure {
+        false => format!(
+            "wss://{}:{}",
+            to_address.host(),
+            to_address.port
+        ),
+        true => format!(
+            "ws://{}:{}",
+            to_address.host(),
+            to_address.port
+        )
+    };
+
+    // The remote relay handed the sender no challenge, so it is told which one was signed.
+    let request = GrinboxRequest::PostSlate {
+        from: from_address.stripped(),
+        to: to_address.stripped(),
+        str,
+        signature,
+        message_expiration_in_seconds,
+        correlation_id,
+        hops: Some(hops - 1),
+        challenge,
+    };
+
+    let started_at = Instant::now();
+    let outcome = match federator.post(&url, request) {
+        Ok(GrinboxResponse::Ok {
+            effective_expiration,
+            ..
+        }) => FederationOutcome::Accepted { effective_expiration },
+        Ok(GrinboxResponse::Error { kind, description, .. }) => {
+            warn!("{} rejected federated slate: {}", url, description);
+            FederationOutcome::Rejected { kind, description }
+        }
+        Ok(_) => FederationOutcome::Accepted { effective_expiration: None },
+        Err(e) => {
+            error!("could not federate slate to {}: {}", url, e);
+            match e.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::GrinboxProtocolError(GrinboxError::FederationTimeout)) => FederationOutcome::TimedOut,
+                Some(ErrorKind::GrinboxProtocolError(kind)) => FederationOutcome::Rejected {
+                    kind: kind.clone(),
+                    description: kind.to_string(),
+                },
+                _ => FederationOutcome::Failed,
+            }
+        }
+    };
+    FederationResult::new(outcome, started_at.elapsed())
+}
+
 impl Drop for AsyncServer {
     fn drop(&mut self) {
-        for (subject, _subscription) in &self.subscriptions {
+        if let Some(ref idle_reaper) = self.idle_reaper {
+            idle_reaper.remove(&self.id);
+        }
+
+        if self.opened {
+            self.metrics.connection_closed();
+        }
+        self.metrics.unsubscribed(self.subscriptions.len() as u64);
+
+        for subscription in self.subscriptions.values() {
             if self
                 .nats_sender
                 .unbounded_send(BrokerRequest::Unsubscribe {
-                    id: self.id.clone(),
+                    id: subscription.id.clone(),
                 })
                 .is_err()
             {
@@ -69,26 +461,51 @@ impl AsyncServer {
         out: Sender,
         nats_sender: UnboundedSender<BrokerRequest>,
         response_handlers_sender: UnboundedSender<BrokerResponseHandler>,
-        grinbox_domain: &str,
-        grinbox_port: u16,
-        grinbox_protocol_unsecure: bool,
+        config: ServerConfig,
+        id_generator: std::sync::Arc<dyn IdGenerator>,
+        authenticator: std::sync::Arc<dyn Authenticator>,
+        federator: std::sync::Arc<dyn Federator>,
+        federation_pool: std::sync::Arc<FederationPool>,
+        idle_reaper: Option<std::sync::Arc<IdleReaper>>,
+        connection_limiter: Option<std::sync::Arc<ConnectionLimiter>>,
+        metrics: std::sync::Arc<RelayMetrics>,
     ) -> AsyncServer {
-        let id = Uuid::new_v4().to_string();
+        let id = id_generator.connection_id();
 
         let server = Server {
             id: id.clone(),
             out,
+            serializer: std::sync::Arc::new(JsonSerializer),
+            backlog: config.max_connection_backlog_bytes.map(Backlog::new),
+            challenge_expired: false,
         };
 
         AsyncServer {
             id: id.clone(),
+            challenge: id_generator.challenge(),
+            challenge_issued_at: Instant::now(),
+            id_generator,
             inner: std::sync::Arc::new(std::sync::Mutex::new(server)),
             nats_sender,
             response_handlers_sender,
             subscriptions: HashMap::new(),
-            grinbox_domain: grinbox_domain.to_string(),
-            grinbox_port,
-            grinbox_protocol_unsecure,
+            receipt_sender: None,
+            next_subscription_id: 0,
+            challenge_requests: RequestWindow::new(
+                config.max_challenges_per_minute,
+                Duration::from_secs(60),
+            ),
+            config,
+            authenticator,
+            authenticated: false,
+            federation_peer: false,
+            federator,
+            federation_pool,
+            idle_reaper,
+            connection_limiter,
+            metrics,
+            opened: false,
+            message_bytes: 0,
         }
     }
 
@@ -100,35 +517,70 @@ impl AsyncServer {
             let fut_loop = fut_rx
                 .for_each(move |handler| {
                     let clone = handler.inner.clone();
+                    let broker_sender = handler.broker_sender.clone();
+                    let metrics = handler.metrics.clone();
+                    let idle_reaper = handler.idle_reaper.clone();
+                    let recipient = handler.recipient.clone();
                     let response_loop = handler.response_receiver.for_each(move |m| {
                         match m {
                             BrokerResponse::Message {
-                                subject: _,
+                                subject,
                                 payload,
                                 reply_to,
+                                correlation_id,
+                                ack_id,
                             } => {
-                                let signed_payload =
-                                    serde_json::from_str::<SignedPayload>(&payload);
-                                if signed_payload.is_ok() {
-                                    let signed_payload = signed_payload.unwrap();
-                                    let response = GrinboxResponse::Slate {
-                                        from: reply_to,
-                                        str: signed_payload.str,
-                                        challenge: signed_payload.challenge,
-                                        signature: signed_payload.signature,
-                                    };
-                                    let guard = clone.lock().unwrap();
-                                    let ref server = *guard;
-                                    info!("[{}] <- {}", server.id.bright_green(), response);
-                                    if server
-                                        .out
-                                        .send(serde_json::to_string(&response).unwrap())
-                                        .is_err()
-                                    {
-                                        error!("failed sending slate to client!");
-                                    };
-                                } else {
-                                    error!("invalid payload!");
+                                match parse_signed_payload(&payload, &metrics) {
+                                    Some(signed_payload) => {
+                                        let response = GrinboxResponse::Slate {
+                                            from: reply_to,
+                                            to: Some(recipient.clone().unwrap_or(subject)),
+                                            str: signed_payload.str,
+                                            challenge: signed_payload.challenge,
+                                            signature: signed_payload.signature,
+                                            correlation_id: correlation_id.clone(),
+                                        };
+                                        let mut guard = clone.lock().unwrap();
+                                        let ref mut server = *guard;
+                                        info!("[{}] <- {}", server.id.bright_green(), response);
+                                        if let Some(correlation_id) = correlation_id {
+                                            info!("[{}] delivered slate with correlation id {}", server.id.bright_green(), correlation_id);
+                                        }
+                                        if let Some(ref idle_reaper) = idle_reaper {
+                                            idle_reaper.touch(&server.id, Instant::now());
+                                        }
+                                        let delivered = server.send(&response).is_ok();
+                                        acknowledge_delivery(&broker_sender, ack_id, delivered, &metrics);
+                                    }
+                                    // Redelivering a payload that cannot be parsed would not help.
+                                    None => acknowledge_delivery(&broker_sender, ack_id, true, &metrics),
+                                }
+                            }
+                            BrokerResponse::Published { reply_to: _, message_expiration_in_seconds } => {
+                                let response = AsyncServer::posted(message_expiration_in_seconds);
+                                let mut guard = clone.lock().unwrap();
+                                let ref mut server = *guard;
+                                info!("[{}] <- {}", server.id.bright_green(), response);
+                                if server.send(&response).is_err() {
+                                    error!("[{}] could not confirm published slate", server.id.bright_green());
+                                }
+                            }
+                            BrokerResponse::PublishFailed { reply_to: _ } => {
+                                let response = AsyncServer::error(GrinboxError::UnknownError);
+                                let mut guard = clone.lock().unwrap();
+                                let ref mut server = *guard;
+                                info!("[{}] <- {}", server.id.bright_green(), response);
+                                if server.send(&response).is_err() {
+                                    error!("[{}] could not report failed publish", server.id.bright_green());
+                                }
+                            }
+                            BrokerResponse::DeliveryExpired { subject: _, to } => {
+                                let response = GrinboxResponse::DeliveryExpired { to };
+                                let mut guard = clone.lock().unwrap();
+                                let ref mut server = *guard;
+                                info!("[{}] <- {}", server.id.bright_green(), response);
+                                if server.send(&response).is_err() {
+                                    error!("[{}] could not notify sender of expired delivery", server.id.bright_green());
                                 }
                             }
                         }
@@ -153,43 +605,161 @@ impl AsyncServer {
 
     fn error(kind: GrinboxError) -> GrinboxResponse {
         let description = format!("{}", kind);
-        GrinboxResponse::Error { kind, description }
+        let code = kind.code().to_string();
+        GrinboxResponse::Error {
+            kind,
+            code,
+            description,
+            limit: None,
+        }
+    }
+
+    fn limit_error(kind: GrinboxError, max: usize, current: usize) -> GrinboxResponse {
+        let description = format!("{} ({} of {})", kind, current, max);
+        let code = kind.code().to_string();
+        GrinboxResponse::Error {
+            kind,
+            code,
+            description,
+            limit: Some(ErrorLimit { max, current }),
+        }
+    }
+
+    /// A client posting right after the relay restarted may still sign the challenge it got
+    /// from the previous process; it is told to fetch the new one rather than that its
+    /// signature is invalid.
+    fn stale_challenge_error() -> GrinboxResponse {
+        let kind = GrinboxError::InvalidChallenge;
+        GrinboxResponse::Error {
+            code: kind.code().to_string(),
+            description: format!("{} request a new challenge and sign again", kind),
+            kind,
+            limit: None,
+        }
     }
 
     fn ok() -> GrinboxResponse {
-        GrinboxResponse::Ok
+        GrinboxResponse::Ok {
+            effective_expiration: None,
+            subscription_id: None,
+        }
+    }
+
+    fn subscribed(subscription_id: String) -> GrinboxResponse {
+        GrinboxResponse::Ok {
+            effective_expiration: None,
+            subscription_id: Some(subscription_id),
+        }
+    }
+
+    fn posted(message_expiration_in_seconds: Option<u32>) -> GrinboxResponse {
+        GrinboxResponse::Ok {
+            effective_expiration: Some(effective_message_expiration(message_expiration_in_seconds)),
+            subscription_id: None,
+        }
     }
 
     fn get_challenge_raw(&self) -> &str {
-        "7WUDtkSaKyGRUnQ22rE3QUXChV8DmA6NnunDYP4vheTpc"
+        &self.challenge
     }
 
     fn get_challenge(&self) -> GrinboxResponse {
         GrinboxResponse::Challenge {
             str: String::from(self.get_challenge_raw()),
+            relay: Some(relay_identity(&self.config)),
         }
     }
 
-    fn verify_signature(&self, public_key: &str, challenge: &str, signature: &str) -> Result<()> {
-        let (public_key, _) = PublicKey::from_base58_check_raw(public_key, 2)?;
-        let signature = Signature::from_hex(signature)?;
-        verify_signature(challenge, &signature, &public_key)
-            .map_err(|_| ErrorKind::GrinboxProtocolError(GrinboxError::InvalidSignature))?;
-        Ok(())
+    fn reissue_challenge(&mut self, now: Instant) -> GrinboxResponse {
+        if !self.challenge_requests.allow(now) {
+            return AsyncServer::error(GrinboxError::RateLimited);
+        }
+        if self.is_challenge_expired(now) {
+            self.rotate_challenge(now);
+        }
+        self.get_challenge()
+    }
+
+    /// Connections tracked by the idle reaper have their challenge expired by its sweep, others
+    /// check its age here.
+    fn is_challenge_expired(&self, now: Instant) -> bool {
+        match self.idle_reaper {
+            Some(_) => self.inner.lock().unwrap().challenge_expired,
+            None => now.duration_since(self.challenge_issued_at) > self.config.challenge_ttl,
+        }
+    }
+
+    fn rotate_challenge(&mut self, now: Instant) {
+        self.challenge = self.id_generator.challenge();
+        self.challenge_issued_at = now;
+        self.inner.lock().unwrap().challenge_expired = false;
+        if let Some(ref idle_reaper) = self.idle_reaper {
+            idle_reaper.challenge_issued(&self.id, now);
+        }
+    }
+
+    /// Signatures over an expired challenge are refused, and the client is sent a fresh
+    /// challenge to sign its request again with.
+    fn refuse_expired_challenge(&mut self, now: Instant) -> GrinboxResponse {
+        self.rotate_challenge(now);
+        let response = self.get_challenge();
+        debug!("[{}] <- {}", self.id.bright_green(), response);
+        if self.inner.lock().unwrap().send(&response).is_err() {
+            error!("could not send challenge to client!");
+        }
+        AsyncServer::error(GrinboxError::InvalidChallenge)
+    }
+
+    fn get_info(&self) -> GrinboxResponse {
+        GrinboxResponse::Info {
+            network: network_name().to_string(),
+            version_bytes: version_bytes(),
+            max_slate_bytes: Some(self.config.max_slate_bytes),
+        }
+    }
+
+    fn verify_signature(
+        &self,
+        public_key: &Base58Key,
+        challenge: &Challenge,
+        signature: &HexSignature,
+    ) -> Result<()> {
+        verify_bounded_signature(public_key, challenge, signature, self.config.max_signed_bytes)
     }
 
     fn subscribe(&mut self, address: String, signature: String) -> GrinboxResponse {
-        let result = self.verify_signature(&address, self.get_challenge_raw(), &signature);
+        if !self.authenticated {
+            return AsyncServer::error(GrinboxError::Unauthorized);
+        }
+
+        let result = Base58Key::from_str(&address).and_then(|public_key| {
+            let signature = HexSignature::from_str(&signature)?;
+            let challenge = Challenge::new(self.get_challenge_raw());
+            self.verify_signature(&public_key, &challenge, &signature)
+        });
         match result {
             Ok(()) => {
-                if self.subscriptions.len() == MAX_SUBSCRIPTIONS {
-                    AsyncServer::error(GrinboxError::TooManySubscriptions)
+                // The broker already has a consumer for this address on this connection; a
+                // second one would be leaked when the first entry is overwritten.
+                if let Some(subscription) = self.subscriptions.get(&address) {
+                    return AsyncServer::subscribed(subscription.id.clone());
+                }
+
+                if self.subscriptions.len() >= self.config.max_subscriptions {
+                    AsyncServer::limit_error(
+                        GrinboxError::TooManySubscriptions,
+                        self.config.max_subscriptions,
+                        self.subscriptions.len(),
+                    )
                 } else {
+                    let subscription_id = format!("{}/{}", self.id, self.next_subscription_id);
+                    self.next_subscription_id += 1;
+
                     let (res_tx, res_rx) = unbounded::<BrokerResponse>();
                     if self
                         .nats_sender
                         .unbounded_send(BrokerRequest::Subscribe {
-                            id: self.id.clone(),
+                            id: subscription_id.clone(),
                             subject: address.clone(),
                             response_sender: res_tx,
                         })
@@ -204,6 +774,10 @@ impl AsyncServer {
                         .unbounded_send(BrokerResponseHandler {
                             inner: self.inner.clone(),
                             response_receiver: res_rx,
+                            broker_sender: self.nats_sender.clone(),
+                            metrics: self.metrics.clone(),
+                            idle_reaper: self.idle_reaper.clone(),
+                            recipient: Some(self.relay_address(&address)),
                         })
                         .is_err()
                     {
@@ -211,23 +785,37 @@ impl AsyncServer {
                         return AsyncServer::error(GrinboxError::UnknownError);
                     };
 
-                    self.subscriptions.insert(address.clone(), Subscription {});
+                    self.subscriptions.insert(address.clone(), Subscription {
+                        id: subscription_id.clone(),
+                    });
+                    self.metrics.subscribed();
 
-                    AsyncServer::ok()
+                    AsyncServer::subscribed(subscription_id)
                 }
             }
-            Err(_) => AsyncServer::error(GrinboxError::UnknownError),
+            Err(e) => AsyncServer::error(verification_error(&e, GrinboxError::UnknownError)),
+        }
+    }
+
+    /// The full address of `public_key` on this relay.
+    fn relay_address(&self, public_key: &str) -> String {
+        GrinboxAddress {
+            public_key: public_key.to_string(),
+            domain: self.config.grinbox_domain.clone(),
+            port: self.config.grinbox_port,
+            version_bytes: None,
         }
+        .stripped()
     }
 
     fn unsubscribe(&mut self, address: String) -> GrinboxResponse {
         let result = self.subscriptions.remove(&address);
         match result {
-            Some(_subscription) => {
+            Some(subscription) => {
                 if self
                     .nats_sender
                     .unbounded_send(BrokerRequest::Unsubscribe {
-                        id: self.id.clone(),
+                        id: subscription.id,
                     })
                     .is_err()
                 {
@@ -235,57 +823,174 @@ impl AsyncServer {
                     return AsyncServer::error(GrinboxError::UnknownError);
                 };
 
+                self.metrics.unsubscribed(1);
                 AsyncServer::ok()
             }
             None => AsyncServer::error(GrinboxError::InvalidRequest),
         }
     }
 
+    fn unsubscribe_by_id(&mut self, subscription_id: String) -> GrinboxResponse {
+        let address = self
+            .subscriptions
+            .iter()
+            .find(|(_, subscription)| subscription.id == subscription_id)
+            .map(|(address, _)| address.clone());
+        match address {
+            Some(address) => self.unsubscribe(address),
+            None => AsyncServer::error(GrinboxError::InvalidRequest),
+        }
+    }
+
+    /// Where the broker confirms slates this connection posts, registered on first use.
+    fn receipt_sender(&mut self) -> Option<UnboundedSender<BrokerResponse>> {
+        if self.receipt_sender.is_none() {
+            let (receipt_sender, receipt_receiver) = unbounded::<BrokerResponse>();
+            if self
+                .response_handlers_sender
+                .unbounded_send(BrokerResponseHandler {
+                    inner: self.inner.clone(),
+                    response_receiver: receipt_receiver,
+                    broker_sender: self.nats_sender.clone(),
+                    metrics: self.metrics.clone(),
+                    idle_reaper: self.idle_reaper.clone(),
+                    recipient: None,
+                })
+                .is_err()
+            {
+                error!("could not register receipt handler!");
+                return None;
+            }
+            self.receipt_sender = Some(receipt_sender);
+        }
+        self.receipt_sender.clone()
+    }
+
     fn post_slate(
-        &self,
+        &mut self,
         from: String,
         to: String,
         str: String,
         signature: String,
+        signed_challenge: Option<String>,
         message_expiration_in_seconds: Option<u32>,
-    ) -> GrinboxResponse {
+        correlation_id: Option<String>,
+        hops: Option<u8>,
+    ) -> Option<GrinboxResponse> {
+        // Federation peers prove themselves with the peer token rather than the authenticator.
+        if !self.authenticated && !self.federation_peer {
+            return Some(AsyncServer::error(GrinboxError::Unauthorized));
+        }
+
+        if str.len() > self.config.max_slate_bytes {
+            return Some(AsyncServer::error(GrinboxError::MessageTooLarge));
+        }
+
+        if let Some(ref correlation_id) = correlation_id {
+            if !is_valid_correlation_id(correlation_id) {
+                return Some(AsyncServer::error(GrinboxError::InvalidRequest));
+            }
+        }
+
         let from_address = GrinboxAddress::from_str_raw(&from);
         if from_address.is_err() {
-            return AsyncServer::error(GrinboxError::InvalidRequest);
+            return Some(AsyncServer::error(GrinboxError::InvalidRequest));
         }
         let from_address = from_address.unwrap();
 
         let to_address = GrinboxAddress::from_str_raw(&to);
         if to_address.is_err() {
-            return AsyncServer::error(GrinboxError::InvalidRequest);
+            return Some(AsyncServer::error(GrinboxError::InvalidRequest));
         }
         let to_address = to_address.unwrap();
 
+        // The same key encodes to different addresses on mainnet and testnet; relaying between
+        // them would hand the receiver a reply address on the wrong network.
+        if from_address.version_bytes != to_address.version_bytes {
+            warn!("rejecting slate from {} to {} on another network", from_address.stripped(), to_address.stripped());
+            return Some(AsyncServer::error(GrinboxError::InvalidRequest));
+        }
+
+        if let Some(max_slate_age) = self.config.max_slate_age {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0);
+            if is_stale_slate(&str, max_slate_age, now) {
+                warn!("rejecting slate from {} sealed over {}s away from now", from_address.stripped(), max_slate_age.as_secs());
+                return Some(AsyncServer::error(GrinboxError::InvalidRequest));
+            }
+        }
+
+        if let Some(e) = self_send_error(&from_address, &to_address, &self.config) {
+            return Some(AsyncServer::error(e));
+        }
+
+        let public_key = Base58Key::from_str(&from_address.public_key);
+        let hex_signature = HexSignature::from_str(&signature);
+        if public_key.is_err() || hex_signature.is_err() {
+            return Some(AsyncServer::error(GrinboxError::InvalidSignature));
+        }
+        let public_key = public_key.unwrap();
+        let hex_signature = hex_signature.unwrap();
+
         let mut challenge = String::new();
         challenge.push_str(&str);
 
         let mut result =
-            self.verify_signature(&from_address.public_key, &challenge, &signature);
+            self.verify_signature(&public_key, &Challenge::new(&challenge), &hex_signature);
 
-        let mut challenge_raw = "";
+        let mut challenge_raw = String::new();
         if result.is_err() {
             challenge.push_str(self.get_challenge_raw());
-            challenge_raw = self.get_challenge_raw();
-            result = self.verify_signature(&from_address.public_key, &challenge, &signature);
+            challenge_raw = self.get_challenge_raw().to_string();
+            result = self.verify_signature(&public_key, &Challenge::new(&challenge), &hex_signature);
         }
 
-        if result.is_err() {
-            return AsyncServer::error(GrinboxError::InvalidSignature);
+        // Slates forwarded by another relay were signed over the challenge that relay gave the
+        // sender, which it passes along. Only peers holding the federation token are trusted to
+        // have issued it; anything in the request itself is up to the client.
+        let forwarded = self.federation_peer && hops.is_some();
+        if result.is_err() && forwarded {
+            if let Some(ref signed_challenge) = signed_challenge {
+                challenge = format!("{}{}", str, signed_challenge);
+                challenge_raw = signed_challenge.clone();
+                result = self.verify_signature(&public_key, &Challenge::new(&challenge), &hex_signature);
+            }
         }
 
-        if to_address.port == self.grinbox_port && to_address.domain == self.grinbox_domain {
+        if let Err(e) = result {
+            if !forwarded && is_stale_challenge(signed_challenge.as_ref().map(String::as_str), self.get_challenge_raw()) {
+                warn!("slate from {} was signed over a challenge this connection was not given", from_address.stripped());
+                return Some(AsyncServer::stale_challenge_error());
+            }
+            return Some(AsyncServer::error(verification_error(&e, GrinboxError::InvalidSignature)));
+        }
+
+        if to_address.port == self.config.grinbox_port && to_address.domain == self.config.grinbox_domain {
             let signed_payload = SignedPayload {
                 str,
-                challenge: challenge_raw.to_string(),
+                challenge: challenge_raw,
                 signature,
             };
 
-            let signed_payload = serde_json::to_string(&signed_payload).unwrap();
+            let signed_payload = match serde_json::to_string(&signed_payload) {
+                Ok(signed_payload) => signed_payload,
+                Err(e) => {
+                    error!("could not serialize signed payload: {}", e);
+                    return Some(AsyncServer::error(GrinboxError::UnknownError));
+                }
+            };
+
+            let receipt_sender = if self.config.publish_receipts {
+                match self.receipt_sender() {
+                    Some(receipt_sender) => Some(receipt_sender),
+                    None => return Some(AsyncServer::error(GrinboxError::UnknownError)),
+                }
+            } else {
+                None
+            };
+            let confirmed_later = receipt_sender.is_some();
 
             if self
                 .nats_sender
@@ -294,141 +999,203 @@ impl AsyncServer {
                     payload: signed_payload,
                     reply_to: from_address.stripped(),
                     message_expiration_in_seconds,
+                    correlation_id: correlation_id.clone(),
+                    receipt_sender,
                 })
                 .is_err()
                 {
                     error!("could not post message to broker!");
-                    return AsyncServer::error(GrinboxError::UnknownError);
+                    return Some(AsyncServer::error(GrinboxError::UnknownError));
                 };
 
-            AsyncServer::ok()
+            if let Some(correlation_id) = correlation_id {
+                info!("[{}] published slate with correlation id {}", self.id.bright_green(), correlation_id);
+            }
+            self.metrics.message_posted();
+
+            // The broker answers through the receipt handler once it has the slate.
+            if confirmed_later {
+                return None;
+            }
+            Some(AsyncServer::posted(message_expiration_in_seconds))
         } else {
-            self.post_slate_federated(&from_address, &to_address, str, signature, message_expiration_in_seconds)
+            let from_address = federated_sender_address(&from, &from_address, &self.config);
+            let challenge = if challenge_raw.is_empty() { None } else { Some(challenge_raw) };
+            self.post_slate_federated(from_address, to_address, str, signature, challenge, message_expiration_in_seconds, correlation_id, hops)
         }
     }
 
-    fn post_slate_federated(&self, from_address: &GrinboxAddress, to_address: &GrinboxAddress, str: String, signature: String, message_expiration_in_seconds: Option<u32>) -> GrinboxResponse {
-        let url = match self.grinbox_protocol_unsecure {
-            false => format!(
-                "wss://{}:{}",
-                to_address.domain,
-                to_address.port
-            ),
-            true => format!(
-                "ws://{}:{}",
-                to_address.domain,
-                to_address.port
-            )
-        };
-
-        let str = str.clone();
-        let signature = signature.clone();
-        let result = connect(url, move |sender| {
-            let str = str.clone();
-            let signature = signature.clone();
-            move |msg: Message| {
-                let response = serde_json::from_str::<GrinboxResponse>(&msg.to_string())
-                    .expect("could not parse response!");
+    /// Forwards the slate from the federation pool, so a slow remote relay does not hold up this
+    /// connection's other requests. The remote relay's answer is sent to the client once known;
+    /// only a full pool is answered right away, with `FederationBusy`.
+    fn post_slate_federated(&self, from_address: GrinboxAddress, to_address: GrinboxAddress, str: String, signature: String, challenge: Option<String>, message_expiration_in_seconds: Option<u32>, correlation_id: Option<String>, hops: Option<u8>) -> Option<GrinboxResponse> {
+        if let Some(ref correlation_id) = correlation_id {
+            info!("[{}] forwarding slate with correlation id {} to {}", self.id.bright_green(), correlation_id, to_address.stripped());
+        }
 
-                match response {
-                    GrinboxResponse::Challenge { str: _ } => {
-                        let request = GrinboxRequest::PostSlate {
-                            from: from_address.stripped(),
-                            to: to_address.stripped(),
-                            str: str.clone(),
-                            signature: signature.clone(),
-                            message_expiration_in_seconds,
-                        };
-
-                        sender
-                            .send(serde_json::to_string(&request).unwrap())
-                            .unwrap();
-                    }
-                    GrinboxResponse::Error {
-                        kind: _,
-                        description: _,
-                    } => {
-                        sender.close(CloseCode::Abnormal).is_ok();
-                    }
-                    GrinboxResponse::Ok => {
-                        sender.close(CloseCode::Normal).is_ok();
-                    }
-                    _ => {}
+        let federator = self.federator.clone();
+        let config = self.config.clone();
+        let inner = self.inner.clone();
+        let metrics = self.metrics.clone();
+        let to = to_address.stripped();
+        let submitted = self.federation_pool.submit(move || {
+            let bytes = str.len();
+            let result = federate(&*federator, &config, &from_address, &to_address, str, signature, challenge, message_expiration_in_seconds, correlation_id, hops);
+            metrics.federation_finished(result.rtt);
+            match result.outcome {
+                FederationOutcome::Accepted { .. } => {
+                    metrics.message_posted();
+                    metrics.message_federated(bytes);
                 }
-                Ok(())
+                FederationOutcome::Rejected { .. } => metrics.federation_rejected(),
+                FederationOutcome::TimedOut | FederationOutcome::Failed => metrics.federation_failed(),
+            }
+            let response = result.response();
+            let mut server = inner.lock().unwrap();
+            info!("[{}] <- {}", server.id.bright_green(), response);
+            if server.send(&response).is_err() {
+                error!("could not send federation result to client!");
             }
         });
 
-        match result {
-            Ok(()) => AsyncServer::ok(),
-            Err(_) => AsyncServer::error(GrinboxError::UnknownError),
+        match submitted {
+            Ok(()) => None,
+            Err(kind) => {
+                warn!("[{}] refusing to forward slate to {}: federation queue is full", self.id.bright_green(), to);
+                self.metrics.federation_rejected();
+                Some(AsyncServer::error(kind))
+            }
         }
     }
 }
 
+impl AsyncServer {
+    /// Answers `request`, or returns `None` when the answer is sent later on, as with slates
+    /// forwarded to another relay.
+    fn handle_request(&mut self, request: GrinboxRequest, now: Instant) -> Option<GrinboxResponse> {
+        let response = match request {
+            GrinboxRequest::Subscribe { .. } | GrinboxRequest::PostSlate { .. } if self.is_challenge_expired(now) => {
+                self.refuse_expired_challenge(now)
+            }
+            GrinboxRequest::Challenge => self.reissue_challenge(now),
+            GrinboxRequest::Info => self.get_info(),
+            GrinboxRequest::Subscribe { address, signature } => {
+                self.subscribe(address, signature)
+            }
+            GrinboxRequest::PostSlate {
+                from,
+                to,
+                str,
+                signature,
+                message_expiration_in_seconds,
+                correlation_id,
+                hops,
+                challenge,
+            } => return self.post_slate(from, to, str, signature, challenge, message_expiration_in_seconds, correlation_id, hops),
+            GrinboxRequest::Unsubscribe { address } => self.unsubscribe(address),
+            GrinboxRequest::UnsubscribeById { subscription_id } => self.unsubscribe_by_id(subscription_id),
+        };
+        Some(response)
+    }
+}
+
 impl Handler for AsyncServer {
     fn on_request(&mut self, req: &Request) -> WsResult<Response> {
+        let metrics_token = match self.config.metrics_token {
+            Some(ref metrics_token) if req.method() == "GET" && req.resource() == "/metrics" => Some(metrics_token.clone()),
+            _ => None,
+        };
+        if let Some(metrics_token) = metrics_token {
+            if !is_metrics_authorized(req.headers(), &metrics_token) {
+                let mut response = Response::new(401, "Unauthorized", vec![]);
+                response
+                    .headers_mut()
+                    .push(("WWW-Authenticate".to_string(), b"Bearer".to_vec()));
+                return Ok(response);
+            }
+            let mut response = Response::new(200, "OK", self.metrics.render().into_bytes());
+            response
+                .headers_mut()
+                .push(("Content-Type".to_string(), b"text/plain; version=0.0.4".to_vec()));
+            return Ok(response);
+        }
+
         let res = Response::from_request(req);
         if let Err(_) = res {
             let response = Response::new(200, "", vec![]);
             Ok(response)
         } else {
-            Ok(res.unwrap())
+            let mut response = res.unwrap();
+            // Clients asking for no subprotocol, or none we know, keep talking JSON.
+            if let Some(serializer) = req.protocols().ok().and_then(|protocols| serializer_for_subprotocols(&protocols)) {
+                debug!("[{}] using subprotocol {}", self.id.bright_green(), serializer.subprotocol());
+                response.set_protocol(serializer.subprotocol());
+                self.inner.lock().unwrap().serializer = std::sync::Arc::from(serializer);
+            }
+            Ok(response)
         }
     }
 
-    fn on_open(&mut self, _: Handshake) -> WsResult<()> {
+    fn on_open(&mut self, handshake: Handshake) -> WsResult<()> {
         info!(
             "[{}] {}",
             self.id.bright_green(),
             "connection established".bright_purple()
         );
 
+        if let (Some(limiter), Some(peer_addr)) = (self.connection_limiter.as_ref(), handshake.peer_addr) {
+            if !limiter.allow(peer_addr.ip(), Instant::now()) {
+                warn!("[{}] refusing connection from {}: too many connections", self.id.bright_green(), peer_addr.ip());
+                return self.inner.lock().unwrap().close(CONNECTION_RATE_LIMITED, "too many connections");
+            }
+        }
+
+        self.opened = true;
+        self.metrics.connection_opened();
+
+        self.authenticated = self.authenticator.authenticate(handshake.request.headers());
+        self.federation_peer = is_federation_peer(handshake.request.headers(), self.config.federation_peer_token.as_ref());
+        if self.federation_peer {
+            info!("[{}] connection from a federation peer", self.id.bright_green());
+        }
+        if !self.authenticated {
+            info!("[{}] connection not authenticated, subscriptions and slates will be refused", self.id.bright_green());
+        }
+
         let response = self.get_challenge();
         debug!("[{}] <- {}", self.id.bright_green(), response);
-        let server = self.inner.lock().unwrap();
-        if server
-            .out
-            .send(serde_json::to_string(&response).unwrap())
-            .is_err()
-        {
+        if let Some(ref idle_reaper) = self.idle_reaper {
+            idle_reaper.register(&self.id, self.inner.clone(), Instant::now());
+        }
+        let mut server = self.inner.lock().unwrap();
+        if server.send(&response).is_err() {
             error!("could not send challenge to client!");
         };
         Ok(())
     }
 
     fn on_message(&mut self, msg: Message) -> WsResult<()> {
-        let request = serde_json::from_str(&msg.to_string());
-
-        let response = if request.is_ok() {
-            let request = request.unwrap();
-            info!("[{}] -> {}", self.id.bright_green(), request);
-            match request {
-                GrinboxRequest::Challenge => self.get_challenge(),
-                GrinboxRequest::Subscribe { address, signature } => {
-                    self.subscribe(address, signature)
+        if let Some(ref idle_reaper) = self.idle_reaper {
+            idle_reaper.touch(&self.id, Instant::now());
+        }
+
+        let serializer = self.inner.lock().unwrap().serializer.clone();
+        let request = parse_request(msg, &*serializer, &self.metrics);
+
+        let response = match request {
+            Some(request) => {
+                info!("[{}] -> {}", self.id.bright_green(), request);
+                match self.handle_request(request, Instant::now()) {
+                    Some(response) => response,
+                    None => return Ok(()),
                 }
-                GrinboxRequest::PostSlate {
-                    from,
-                    to,
-                    str,
-                    signature,
-                    message_expiration_in_seconds,
-                } => self.post_slate(from, to, str, signature, message_expiration_in_seconds),
-                GrinboxRequest::Unsubscribe { address } => self.unsubscribe(address),
             }
-        } else {
-            debug!(
-                "[{}] -> {}",
-                self.id.bright_green(),
-                "invalid request!".bright_red()
-            );
-            AsyncServer::error(GrinboxError::InvalidRequest)
+            None => AsyncServer::error(GrinboxError::InvalidRequest),
         };
 
         info!("[{}] <- {}", self.id.bright_green(), response);
-        let server = self.inner.lock().unwrap();
-        server.out.send(serde_json::to_string(&response).unwrap())
+        let mut server = self.inner.lock().unwrap();
+        server.send(&response)
     }
 
     fn on_close(&mut self, code: CloseCode, _reason: &str) {
@@ -441,7 +1208,1767 @@ impl Handler for AsyncServer {
         );
     }
 
+    fn on_frame(&mut self, frame: Frame) -> WsResult<Option<Frame>> {
+        // Same check as ws's default handler.
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(ws::Error::new(ws::ErrorKind::Protocol, "Encountered frame with reserved bits set."));
+        }
+
+        match frame.opcode() {
+            OpCode::Pong => {
+                self.inner.lock().unwrap().pong(frame.payload());
+                if let Some(ref idle_reaper) = self.idle_reaper {
+                    idle_reaper.touch(&self.id, Instant::now());
+                }
+                return Ok(Some(frame));
+            }
+            OpCode::Text | OpCode::Binary => self.message_bytes = frame.payload().len(),
+            OpCode::Continue => self.message_bytes += frame.payload().len(),
+            _ => return Ok(Some(frame)),
+        }
+
+        // ws holds on to the fragments until the last one arrives, so a message is refused as
+        // soon as its fragments add up past the cap rather than once it was reassembled.
+        if let Some(max_message_bytes) = self.config.max_message_bytes {
+            if self.message_bytes > max_message_bytes {
+                if self.message_bytes - frame.payload().len() <= max_message_bytes {
+                    warn!("[{}] rejected message: {}", self.id.bright_green(), GrinboxError::MessageTooLarge);
+                    self.inner.lock().unwrap().close(CloseCode::Size, "message too large")?;
+                }
+                return Ok(None);
+            }
+        }
+        Ok(Some(frame))
+    }
+
     fn on_error(&mut self, err: ws::Error) {
         error!("the server encountered an error: {:?}", err);
     }
 }
+
+/// Largest websocket frame header: two bytes, an eight byte extended length and a mask.
+const MAX_FRAME_HEADER_BYTES: usize = 14;
+
+/// Listener settings for `config`. With a message cap, ws refuses frames larger than it before
+/// reading their payload, and never buffers more than one such frame of unread input.
+pub fn listener_settings(config: &ServerConfig) -> ws::Settings {
+    let mut settings = ws::Settings::default();
+    if let Some(max_message_bytes) = config.max_message_bytes {
+        settings.max_fragment_size = max_message_bytes;
+        settings.in_buffer_capacity_hard_limit =
+            std::cmp::max(settings.in_buffer_capacity, max_message_bytes + MAX_FRAME_HEADER_BYTES);
+    }
+    settings
+}
+
+/// Binds a websocket listener to each of `addresses`, each building its connections with a
+/// copy of `factory`. Every address is bound before any listener runs, so one that cannot be
+/// bound is reported before the relay starts accepting anywhere.
+pub fn bind_listeners<F>(addresses: &[String], settings: ws::Settings, factory: F) -> WsResult<Vec<ws::WebSocket<F>>>
+where
+    F: ws::Factory + Clone,
+{
+    addresses
+        .iter()
+        .map(|address| {
+            ws::Builder::new()
+                .with_settings(settings)
+                .build(factory.clone())
+                .and_then(|listener| listener.bind(&address[..]))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use grinboxlib::types::{GRINBOX_ADDRESS_VERSION_MAINNET, GRINBOX_ADDRESS_VERSION_TESTNET};
+    use grinboxlib::utils::base58::FromBase58;
+    use grinboxlib::utils::crypto::{public_key_from_secret_key, sign_challenge, Base58, Hex};
+    use grinboxlib::utils::secp::{Secp256k1, SecretKey};
+    use crate::broker::{InMemoryBroker, MessageBroker};
+    use log::{self, LevelFilter, Log, Metadata, Record};
+
+    fn config() -> ServerConfig {
+        ServerConfig {
+            grinbox_domain: "relay.example.com".to_string(),
+            grinbox_port: 13420,
+            grinbox_protocol_unsecure: false,
+            max_challenges_per_minute: 10,
+            max_message_bytes: Some(64),
+            max_signed_bytes: Some(16),
+            max_slate_bytes: 262144,
+            max_slate_age: None,
+            reject_self_sends: false,
+            max_subscriptions: 1,
+            challenge_ttl: Duration::from_secs(60),
+            publish_receipts: false,
+            max_connection_backlog_bytes: None,
+            federation_peer_token: None,
+            metrics_token: None,
+        }
+    }
+
+    fn address(domain: &str, port: u16) -> GrinboxAddress {
+        GrinboxAddress {
+            public_key: "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN".to_string(),
+            domain: domain.to_string(),
+            port,
+            version_bytes: None,
+        }
+    }
+
+    #[test]
+    fn federated_sender_without_domain_replies_to_this_relay() {
+        let from = "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN";
+        let sender = federated_sender_address(from, &address("grinbox.io", 443), &config());
+        assert_eq!(
+            sender.stripped(),
+            "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN@relay.example.com:13420"
+        );
+    }
+
+    #[test]
+    fn federated_sender_with_domain_is_kept() {
+        let from = "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN@other.example.com";
+        let sender = federated_sender_address(from, &address("other.example.com", 443), &config());
+        assert_eq!(sender, address("other.example.com", 443));
+    }
+
+    #[test]
+    fn federated_slate_reaches_recipient_with_sender_relay() {
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut remote_config = config();
+        remote_config.grinbox_domain = "other.example.com".to_string();
+        remote_config.grinbox_port = 443;
+        remote_config.max_signed_bytes = None;
+
+        // The recipient is a client of the remote relay.
+        let (out, messages) = recorded_connection();
+        let (mut recipient_connection, recipient_handlers) = async_server_with_connection(
+            remote_config.clone(),
+            std::sync::Arc::new(WebsocketFederator::new()),
+            broker_sender.clone(),
+            out,
+        );
+        match subscribe_with_key(&mut recipient_connection, 2) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let (mut remote, _remote_handlers_receiver) =
+            async_server_with_broker(remote_config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender);
+        // As if the origin had presented the federation token when connecting.
+        remote.federation_peer = true;
+        let (responses_sender, responses) = std::sync::mpsc::channel();
+        let federator = RelayFederator {
+            relay: std::sync::Mutex::new(remote),
+            responses: std::sync::Mutex::new(responses_sender),
+        };
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut origin, _nats_receiver, _response_handlers_receiver) =
+            async_server_with_federator(config, std::sync::Arc::new(federator));
+        let origin_challenge = origin.get_challenge_raw().to_string();
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let sender = public_key_from_secret_key(&secret_key).unwrap().to_base58_check(version_bytes());
+        let recipient_key = SecretKey::from_slice(&secp, &[2; 32]).unwrap();
+        let recipient = GrinboxAddress::new(
+            public_key_from_secret_key(&recipient_key).unwrap(),
+            Some("other.example.com".to_string()),
+            Some(443),
+        );
+        // The sender is a client of the origin relay and does not name it.
+        let request = GrinboxRequest::PostSlate {
+            from: sender.clone(),
+            to: recipient.stripped(),
+            str: "slate".to_string(),
+            signature: sign_challenge(&format!("slate{}", origin_challenge), &secret_key).unwrap().to_hex(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge: Some(origin_challenge),
+        };
+        assert!(origin.handle_request(request, Instant::now()).is_none());
+        match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Some(GrinboxResponse::Ok { .. }) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let handler = recipient_handlers.wait().next().unwrap().unwrap();
+        AsyncServer::init().unbounded_send(handler).unwrap();
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        match JsonSerializer.decode_response(message).unwrap() {
+            GrinboxResponse::Slate { from, str, .. } => {
+                assert_eq!(str, "slate");
+                assert_eq!(from, format!("{}@relay.example.com:13420", sender));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn posting_over_max_expiration_reports_clamped_value() {
+        match AsyncServer::posted(Some(7 * 86400)) {
+            GrinboxResponse::Ok {
+                effective_expiration,
+                ..
+            } => assert_eq!(effective_expiration, Some(86400)),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn malformed_payloads_are_counted() {
+        let metrics = RelayMetrics::new();
+        assert!(parse_signed_payload("not a signed payload", &metrics).is_none());
+        assert!(parse_request(Message::text(r#"{"type":"NoSuchRequest"}"#), &JsonSerializer, &metrics).is_none());
+
+        let body = metrics.render();
+        assert!(body.contains("grinbox_messages_dropped_parse_total 1\n"));
+        assert!(body.contains("grinbox_requests_invalid_total 1\n"));
+    }
+
+    struct RecordingFederator {
+        posts: std::sync::Mutex<Vec<(String, GrinboxRequest)>>,
+    }
+
+    impl Federator for RecordingFederator {
+        fn post(&self, url: &str, request: GrinboxRequest) -> Result<GrinboxResponse> {
+            self.posts.lock().unwrap().push((url.to_string(), request));
+            Ok(GrinboxResponse::Ok {
+                effective_expiration: Some(600),
+                subscription_id: None,
+            })
+        }
+    }
+
+    #[test]
+    fn cross_relay_slate_is_posted_through_federator() {
+        let federator = RecordingFederator {
+            posts: std::sync::Mutex::new(vec![]),
+        };
+        let from = address("relay.example.com", 13420);
+        let to = address("other.example.com", 443);
+
+        let response = federate(&federator, &config(), &from, &to, "slate".to_string(), "signature".to_string(), None, Some(600), None, None).response();
+        match response {
+            GrinboxResponse::Ok {
+                effective_expiration,
+                ..
+            } => assert_eq!(effective_expiration, Some(600)),
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let posts = federator.posts.lock().unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].0, "wss://other.example.com:443");
+        match posts[0].1 {
+            GrinboxRequest::PostSlate {
+                ref from,
+                ref to,
+                ref str,
+                ref signature,
+                message_expiration_in_seconds,
+                hops,
+                ..
+            } => {
+                assert_eq!(hops, Some(DEFAULT_FEDERATION_HOPS - 1));
+                assert_eq!(from, "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN@relay.example.com:13420");
+                assert_eq!(to, "xd8dyGUuP89t2TT9N9yU7pZsr6VyJAH6wZauESBQe67q741bVoaN@other.example.com");
+                assert_eq!(str, "slate");
+                assert_eq!(signature, "signature");
+                assert_eq!(message_expiration_in_seconds, Some(600));
+            }
+            ref request => panic!("unexpected request: {:?}", request),
+        }
+    }
+
+    struct RejectingFederator;
+
+    impl Federator for RejectingFederator {
+        fn post(&self, _url: &str, _request: GrinboxRequest) -> Result<GrinboxResponse> {
+            Ok(AsyncServer::error(GrinboxError::InvalidSignature))
+        }
+    }
+
+    #[test]
+    fn federation_result_tells_rejection_from_acceptance() {
+        let from = address("relay.example.com", 13420);
+        let to = address("other.example.com", 443);
+
+        let accepting = RecordingFederator {
+            posts: std::sync::Mutex::new(vec![]),
+        };
+        let result = federate(&accepting, &config(), &from, &to, "slate".to_string(), "signature".to_string(), None, Some(600), None, None);
+        assert_eq!(result.outcome, FederationOutcome::Accepted { effective_expiration: Some(600) });
+
+        let result = federate(&RejectingFederator, &config(), &from, &to, "slate".to_string(), "signature".to_string(), None, Some(600), None, None);
+        match result.outcome {
+            FederationOutcome::Rejected { ref kind, .. } => assert_eq!(*kind, GrinboxError::InvalidSignature),
+            ref outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        match result.response() {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::InvalidSignature),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn oversized_slate_is_not_federated() {
+        let federator = RecordingFederator {
+            posts: std::sync::Mutex::new(vec![]),
+        };
+        let mut config = config();
+        config.max_slate_bytes = 8;
+        let from = address("relay.example.com", 13420);
+        let to = address("other.example.com", 443);
+
+        let response = federate(&federator, &config, &from, &to, "a".repeat(9), "signature".to_string(), None, None, None, None).response();
+        match response {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::MessageTooLarge),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert!(federator.posts.lock().unwrap().is_empty());
+    }
+
+    struct BlockedFederator {
+        release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+        posts: std::sync::mpsc::Sender<String>,
+    }
+
+    impl Federator for BlockedFederator {
+        fn post(&self, url: &str, _request: GrinboxRequest) -> Result<GrinboxResponse> {
+            self.release.lock().unwrap().recv().unwrap();
+            self.posts.send(url.to_string()).unwrap();
+            Ok(AsyncServer::ok())
+        }
+    }
+
+    #[test]
+    fn federated_slate_does_not_block_the_connection() {
+        let (release, released) = std::sync::mpsc::channel();
+        let (posted, posts) = std::sync::mpsc::channel();
+        let federator = BlockedFederator {
+            release: std::sync::Mutex::new(released),
+            posts: posted,
+        };
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) =
+            async_server_with_federator(config, std::sync::Arc::new(federator));
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let request = GrinboxRequest::PostSlate {
+            from: public_key.to_base58_check(version_bytes()),
+            to: address("other.example.com", 443).stripped(),
+            str: "slate".to_string(),
+            signature: sign_challenge("slate", &secret_key).unwrap().to_hex(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge: None,
+        };
+
+        assert!(server.handle_request(request, Instant::now()).is_none());
+        match server.handle_request(GrinboxRequest::Info, Instant::now()) {
+            Some(GrinboxResponse::Info { .. }) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        release.send(()).unwrap();
+        assert_eq!(posts.recv_timeout(Duration::from_secs(5)).unwrap(), "wss://other.example.com:443");
+    }
+
+    /// Hands forwarded requests straight to another relay's connection, reporting its answers.
+    struct RelayFederator {
+        relay: std::sync::Mutex<AsyncServer>,
+        responses: std::sync::Mutex<std::sync::mpsc::Sender<Option<GrinboxResponse>>>,
+    }
+
+    impl Federator for RelayFederator {
+        fn post(&self, _url: &str, request: GrinboxRequest) -> Result<GrinboxResponse> {
+            let response = self.relay.lock().unwrap().handle_request(request, Instant::now());
+            self.responses.lock().unwrap().send(response).unwrap();
+            Ok(AsyncServer::ok())
+        }
+    }
+
+    #[test]
+    fn federated_slate_is_verified_against_origin_challenge() {
+        let mut remote_config = config();
+        remote_config.grinbox_domain = "other.example.com".to_string();
+        remote_config.grinbox_port = 443;
+        remote_config.max_signed_bytes = None;
+        let (mut remote, remote_nats_receiver, _remote_handlers_receiver) = async_server(remote_config);
+        // As if the origin had presented the federation token when connecting.
+        remote.federation_peer = true;
+        let remote_challenge = remote.get_challenge_raw().to_string();
+
+        let (responses_sender, responses) = std::sync::mpsc::channel();
+        let federator = RelayFederator {
+            relay: std::sync::Mutex::new(remote),
+            responses: std::sync::Mutex::new(responses_sender),
+        };
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut origin, _nats_receiver, _response_handlers_receiver) =
+            async_server_with_federator(config, std::sync::Arc::new(federator));
+        let origin_challenge = origin.get_challenge_raw().to_string();
+        assert_ne!(origin_challenge, remote_challenge);
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let recipient_key = SecretKey::from_slice(&secp, &[2; 32]).unwrap();
+        let recipient = GrinboxAddress::new(
+            public_key_from_secret_key(&recipient_key).unwrap(),
+            Some("other.example.com".to_string()),
+            Some(443),
+        );
+        let request = GrinboxRequest::PostSlate {
+            from: public_key.to_base58_check(version_bytes()),
+            to: recipient.stripped(),
+            str: "slate".to_string(),
+            signature: sign_challenge(&format!("slate{}", origin_challenge), &secret_key).unwrap().to_hex(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge: Some(origin_challenge.clone()),
+        };
+        assert!(origin.handle_request(request, Instant::now()).is_none());
+
+        match responses.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Some(GrinboxResponse::Ok { .. }) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        match remote_nats_receiver.wait().next() {
+            Some(Ok(BrokerRequest::PostMessage { payload, .. })) => {
+                // The recipient can check the signature against the challenge it is handed.
+                let signed_payload: SignedPayload = serde_json::from_str(&payload).unwrap();
+                assert_eq!(signed_payload.challenge, origin_challenge);
+            }
+            request => panic!("unexpected request: {:?}", request),
+        }
+    }
+
+    #[test]
+    fn direct_client_cannot_claim_a_forwarded_challenge() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_message_bytes = None;
+        config.federation_peer_token = Some("peers".to_string());
+        let (nats_sender, nats_receiver) = unbounded();
+        let (out, messages) = recorded_connection();
+        let (mut server, _response_handlers_receiver) =
+            async_server_with_connection(config, std::sync::Arc::new(WebsocketFederator::new()), nats_sender, out);
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let address = format!("{}@relay.example.com:13420", public_key.to_base58_check(version_bytes()));
+        let request = GrinboxRequest::PostSlate {
+            from: address.clone(),
+            to: address,
+            str: "slate".to_string(),
+            signature: sign_challenge("slateforged", &secret_key).unwrap().to_hex(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: Some(2),
+            challenge: Some("forged".to_string()),
+        };
+        server.on_message(Message::text(serde_json::to_string(&request).unwrap())).unwrap();
+
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        match JsonSerializer.decode_response(message).unwrap() {
+            GrinboxResponse::Error { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        drop(server);
+        let posts = nats_receiver
+            .wait()
+            .filter(|request| match request {
+                Ok(BrokerRequest::PostMessage { .. }) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(posts, 0);
+    }
+
+    #[test]
+    fn federation_peers_are_recognized_by_token() {
+        let headers = |token: &str| vec![(FEDERATION_PEER_TOKEN_HEADER.to_lowercase(), token.as_bytes().to_vec())];
+        let peer_token = "peers".to_string();
+        assert!(is_federation_peer(&headers("peers"), Some(&peer_token)));
+        assert!(!is_federation_peer(&headers("other"), Some(&peer_token)));
+        assert!(!is_federation_peer(&headers("peers"), None));
+        assert!(!is_federation_peer(&[], Some(&peer_token)));
+    }
+
+    #[test]
+    fn slate_out_of_hops_is_not_federated() {
+        let federator = RecordingFederator {
+            posts: std::sync::Mutex::new(vec![]),
+        };
+        let from = address("relay.example.com", 13420);
+        let to = address("other.example.com", 443);
+
+        let response = federate(&federator, &config(), &from, &to, "slate".to_string(), "signature".to_string(), None, None, None, Some(0)).response();
+        match response {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::UnknownError),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert!(federator.posts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn over_length_challenge_is_rejected_before_verification() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let key = Base58Key::from_str(&public_key.to_base58_check(version_bytes())).unwrap();
+        let max_signed_bytes = config().max_signed_bytes;
+
+        let challenge = "a".repeat(16);
+        let signature = HexSignature::from_str(&sign_challenge(&challenge, &secret_key).unwrap().to_hex()).unwrap();
+        assert!(verify_bounded_signature(&key, &Challenge::new(&challenge), &signature, max_signed_bytes).is_ok());
+
+        let challenge = "a".repeat(17);
+        let signature = HexSignature::from_str(&sign_challenge(&challenge, &secret_key).unwrap().to_hex()).unwrap();
+        let error = verify_bounded_signature(&key, &Challenge::new(&challenge), &signature, max_signed_bytes).unwrap_err();
+        assert_eq!(verification_error(&error, GrinboxError::InvalidSignature), GrinboxError::InvalidRequest);
+    }
+
+    #[test]
+    fn slate_signed_over_previous_challenge_gets_invalid_challenge() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let address = format!("{}@relay.example.com:13420", public_key.to_base58_check(version_bytes()));
+
+        // The client fetched a challenge, then the relay handed out another one before the post
+        // arrived, as it does after a restart.
+        let fetched = server.get_challenge_raw().to_string();
+        server.rotate_challenge(Instant::now());
+        assert_ne!(server.get_challenge_raw(), fetched);
+
+        let request = |challenge: Option<String>| GrinboxRequest::PostSlate {
+            from: address.clone(),
+            to: address.clone(),
+            str: "slate".to_string(),
+            signature: sign_challenge(&format!("slate{}", fetched), &secret_key).unwrap().to_hex(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge,
+        };
+
+        match server.handle_request(request(Some(fetched.clone())), Instant::now()) {
+            Some(GrinboxResponse::Error { kind, description, .. }) => {
+                assert_eq!(kind, GrinboxError::InvalidChallenge);
+                assert!(description.contains("request a new challenge"));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        // Clients that do not say which challenge they signed are told as before.
+        match server.handle_request(request(None), Instant::now()) {
+            Some(GrinboxResponse::Error { kind, .. }) => assert_eq!(kind, GrinboxError::InvalidSignature),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn verification_infrastructure_errors_are_retried() {
+        let mut attempts = 0;
+        let error = verify_with_retries(|| {
+            attempts += 1;
+            Err(ErrorKind::SecpError.into())
+        })
+        .unwrap_err();
+        assert_eq!(attempts, SIGNATURE_VERIFICATION_ATTEMPTS);
+        let kind = verification_error(&error, GrinboxError::InvalidSignature);
+        assert_eq!(kind, GrinboxError::UnknownError);
+        assert!(kind.is_retryable());
+
+        let mut attempts = 0;
+        assert!(verify_with_retries(|| {
+            attempts += 1;
+            if attempts < 2 {
+                Err(ErrorKind::SecpError.into())
+            } else {
+                Ok(())
+            }
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn mismatching_signature_is_not_retried() {
+        let mut attempts = 0;
+        let error = verify_with_retries(|| {
+            attempts += 1;
+            Err(ErrorKind::IncorrectSignature.into())
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+        let kind = verification_error(&error, GrinboxError::InvalidSignature);
+        assert_eq!(kind, GrinboxError::InvalidSignature);
+        assert!(!kind.is_retryable());
+    }
+
+    #[test]
+    fn challenge_advertises_relay_identity() {
+        let relay = relay_identity(&config());
+        let challenge = GrinboxResponse::Challenge {
+            str: "challenge".to_string(),
+            relay: Some(relay),
+        };
+        let json: serde_json::Value = serde_json::to_value(&challenge).unwrap();
+        assert_eq!(json["relay"]["domain"], "relay.example.com");
+        assert_eq!(json["relay"]["port"], 13420);
+        assert_eq!(json["relay"]["network"], network_name());
+        assert_eq!(json["relay"]["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn self_sends_are_only_refused_when_configured() {
+        let sender = address("relay.example.com", 13420);
+        let mut other = sender.clone();
+        other.public_key = "xQ7PpcUdsBCY7TZqsUvk2zKaxmVQwJrgfDQ1AHrXXNK5mo7yN3Bd".to_string();
+
+        let mut config = config();
+        assert_eq!(self_send_error(&sender, &sender, &config), None);
+
+        config.reject_self_sends = true;
+        assert_eq!(self_send_error(&sender, &sender, &config), Some(GrinboxError::InvalidRequest));
+        assert_eq!(self_send_error(&sender, &other, &config), None);
+    }
+
+    #[test]
+    fn failed_delivery_is_returned_for_redelivery() {
+        let (broker_sender, broker_receiver) = unbounded();
+        let metrics = RelayMetrics::new();
+
+        acknowledge_delivery(&broker_sender, Some("ack-1".to_string()), false, &metrics);
+        acknowledge_delivery(&broker_sender, Some("ack-2".to_string()), true, &metrics);
+        acknowledge_delivery(&broker_sender, None, true, &metrics);
+        drop(broker_sender);
+
+        assert!(metrics.render().contains("grinbox_deliveries_failed_total 1\n"));
+        let requests: Vec<BrokerRequest> = broker_receiver.wait().map(|request| request.unwrap()).collect();
+        assert_eq!(requests.len(), 2);
+        match requests[0] {
+            BrokerRequest::Acknowledge { ref ack_id, delivered } => {
+                assert_eq!(ack_id, "ack-1");
+                assert!(!delivered);
+            }
+            ref request => panic!("unexpected request: {:?}", request),
+        }
+        match requests[1] {
+            BrokerRequest::Acknowledge { ref ack_id, delivered } => {
+                assert_eq!(ack_id, "ack-2");
+                assert!(delivered);
+            }
+            ref request => panic!("unexpected request: {:?}", request),
+        }
+    }
+
+    /// The server side sender of a live websocket connection, for tests that need a real
+    /// `AsyncServer`.
+    fn connected_sender() -> Sender {
+        recorded_connection().0
+    }
+
+    /// Like `connected_sender`, also returning what the client receives.
+    fn recorded_connection() -> (Sender, std::sync::mpsc::Receiver<Message>) {
+        let (senders, connected) = std::sync::mpsc::channel();
+        let relay = ws::WebSocket::new(move |out: Sender| {
+            senders.send(out).unwrap();
+            |_msg: Message| -> WsResult<()> { Ok(()) }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+        let (messages_sender, messages) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            ws::connect(url, |_out| {
+                let messages_sender = messages_sender.clone();
+                move |msg: Message| -> WsResult<()> {
+                    let _ = messages_sender.send(msg);
+                    Ok(())
+                }
+            })
+            .unwrap()
+        });
+
+        (connected.recv_timeout(Duration::from_secs(5)).unwrap(), messages)
+    }
+
+    fn async_server(config: ServerConfig) -> (AsyncServer, UnboundedReceiver<BrokerRequest>, UnboundedReceiver<BrokerResponseHandler>) {
+        async_server_with_federator(config, std::sync::Arc::new(WebsocketFederator::new()))
+    }
+
+    fn async_server_with_federator(config: ServerConfig, federator: std::sync::Arc<dyn Federator>) -> (AsyncServer, UnboundedReceiver<BrokerRequest>, UnboundedReceiver<BrokerResponseHandler>) {
+        let (nats_sender, nats_receiver) = unbounded();
+        let (server, response_handlers_receiver) = async_server_with_broker(config, federator, nats_sender);
+        (server, nats_receiver, response_handlers_receiver)
+    }
+
+    fn async_server_with_broker(config: ServerConfig, federator: std::sync::Arc<dyn Federator>, nats_sender: UnboundedSender<BrokerRequest>) -> (AsyncServer, UnboundedReceiver<BrokerResponseHandler>) {
+        async_server_with_connection(config, federator, nats_sender, connected_sender())
+    }
+
+    fn async_server_with_connection(config: ServerConfig, federator: std::sync::Arc<dyn Federator>, nats_sender: UnboundedSender<BrokerRequest>, out: Sender) -> (AsyncServer, UnboundedReceiver<BrokerResponseHandler>) {
+        let (response_handlers_sender, response_handlers_receiver) = unbounded();
+        let metrics = std::sync::Arc::new(RelayMetrics::new());
+        let mut server = AsyncServer::new(
+            out,
+            nats_sender,
+            response_handlers_sender,
+            config,
+            std::sync::Arc::new(DefaultIdGenerator),
+            std::sync::Arc::new(DefaultAuthenticator),
+            federator,
+            std::sync::Arc::new(FederationPool::new(2, 16, metrics.clone())),
+            None,
+            None,
+            metrics,
+        );
+        server.authenticated = true;
+        (server, response_handlers_receiver)
+    }
+
+    fn subscribe_request(server: &AsyncServer, secret: u8) -> GrinboxRequest {
+        subscribe_to_challenge(server.get_challenge_raw(), secret)
+    }
+
+    fn subscribe_to_challenge(challenge: &str, secret: u8) -> GrinboxRequest {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        GrinboxRequest::Subscribe {
+            address: public_key.to_base58_check(version_bytes()),
+            signature: sign_challenge(challenge, &secret_key).unwrap().to_hex(),
+        }
+    }
+
+    fn subscribe_with_key(server: &mut AsyncServer, secret: u8) -> GrinboxResponse {
+        let request = subscribe_request(server, secret);
+        server.handle_request(request, Instant::now()).unwrap()
+    }
+
+    #[test]
+    fn subscriptions_are_capped_by_config() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_subscriptions = 2;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        for secret in 1..3 {
+            match subscribe_with_key(&mut server, secret) {
+                GrinboxResponse::Ok { subscription_id, .. } => assert!(subscription_id.is_some()),
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+
+        match subscribe_with_key(&mut server, 3) {
+            GrinboxResponse::Error { kind, description, limit, .. } => {
+                assert_eq!(kind, GrinboxError::TooManySubscriptions);
+                assert_eq!(limit, Some(ErrorLimit { max: 2, current: 2 }));
+                assert!(description.contains("2 of 2"));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn resubscribing_same_address_reuses_consumer() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_subscriptions = 1;
+        let (mut server, nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let mut subscription_ids = vec![];
+        for _ in 0..2 {
+            match subscribe_with_key(&mut server, 1) {
+                GrinboxResponse::Ok { subscription_id, .. } => subscription_ids.push(subscription_id.unwrap()),
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+        assert_eq!(subscription_ids[0], subscription_ids[1]);
+        drop(server);
+
+        let subscribes = nats_receiver
+            .wait()
+            .filter(|request| match request {
+                Ok(BrokerRequest::Subscribe { .. }) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(subscribes, 1);
+    }
+
+    #[test]
+    fn dropping_server_unsubscribes_every_subscription() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_subscriptions = 2;
+        let (mut server, nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        subscribe_with_key(&mut server, 1);
+        subscribe_with_key(&mut server, 2);
+        drop(server);
+
+        let requests: Vec<BrokerRequest> = nats_receiver.wait().map(|request| request.unwrap()).collect();
+        let mut subscribed: Vec<String> = requests
+            .iter()
+            .filter_map(|request| match request {
+                BrokerRequest::Subscribe { id, .. } => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut unsubscribed: Vec<String> = requests
+            .iter()
+            .filter_map(|request| match request {
+                BrokerRequest::Unsubscribe { id } => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+
+        subscribed.sort();
+        unsubscribed.sort();
+        assert_eq!(unsubscribed.len(), 2);
+        assert_eq!(unsubscribed, subscribed);
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let mut config = config();
+        config.metrics_token = Some("scraper".to_string());
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+        let request = Request::from_bytes(
+            b"GET /metrics HTTP/1.1\r\nHost: relay.example.com\r\nAuthorization: Bearer scraper\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+
+        let response = server.on_request(&request).unwrap();
+        assert_eq!(response.status(), 200);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("grinbox_active_connections 0\n"));
+        assert!(body.contains("grinbox_messages_posted_total 0\n"));
+    }
+
+    #[test]
+    fn metrics_require_the_token() {
+        let mut config = config();
+        config.metrics_token = Some("scraper".to_string());
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let anonymous = Request::from_bytes(b"GET /metrics HTTP/1.1\r\nHost: relay.example.com\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.on_request(&anonymous).unwrap().status(), 401);
+
+        let wrong = Request::from_bytes(
+            b"GET /metrics HTTP/1.1\r\nHost: relay.example.com\r\nAuthorization: Bearer guess\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        let response = server.on_request(&wrong).unwrap();
+        assert_eq!(response.status(), 401);
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn metrics_are_not_served_without_a_token() {
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config());
+        let request = Request::from_bytes(b"GET /metrics HTTP/1.1\r\nHost: relay.example.com\r\n\r\n")
+            .unwrap()
+            .unwrap();
+
+        let response = server.on_request(&request).unwrap();
+        assert!(!String::from_utf8_lossy(response.body()).contains("grinbox_active_connections"));
+    }
+
+    fn post_with_key(server: &mut AsyncServer, secret: u8, str: String) -> GrinboxResponse {
+        server.handle_request(post_request(secret, str), Instant::now()).unwrap()
+    }
+
+    fn post_request(secret: u8, str: String) -> GrinboxRequest {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[secret; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let address = format!("{}@relay.example.com:13420", public_key.to_base58_check(version_bytes()));
+        GrinboxRequest::PostSlate {
+            from: address.clone(),
+            to: address,
+            signature: sign_challenge(&str, &secret_key).unwrap().to_hex(),
+            str,
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge: None,
+        }
+    }
+
+    #[test]
+    fn posted_slate_reaches_subscriber_through_broker() {
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, response_handlers_receiver) =
+            async_server_with_broker(config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender);
+
+        match subscribe_with_key(&mut server, 1) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        match post_with_key(&mut server, 1, "slate".to_string()) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let handler = response_handlers_receiver.wait().next().unwrap().unwrap();
+        match handler.response_receiver.wait().next() {
+            Some(Ok(BrokerResponse::Message { payload, reply_to, .. })) => {
+                let signed_payload: SignedPayload = serde_json::from_str(&payload).unwrap();
+                assert_eq!(signed_payload.str, "slate");
+                assert!(reply_to.ends_with("@relay.example.com:13420"));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    /// Keeps every line logged, so tests can check what the relay logs.
+    struct CapturingLogger {
+        lines: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl Log for CapturingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.lines.lock().unwrap().push(format!("{}", record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Installs the `CapturingLogger` the first time it is asked for. Tests run in parallel, so
+    /// lines must be looked for by something unique to the test.
+    fn captured_logs() -> &'static CapturingLogger {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        static mut LOGGER: Option<&'static CapturingLogger> = None;
+        unsafe {
+            INIT.call_once(|| {
+                let logger: &'static CapturingLogger = Box::leak(Box::new(CapturingLogger {
+                    lines: std::sync::Mutex::new(Vec::new()),
+                }));
+                log::set_logger(logger).unwrap();
+                log::set_max_level(LevelFilter::Info);
+                LOGGER = Some(logger);
+            });
+            LOGGER.unwrap()
+        }
+    }
+
+    fn logged(logs: &CapturingLogger, line: &str) -> bool {
+        logs.lines.lock().unwrap().iter().any(|logged| logged.contains(line))
+    }
+
+    #[test]
+    fn correlation_id_follows_slate_to_subscriber() {
+        let logs = captured_logs();
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (out, messages) = recorded_connection();
+        let (mut server, response_handlers_receiver) =
+            async_server_with_connection(config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender, out);
+
+        match subscribe_with_key(&mut server, 1) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        let mut request = post_request(1, "slate".to_string());
+        if let GrinboxRequest::PostSlate { ref mut correlation_id, .. } = request {
+            *correlation_id = Some("trace-7f3a".to_string());
+        }
+        match server.handle_request(request, Instant::now()) {
+            Some(GrinboxResponse::Ok { .. }) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert!(logged(logs, "published slate with correlation id trace-7f3a"));
+
+        let handler = response_handlers_receiver.wait().next().unwrap().unwrap();
+        AsyncServer::init().unbounded_send(handler).unwrap();
+        let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+        match JsonSerializer.decode_response(message).unwrap() {
+            GrinboxResponse::Slate { str, correlation_id, .. } => {
+                assert_eq!(str, "slate");
+                assert_eq!(correlation_id, Some("trace-7f3a".to_string()));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert!(logged(logs, "delivered slate with correlation id trace-7f3a"));
+    }
+
+    #[test]
+    fn malformed_correlation_id_is_refused_before_logging() {
+        let logs = captured_logs();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let too_long = "a".repeat(MAX_CORRELATION_ID_LENGTH + 1);
+        for correlation_id in &["trace\nforged log line", "trace:7f3a", "", too_long.as_str()] {
+            let mut request = post_request(1, "slate".to_string());
+            if let GrinboxRequest::PostSlate { correlation_id: ref mut id, .. } = request {
+                *id = Some(correlation_id.to_string());
+            }
+            match server.handle_request(request, Instant::now()) {
+                Some(GrinboxResponse::Error { kind: GrinboxError::InvalidRequest, .. }) => {}
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+        assert!(!logged(logs, "forged log line"));
+        assert!(is_valid_correlation_id("trace-7f3a_v1.2"));
+    }
+
+    #[test]
+    fn slates_report_the_full_address_of_their_subscription() {
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_subscriptions = 2;
+        let (out, messages) = recorded_connection();
+        let (mut server, response_handlers_receiver) =
+            async_server_with_connection(config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender, out);
+
+        let mut recipients = HashMap::new();
+        for (secret, str) in &[(1, "first"), (2, "second")] {
+            match subscribe_with_key(&mut server, *secret) {
+                GrinboxResponse::Ok { .. } => {}
+                response => panic!("unexpected response: {:?}", response),
+            }
+            let request = post_request(*secret, str.to_string());
+            if let GrinboxRequest::PostSlate { ref to, .. } = request {
+                recipients.insert(str.to_string(), GrinboxAddress::from_str(to).unwrap());
+            }
+            match server.handle_request(request, Instant::now()) {
+                Some(GrinboxResponse::Ok { .. }) => {}
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+
+        let response_handlers = AsyncServer::init();
+        for handler in response_handlers_receiver.wait().take(2) {
+            response_handlers.unbounded_send(handler.unwrap()).unwrap();
+        }
+        for _ in 0..2 {
+            let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+            match JsonSerializer.decode_response(message).unwrap() {
+                GrinboxResponse::Slate { str, to, .. } => {
+                    // What a client gets is a whole address, not the bare key it subscribed with.
+                    let to = GrinboxAddress::from_str(&to.unwrap()).unwrap();
+                    assert_eq!(to, recipients[&str]);
+                    assert_eq!(to.domain, "relay.example.com");
+                }
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+    }
+
+    #[test]
+    fn posted_slate_is_confirmed_by_broker_receipt() {
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.publish_receipts = true;
+        let (mut server, response_handlers_receiver) =
+            async_server_with_broker(config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender);
+
+        assert!(server.handle_request(post_request(1, "slate".to_string()), Instant::now()).is_none());
+
+        let handler = response_handlers_receiver.wait().next().unwrap().unwrap();
+        match handler.response_receiver.wait().next() {
+            Some(Ok(BrokerResponse::Published { reply_to, .. })) => {
+                assert!(reply_to.ends_with("@relay.example.com:13420"));
+            }
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn slates_across_networks_are_refused() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+        let public_key = public_key_from_secret_key(&secret_key).unwrap();
+        let on_network = |version_bytes: &[u8]| {
+            GrinboxAddress::new_raw(public_key.clone(), Some("relay.example.com".to_string()), Some(13420), version_bytes.to_vec())
+                .stripped()
+        };
+
+        let request = GrinboxRequest::PostSlate {
+            from: on_network(&GRINBOX_ADDRESS_VERSION_MAINNET),
+            to: on_network(&GRINBOX_ADDRESS_VERSION_TESTNET),
+            signature: sign_challenge("slate", &secret_key).unwrap().to_hex(),
+            str: "slate".to_string(),
+            message_expiration_in_seconds: None,
+            correlation_id: None,
+            hops: None,
+            challenge: None,
+        };
+        match server.handle_request(request, Instant::now()).unwrap() {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::InvalidRequest),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn slates_over_max_bytes_are_refused() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_slate_bytes = 8;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        match post_with_key(&mut server, 1, "a".repeat(9)) {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::MessageTooLarge),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        match post_with_key(&mut server, 1, "a".repeat(7)) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn slates_sealed_outside_max_age_are_refused() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        config.max_slate_age = Some(Duration::from_secs(600));
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let sealed_at = |timestamp: u64| {
+            format!(r#"{{"encrypted_message":"00","salt":"00","nonce":"00","timestamp":{}}}"#, timestamp)
+        };
+
+        for stale in vec![sealed_at(now - 3600), sealed_at(now + 3600)] {
+            match post_with_key(&mut server, 1, stale) {
+                GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::InvalidRequest),
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+        for fresh in vec![sealed_at(now - 60), r#"{"encrypted_message":"00","salt":"00","nonce":"00"}"#.to_string()] {
+            match post_with_key(&mut server, 1, fresh) {
+                GrinboxResponse::Ok { .. } => {}
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+    }
+
+    #[test]
+    fn every_connection_gets_its_own_challenge() {
+        let (first, _first_nats_receiver, _first_handlers_receiver) = async_server(config());
+        let (second, _second_nats_receiver, _second_handlers_receiver) = async_server(config());
+
+        assert_ne!(first.get_challenge_raw(), second.get_challenge_raw());
+        assert!(first.get_challenge_raw().from_base58().unwrap().len() >= 32);
+    }
+
+    #[test]
+    fn connection_requesting_too_many_challenges_is_rate_limited() {
+        let mut config = config();
+        config.max_challenges_per_minute = 2;
+        let (nats_sender, _nats_receiver) = unbounded();
+        let (out, messages) = recorded_connection();
+        let (mut server, _response_handlers_receiver) =
+            async_server_with_connection(config, std::sync::Arc::new(WebsocketFederator::new()), nats_sender, out);
+
+        for _ in 0..3 {
+            server.on_message(Message::text(r#"{"type":"Challenge"}"#)).unwrap();
+        }
+
+        let mut responses = (0..3).map(|_| {
+            let message = messages.recv_timeout(Duration::from_secs(5)).unwrap();
+            JsonSerializer.decode_response(message).unwrap()
+        });
+        for _ in 0..2 {
+            match responses.next() {
+                Some(GrinboxResponse::Challenge { .. }) => {}
+                response => panic!("unexpected response: {:?}", response),
+            }
+        }
+        match responses.next() {
+            Some(GrinboxResponse::Error { kind, .. }) => assert_eq!(kind, GrinboxError::RateLimited),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn challenge_expires_after_ttl() {
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config());
+        let issued_at = Instant::now();
+        server.challenge_issued_at = issued_at;
+
+        assert!(!server.is_challenge_expired(issued_at + Duration::from_secs(60)));
+        assert!(server.is_challenge_expired(issued_at + Duration::from_secs(60) + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn expired_challenge_is_refused_and_reissued() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+        let issued_at = Instant::now();
+        server.challenge_issued_at = issued_at;
+        let expired = issued_at + Duration::from_secs(61);
+
+        let stale_challenge = server.get_challenge_raw().to_string();
+        let request = subscribe_request(&server, 1);
+        match server.handle_request(request, expired).unwrap() {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::InvalidChallenge),
+            response => panic!("unexpected response: {:?}", response),
+        }
+        assert_ne!(server.get_challenge_raw(), stale_challenge);
+
+        let request = subscribe_request(&server, 1);
+        match server.handle_request(request, expired).unwrap() {
+            GrinboxResponse::Ok { subscription_id, .. } => assert!(subscription_id.is_some()),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn reaper_expires_stale_challenge() {
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config);
+        let reaper = std::sync::Arc::new(IdleReaper::new());
+        let issued_at = Instant::now();
+        reaper.register(&server.id, server.inner.clone(), issued_at);
+        server.idle_reaper = Some(reaper.clone());
+
+        reaper.reap(issued_at + Duration::from_secs(60), None, Duration::from_secs(60));
+        assert!(!server.is_challenge_expired(issued_at + Duration::from_secs(60)));
+
+        reaper.reap(issued_at + Duration::from_secs(61), None, Duration::from_secs(60));
+        let request = subscribe_request(&server, 1);
+        match server.handle_request(request, issued_at + Duration::from_secs(61)).unwrap() {
+            GrinboxResponse::Error { kind, .. } => assert_eq!(kind, GrinboxError::InvalidChallenge),
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let request = subscribe_request(&server, 1);
+        match server.handle_request(request, issued_at + Duration::from_secs(61)).unwrap() {
+            GrinboxResponse::Ok { subscription_id, .. } => assert!(subscription_id.is_some()),
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn pongs_keep_a_connection_active() {
+        let (mut server, _nats_receiver, _response_handlers_receiver) = async_server(config());
+        let reaper = std::sync::Arc::new(IdleReaper::new());
+        reaper.register(&server.id, server.inner.clone(), Instant::now());
+        server.idle_reaper = Some(reaper.clone());
+
+        std::thread::sleep(Duration::from_millis(10));
+        let ponged_at = Instant::now();
+        server.on_frame(Frame::pong(vec![])).unwrap();
+
+        let reaped = reaper.reap(ponged_at + Duration::from_secs(60), Some(Duration::from_secs(60)), Duration::from_secs(600));
+        assert!(reaped.is_empty());
+    }
+
+    #[test]
+    fn deliveries_keep_a_connection_active() {
+        let (status_sender, _status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let mut config = config();
+        config.max_signed_bytes = None;
+        let (out, messages) = recorded_connection();
+        let (mut server, response_handlers_receiver) =
+            async_server_with_connection(config, std::sync::Arc::new(WebsocketFederator::new()), broker_sender, out);
+        let reaper = std::sync::Arc::new(IdleReaper::new());
+        reaper.register(&server.id, server.inner.clone(), Instant::now());
+        server.idle_reaper = Some(reaper.clone());
+
+        match subscribe_with_key(&mut server, 1) {
+            GrinboxResponse::Ok { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        let delivered_after = Instant::now();
+        match server.handle_request(post_request(1, "slate".to_string()), Instant::now()) {
+            Some(GrinboxResponse::Ok { .. }) => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let handler = response_handlers_receiver.wait().next().unwrap().unwrap();
+        AsyncServer::init().unbounded_send(handler).unwrap();
+        match JsonSerializer.decode_response(messages.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap() {
+            GrinboxResponse::Slate { str, .. } => assert_eq!(str, "slate"),
+            response => panic!("unexpected response: {:?}", response),
+        }
+
+        let reaped = reaper.reap(delivered_after + Duration::from_secs(60), Some(Duration::from_secs(60)), Duration::from_secs(600));
+        assert!(reaped.is_empty());
+    }
+
+    struct CloseRecorder {
+        closes: std::sync::mpsc::Sender<(CloseCode, String)>,
+    }
+
+    impl Handler for CloseRecorder {
+        fn on_close(&mut self, code: CloseCode, reason: &str) {
+            self.closes.send((code, reason.to_string())).unwrap();
+        }
+    }
+
+    #[test]
+    fn closing_server_handle_disconnects_client() {
+        let (handles_sender, handles) = std::sync::mpsc::channel();
+        let relay = ws::WebSocket::new(move |out: Sender| {
+            let server = std::sync::Arc::new(std::sync::Mutex::new(Server {
+                id: "0".to_string(),
+                out,
+                serializer: std::sync::Arc::new(JsonSerializer),
+                backlog: None,
+                challenge_expired: false,
+            }));
+            handles_sender.send(server).unwrap();
+            |_msg: Message| -> WsResult<()> { Ok(()) }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (closes_sender, closes) = std::sync::mpsc::channel();
+        let client = std::thread::spawn(move || {
+            ws::connect(url, |_out| CloseRecorder {
+                closes: closes_sender.clone(),
+            })
+            .unwrap()
+        });
+
+        let server = handles.recv_timeout(Duration::from_secs(5)).unwrap();
+        server.lock().unwrap().close(CloseCode::Policy, "kicked").unwrap();
+
+        let (code, reason) = closes.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, CloseCode::Policy);
+        assert_eq!(reason, "kicked");
+        client.join().unwrap();
+    }
+
+    /// Encodes like `JsonSerializer`, except that no response can be encoded.
+    struct FailingSerializer;
+
+    impl Serializer for FailingSerializer {
+        fn subprotocol(&self) -> &'static str {
+            JsonSerializer.subprotocol()
+        }
+
+        fn encode_request(&self, request: &GrinboxRequest) -> Result<Message> {
+            JsonSerializer.encode_request(request)
+        }
+
+        fn decode_request(&self, message: Message) -> Result<GrinboxRequest> {
+            JsonSerializer.decode_request(message)
+        }
+
+        fn encode_response(&self, _response: &GrinboxResponse) -> Result<Message> {
+            Err(ErrorKind::GenericError("not serializable".to_string()).into())
+        }
+
+        fn decode_response(&self, message: Message) -> Result<GrinboxResponse> {
+            JsonSerializer.decode_response(message)
+        }
+    }
+
+    #[test]
+    fn response_failing_to_serialize_closes_the_connection() {
+        let (handles_sender, handles) = std::sync::mpsc::channel();
+        let relay = ws::WebSocket::new(move |out: Sender| {
+            let server = std::sync::Arc::new(std::sync::Mutex::new(Server {
+                id: "0".to_string(),
+                out,
+                serializer: std::sync::Arc::new(FailingSerializer),
+                backlog: None,
+                challenge_expired: false,
+            }));
+            handles_sender.send(server).unwrap();
+            |_msg: Message| -> WsResult<()> { Ok(()) }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (closes_sender, closes) = std::sync::mpsc::channel();
+        let client = std::thread::spawn(move || {
+            ws::connect(url, |_out| CloseRecorder {
+                closes: closes_sender.clone(),
+            })
+            .unwrap()
+        });
+
+        let server = handles.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(server.lock().unwrap().send(&AsyncServer::ok()).is_ok());
+
+        let (code, reason) = closes.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, CloseCode::Error);
+        assert_eq!(reason, "could not serialize response");
+        client.join().unwrap();
+    }
+
+    /// Tells the test once connected, then reads nothing until it is told to resume.
+    struct StalledClient {
+        opened: std::sync::mpsc::Sender<()>,
+        resume: std::sync::mpsc::Receiver<()>,
+        closes: std::sync::mpsc::Sender<(CloseCode, String)>,
+    }
+
+    impl Handler for StalledClient {
+        fn on_open(&mut self, _handshake: Handshake) -> WsResult<()> {
+            self.opened.send(()).unwrap();
+            self.resume.recv().unwrap();
+            Ok(())
+        }
+
+        fn on_close(&mut self, code: CloseCode, reason: &str) {
+            self.closes.send((code, reason.to_string())).unwrap();
+        }
+    }
+
+    #[test]
+    fn stalled_client_is_disconnected_past_backlog() {
+        let (handles_sender, handles) = std::sync::mpsc::channel();
+        let relay = ws::WebSocket::new(move |out: Sender| {
+            let server = std::sync::Arc::new(std::sync::Mutex::new(Server {
+                id: "0".to_string(),
+                out,
+                serializer: std::sync::Arc::new(JsonSerializer),
+                backlog: Some(Backlog::new(4096)),
+                challenge_expired: false,
+            }));
+            handles_sender.send(server).unwrap();
+            |_msg: Message| -> WsResult<()> { Ok(()) }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        std::thread::spawn(move || relay.run().unwrap());
+
+        let (opened_sender, opened) = std::sync::mpsc::channel();
+        let (resume, resume_receiver) = std::sync::mpsc::channel();
+        let (closes_sender, closes) = std::sync::mpsc::channel();
+        let mut resume_receiver = Some(resume_receiver);
+        let client = std::thread::spawn(move || {
+            ws::connect(url, |_out| StalledClient {
+                opened: opened_sender.clone(),
+                resume: resume_receiver.take().unwrap(),
+                closes: closes_sender.clone(),
+            })
+            .unwrap()
+        });
+
+        let server = handles.recv_timeout(Duration::from_secs(5)).unwrap();
+        opened.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let slate = || GrinboxResponse::Slate {
+            from: "sender".to_string(),
+            to: None,
+            str: "x".repeat(1024),
+            signature: "signature".to_string(),
+            challenge: "challenge".to_string(),
+            correlation_id: None,
+        };
+        let mut sent = 0;
+        while server.lock().unwrap().send(&slate()).is_ok() {
+            sent += 1;
+            assert!(sent < 100, "stalled client was never disconnected");
+        }
+        assert!(sent > 0);
+        assert!(server.lock().unwrap().backlog.as_ref().unwrap().pending() <= 4096);
+        assert!(server.lock().unwrap().send(&slate()).is_err());
+
+        resume.send(()).unwrap();
+        let (code, reason) = closes.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(code, CloseCode::Policy);
+        assert_eq!(reason, "outbound backlog exceeded");
+        client.join().unwrap();
+    }
+
+    /// Runs a relay on a loopback port, returning its url.
+    fn live_relay(config: ServerConfig) -> String {
+        live_relay_with_authenticator(config, std::sync::Arc::new(DefaultAuthenticator))
+    }
+
+    /// Runs a relay backed by an in-memory broker on a loopback port, letting `authenticator`
+    /// check its connections, and returns its url.
+    fn live_relay_with_authenticator(config: ServerConfig, authenticator: std::sync::Arc<dyn Authenticator>) -> String {
+        let (status_sender, status_receiver) = std::sync::mpsc::channel();
+        let broker_sender = InMemoryBroker::new().start(status_sender).unwrap();
+        let response_handlers_sender = AsyncServer::init();
+        let metrics = std::sync::Arc::new(RelayMetrics::new());
+        let federation_pool = std::sync::Arc::new(FederationPool::new(2, 16, metrics.clone()));
+        let settings = listener_settings(&config);
+        let factory = move |out: Sender| {
+            AsyncServer::new(
+                out,
+                broker_sender.clone(),
+                response_handlers_sender.clone(),
+                config.clone(),
+                std::sync::Arc::new(DefaultIdGenerator),
+                authenticator.clone(),
+                std::sync::Arc::new(WebsocketFederator::new()),
+                federation_pool.clone(),
+                None,
+                None,
+                metrics.clone(),
+            )
+        };
+        let listener = bind_listeners(&["127.0.0.1:0".to_string()], settings, factory)
+            .unwrap()
+            .pop()
+            .unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let _status_receiver = status_receiver;
+            listener.run().unwrap()
+        });
+        url
+    }
+
+    /// Connects to `url` with a client splitting its messages into frames of at most
+    /// `fragment_size` bytes, sends `text` and reports the reply to it, past the challenge every
+    /// connection opens with, or the close it gets instead.
+    fn send_fragmented(url: String, fragment_size: usize, text: String) -> std::result::Result<String, CloseCode> {
+        struct FragmentingClient {
+            out: Sender,
+            text: String,
+            greeted: bool,
+            result: std::sync::mpsc::Sender<std::result::Result<String, CloseCode>>,
+        }
+
+        impl Handler for FragmentingClient {
+            fn on_open(&mut self, _: Handshake) -> WsResult<()> {
+                self.out.send(self.text.clone())
+            }
+
+            fn on_message(&mut self, msg: Message) -> WsResult<()> {
+                if !self.greeted {
+                    self.greeted = true;
+                    return Ok(());
+                }
+                let _ = self.result.send(Ok(msg.into_text()?));
+                self.out.close(CloseCode::Normal)
+            }
+
+            fn on_close(&mut self, code: CloseCode, _reason: &str) {
+                let _ = self.result.send(Err(code));
+            }
+        }
+
+        let (result_sender, result) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut settings = ws::Settings::default();
+            settings.fragment_size = fragment_size;
+            let mut client = ws::Builder::new()
+                .with_settings(settings)
+                .build(move |out| FragmentingClient {
+                    out,
+                    text: text.clone(),
+                    greeted: false,
+                    result: result_sender.clone(),
+                })
+                .unwrap();
+            client.connect(ws::util::Url::parse(&url).unwrap()).unwrap();
+            client.run().unwrap();
+        });
+        result.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn fragmented_message_within_limit_is_accepted() {
+        let url = live_relay(config());
+        let request = r#"{"type":"Info"}"#;
+        assert!(request.len() > 4 && request.len() <= config().max_message_bytes.unwrap());
+
+        let response = send_fragmented(url, 4, request.to_string()).unwrap();
+        match JsonSerializer.decode_response(Message::text(response)).unwrap() {
+            GrinboxResponse::Info { .. } => {}
+            response => panic!("unexpected response: {:?}", response),
+        }
+    }
+
+    #[test]
+    fn fragmented_message_past_limit_closes_the_connection() {
+        let url = live_relay(config());
+        let request = format!(r#"{{"type":"PostSlate","str":"{}"}}"#, "a".repeat(64));
+
+        // Every fragment is below the cap, only their sum is above it.
+        assert_eq!(send_fragmented(url, 16, request), Err(CloseCode::Size));
+    }
+
+    #[test]
+    fn frame_past_limit_is_refused() {
+        let url = live_relay(config());
+        let request = format!(r#"{{"type":"PostSlate","str":"{}"}}"#, "a".repeat(64));
+
+        assert!(send_fragmented(url, 1024, request).is_err());
+    }
+
+    /// Lets through connections presenting `Authorization: Bearer <token>`.
+    struct BearerAuthenticator(&'static str);
+
+    impl Authenticator for BearerAuthenticator {
+        fn authenticate(&self, headers: &[(String, Vec<u8>)]) -> bool {
+            let expected = format!("Bearer {}", self.0);
+            headers
+                .iter()
+                .any(|(name, value)| name.eq_ignore_ascii_case("authorization") && value[..] == *expected.as_bytes())
+        }
+    }
+
+    /// Connects to `url`, presenting `authorization` when given, then subscribes with the key
+    /// made of `secret` and posts a slate to it, returning the answers to both.
+    fn subscribe_and_post(url: String, authorization: Option<&'static str>, secret: u8) -> Vec<GrinboxResponse> {
+        struct Client {
+            out: Sender,
+            authorization: Option<&'static str>,
+            secret: u8,
+            greeted: bool,
+            responses: std::sync::mpsc::Sender<GrinboxResponse>,
+        }
+
+        impl Handler for Client {
+            fn build_request(&mut self, url: &ws::util::Url) -> WsResult<Request> {
+                let mut request = Request::from_url(url)?;
+                if let Some(authorization) = self.authorization {
+                    request
+                        .headers_mut()
+                        .push(("Authorization".to_string(), authorization.as_bytes().to_vec()));
+                }
+                Ok(request)
+            }
+
+            fn on_message(&mut self, msg: Message) -> WsResult<()> {
+                match JsonSerializer.decode_response(msg).unwrap() {
+                    GrinboxResponse::Challenge { str, .. } => {
+                        if self.greeted {
+                            return Ok(());
+                        }
+                        self.greeted = true;
+                        let subscribe = subscribe_to_challenge(&str, self.secret);
+                        let post = post_request(self.secret, "slate".to_string());
+                        self.out.send(JsonSerializer.encode_request(&subscribe).unwrap())?;
+                        self.out.send(JsonSerializer.encode_request(&post).unwrap())
+                    }
+                    // The posted slate may be delivered before the post is answered.
+                    GrinboxResponse::Slate { .. } => Ok(()),
+                    response => {
+                        let _ = self.responses.send(response);
+                        Ok(())
+                    }
+                }
+            }
+        }
+
+        let (responses_sender, responses) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut client = ws::Builder::new()
+                .build(move |out| Client {
+                    out,
+                    authorization,
+                    secret,
+                    greeted: false,
+                    responses: responses_sender.clone(),
+                })
+                .unwrap();
+            client.connect(ws::util::Url::parse(&url).unwrap()).unwrap();
+            client.run().unwrap();
+        });
+        (0..2)
+            .map(|_| responses.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn authenticator_gates_subscribing_and_posting() {
+        let mut config = config();
+        config.max_message_bytes = None;
+        config.max_signed_bytes = None;
+        let url = live_relay_with_authenticator(config, std::sync::Arc::new(BearerAuthenticator("secret")));
+
+        for authorization in &[None, Some("Bearer wrong")] {
+            for response in subscribe_and_post(url.clone(), *authorization, 1) {
+                match response {
+                    GrinboxResponse::Error { kind: GrinboxError::Unauthorized, .. } => {}
+                    response => panic!("unexpected response: {:?}", response),
+                }
+            }
+        }
+
+        let responses = subscribe_and_post(url, Some("Bearer secret"), 1);
+        match (&responses[0], &responses[1]) {
+            (GrinboxResponse::Ok { subscription_id: Some(_), .. }, GrinboxResponse::Ok { subscription_id: None, .. }) => {}
+            responses => panic!("unexpected responses: {:?}", responses),
+        }
+    }
+
+    #[test]
+    fn listeners_accept_on_every_address() {
+        let (connections_sender, connections) = std::sync::mpsc::channel();
+        let addresses = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let listeners = bind_listeners(&addresses, ws::Settings::default(), move |_out: Sender| {
+            connections_sender.send(()).unwrap();
+            |_msg: Message| -> WsResult<()> { Ok(()) }
+        })
+        .unwrap();
+        assert_eq!(listeners.len(), 2);
+
+        let urls: Vec<_> = listeners
+            .iter()
+            .map(|listener| format!("ws://{}", listener.local_addr().unwrap()))
+            .collect();
+        assert_ne!(urls[0], urls[1]);
+        for listener in listeners {
+            std::thread::spawn(move || listener.run().unwrap());
+        }
+
+        for url in urls {
+            std::thread::spawn(move || ws::connect(url, |_out| |_msg: Message| -> WsResult<()> { Ok(()) }).unwrap());
+            connections.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Fixed-window counter allowing at most `limit` events per `window`.
+pub struct RequestWindow {
+    limit: u32,
+    window: Duration,
+    started_at: Instant,
+    count: u32,
+}
+
+impl RequestWindow {
+    pub fn new(limit: u32, window: Duration) -> RequestWindow {
+        RequestWindow {
+            limit,
+            window,
+            started_at: Instant::now(),
+            count: 0,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.started_at) >= self.window {
+            self.started_at = now;
+            self.count = 0;
+        }
+
+        if self.count >= self.limit {
+            return false;
+        }
+
+        self.count += 1;
+        true
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets keyed by peer IP, shared by every connection, each holding up to `limit`
+/// connection attempts and refilling at `limit` per `window`. Buckets that have refilled are
+/// dropped once per `window`, so addresses that stopped connecting are not kept around.
+pub struct ConnectionLimiter {
+    limit: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    pruned_at: Mutex<Instant>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: u32, window: Duration) -> ConnectionLimiter {
+        ConnectionLimiter {
+            limit,
+            window,
+            buckets: Mutex::new(HashMap::new()),
+            pruned_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.prune(&mut buckets, now);
+
+        let limit = f64::from(self.limit);
+        let bucket = buckets.entry(ip).or_insert(TokenBucket {
+            tokens: limit,
+            updated_at: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refilled(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let window = self.window.as_secs() as f64 + f64::from(self.window.subsec_nanos()) / 1e9;
+        let limit = f64::from(self.limit);
+        (bucket.tokens + elapsed * limit / window).min(limit)
+    }
+
+    fn prune(&self, buckets: &mut HashMap<IpAddr, TokenBucket>, now: Instant) {
+        let mut pruned_at = self.pruned_at.lock().unwrap();
+        if now.duration_since(*pruned_at) < self.window {
+            return;
+        }
+        *pruned_at = now;
+
+        let limit = f64::from(self.limit);
+        buckets.retain(|_, bucket| self.refilled(bucket, now) < limit);
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_beyond_limit_until_window_passes() {
+        let mut window = RequestWindow::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(window.allow(now));
+        assert!(window.allow(now));
+        assert!(window.allow(now));
+        assert!(!window.allow(now));
+        assert!(!window.allow(now + Duration::from_secs(59)));
+        assert!(window.allow(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn limits_connections_per_ip() {
+        let limiter = ConnectionLimiter::new(2, Duration::from_secs(60));
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.allow(first, now));
+        assert!(limiter.allow(first, now));
+        assert!(!limiter.allow(first, now));
+        assert!(limiter.allow(second, now));
+        assert!(limiter.allow(first, now + Duration::from_secs(30)));
+        assert!(!limiter.allow(first, now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn refilled_buckets_are_pruned() {
+        let limiter = ConnectionLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        for i in 0..10 {
+            assert!(limiter.allow(IpAddr::from([10, 0, 0, i]), now));
+        }
+        assert_eq!(limiter.tracked(), 10);
+
+        assert!(limiter.allow(IpAddr::from([10, 0, 1, 0]), now + Duration::from_secs(120)));
+        assert_eq!(limiter.tracked(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+
+/// Asks the SOCKS5 proxy at `proxy` to connect to `host`:`port`. The host is handed to the proxy
+/// as a domain name, so it is resolved on the proxy's side, which is what Tor needs for onion
+/// services. `timeout` bounds every step of the handshake.
+pub fn socks5_connect(proxy: &SocketAddr, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    if host.len() > 255 {
+        return Err(socks_error("host name is too long"));
+    }
+
+    let mut stream = TcpStream::connect_timeout(proxy, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method)?;
+    if method != [SOCKS_VERSION, NO_AUTHENTICATION] {
+        return Err(socks_error("proxy requires authentication"));
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0, ADDRESS_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error("proxy answered with another protocol version"));
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(socks_error(&format!("proxy could not connect (reply {})", reply[1])));
+    }
+
+    // The address the proxy bound is of no use here, but has to be read past.
+    let address_length = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length)?;
+            length[0] as usize
+        }
+        _ => return Err(socks_error("proxy answered with an unknown address type")),
+    };
+    let mut bound_address = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound_address)?;
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+fn socks_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("socks5: {}", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Accepts one connection, records the host of its CONNECT request and then echoes whatever
+    /// is sent through it.
+    fn fake_proxy() -> (SocketAddr, mpsc::Receiver<(String, u16)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[SOCKS_VERSION, NO_AUTHENTICATION]).unwrap();
+
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            let mut host = vec![0u8; request[4] as usize];
+            stream.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            stream.read_exact(&mut port).unwrap();
+            sender
+                .send((String::from_utf8(host).unwrap(), (port[0] as u16) << 8 | port[1] as u16))
+                .unwrap();
+            stream
+                .write_all(&[SOCKS_VERSION, SUCCEEDED, 0, ADDRESS_IPV4, 127, 0, 0, 1, 0, 0])
+                .unwrap();
+
+            let mut echo = stream.try_clone().unwrap();
+            let _ = io::copy(&mut stream, &mut echo);
+        });
+        (address, receiver)
+    }
+
+    #[test]
+    fn onion_host_is_passed_to_proxy() {
+        let (proxy, connects) = fake_proxy();
+        let host = format!("{}.onion", "a".repeat(56));
+
+        let mut remote = socks5_connect(&proxy, &host, 13420, Duration::from_secs(5)).unwrap();
+        assert_eq!(connects.recv().unwrap(), (host, 13420));
+
+        remote.write_all(b"ping").unwrap();
+        let mut echoed = [0u8; 4];
+        remote.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+}
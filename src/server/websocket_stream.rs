@@ -0,0 +1,217 @@
+use std::io::{self, Read, Write};
+use uuid::Uuid;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+const OPCODE_MASK: u8 = 0x0F;
+const LENGTH_MASK: u8 = 0x7F;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+const CLOSE_NORMAL: u16 = 1000;
+/// Longest upgrade response read from the relay.
+const MAX_HEAD_BYTES: usize = 8192;
+/// Longest message read from the relay; its answers to a forwarded request are small.
+const MAX_MESSAGE_BYTES: u64 = 1 << 20;
+
+/// A client websocket over a stream that is already connected, such as one opened through a
+/// SOCKS proxy, which `ws` cannot be handed. It only does what forwarding a request needs:
+/// text messages, answering pings and closing.
+pub struct WebsocketStream<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> WebsocketStream<S> {
+    /// Upgrades `stream` to a websocket to `host`, sending `headers` with the upgrade request.
+    /// Only the status of the upgrade response is checked, as the streams this is used for are
+    /// authenticated by the proxy that opened them.
+    pub fn handshake(mut stream: S, host: &str, headers: &[(&str, &str)]) -> io::Result<WebsocketStream<S>> {
+        let mut request = format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            host,
+            base64(Uuid::new_v4().as_bytes())
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        let head = read_head(&mut stream)?;
+        let status = head.lines().next().unwrap_or("");
+        if !status.starts_with("HTTP/1.1 101") {
+            return Err(websocket_error(&format!("upgrade refused: {}", status)));
+        }
+        Ok(WebsocketStream { stream })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    /// Reads the next message, answering pings on the way. The relay closing the connection is
+    /// an `UnexpectedEof` error.
+    pub fn read_text(&mut self) -> io::Result<String> {
+        let mut message = vec![];
+        loop {
+            let mut head = [0u8; 2];
+            self.stream.read_exact(&mut head)?;
+            if head[1] & MASKED != 0 {
+                return Err(websocket_error("relay sent a masked frame"));
+            }
+            let length = match head[1] & LENGTH_MASK {
+                126 => {
+                    let mut length = [0u8; 2];
+                    self.stream.read_exact(&mut length)?;
+                    u64::from(u16::from_be_bytes(length))
+                }
+                127 => {
+                    let mut length = [0u8; 8];
+                    self.stream.read_exact(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => u64::from(length),
+            };
+            if message.len() as u64 + length > MAX_MESSAGE_BYTES {
+                return Err(websocket_error("relay sent a message that is too large"));
+            }
+            let mut payload = vec![0u8; length as usize];
+            self.stream.read_exact(&mut payload)?;
+
+            match head[0] & OPCODE_MASK {
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "relay closed the connection")),
+                _ => {
+                    message.extend_from_slice(&payload);
+                    if head[0] & FIN != 0 {
+                        return String::from_utf8(message).map_err(|_| websocket_error("relay sent a message that is not text"));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.write_frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())
+    }
+
+    /// Client frames have to be masked, with a key the relay cannot predict.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![FIN | opcode];
+        let length = payload.len();
+        if length < 126 {
+            frame.push(MASKED | length as u8);
+        } else if length <= 0xFFFF {
+            frame.push(MASKED | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            frame.push(MASKED | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+
+        let key = Uuid::new_v4();
+        let mask = &key.as_bytes()[..4];
+        frame.extend_from_slice(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        self.stream.write_all(&frame)
+    }
+}
+
+/// Reads the response head up to the blank line ending it, one byte at a time so nothing the
+/// relay sends after it is consumed.
+fn read_head<S: Read>(stream: &mut S) -> io::Result<String> {
+    let mut head = vec![];
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_BYTES {
+            return Err(websocket_error("upgrade response is too large"));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | u32::from(*byte) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn websocket_error(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("websocket: {}", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpStream;
+    use ws::{Handler, Handshake, Message, Result as WsResult, Sender};
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(&[0xFF; 16]).len(), 24);
+    }
+
+    struct EchoServer {
+        out: Sender,
+    }
+
+    impl Handler for EchoServer {
+        fn on_open(&mut self, handshake: Handshake) -> WsResult<()> {
+            let greeting = handshake
+                .request
+                .header("X-Greeting")
+                .map(|value| String::from_utf8_lossy(value).to_string())
+                .unwrap_or_default();
+            self.out.ping(vec![])?;
+            self.out.send(greeting)
+        }
+
+        fn on_message(&mut self, msg: Message) -> WsResult<()> {
+            self.out.send(msg)
+        }
+    }
+
+    #[test]
+    fn talks_to_a_ws_server_over_a_connected_stream() {
+        let server = ws::Builder::new()
+            .build(|out| EchoServer { out })
+            .unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run().unwrap());
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut websocket = WebsocketStream::handshake(stream, &address.to_string(), &[("X-Greeting", "hello")]).unwrap();
+        assert_eq!(websocket.read_text().unwrap(), "hello");
+
+        let long = "a".repeat(70000);
+        for text in &["ping", long.as_str()] {
+            websocket.send_text(text).unwrap();
+            assert_eq!(&websocket.read_text().unwrap(), text);
+        }
+        websocket.close().unwrap();
+        assert_eq!(websocket.read_text().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}